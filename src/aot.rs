@@ -0,0 +1,596 @@
+//! Ahead-of-time compilation of whole programs to relocatable objects.
+//!
+//! Top-level functions become native functions and the remaining top-level
+//! expressions the body of `main`, so the object can be linked into an
+//! executable with the system C compiler. `print`, string concatenation and
+//! error reporting are implemented by a small runtime emitted into the same
+//! object, which only depends on libc. Float `^` calls `pow`, so programs
+//! using it also need libm.
+
+use cranelift::codegen::ir::{StackSlot, UserFuncName};
+use cranelift::prelude::*;
+use cranelift_module::{default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use crate::{
+    ast::{Expression, Value as AstValue},
+    interpreter::Arithmetic,
+    native::{CompileError, Definition, Errors, Program, Runtime},
+};
+
+/// Name of the definition holding the top-level expressions. It cannot clash
+/// with a Kox function since it is not a valid identifier.
+const MAIN: &str = "<main>";
+
+/// Largest number of decimals tried when printing a float.
+const MAX_DECIMALS: usize = 30;
+
+pub fn compile(program: &[Expression], arithmetic: Arithmetic) -> Result<Vec<u8>, CompileError> {
+    let error = |message: String| CompileError {
+        message,
+        line: 0,
+        column: 0,
+    };
+
+    let mut flag_builder = settings::builder();
+    flag_builder
+        .set("is_pic", "true")
+        .map_err(|e| error(e.to_string()))?;
+    flag_builder
+        .set("opt_level", "speed")
+        .map_err(|e| error(e.to_string()))?;
+    let isa = cranelift_native::builder()
+        .map_err(|e| error(format!("host machine is not supported: {}", e)))?
+        .finish(settings::Flags::new(flag_builder))
+        .map_err(|e| error(e.to_string()))?;
+    let builder = ObjectBuilder::new(isa, "kox", default_libcall_names())
+        .map_err(|e| error(e.to_string()))?;
+    let mut module = ObjectModule::new(builder);
+    let mut ctx = module.make_context();
+    let mut builder_context = FunctionBuilderContext::new();
+
+    // `main` ends in nil so that the value of the last expression, which is
+    // discarded, needs no type.
+    let mut expressions: Vec<Expression> = program
+        .iter()
+        .filter(|expression| !matches!(expression, Expression::Function { .. }))
+        .cloned()
+        .collect();
+    expressions.push(Expression::Value {
+        value: AstValue::Nil,
+        line: 1,
+        column: 0,
+    });
+    let main_body = Expression::Block {
+        expressions,
+        line: 1,
+        column: 0,
+    };
+    let mut definitions = vec![Definition {
+        name: MAIN,
+        parameters: &[],
+        defaults: &[],
+        body: &main_body,
+    }];
+    for expression in program {
+        if let Expression::Function {
+            name,
+            parameters,
+            defaults,
+            rest,
+            body,
+            line,
+            column,
+            ..
+        } = expression
+        {
+            if rest.is_some() {
+                return Err(CompileError {
+                    message: "rest parameters are not supported".to_string(),
+                    line: *line,
+                    column: *column,
+                });
+            }
+            definitions.push(Definition {
+                name,
+                parameters,
+                defaults,
+                body,
+            });
+        }
+    }
+
+    let (runtime, errors) = RuntimeBuilder {
+        module: &mut module,
+        ctx: &mut ctx,
+        builder_context: &mut builder_context,
+    }
+    .build()?;
+
+    let mut program = Program::new(definitions, Some(runtime), errors, arithmetic);
+    let entry = program.specialize(MAIN, &[])?;
+    let ids = program.define(
+        &mut module,
+        &mut ctx,
+        &mut builder_context,
+        |i, instance| {
+            if i == entry {
+                ("kox_main".to_string(), Linkage::Local)
+            } else {
+                (
+                    format!("kox_{}_{}", instance.definition.name, i),
+                    Linkage::Local,
+                )
+            }
+        },
+    )?;
+
+    define_main(&mut module, &mut ctx, &mut builder_context, ids[entry])?;
+
+    module.finish().emit().map_err(|e| error(e.to_string()))
+}
+
+/// Emits the C entry point, which runs the top-level expressions.
+fn define_main(
+    module: &mut ObjectModule,
+    ctx: &mut codegen::Context,
+    builder_context: &mut FunctionBuilderContext,
+    kox_main: FuncId,
+) -> Result<(), CompileError> {
+    let mut signature = module.make_signature();
+    signature.returns.push(AbiParam::new(types::I32));
+    let id = module.declare_function("main", Linkage::Export, &signature)?;
+    ctx.func.signature = signature;
+    ctx.func.name = UserFuncName::user(0, id.as_u32());
+
+    let mut builder = FunctionBuilder::new(&mut ctx.func, builder_context);
+    let callee = module.declare_func_in_func(kox_main, builder.func);
+    let block = builder.create_block();
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    builder.ins().call(callee, &[]);
+    let zero = builder.ins().iconst(types::I32, 0);
+    builder.ins().return_(&[zero]);
+    builder.finalize();
+
+    module.define_function(id, ctx)?;
+    module.clear_context(ctx);
+    Ok(())
+}
+
+struct Libc {
+    puts: FuncId,
+    strlen: FuncId,
+    malloc: FuncId,
+    memcpy: FuncId,
+    strfromd: FuncId,
+    strtod: FuncId,
+    write: FuncId,
+    exit: FuncId,
+}
+
+struct RuntimeBuilder<'a> {
+    module: &'a mut ObjectModule,
+    ctx: &'a mut codegen::Context,
+    builder_context: &'a mut FunctionBuilderContext,
+}
+
+impl<'a> RuntimeBuilder<'a> {
+    fn pointer(&self) -> Type {
+        self.module.target_config().pointer_type()
+    }
+
+    fn declare(
+        &mut self,
+        name: &str,
+        linkage: Linkage,
+        params: &[Type],
+        returns: &[Type],
+    ) -> Result<FuncId, CompileError> {
+        let mut signature = self.module.make_signature();
+        for param in params {
+            signature.params.push(AbiParam::new(*param));
+        }
+        for ret in returns {
+            signature.returns.push(AbiParam::new(*ret));
+        }
+        Ok(self.module.declare_function(name, linkage, &signature)?)
+    }
+
+    fn data(&mut self, bytes: Vec<u8>) -> Result<DataId, CompileError> {
+        let id = self.module.declare_anonymous_data(false, false)?;
+        let mut data = DataDescription::new();
+        data.define(bytes.into_boxed_slice());
+        self.module.define_data(id, &data)?;
+        Ok(id)
+    }
+
+    fn string(&mut self, s: &str) -> Result<DataId, CompileError> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        self.data(bytes)
+    }
+
+    /// Defines a runtime function. `body` is handed a builder positioned in
+    /// the entry block and the function's parameters.
+    fn define(
+        &mut self,
+        name: &str,
+        params: &[Type],
+        returns: &[Type],
+        body: impl FnOnce(&mut FunctionBuilder, &mut ObjectModule, &[Value]),
+    ) -> Result<FuncId, CompileError> {
+        let id = self.declare(name, Linkage::Local, params, returns)?;
+        self.ctx.func.signature = self
+            .module
+            .declarations()
+            .get_function_decl(id)
+            .signature
+            .clone();
+        self.ctx.func.name = UserFuncName::user(0, id.as_u32());
+
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, self.builder_context);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        let params = builder.block_params(block).to_vec();
+        body(&mut builder, self.module, &params);
+        builder.seal_all_blocks();
+        builder.finalize();
+
+        self.module.define_function(id, self.ctx)?;
+        self.module.clear_context(self.ctx);
+        Ok(id)
+    }
+
+    fn build(mut self) -> Result<(Runtime, Errors), CompileError> {
+        let pointer = self.pointer();
+        let libc = Libc {
+            puts: self.declare("puts", Linkage::Import, &[pointer], &[types::I32])?,
+            strlen: self.declare("strlen", Linkage::Import, &[pointer], &[pointer])?,
+            malloc: self.declare("malloc", Linkage::Import, &[pointer], &[pointer])?,
+            memcpy: self.declare(
+                "memcpy",
+                Linkage::Import,
+                &[pointer, pointer, pointer],
+                &[pointer],
+            )?,
+            strfromd: self.declare(
+                "strfromd",
+                Linkage::Import,
+                &[pointer, pointer, pointer, types::F64],
+                &[types::I32],
+            )?,
+            strtod: self.declare(
+                "strtod",
+                Linkage::Import,
+                &[pointer, pointer],
+                &[types::F64],
+            )?,
+            write: self.declare(
+                "write",
+                Linkage::Import,
+                &[types::I32, pointer, pointer],
+                &[pointer],
+            )?,
+            exit: self.declare("exit", Linkage::Import, &[types::I32], &[])?,
+        };
+
+        let true_string = self.string("true")?;
+        let false_string = self.string("false")?;
+        let nil_string = self.string("nil")?;
+        let nan_string = self.string("NaN")?;
+        let error_strings = [
+            self.string("runtime error: Runtime error at line ")?,
+            self.string(" column ")?,
+            self.string(": ")?,
+            self.string("\n")?,
+        ];
+        // "%.0f", "%.1f", ... padded to a fixed stride so they can be indexed.
+        let mut formats = vec![];
+        for decimals in 0..=MAX_DECIMALS {
+            let mut format = format!("%.{}f", decimals).into_bytes();
+            format.resize(6, 0);
+            formats.extend(format);
+        }
+        let formats = self.data(formats)?;
+
+        let print_int = self.define("kox_print_int", &[types::I64], &[], |b, m, params| {
+            print_int(b, m, &libc, params[0])
+        })?;
+        let print_float = self.define("kox_print_float", &[types::F64], &[], |b, m, params| {
+            print_float(b, m, &libc, formats, nan_string, params[0])
+        })?;
+        let print_boolean =
+            self.define("kox_print_boolean", &[types::I8], &[], |b, m, params| {
+                let pointer = m.target_config().pointer_type();
+                let t = m.declare_data_in_func(true_string, b.func);
+                let f = m.declare_data_in_func(false_string, b.func);
+                let t = b.ins().global_value(pointer, t);
+                let f = b.ins().global_value(pointer, f);
+                let s = b.ins().select(params[0], t, f);
+                call(b, m, libc.puts, &[s]);
+                b.ins().return_(&[]);
+            })?;
+        let print_string = self.define("kox_print_string", &[pointer], &[], |b, m, params| {
+            call(b, m, libc.puts, &[params[0]]);
+            b.ins().return_(&[]);
+        })?;
+        let print_nil = self.define("kox_print_nil", &[], &[], |b, m, _| {
+            let pointer = m.target_config().pointer_type();
+            let nil = m.declare_data_in_func(nil_string, b.func);
+            let nil = b.ins().global_value(pointer, nil);
+            call(b, m, libc.puts, &[nil]);
+            b.ins().return_(&[]);
+        })?;
+        let concat = self.define(
+            "kox_concat",
+            &[pointer, pointer],
+            &[pointer],
+            |b, m, params| {
+                let (left, right) = (params[0], params[1]);
+                let left_len = call(b, m, libc.strlen, &[left]);
+                let right_len = call(b, m, libc.strlen, &[right]);
+                let len = b.ins().iadd(left_len, right_len);
+                let size = b.ins().iadd_imm(len, 1);
+                let result = call(b, m, libc.malloc, &[size]);
+                call(b, m, libc.memcpy, &[result, left, left_len]);
+                let tail = b.ins().iadd(result, left_len);
+                call(b, m, libc.memcpy, &[tail, right, right_len]);
+                let end = b.ins().iadd(result, len);
+                let nul = b.ins().iconst(types::I8, 0);
+                b.ins().store(MemFlags::trusted(), nul, end, 0);
+                b.ins().return_(&[result]);
+            },
+        )?;
+
+        let raise = self.define(
+            "kox_raise",
+            &[pointer, types::I64, types::I64],
+            &[],
+            |b, m, params| raise(b, m, &libc, error_strings, params),
+        )?;
+
+        Ok((
+            Runtime {
+                print_int,
+                print_float,
+                print_boolean,
+                print_string,
+                print_nil,
+                concat,
+            },
+            Errors {
+                raise,
+                failed: None,
+            },
+        ))
+    }
+}
+
+fn call(
+    builder: &mut FunctionBuilder,
+    module: &mut ObjectModule,
+    id: FuncId,
+    args: &[Value],
+) -> Value {
+    let callee = module.declare_func_in_func(id, builder.func);
+    let call = builder.ins().call(callee, args);
+    builder.inst_results(call)[0]
+}
+
+fn stack_buffer(builder: &mut FunctionBuilder, pointer: Type, size: u32) -> (StackSlot, Value) {
+    let slot =
+        builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, size));
+    let address = builder.ins().stack_addr(pointer, slot, 0);
+    (slot, address)
+}
+
+fn print_int(builder: &mut FunctionBuilder, module: &mut ObjectModule, libc: &Libc, value: Value) {
+    let digits = format_int(builder, module, value);
+    call(builder, module, libc.puts, &[digits]);
+    builder.ins().return_(&[]);
+}
+
+/// Writes the decimal digits of `value` backwards into a stack buffer and
+/// returns a pointer to the first one.
+fn format_int(builder: &mut FunctionBuilder, module: &mut ObjectModule, value: Value) -> Value {
+    let pointer = module.target_config().pointer_type();
+    let (_, buffer) = stack_buffer(builder, pointer, 24);
+    let end = builder.ins().iadd_imm(buffer, 23);
+    let nul = builder.ins().iconst(types::I8, 0);
+    builder.ins().store(MemFlags::trusted(), nul, end, 0);
+
+    // Negating i64::MIN overflows to itself, which is still the right
+    // magnitude when treated as unsigned.
+    let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, value, 0);
+    let negated = builder.ins().ineg(value);
+    let magnitude = builder.ins().select(negative, negated, value);
+
+    let digits = builder.create_block();
+    builder.append_block_param(digits, types::I64);
+    builder.append_block_param(digits, pointer);
+    let sign = builder.create_block();
+    builder.append_block_param(sign, pointer);
+    let output = builder.create_block();
+    builder.append_block_param(output, pointer);
+    builder.ins().jump(digits, &[magnitude, end]);
+
+    builder.switch_to_block(digits);
+    let n = builder.block_params(digits)[0];
+    let position = builder.block_params(digits)[1];
+    let position = builder.ins().iadd_imm(position, -1);
+    let digit = builder.ins().urem_imm(n, 10);
+    let digit = builder.ins().iadd_imm(digit, '0' as i64);
+    let digit = builder.ins().ireduce(types::I8, digit);
+    builder.ins().store(MemFlags::trusted(), digit, position, 0);
+    let rest = builder.ins().udiv_imm(n, 10);
+    builder
+        .ins()
+        .brif(rest, digits, &[rest, position], sign, &[position]);
+
+    builder.switch_to_block(sign);
+    let position = builder.block_params(sign)[0];
+    let minus = builder.create_block();
+    builder
+        .ins()
+        .brif(negative, minus, &[], output, &[position]);
+
+    builder.switch_to_block(minus);
+    let position = builder.ins().iadd_imm(position, -1);
+    let dash = builder.ins().iconst(types::I8, '-' as i64);
+    builder.ins().store(MemFlags::trusted(), dash, position, 0);
+    builder.ins().jump(output, &[position]);
+
+    builder.switch_to_block(output);
+    builder.block_params(output)[0]
+}
+
+/// Writes a NUL-terminated string to stderr.
+fn write_error(builder: &mut FunctionBuilder, module: &mut ObjectModule, libc: &Libc, s: Value) {
+    let len = call(builder, module, libc.strlen, &[s]);
+    let stderr = builder.ins().iconst(types::I32, 2);
+    call(builder, module, libc.write, &[stderr, s, len]);
+}
+
+/// Reports a runtime error the way the interpreter does and exits.
+fn raise(
+    builder: &mut FunctionBuilder,
+    module: &mut ObjectModule,
+    libc: &Libc,
+    strings: [DataId; 4],
+    params: &[Value],
+) {
+    let pointer = module.target_config().pointer_type();
+    let [prefix, column_label, separator, newline] = strings.map(|id| {
+        let global = module.declare_data_in_func(id, builder.func);
+        builder.ins().global_value(pointer, global)
+    });
+    let (message, line, column) = (params[0], params[1], params[2]);
+
+    write_error(builder, module, libc, prefix);
+    let line = format_int(builder, module, line);
+    write_error(builder, module, libc, line);
+    write_error(builder, module, libc, column_label);
+    let column = format_int(builder, module, column);
+    write_error(builder, module, libc, column);
+    write_error(builder, module, libc, separator);
+    write_error(builder, module, libc, message);
+    write_error(builder, module, libc, newline);
+
+    let exit = module.declare_func_in_func(libc.exit, builder.func);
+    let status = builder.ins().iconst(types::I32, 1);
+    builder.ins().call(exit, &[status]);
+    builder.ins().trap(TrapCode::UnreachableCodeReached);
+}
+
+/// Prints a float with the fewest decimals that read back as the same value,
+/// which matches how the interpreter displays floats.
+fn print_float(
+    builder: &mut FunctionBuilder,
+    module: &mut ObjectModule,
+    libc: &Libc,
+    formats: DataId,
+    nan_string: DataId,
+    value: Value,
+) {
+    let pointer = module.target_config().pointer_type();
+    let (_, buffer) = stack_buffer(builder, pointer, 512);
+
+    let is_nan = builder.ins().fcmp(FloatCC::Unordered, value, value);
+    let nan = builder.create_block();
+    let attempt = builder.create_block();
+    builder.append_block_param(attempt, pointer);
+    let output = builder.create_block();
+    let zero = builder.ins().iconst(pointer, 0);
+    builder.ins().brif(is_nan, nan, &[], attempt, &[zero]);
+
+    builder.switch_to_block(nan);
+    let nan_global = module.declare_data_in_func(nan_string, builder.func);
+    let nan_pointer = builder.ins().global_value(pointer, nan_global);
+    call(builder, module, libc.puts, &[nan_pointer]);
+    builder.ins().return_(&[]);
+
+    builder.switch_to_block(attempt);
+    let decimals = builder.block_params(attempt)[0];
+    let formats_global = module.declare_data_in_func(formats, builder.func);
+    let formats = builder.ins().global_value(pointer, formats_global);
+    let offset = builder.ins().imul_imm(decimals, 6);
+    let format = builder.ins().iadd(formats, offset);
+    let size = builder.ins().iconst(pointer, 512);
+    call(
+        builder,
+        module,
+        libc.strfromd,
+        &[buffer, size, format, value],
+    );
+    let null = builder.ins().iconst(pointer, 0);
+    let parsed = call(builder, module, libc.strtod, &[buffer, null]);
+    let exact = builder.ins().fcmp(FloatCC::Equal, parsed, value);
+    let last = builder
+        .ins()
+        .icmp_imm(IntCC::Equal, decimals, MAX_DECIMALS as i64);
+    let done = builder.ins().bor(exact, last);
+    let next = builder.ins().iadd_imm(decimals, 1);
+    builder.ins().brif(done, output, &[], attempt, &[next]);
+
+    builder.switch_to_block(output);
+    call(builder, module, libc.puts, &[buffer]);
+    builder.ins().return_(&[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed;
+
+    fn compile(input: &str) -> Result<Vec<u8>, CompileError> {
+        super::compile(&parsed(input), Arithmetic::Checked)
+    }
+
+    fn error(input: &str) -> String {
+        match compile(input) {
+            Ok(_) => panic!("compiled"),
+            Err(e) => e.message,
+        }
+    }
+
+    #[test]
+    fn programs_compile_to_objects() {
+        let object = compile(
+            "fn fib(n, a = 0, b = 1) { if n == 0 { a } else { fib(n - 1, b, a + b) } }
+            print(fib(50));
+            print(\"done\" + \"!\");",
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        #[cfg(target_os = "linux")]
+        assert_eq!(&object[..4], b"\x7fELF");
+        assert!(!object.is_empty());
+    }
+
+    #[test]
+    fn unsupported_programs_are_errors() {
+        assert_eq!(
+            error("fn f(...r) { 1 } print(1);"),
+            "rest parameters are not supported"
+        );
+        assert_eq!(
+            error("fn f(a, b = 1) { a } print(f(1, 2, 3));"),
+            "expected 1 to 2 arguments but got 3"
+        );
+        assert_eq!(error("let l = [1];"), "lists are not supported");
+        assert_eq!(
+            error("fn f(n) { 2 ^ n } print(f(-1));"),
+            "integer powers need a literal exponent, since a negative one makes a float"
+        );
+    }
+
+    #[test]
+    fn discarded_branches_may_differ_in_type() {
+        compile("let m = 0; if m == 0 { m = 1 }").unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            error("fn f(n) { if n > 0 { 1 } } print(f(1));"),
+            "branches of the if have different types"
+        );
+    }
+}
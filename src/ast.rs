@@ -1,4 +1,5 @@
 use crate::token::Token;
+use num_bigint::BigInt;
 use std::fmt::Display;
 
 #[derive(PartialEq, Debug, Clone)]
@@ -10,19 +11,38 @@ pub enum Expression {
         line: usize,
         column: usize,
     },
+    Logical {
+        left: Box<Expression>,
+        operator: Token,
+        right: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
     Call {
         function: Box<Expression>,
         arguments: Vec<Expression>,
+        /// Arguments given by name, `name: value`, after the others.
+        named: Vec<(String, Expression)>,
         line: usize,
         column: usize,
     },
     Identifier {
         ident: String,
+        /// Where the variable is, filled in by the resolver.
+        slot: Option<Slot>,
         line: usize,
         column: usize,
     },
     Assign {
         name: String,
+        /// Where the variable is, filled in by the resolver.
+        slot: Option<Slot>,
         value: Box<Expression>,
         line: usize,
         column: usize,
@@ -34,6 +54,8 @@ pub enum Expression {
     },
     Let {
         name: String,
+        /// The index of the variable in its scope, filled in by the resolver.
+        slot: Option<usize>,
         value: Box<Expression>,
         line: usize,
         column: usize,
@@ -57,29 +79,246 @@ pub enum Expression {
     },
     Function {
         name: String,
+        /// The index of the function in its scope, filled in by the resolver.
+        /// Methods are not variables and have none.
+        slot: Option<usize>,
         parameters: Vec<String>,
+        /// The default values of the last parameters, which calls can leave
+        /// out.
+        defaults: Vec<Expression>,
+        /// `...name`, a parameter after the others holding a list of any
+        /// further arguments.
+        rest: Option<String>,
+        body: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    /// An anonymous function, `fn(x) { ... }` or `(x) => ...`, which is a
+    /// value rather than a declaration.
+    Lambda {
+        parameters: Vec<String>,
+        defaults: Vec<Expression>,
+        rest: Option<String>,
         body: Box<Expression>,
         line: usize,
         column: usize,
     },
     For {
         ident: String,
+        /// The second name in `for (key, value) in map`.
+        value_ident: Option<String>,
         expr: Box<Expression>,
         body: Box<Expression>,
         line: usize,
         column: usize,
     },
+    While {
+        condition: Box<Expression>,
+        body: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    Break {
+        value: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    Continue {
+        line: usize,
+        column: usize,
+    },
+    Range {
+        start: Box<Expression>,
+        end: Box<Expression>,
+        step: Option<Box<Expression>>,
+        inclusive: bool,
+        line: usize,
+        column: usize,
+    },
+    List {
+        elements: Vec<Expression>,
+        line: usize,
+        column: usize,
+    },
+    Index {
+        object: Box<Expression>,
+        index: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    SetIndex {
+        object: Box<Expression>,
+        index: Box<Expression>,
+        value: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    Map {
+        entries: Vec<(Expression, Expression)>,
+        line: usize,
+        column: usize,
+    },
+    /// A struct declaration. Every method is an `Expression::Function`.
+    Struct {
+        name: String,
+        /// The index of the struct in its scope, filled in by the resolver.
+        slot: Option<usize>,
+        fields: Vec<String>,
+        methods: Vec<Expression>,
+        line: usize,
+        column: usize,
+    },
+    /// A class declaration. Every method is an `Expression::Function`.
+    Class {
+        name: String,
+        /// The index of the class in its scope, filled in by the resolver.
+        slot: Option<usize>,
+        superclass: Option<String>,
+        /// Where the superclass is, filled in by the resolver.
+        superclass_slot: Option<Slot>,
+        methods: Vec<Expression>,
+        line: usize,
+        column: usize,
+    },
+    /// `super.method`, a method of the superclass bound to `this`.
+    Super {
+        method: String,
+        /// Where `super` is, filled in by the resolver. `this` is the first
+        /// variable of the same scope.
+        slot: Option<Slot>,
+        line: usize,
+        column: usize,
+    },
+    /// `Name { field: value, ... }`, with the fields in source order.
+    Construct {
+        name: String,
+        /// Where the struct is, filled in by the resolver.
+        slot: Option<Slot>,
+        fields: Vec<(String, Expression)>,
+        line: usize,
+        column: usize,
+    },
+    Get {
+        object: Box<Expression>,
+        name: String,
+        line: usize,
+        column: usize,
+    },
+    Set {
+        object: Box<Expression>,
+        name: String,
+        value: Box<Expression>,
+        line: usize,
+        column: usize,
+    },
+    /// An enum declaration. Each variant has the names of its fields, which
+    /// are empty for a variant written without parentheses.
+    Enum {
+        name: String,
+        /// The index of the enum in its scope, filled in by the resolver.
+        slot: Option<usize>,
+        variants: Vec<(String, Vec<String>)>,
+        line: usize,
+        column: usize,
+    },
+    /// `match value { pattern if guard => result, ... }`, which evaluates to
+    /// the result of the first arm that matches, or nil if none does.
+    Match {
+        value: Box<Expression>,
+        arms: Vec<MatchArm>,
+        line: usize,
+        column: usize,
+    },
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub body: Expression,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Pattern {
+    /// `_`
+    Wildcard,
+    /// A name, which is bound to the value matched. The names of a pattern
+    /// take the first slots of its arm's scope, in order.
+    Binding(String),
+    /// A literal, matched by equality.
+    Value(Value),
+    /// `Enum.Variant(pattern, ...)`, with a pattern for each field.
+    Variant {
+        enumeration: String,
+        /// Where the enum is, filled in by the resolver.
+        slot: Option<Slot>,
+        variant: String,
+        fields: Vec<Pattern>,
+    },
+}
+
+/// Where a variable lives: `depth` scopes out from the one it is used in, at
+/// `index` among the variables of that scope.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Value {
     Int(i64),
+    BigInt(BigInt),
     Float(f64),
     String(String),
     Boolean(bool),
     Nil,
 }
 
+impl Expression {
+    pub fn position(&self) -> (usize, usize) {
+        match self {
+            Expression::Binary { line, column, .. }
+            | Expression::Logical { line, column, .. }
+            | Expression::Unary { line, column, .. }
+            | Expression::Call { line, column, .. }
+            | Expression::Identifier { line, column, .. }
+            | Expression::Assign { line, column, .. }
+            | Expression::Value { line, column, .. }
+            | Expression::Let { line, column, .. }
+            | Expression::Return { line, column, .. }
+            | Expression::Block { line, column, .. }
+            | Expression::If { line, column, .. }
+            | Expression::Function { line, column, .. }
+            | Expression::Lambda { line, column, .. }
+            | Expression::For { line, column, .. }
+            | Expression::While { line, column, .. }
+            | Expression::Break { line, column, .. }
+            | Expression::Continue { line, column }
+            | Expression::Range { line, column, .. }
+            | Expression::List { line, column, .. }
+            | Expression::Index { line, column, .. }
+            | Expression::SetIndex { line, column, .. }
+            | Expression::Map { line, column, .. }
+            | Expression::Struct { line, column, .. }
+            | Expression::Class { line, column, .. }
+            | Expression::Super { line, column, .. }
+            | Expression::Construct { line, column, .. }
+            | Expression::Get { line, column, .. }
+            | Expression::Set { line, column, .. }
+            | Expression::Enum { line, column, .. }
+            | Expression::Match { line, column, .. } => (*line, *column),
+        }
+    }
+}
+
+impl Pattern {
+    /// Whether the pattern matches every value.
+    pub fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Binding(_))
+    }
+}
+
 impl Display for Expression {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
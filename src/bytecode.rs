@@ -0,0 +1,126 @@
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+
+/// A single VM instruction. Jump targets are absolute instruction indices
+/// and the other operands index the chunk's constants, the frame's local
+/// slots, the closure's upvalues or the program's globals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    Constant(u16),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal(u16),
+    SetLocal(u16),
+    GetUpvalue(u16),
+    SetUpvalue(u16),
+    GetGlobal(u16),
+    SetGlobal(u16),
+    DefineGlobal(u16),
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Negate,
+    Not,
+    Jump(u32),
+    JumpIfFalse(u32),
+    Call(u8),
+    Closure(u16),
+    /// Replaces the operand's number of values on the stack with a list of
+    /// them.
+    BuildList(u16),
+    /// Like `BuildList`, but for a map from the operand's number of key and
+    /// value pairs.
+    BuildMap(u16),
+    /// Replaces a start, an end and a step on the stack with the range
+    /// between them, which includes the end if the operand is true.
+    BuildRange(bool),
+    GetIndex,
+    SetIndex,
+    /// Replaces a range, string, list or map on the stack with an iterator
+    /// over its values, or over the keys of a map.
+    GetIter,
+    /// Replaces a map on the stack with an iterator over its entries.
+    GetEntries,
+    /// Pushes the next value of the iterator on top of the stack and the
+    /// value of its key, or nil if it is not over entries. Jumps to the
+    /// operand instead once there are no more.
+    ForIter(u32),
+    /// Moves the variables in slots from the operand up to the heap, for
+    /// the closures that captured them, when their scope ends.
+    CloseUpvalues(u16),
+    Return,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    String(String),
+    Function(Rc<Prototype>),
+}
+
+/// Where a closure finds a captured variable when it is created: a local
+/// slot of the enclosing function or one of the enclosing closure's own
+/// upvalues.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capture {
+    pub local: bool,
+    pub index: u16,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    /// Source line and column of each instruction, for runtime errors.
+    pub positions: Vec<(usize, usize)>,
+    pub constants: Vec<Constant>,
+}
+
+impl Chunk {
+    pub fn push(&mut self, instruction: Instruction, position: (usize, usize)) -> usize {
+        self.code.push(instruction);
+        self.positions.push(position);
+        self.code.len() - 1
+    }
+}
+
+/// A compiled function. Slot 0 of its frame holds the function itself,
+/// followed by the parameters, the rest parameter if there is one and then
+/// the other locals.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prototype {
+    pub name: String,
+    /// The names of the parameters, not counting the rest parameter, which
+    /// errors about missing arguments give.
+    pub parameters: Vec<String>,
+    /// Where a call starts running: at `entries[i]` when it gives `i` of the
+    /// parameters with default values. The code from there computes the
+    /// defaults left out, in order, and then runs the body.
+    pub entries: Vec<u32>,
+    /// Whether a list of any further arguments follows the parameters.
+    pub rest: bool,
+    pub slots: u16,
+    pub upvalues: Vec<Capture>,
+    pub chunk: Chunk,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    /// Names of the global variables, indexed by the global instructions.
+    pub globals: Vec<String>,
+    /// The top-level code, as a function without parameters.
+    pub main: Rc<Prototype>,
+}
@@ -0,0 +1,933 @@
+//! Compiles the AST to bytecode for the VM.
+//!
+//! Variables are found from the slots the resolver gave them: locals live in
+//! numbered slots of their function's frame, variables of enclosing
+//! functions are reached through the closure's upvalues, and those of the
+//! top level and the builtins are globals.
+
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    ast::{Expression, Slot, Value},
+    bytecode::{Capture, Chunk, Constant, Instruction, Program, Prototype},
+    native::CompileError,
+    resolver::declarations,
+    token::Token,
+};
+
+fn error(message: String, expression: &Expression) -> CompileError {
+    let (line, column) = expression.position();
+    CompileError {
+        message,
+        line,
+        column,
+    }
+}
+
+/// Whether two constants can share a slot in the pool. Floats are compared
+/// bitwise so that `0.0` and `-0.0` stay apart.
+fn same(a: &Constant, b: &Constant) -> bool {
+    match (a, b) {
+        (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits(),
+        (Constant::Function(_), _) => false,
+        _ => a == b,
+    }
+}
+
+struct Local {
+    depth: usize,
+    slot: u16,
+    captured: bool,
+}
+
+/// A loop being compiled, for the `break`s and `continue`s in it.
+struct Loop {
+    /// Where `continue` jumps to.
+    start: usize,
+    /// How many values were on the stack above the locals when the loop
+    /// started, which `break` leaves.
+    pending: usize,
+    /// How many values are on the stack in the body, which `continue`
+    /// leaves.
+    kept: usize,
+    /// The first slot of the locals declared in the loop.
+    slot: u16,
+    /// Jumps out of the loop from `break`s, to patch once its end is known.
+    breaks: Vec<usize>,
+}
+
+/// A function whose body is being compiled.
+struct FunctionState {
+    name: String,
+    parameters: Vec<String>,
+    entries: Vec<u32>,
+    rest: bool,
+    chunk: Chunk,
+    upvalues: Vec<Capture>,
+    locals: Vec<Local>,
+    depth: usize,
+    slots: u16,
+    /// How many values the code being compiled leaves on the stack above
+    /// the locals, such as the left operand while compiling the right.
+    pending: usize,
+    loops: Vec<Loop>,
+}
+
+impl FunctionState {
+    fn new(name: String, parameters: Vec<String>, depth: usize) -> Self {
+        Self {
+            name,
+            parameters,
+            entries: vec![0],
+            rest: false,
+            chunk: Chunk::default(),
+            upvalues: vec![],
+            // Slot 0 holds the function being called.
+            locals: vec![Local {
+                depth,
+                slot: 0,
+                captured: false,
+            }],
+            depth,
+            slots: 1,
+            pending: 0,
+            loops: vec![],
+        }
+    }
+
+    fn finish(self) -> Prototype {
+        Prototype {
+            name: self.name,
+            parameters: self.parameters,
+            entries: self.entries,
+            rest: self.rest,
+            slots: self.slots,
+            upvalues: self.upvalues,
+            chunk: self.chunk,
+        }
+    }
+}
+
+/// A scope the resolver made inside the top level.
+struct Scope {
+    /// The function the scope is in.
+    function: usize,
+    /// The local slot of each variable of the scope, by the index the
+    /// resolver gave it.
+    slots: Vec<u16>,
+}
+
+enum Variable {
+    Local(u16),
+    Upvalue(u16),
+    Global(u16),
+}
+
+pub struct Compiler {
+    globals: Vec<String>,
+    global_indices: HashMap<String, u16>,
+    functions: Vec<FunctionState>,
+    /// The scopes around the code being compiled, from the outermost.
+    scopes: Vec<Scope>,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            globals: vec![],
+            global_indices: HashMap::new(),
+            functions: vec![FunctionState::new("<main>".to_string(), vec![], 0)],
+            scopes: vec![],
+        }
+    }
+
+    pub fn compile(mut self, program: &[Expression]) -> Result<Program, CompileError> {
+        for expression in program {
+            self.expression(expression)?;
+            self.emit(Instruction::Pop, expression);
+        }
+        let end = (0, 0);
+        self.current().chunk.push(Instruction::Nil, end);
+        self.current().chunk.push(Instruction::Return, end);
+
+        let main = self.functions.pop().unwrap().finish();
+        Ok(Program {
+            globals: self.globals,
+            main: Rc::new(main),
+        })
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, instruction: Instruction, expression: &Expression) -> usize {
+        let position = expression.position();
+        self.current().chunk.push(instruction, position)
+    }
+
+    fn constant(
+        &mut self,
+        constant: Constant,
+        expression: &Expression,
+    ) -> Result<u16, CompileError> {
+        let constants = &mut self.current().chunk.constants;
+        let index = match constants
+            .iter()
+            .position(|existing| same(existing, &constant))
+        {
+            Some(index) => index,
+            None => {
+                constants.push(constant);
+                constants.len() - 1
+            }
+        };
+        u16::try_from(index)
+            .map_err(|_| error("too many constants in one function".to_string(), expression))
+    }
+
+    /// Points a jump emitted earlier at the next instruction.
+    fn patch(&mut self, jump: usize) {
+        let target = self.current().chunk.code.len() as u32;
+        match &mut self.current().chunk.code[jump] {
+            Instruction::Jump(to) | Instruction::JumpIfFalse(to) | Instruction::ForIter(to) => {
+                *to = target
+            }
+            instruction => unreachable!("patching {:?}", instruction),
+        }
+    }
+
+    /// Compiles an expression while `pending` more values computed before it
+    /// wait on the stack.
+    fn operand(&mut self, expression: &Expression, pending: usize) -> Result<(), CompileError> {
+        self.current().pending += pending;
+        let result = self.expression(expression);
+        self.current().pending -= pending;
+        result
+    }
+
+    /// Starts a loop whose `continue`s jump to `start` and whose body runs
+    /// with `kept` more values on the stack than the loop started with.
+    fn begin_loop(&mut self, start: usize, kept: usize) {
+        let function = self.current();
+        function.loops.push(Loop {
+            start,
+            pending: function.pending,
+            kept: function.pending + kept,
+            slot: function.locals.len() as u16,
+            breaks: vec![],
+        });
+    }
+
+    /// Points the `break`s of the innermost loop at the next instruction.
+    fn end_loop(&mut self) {
+        let breaks = self.current().loops.pop().unwrap().breaks;
+        for jump in breaks {
+            self.patch(jump);
+        }
+    }
+
+    fn innermost(&mut self) -> &mut Loop {
+        self.current()
+            .loops
+            .last_mut()
+            .expect("the parser only allows 'break' and 'continue' in loops")
+    }
+
+    /// Leaves the body of the innermost loop for a `break` or `continue`,
+    /// dropping the values on the stack down to `kept` and closing the
+    /// loop's locals that closures captured.
+    fn unwind(&mut self, kept: usize, expression: &Expression) {
+        let slot = self.innermost().slot;
+        let function = self.current();
+        let pops = function.pending - kept;
+        let captured = function
+            .locals
+            .iter()
+            .any(|local| local.slot >= slot && local.captured);
+        for _ in 0..pops {
+            self.emit(Instruction::Pop, expression);
+        }
+        if captured {
+            self.emit(Instruction::CloseUpvalues(slot), expression);
+        }
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.scopes.is_empty()
+    }
+
+    fn global(&mut self, name: &str, expression: &Expression) -> Result<u16, CompileError> {
+        if let Some(index) = self.global_indices.get(name) {
+            return Ok(*index);
+        }
+        let index = u16::try_from(self.globals.len())
+            .map_err(|_| error("too many global variables".to_string(), expression))?;
+        self.globals.push(name.to_string());
+        self.global_indices.insert(name.to_string(), index);
+        Ok(index)
+    }
+
+    /// Gives a new local a slot in the innermost block of the function.
+    fn declare(&mut self, expression: &Expression) -> Result<u16, CompileError> {
+        let function = self.current();
+        let slot = u16::try_from(function.locals.len())
+            .map_err(|_| error("too many local variables".to_string(), expression))?;
+        function.locals.push(Local {
+            depth: function.depth,
+            slot,
+            captured: false,
+        });
+        function.slots = function.slots.max(slot + 1);
+        Ok(slot)
+    }
+
+    /// Starts a scope of the resolver with a slot for each of its variables:
+    /// the first `declared`, such as parameters, and then those declared by
+    /// `expressions`, in the order the resolver numbered them.
+    fn begin_variables<'a>(
+        &mut self,
+        declared: usize,
+        expressions: impl IntoIterator<Item = &'a Expression>,
+        expression: &Expression,
+    ) -> Result<(), CompileError> {
+        let mut found = vec![];
+        for inner in expressions {
+            declarations(inner, &mut found);
+        }
+        let slots = (0..declared + found.len())
+            .map(|_| self.declare(expression))
+            .collect::<Result<_, _>>()?;
+        self.scopes.push(Scope {
+            function: self.functions.len() - 1,
+            slots,
+        });
+        Ok(())
+    }
+
+    /// The slot of a variable declared in the innermost scope.
+    fn local(&self, index: Option<usize>) -> u16 {
+        let scope = self.scopes.last().expect("globals have no slots");
+        scope.slots[index.expect("the resolver fills in slots")]
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().depth += 1;
+    }
+
+    fn end_scope(&mut self, expression: &Expression) {
+        self.scopes.pop();
+        let function = self.current();
+        function.depth -= 1;
+        let depth = function.depth;
+        let mut first_captured = None;
+        while let Some(local) = function.locals.last() {
+            if local.depth <= depth {
+                break;
+            }
+            if local.captured {
+                first_captured = Some(local.slot);
+            }
+            function.locals.pop();
+        }
+        if let Some(slot) = first_captured {
+            self.emit(Instruction::CloseUpvalues(slot), expression);
+        }
+    }
+
+    fn add_upvalue(
+        &mut self,
+        function: usize,
+        capture: Capture,
+        expression: &Expression,
+    ) -> Result<u16, CompileError> {
+        let upvalues = &mut self.functions[function].upvalues;
+        let index = match upvalues.iter().position(|existing| *existing == capture) {
+            Some(index) => index,
+            None => {
+                upvalues.push(capture);
+                upvalues.len() - 1
+            }
+        };
+        u16::try_from(index)
+            .map_err(|_| error("too many captured variables".to_string(), expression))
+    }
+
+    /// Reaches the local `slot` of the function `owner`, which encloses
+    /// `function`, capturing it in every function in between.
+    fn resolve_upvalue(
+        &mut self,
+        function: usize,
+        owner: usize,
+        slot: u16,
+        expression: &Expression,
+    ) -> Result<u16, CompileError> {
+        let enclosing = function - 1;
+        let capture = if enclosing == owner {
+            let locals = &mut self.functions[owner].locals;
+            if let Some(local) = locals.iter_mut().find(|local| local.slot == slot) {
+                local.captured = true;
+            }
+            Capture {
+                local: true,
+                index: slot,
+            }
+        } else {
+            Capture {
+                local: false,
+                index: self.resolve_upvalue(enclosing, owner, slot, expression)?,
+            }
+        };
+        self.add_upvalue(function, capture, expression)
+    }
+
+    fn resolve(
+        &mut self,
+        name: &str,
+        slot: &Option<Slot>,
+        expression: &Expression,
+    ) -> Result<Variable, CompileError> {
+        let slot = slot.expect("the resolver fills in slots");
+        if slot.depth >= self.scopes.len() {
+            return self.global(name, expression).map(Variable::Global);
+        }
+        let scope = &self.scopes[self.scopes.len() - 1 - slot.depth];
+        let (owner, local) = (scope.function, scope.slots[slot.index]);
+        let function = self.functions.len() - 1;
+        if owner == function {
+            return Ok(Variable::Local(local));
+        }
+        self.resolve_upvalue(function, owner, local, expression)
+            .map(Variable::Upvalue)
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        match expression {
+            Expression::Value { value, .. } => match value {
+                Value::Int(i) => {
+                    let index = self.constant(Constant::Int(*i), expression)?;
+                    self.emit(Instruction::Constant(index), expression);
+                }
+                Value::BigInt(i) => {
+                    let index = self.constant(Constant::BigInt(i.clone()), expression)?;
+                    self.emit(Instruction::Constant(index), expression);
+                }
+                Value::Float(f) => {
+                    let index = self.constant(Constant::Float(*f), expression)?;
+                    self.emit(Instruction::Constant(index), expression);
+                }
+                Value::String(s) => {
+                    let index = self.constant(Constant::String(s.clone()), expression)?;
+                    self.emit(Instruction::Constant(index), expression);
+                }
+                Value::Boolean(true) => {
+                    self.emit(Instruction::True, expression);
+                }
+                Value::Boolean(false) => {
+                    self.emit(Instruction::False, expression);
+                }
+                Value::Nil => {
+                    self.emit(Instruction::Nil, expression);
+                }
+            },
+            Expression::Identifier { ident, slot, .. } => {
+                let instruction = match self.resolve(ident, slot, expression)? {
+                    Variable::Local(slot) => Instruction::GetLocal(slot),
+                    Variable::Upvalue(index) => Instruction::GetUpvalue(index),
+                    Variable::Global(index) => Instruction::GetGlobal(index),
+                };
+                self.emit(instruction, expression);
+            }
+            Expression::Assign {
+                name, slot, value, ..
+            } => {
+                self.expression(value)?;
+                let instruction = match self.resolve(name, slot, expression)? {
+                    Variable::Local(slot) => Instruction::SetLocal(slot),
+                    Variable::Upvalue(index) => Instruction::SetUpvalue(index),
+                    Variable::Global(index) => Instruction::SetGlobal(index),
+                };
+                self.emit(instruction, expression);
+            }
+            Expression::Let {
+                name, slot, value, ..
+            } => {
+                self.expression(value)?;
+                let instruction = if self.is_global_scope() {
+                    Instruction::DefineGlobal(self.global(name, expression)?)
+                } else {
+                    Instruction::SetLocal(self.local(*slot))
+                };
+                self.emit(instruction, expression);
+            }
+            Expression::Binary {
+                left,
+                operator,
+                right,
+                ..
+            } => {
+                self.expression(left)?;
+                self.operand(right, 1)?;
+                let instruction = match operator {
+                    Token::Plus => Instruction::Add,
+                    Token::Minus => Instruction::Subtract,
+                    Token::Asterisk => Instruction::Multiply,
+                    Token::Slash => Instruction::Divide,
+                    Token::Percent => Instruction::Modulo,
+                    Token::Pow => Instruction::Power,
+                    Token::EqEq => Instruction::Equal,
+                    Token::BangEq => Instruction::NotEqual,
+                    Token::LessThan => Instruction::Less,
+                    Token::GreaterThan => Instruction::Greater,
+                    Token::LessThanEqual => Instruction::LessEqual,
+                    Token::GreaterThanEqual => Instruction::GreaterEqual,
+                    _ => {
+                        return Err(error(
+                            format!("Invalid operator: {:?}", operator),
+                            expression,
+                        ))
+                    }
+                };
+                self.emit(instruction, expression);
+            }
+            Expression::Logical {
+                left,
+                operator,
+                right,
+                ..
+            } => {
+                // Both operands are tested so that each must be a boolean,
+                // and the right one is skipped if the left decides the result.
+                self.expression(left)?;
+                let mut ends = vec![];
+                let mut falses = vec![self.emit(Instruction::JumpIfFalse(0), expression)];
+                if *operator == Token::Or {
+                    self.emit(Instruction::True, expression);
+                    ends.push(self.emit(Instruction::Jump(0), expression));
+                    self.patch(falses.pop().unwrap());
+                }
+                self.expression(right)?;
+                falses.push(self.emit(Instruction::JumpIfFalse(0), expression));
+                self.emit(Instruction::True, expression);
+                ends.push(self.emit(Instruction::Jump(0), expression));
+                for jump in falses {
+                    self.patch(jump);
+                }
+                self.emit(Instruction::False, expression);
+                for jump in ends {
+                    self.patch(jump);
+                }
+            }
+            Expression::Unary {
+                operator, right, ..
+            } => {
+                self.expression(right)?;
+                let instruction = match operator {
+                    Token::Minus => Instruction::Negate,
+                    Token::Bang => Instruction::Not,
+                    _ => {
+                        return Err(error(
+                            format!("Invalid unary operator: {:?}", operator),
+                            expression,
+                        ))
+                    }
+                };
+                self.emit(instruction, expression);
+            }
+            Expression::Call {
+                function,
+                arguments,
+                named,
+                ..
+            } => {
+                if !named.is_empty() {
+                    return Err(error(
+                        "named arguments are not supported by the bytecode compiler".to_string(),
+                        expression,
+                    ));
+                }
+                let count = u8::try_from(arguments.len())
+                    .map_err(|_| error("too many arguments in one call".to_string(), expression))?;
+                self.expression(function)?;
+                for (i, argument) in arguments.iter().enumerate() {
+                    self.operand(argument, i + 1)?;
+                }
+                self.emit(Instruction::Call(count), expression);
+            }
+            Expression::Return { value, .. } => {
+                self.expression(value)?;
+                self.emit(Instruction::Return, expression);
+            }
+            Expression::Block { expressions, .. } => {
+                self.begin_scope();
+                self.begin_variables(0, expressions, expression)?;
+                if expressions.is_empty() {
+                    self.emit(Instruction::Nil, expression);
+                }
+                for (i, inner) in expressions.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Instruction::Pop, inner);
+                    }
+                    self.expression(inner)?;
+                }
+                self.end_scope(expression);
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                self.expression(condition)?;
+                let otherwise = self.emit(Instruction::JumpIfFalse(0), expression);
+                self.expression(consequence)?;
+                let end = self.emit(Instruction::Jump(0), expression);
+                self.patch(otherwise);
+                match alternative {
+                    Some(alternative) => self.expression(alternative)?,
+                    None => {
+                        self.emit(Instruction::Nil, expression);
+                    }
+                }
+                self.patch(end);
+            }
+            Expression::Function {
+                name,
+                slot,
+                parameters,
+                defaults,
+                rest,
+                body,
+                ..
+            } => {
+                let define = if self.is_global_scope() {
+                    Instruction::DefineGlobal(self.global(name, expression)?)
+                } else {
+                    Instruction::SetLocal(self.local(*slot))
+                };
+                self.closure(name, parameters, defaults, rest, body, expression)?;
+                self.emit(define, expression);
+                self.emit(Instruction::Pop, expression);
+                self.emit(Instruction::Nil, expression);
+            }
+            Expression::Lambda {
+                parameters,
+                defaults,
+                rest,
+                body,
+                ..
+            } => self.closure("<lambda>", parameters, defaults, rest, body, expression)?,
+            // The iterator stays on the stack under the body, and each
+            // iteration has its own variables so that closures made in the
+            // body keep the values they had then.
+            Expression::For {
+                value_ident,
+                expr,
+                body,
+                ..
+            } => {
+                self.expression(expr)?;
+                let iterator = match value_ident {
+                    Some(_) => Instruction::GetEntries,
+                    None => Instruction::GetIter,
+                };
+                self.emit(iterator, expression);
+                let start = self.emit(Instruction::ForIter(0), expression);
+                self.begin_scope();
+                self.begin_loop(start, 1);
+                let declared = 1 + value_ident.iter().count();
+                self.begin_variables(declared, [body.as_ref()], expression)?;
+                let slot = self.local(Some(0));
+                if value_ident.is_some() {
+                    let value_slot = self.local(Some(1));
+                    self.emit(Instruction::SetLocal(value_slot), expression);
+                }
+                self.emit(Instruction::Pop, expression);
+                self.emit(Instruction::SetLocal(slot), expression);
+                self.emit(Instruction::Pop, expression);
+                self.operand(body, 1)?;
+                self.emit(Instruction::Pop, expression);
+                self.end_scope(expression);
+                self.emit(Instruction::Jump(start as u32), expression);
+                self.patch(start);
+                self.emit(Instruction::Pop, expression);
+                self.emit(Instruction::Nil, expression);
+                self.end_loop();
+            }
+            Expression::Range {
+                start,
+                end,
+                step,
+                inclusive,
+                ..
+            } => {
+                self.expression(start)?;
+                self.operand(end, 1)?;
+                match step {
+                    Some(step) => self.operand(step, 2)?,
+                    None => {
+                        let index = self.constant(Constant::Int(1), expression)?;
+                        self.emit(Instruction::Constant(index), expression);
+                    }
+                }
+                self.emit(Instruction::BuildRange(*inclusive), expression);
+            }
+            Expression::List { elements, .. } => {
+                let count = u16::try_from(elements.len())
+                    .map_err(|_| error("too many elements in one list".to_string(), expression))?;
+                for (i, element) in elements.iter().enumerate() {
+                    self.operand(element, i)?;
+                }
+                self.emit(Instruction::BuildList(count), expression);
+            }
+            Expression::Map { entries, .. } => {
+                let count = u16::try_from(entries.len())
+                    .map_err(|_| error("too many entries in one map".to_string(), expression))?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    self.operand(key, 2 * i)?;
+                    self.operand(value, 2 * i + 1)?;
+                }
+                self.emit(Instruction::BuildMap(count), expression);
+            }
+            Expression::Index { object, index, .. } => {
+                self.expression(object)?;
+                self.operand(index, 1)?;
+                self.emit(Instruction::GetIndex, expression);
+            }
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.expression(object)?;
+                self.operand(index, 1)?;
+                self.operand(value, 2)?;
+                self.emit(Instruction::SetIndex, expression);
+            }
+            Expression::While {
+                condition, body, ..
+            } => {
+                let start = self.current().chunk.code.len();
+                self.expression(condition)?;
+                let exit = self.emit(Instruction::JumpIfFalse(0), expression);
+                self.begin_loop(start, 0);
+                self.expression(body)?;
+                self.emit(Instruction::Pop, expression);
+                self.emit(Instruction::Jump(start as u32), expression);
+                self.patch(exit);
+                self.emit(Instruction::Nil, expression);
+                self.end_loop();
+            }
+            // The value of the loop is that of the `break`, which is left on
+            // the stack in place of the values dropped.
+            Expression::Break { value, .. } => {
+                let pending = self.current().pending;
+                let kept = self.innermost().pending;
+                self.unwind(kept, expression);
+                self.current().pending = kept;
+                self.expression(value)?;
+                self.current().pending = pending;
+                let jump = self.emit(Instruction::Jump(0), expression);
+                self.innermost().breaks.push(jump);
+            }
+            Expression::Continue { .. } => {
+                let (start, kept) = (self.innermost().start, self.innermost().kept);
+                self.unwind(kept, expression);
+                self.emit(Instruction::Jump(start as u32), expression);
+            }
+            Expression::Struct { .. }
+            | Expression::Construct { .. }
+            | Expression::Get { .. }
+            | Expression::Set { .. } => {
+                return Err(error(
+                    "structs are not supported by the bytecode compiler".to_string(),
+                    expression,
+                ))
+            }
+            Expression::Class { .. } | Expression::Super { .. } => {
+                return Err(error(
+                    "classes are not supported by the bytecode compiler".to_string(),
+                    expression,
+                ))
+            }
+            Expression::Enum { .. } => {
+                return Err(error(
+                    "enums are not supported by the bytecode compiler".to_string(),
+                    expression,
+                ))
+            }
+            Expression::Match { .. } => {
+                return Err(error(
+                    "match expressions are not supported by the bytecode compiler".to_string(),
+                    expression,
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles a function body and pushes a closure of it.
+    fn closure(
+        &mut self,
+        name: &str,
+        parameters: &[String],
+        defaults: &[Expression],
+        rest: &Option<String>,
+        body: &Expression,
+        expression: &Expression,
+    ) -> Result<(), CompileError> {
+        if u8::try_from(parameters.len()).is_err() {
+            return Err(error("too many parameters".to_string(), expression));
+        }
+        self.functions
+            .push(FunctionState::new(name.to_string(), parameters.to_vec(), 1));
+        let declared = parameters.len() + rest.iter().count();
+        self.begin_variables(declared, defaults.iter().chain([body]), expression)?;
+        let slots = self.scopes.last().unwrap().slots.clone();
+        self.current().rest = rest.is_some();
+
+        // The resolver only lets a default value use the parameters before
+        // it, so computing them in order is enough, and each one left out
+        // is where calls giving fewer arguments start.
+        let mut entries = vec![];
+        let first_default = parameters.len() - defaults.len();
+        for (default, slot) in defaults.iter().zip(&slots[first_default..]) {
+            entries.push(self.current().chunk.code.len() as u32);
+            self.expression(default)?;
+            self.emit(Instruction::SetLocal(*slot), default);
+            self.emit(Instruction::Pop, default);
+        }
+        entries.push(self.current().chunk.code.len() as u32);
+        self.current().entries = entries;
+
+        self.expression(body)?;
+        self.emit(Instruction::Return, body);
+        self.scopes.pop();
+        let prototype = self.functions.pop().unwrap().finish();
+
+        let index = self.constant(Constant::Function(Rc::new(prototype)), expression)?;
+        self.emit(Instruction::Closure(index), expression);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed;
+
+    fn try_compile(input: &str) -> Result<Program, CompileError> {
+        Compiler::new().compile(&parsed(input))
+    }
+
+    fn compile(input: &str) -> Program {
+        try_compile(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn error(input: &str) -> String {
+        match try_compile(input) {
+            Ok(program) => panic!("compiled {:?}", program),
+            Err(e) => e.message,
+        }
+    }
+
+    /// The prototype of the first function `chunk` creates.
+    fn nested(chunk: &Chunk) -> Rc<Prototype> {
+        chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Function(function) => Some(function.clone()),
+                _ => None,
+            })
+            .expect("the code creates a function")
+    }
+
+    #[test]
+    fn top_level_variables_are_globals() {
+        let program = compile("let a = 1; fn f() { a }");
+        assert_eq!(program.globals, ["a", "f"]);
+        assert!(program.main.parameters.is_empty());
+        assert_eq!(program.main.chunk.code.last(), Some(&Instruction::Return));
+    }
+
+    #[test]
+    fn equal_constants_share_a_slot() {
+        let program = compile("print(1 + 1); print(0.5 + 0.5); print(1.0);");
+        assert_eq!(
+            program.main.chunk.constants,
+            [Constant::Int(1), Constant::Float(0.5), Constant::Float(1.0),]
+        );
+    }
+
+    #[test]
+    fn locals_live_in_slots_after_the_parameters() {
+        let function = nested(&compile("fn f(a, b) { let c = a; c + b }").main.chunk);
+        assert_eq!(function.parameters, ["a", "b"]);
+        assert_eq!(function.slots, 4);
+        assert_eq!(function.chunk.code[0], Instruction::GetLocal(1));
+    }
+
+    #[test]
+    fn defaults_have_an_entry_point_each() {
+        let function = nested(&compile("fn f(a, b = 1, c = 2, ...d) { a }").main.chunk);
+        assert_eq!(function.parameters, ["a", "b", "c"]);
+        assert!(function.rest);
+        // Each default is computed, stored and popped before the body.
+        assert_eq!(function.entries, [0, 3, 6]);
+        assert_eq!(function.chunk.code[1], Instruction::SetLocal(2));
+        assert_eq!(function.chunk.code[4], Instruction::SetLocal(3));
+
+        let plain = nested(&compile("fn g(a) { a }").main.chunk);
+        assert_eq!(plain.entries, [0]);
+        assert!(!plain.rest);
+    }
+
+    #[test]
+    fn closures_capture_enclosing_locals() {
+        let outer = nested(&compile("fn f() { let n = 0; fn() { n } }").main.chunk);
+        let inner = nested(&outer.chunk);
+        assert_eq!(
+            inner.upvalues,
+            [Capture {
+                local: true,
+                index: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn functions_can_use_locals_declared_after_them() {
+        let program = compile("fn outer() { fn a() { b() } fn b() { 7 } a() }");
+        let outer = nested(&program.main.chunk);
+        let a = nested(&outer.chunk);
+        assert_eq!(
+            a.upvalues,
+            [Capture {
+                local: true,
+                index: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn unsupported_features_are_errors() {
+        assert_eq!(
+            error("struct P { x } P { x: 1 };"),
+            "structs are not supported by the bytecode compiler"
+        );
+        assert_eq!(
+            error("fn f(a) { a } f(a: 1);"),
+            "named arguments are not supported by the bytecode compiler"
+        );
+        assert_eq!(
+            error("enum E { A } E.A;"),
+            "enums are not supported by the bytecode compiler"
+        );
+        assert_eq!(
+            error("match 1 { 1 => 2, _ => 3 }"),
+            "match expressions are not supported by the bytecode compiler"
+        );
+    }
+}
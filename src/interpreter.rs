@@ -1,12 +1,23 @@
-use std::{collections::HashMap, fmt::Display, mem::discriminant};
+use std::{cell::RefCell, collections::HashMap, fmt::Display, mem::discriminant, rc::Rc};
 
-use crate::{ast::Expression, ast::Value, token::Token};
+use indexmap::IndexMap;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{Signed, ToPrimitive, Zero};
+
+use crate::{
+    ast::Expression,
+    ast::{Pattern, Slot, Value},
+    jit::{self, CompiledFunction, Jit, JitCache},
+    token::Token,
+    vm::Closure,
+};
 
 macro_rules! return_check {
     ($expression: expr) => {
         match $expression {
             Ok(value) => match value {
-                KoxValue::Return(value) => return Ok(KoxValue::Return(value)),
+                KoxValue::Return(_) | KoxValue::Break(_) | KoxValue::Continue => return Ok(value),
                 value => value,
             },
             Err(e) => return Err(e),
@@ -30,28 +41,253 @@ impl Display for RuntimeError {
     }
 }
 
+/// The native functions every program starts with.
+pub fn builtins() -> Vec<(&'static str, NativeFunction)> {
+    vec![
+        (
+            "print",
+            NativeFunction {
+                arity: 1,
+                callable: |args| {
+                    println!("{}", args[0]);
+                    Ok(KoxValue::Nil)
+                },
+            },
+        ),
+        (
+            "len",
+            NativeFunction {
+                arity: 1,
+                callable: |args| match &args[0] {
+                    KoxValue::List(list) => Ok(KoxValue::Int(list.borrow().len() as i64)),
+                    KoxValue::Map(map) => Ok(KoxValue::Int(map.borrow().len() as i64)),
+                    KoxValue::String(s) => Ok(KoxValue::Int(s.chars().count() as i64)),
+                    value => Err(format!(
+                        "Can only take the length of lists, maps and strings! Not {}",
+                        value
+                    )),
+                },
+            },
+        ),
+        (
+            "push",
+            NativeFunction {
+                arity: 2,
+                callable: |args| {
+                    list(&args[0], "push")?.borrow_mut().push(args[1].clone());
+                    Ok(KoxValue::Nil)
+                },
+            },
+        ),
+        (
+            "pop",
+            NativeFunction {
+                arity: 1,
+                callable: |args| {
+                    list(&args[0], "pop")?
+                        .borrow_mut()
+                        .pop()
+                        .ok_or_else(|| "Cannot pop from an empty list".to_string())
+                },
+            },
+        ),
+        (
+            "slice",
+            NativeFunction {
+                arity: 3,
+                callable: |args| {
+                    let list = list(&args[0], "slice")?.borrow();
+                    let bound = |value: &KoxValue| match value {
+                        KoxValue::Int(i) if *i < 0 => {
+                            Ok((list.len() as i64).saturating_add(*i).max(0) as usize)
+                        }
+                        KoxValue::Int(i) => Ok((*i as u64).min(list.len() as u64) as usize),
+                        _ => Err("Slice bounds must be integers".to_string()),
+                    };
+                    let (start, end) = (bound(&args[1])?, bound(&args[2])?);
+                    let elements = list[start..end.max(start)].to_vec();
+                    Ok(KoxValue::List(Rc::new(RefCell::new(elements))))
+                },
+            },
+        ),
+        (
+            "keys",
+            NativeFunction {
+                arity: 1,
+                callable: |args| {
+                    let keys = map(&args[0], "keys")?
+                        .borrow()
+                        .keys()
+                        .map(MapKey::value)
+                        .collect();
+                    Ok(KoxValue::List(Rc::new(RefCell::new(keys))))
+                },
+            },
+        ),
+        (
+            "values",
+            NativeFunction {
+                arity: 1,
+                callable: |args| {
+                    let values = map(&args[0], "values")?
+                        .borrow()
+                        .values()
+                        .cloned()
+                        .collect();
+                    Ok(KoxValue::List(Rc::new(RefCell::new(values))))
+                },
+            },
+        ),
+        (
+            "has",
+            NativeFunction {
+                arity: 2,
+                callable: |args| {
+                    let key = MapKey::new(args[1].clone())?;
+                    Ok(KoxValue::Boolean(
+                        map(&args[0], "has")?.borrow().contains_key(&key),
+                    ))
+                },
+            },
+        ),
+        (
+            "remove",
+            NativeFunction {
+                arity: 2,
+                callable: |args| {
+                    let key = MapKey::new(args[1].clone())?;
+                    let removed = map(&args[0], "remove")?.borrow_mut().shift_remove(&key);
+                    Ok(removed.unwrap_or(KoxValue::Nil))
+                },
+            },
+        ),
+    ]
+}
+
+/// The map passed to a builtin.
+fn map<'a>(value: &'a KoxValue, builtin: &str) -> Result<&'a Map, String> {
+    match value {
+        KoxValue::Map(map) => Ok(map),
+        value => Err(format!("{} expects a map! Not {}", builtin, value)),
+    }
+}
+
+/// The list passed to a builtin.
+fn list<'a>(value: &'a KoxValue, builtin: &str) -> Result<&'a List, String> {
+    match value {
+        KoxValue::List(list) => Ok(list),
+        value => Err(format!("{} expects a list! Not {}", builtin, value)),
+    }
+}
+
+/// Number of calls after which an eligible function is handed to the JIT.
+pub const JIT_THRESHOLD: usize = 100;
+
+/// What integer arithmetic does when the result does not fit in an `i64`.
+/// Division by zero is an error either way.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Arithmetic {
+    /// Overflow is a runtime error.
+    Checked,
+    /// Results wrap around in two's complement.
+    Wrapping,
+    /// Results become arbitrary-precision integers.
+    #[default]
+    Promoting,
+}
+
 pub struct Interpreter {
     global_environment: Environment,
+    jit: Option<Jit>,
+    jit_threshold: usize,
+    arithmetic: Arithmetic,
 }
 
 impl Interpreter {
     pub fn new() -> Self {
-        let mut env = Environment::new();
-
-        env.insert(
-            "print".to_string(),
-            KoxValue::NativeFunction(NativeFunction {
-                arity: 1,
-                callable: |args| {
-                    println!("{}", args[0]);
-                    Ok(KoxValue::Nil)
-                },
-            }),
-        );
+        // The builtins are in a scope around the top level, in the order the
+        // resolver gives them slots, so programs can declare their own.
+        let builtins_environment = Environment::new();
+        for (index, (_, function)) in builtins().into_iter().enumerate() {
+            builtins_environment.define(index, KoxValue::NativeFunction(function));
+        }
 
         Self {
-            global_environment: env,
+            global_environment: builtins_environment.child(),
+            // Without a usable JIT everything simply stays in the tree-walker.
+            jit: Jit::new().ok(),
+            jit_threshold: JIT_THRESHOLD,
+            arithmetic: Arithmetic::default(),
+        }
+    }
+
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn disable_jit(&mut self) {
+        self.jit = None;
+    }
+
+    pub fn set_jit_threshold(&mut self, threshold: usize) {
+        self.jit_threshold = threshold;
+    }
+
+    /// Counts a call to `function` and, once it is hot, returns native code
+    /// for it specialized to the argument types, if the JIT can handle it.
+    fn compiled(
+        &mut self,
+        function: &KoxFunction,
+        args: &[KoxValue],
+    ) -> Option<Rc<CompiledFunction>> {
+        let threshold = self.jit_threshold;
+        // Native code has no big integers, so where they would be made it
+        // stops at the overflow instead and the call is interpreted.
+        let arithmetic = match self.arithmetic {
+            Arithmetic::Promoting => Arithmetic::Checked,
+            arithmetic => arithmetic,
+        };
+        let jit = self.jit.as_mut()?;
+
+        let mut cache = function.jit.borrow_mut();
+        cache.calls += 1;
+        if cache.calls <= threshold {
+            return None;
         }
+        // The function's own variable is one scope out from its parameters.
+        let own = function.slot.map(|index| Slot { depth: 1, index });
+        if !*cache
+            .eligible
+            .get_or_insert_with(|| jit::eligible(own, &function.body))
+        {
+            return None;
+        }
+        // Compiled code calls itself directly, which is only right while the
+        // variable still holds the function.
+        if let Some(index) = function.slot {
+            match function.closure.get(Slot { depth: 0, index }) {
+                Some(KoxValue::KoxFunction(own)) if Rc::ptr_eq(&own.jit, &function.jit) => {}
+                _ => return None,
+            }
+        }
+
+        let types = jit::argument_types(args)?;
+        cache
+            .specializations
+            .entry(types)
+            .or_insert_with_key(|types| {
+                jit.compile(
+                    &function.name,
+                    &function.parameters,
+                    &function.defaults,
+                    &function.body,
+                    types,
+                    arithmetic,
+                )
+                .ok()
+                .map(Rc::new)
+            })
+            .clone()
     }
 
     pub fn global_environment(&self) -> Environment {
@@ -63,78 +299,23 @@ impl Interpreter {
         left: Expression,
         operator: Token,
         right: Expression,
-        environment: &mut Environment,
+        environment: &Environment,
         line: usize,
         column: usize,
     ) -> Result<KoxValue, RuntimeError> {
         let left = return_check!(self.evaluate(left, environment));
         let right = return_check!(self.evaluate(right, environment));
-
-        if discriminant(&left) != discriminant(&right) {
-            return Err(RuntimeError {
-                message: "Operands must be of the same type".to_string(),
-                line,
-                column,
-            });
-        }
-
-        match (left, right) {
-            (KoxValue::Int(left), KoxValue::Int(right)) => match operator {
-                Token::Plus => Ok(KoxValue::Int(left + right)),
-                Token::Minus => Ok(KoxValue::Int(left - right)),
-                Token::Asterisk => Ok(KoxValue::Int(left * right)),
-                Token::Slash => Ok(KoxValue::Int(left / right)),
-                Token::GreaterThan => Ok(KoxValue::Boolean(left > right)),
-                Token::LessThan => Ok(KoxValue::Boolean(left < right)),
-                Token::GreaterThanEqual => Ok(KoxValue::Boolean(left >= right)),
-                Token::LessThanEqual => Ok(KoxValue::Boolean(left <= right)),
-                Token::EqEq => Ok(KoxValue::Boolean(left == right)),
-                _ => Err(RuntimeError {
-                    message: format!("Invalid operator for integers: {:?}", operator),
-                    line,
-                    column,
-                }),
-            },
-            (KoxValue::Float(left), KoxValue::Float(right)) => match operator {
-                Token::Plus => Ok(KoxValue::Float(left + right)),
-                Token::Minus => Ok(KoxValue::Float(left - right)),
-                Token::Asterisk => Ok(KoxValue::Float(left * right)),
-                Token::Slash => Ok(KoxValue::Float(left / right)),
-                _ => Err(RuntimeError {
-                    message: format!("Invalid operator for floats: {:?}", operator),
-                    line,
-                    column,
-                }),
-            },
-            (KoxValue::String(left), KoxValue::String(right)) => match operator {
-                Token::Plus => Ok(KoxValue::String(format!("{}{}", left, right))),
-                _ => Err(RuntimeError {
-                    message: format!("Invalid operator for strings: {:?}", operator),
-                    line,
-                    column,
-                }),
-            },
-            (KoxValue::Boolean(left), KoxValue::Boolean(right)) => match operator {
-                Token::EqEq => Ok(KoxValue::Boolean(left == right)),
-                Token::BangEq => Ok(KoxValue::Boolean(left != right)),
-                _ => Err(RuntimeError {
-                    message: format!("Invalid operator for booleans: {:?}", operator),
-                    line,
-                    column,
-                }),
-            },
-            _ => Err(RuntimeError {
-                message: format!("Invalid operands for operator: {:?}", operator),
-                line,
-                column,
-            }),
-        }
+        binary(left, &operator, right, self.arithmetic).map_err(|message| RuntimeError {
+            message,
+            line,
+            column,
+        })
     }
 
     pub fn evaluate_program(
         &mut self,
         program: Vec<Expression>,
-        env: &mut Environment,
+        env: &Environment,
     ) -> Result<KoxValue, RuntimeError> {
         let mut result = KoxValue::Nil;
         for expr in program {
@@ -147,13 +328,13 @@ impl Interpreter {
         &mut self,
         expression: Expression,
     ) -> Result<KoxValue, RuntimeError> {
-        self.evaluate(expression, &mut self.global_environment.clone())
+        self.evaluate(expression, &self.global_environment.clone())
     }
 
     fn evaluate(
         &mut self,
         expression: Expression,
-        environment: &mut Environment,
+        environment: &Environment,
     ) -> Result<KoxValue, RuntimeError> {
         match expression {
             Expression::Binary {
@@ -163,17 +344,60 @@ impl Interpreter {
                 line,
                 column,
             } => self.evaluate_binary(*left, operator, *right, environment, line, column),
+            Expression::Logical {
+                left,
+                operator,
+                right,
+                line,
+                column,
+            } => {
+                let error = || RuntimeError {
+                    message: "Condition must be a boolean".to_string(),
+                    line,
+                    column,
+                };
+                // The right operand is only evaluated if the left one does not
+                // already decide the result.
+                let left = return_check!(self.evaluate(*left, environment));
+                match (&operator, &left) {
+                    (Token::And, KoxValue::Boolean(false))
+                    | (Token::Or, KoxValue::Boolean(true)) => return Ok(left),
+                    (_, KoxValue::Boolean(_)) => {}
+                    _ => return Err(error()),
+                }
+                match return_check!(self.evaluate(*right, environment)) {
+                    right @ KoxValue::Boolean(_) => Ok(right),
+                    _ => Err(error()),
+                }
+            }
+            Expression::Unary {
+                operator,
+                right,
+                line,
+                column,
+            } => {
+                let right = return_check!(self.evaluate(*right, environment));
+                unary(&operator, right, self.arithmetic).map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })
+            }
             Expression::Call {
                 function,
                 arguments,
+                named,
                 line,
                 column,
             } => {
-                let callee = self.evaluate(*function, environment)?;
+                let callee = return_check!(self.evaluate(*function, environment));
 
                 let mut function: Box<dyn Callable> = match callee {
                     KoxValue::NativeFunction(function) => Box::new(function),
-                    KoxValue::KoxFunction(function) => Box::new(function),
+                    KoxValue::KoxFunction(function) => Box::new(function.as_ref().clone()),
+                    KoxValue::BoundMethod(method) => Box::new(method.as_ref().clone()),
+                    KoxValue::Struct(class) if class.class => Box::new(class),
+                    KoxValue::Constructor(constructor) => Box::new(constructor),
                     _ => {
                         return Err(RuntimeError {
                             message: format!("Can only call functions! Not {}", callee),
@@ -185,22 +409,25 @@ impl Interpreter {
 
                 let mut args: Vec<KoxValue> = vec![];
                 for arg in arguments {
-                    args.push(self.evaluate(arg, environment)?);
+                    args.push(return_check!(self.evaluate(arg, environment)));
+                }
+                let mut named_args: Vec<(String, KoxValue)> = vec![];
+                for (name, arg) in named {
+                    named_args.push((name, return_check!(self.evaluate(arg, environment))));
                 }
 
-                if args.len() as u8 != function.arity() {
+                // Too few arguments are left to the callee, which knows the
+                // names of the parameters they are missing.
+                let arity = function.arity();
+                if arity.max.is_some_and(|max| args.len() > max) {
                     return Err(RuntimeError {
-                        message: format!(
-                            "Expected {} arguments but got {}",
-                            function.arity(),
-                            args.len()
-                        ),
+                        message: arity.mismatch(args.len()),
                         line,
                         column,
                     });
                 }
 
-                let result = function.call(self, &args)?;
+                let result = function.call(self, &args, &named_args, (line, column))?;
                 if let KoxValue::Return(return_value) = result {
                     Ok(*return_value)
                 } else {
@@ -209,10 +436,11 @@ impl Interpreter {
             }
             Expression::Identifier {
                 ident,
+                slot,
                 line,
                 column,
-            } => match environment.get(&ident) {
-                Some(value) => Ok(value.clone()),
+            } => match environment.get(resolved(slot)) {
+                Some(value) => Ok(value),
                 None => Err(RuntimeError {
                     message: format!("undefined variable referenced '{}'", ident),
                     line,
@@ -221,38 +449,33 @@ impl Interpreter {
             },
             Expression::Assign {
                 name,
+                slot,
                 value,
                 line,
                 column,
             } => {
-                let value = self.evaluate(*value, environment)?;
-                environment.assign(
-                    Expression::Identifier {
-                        ident: name.clone(),
+                let value = return_check!(self.evaluate(*value, environment));
+                if !environment.assign(resolved(slot), value.clone()) {
+                    return Err(RuntimeError {
+                        message: format!("Undefined variable '{}'", name),
                         line,
                         column,
-                    },
-                    value,
-                )
+                    });
+                }
+                Ok(value)
             }
-            Expression::Value { value, .. } => Ok(match value {
-                Value::Int(i) => KoxValue::Int(i),
-                Value::Float(f) => KoxValue::Float(f),
-                Value::String(s) => KoxValue::String(s),
-                Value::Boolean(b) => KoxValue::Boolean(b),
-                Value::Nil => KoxValue::Nil,
-            }),
-            Expression::Let { name, value, .. } => {
-                let value = self.evaluate(*value, environment)?;
-                environment.insert(name, value.clone());
+            Expression::Value { value, .. } => Ok(KoxValue::from(value)),
+            Expression::Let { slot, value, .. } => {
+                let value = return_check!(self.evaluate(*value, environment));
+                environment.define(resolved(slot), value.clone());
                 Ok(value)
             }
             Expression::Return { value, .. } => {
-                let result = self.evaluate(*value, environment)?;
+                let result = return_check!(self.evaluate(*value, environment));
                 Ok(KoxValue::Return(Box::new(result)))
             }
             Expression::Block { expressions, .. } => {
-                self.evaluate_program(expressions, &mut environment.child())
+                self.evaluate_program(expressions, &environment.child())
             }
             Expression::If {
                 condition,
@@ -261,7 +484,7 @@ impl Interpreter {
                 line,
                 column,
             } => {
-                let condition = self.evaluate(*condition, environment)?;
+                let condition = return_check!(self.evaluate(*condition, environment));
                 match condition {
                     KoxValue::Boolean(true) => self.evaluate(*consequence, environment),
                     KoxValue::Boolean(false) => match alternative {
@@ -277,177 +500,1705 @@ impl Interpreter {
             }
             Expression::Function {
                 name,
+                slot,
                 parameters,
+                defaults,
+                rest,
                 body,
                 ..
             } => {
-                let mut function = KoxFunction {
+                // The function shares the environment it is declared in, so it
+                // sees its own name and can recurse.
+                let function = KoxFunction {
+                    name: name.clone(),
+                    slot,
                     parameters,
+                    defaults,
+                    rest: rest.is_some(),
                     body: *body,
                     closure: environment.clone(),
+                    jit: Rc::new(RefCell::new(JitCache::default())),
                 };
-                function
-                    .closure
-                    .insert(name.clone(), KoxValue::KoxFunction(function.clone()));
-                environment.insert(name.clone(), KoxValue::KoxFunction(function));
+                environment.define(resolved(slot), KoxValue::KoxFunction(Rc::new(function)));
                 Ok(KoxValue::Nil)
             }
-            // Expression::For {
-            //     ident,
-            //     expr,
-            //     body,
-            //     line,
-            //     column,
-            // } => todo!(),
-            _ => todo!(),
-        }
-    }
-}
-
-trait Callable {
-    fn arity(&self) -> u8;
-    fn call(
-        &mut self,
-        interpreter: &mut Interpreter,
-        args: &[KoxValue],
-    ) -> Result<KoxValue, RuntimeError>;
-}
-
-#[derive(Clone)]
-pub struct NativeFunction {
-    pub arity: u8,
-    pub callable: fn(&[KoxValue]) -> Result<KoxValue, RuntimeError>,
-}
-
-impl Callable for NativeFunction {
-    fn arity(&self) -> u8 {
-        self.arity
-    }
-
-    fn call(
-        &mut self,
-        _interpreter: &mut Interpreter,
-        args: &[KoxValue],
-    ) -> Result<KoxValue, RuntimeError> {
-        (self.callable)(args)
-    }
-}
-
-#[derive(Clone)]
-pub struct KoxFunction {
-    pub body: Expression,
-    pub closure: Environment,
-    pub parameters: Vec<String>,
-}
-
-impl Callable for KoxFunction {
-    fn arity(&self) -> u8 {
-        self.parameters.len() as u8
-    }
-
-    fn call(
-        &mut self,
-        interpreter: &mut Interpreter,
-        args: &[KoxValue],
-    ) -> Result<KoxValue, RuntimeError> {
-        let mut environment = self.closure.child();
-
-        for (param, arg) in self.parameters.iter().zip(args.iter()) {
-            environment.insert(param.clone(), arg.clone());
-        }
-
-        interpreter.evaluate(self.body.clone(), &mut environment)
-    }
-}
-
-#[derive(Clone)]
-pub struct Environment {
-    enclosing: Option<Box<Environment>>,
-    venv: HashMap<String, KoxValue>,
-}
-
-impl Environment {
-    pub fn new() -> Self {
-        Self {
-            enclosing: None,
-            venv: HashMap::new(),
-        }
-    }
-
-    pub fn child(&mut self) -> Self {
-        Self {
-            enclosing: Some(Box::new(self.clone())),
-            venv: HashMap::new(),
-        }
-    }
+            Expression::Lambda {
+                parameters,
+                defaults,
+                rest,
+                body,
+                ..
+            } => Ok(KoxValue::KoxFunction(Rc::new(KoxFunction {
+                name: "<lambda>".to_string(),
+                slot: None,
+                parameters,
+                defaults,
+                rest: rest.is_some(),
+                body: *body,
+                closure: environment.clone(),
+                jit: Rc::new(RefCell::new(JitCache::default())),
+            }))),
+            Expression::For {
+                value_ident,
+                expr,
+                body,
+                line,
+                column,
+                ..
+            } => {
+                let iterable = return_check!(self.evaluate(*expr, environment));
+                let iterator = match value_ident {
+                    Some(_) => entries(iterable),
+                    None => iterate(iterable).map(|values| {
+                        Box::new(values.map(|value| (value, KoxValue::Nil)))
+                            as Box<dyn Iterator<Item = _>>
+                    }),
+                }
+                .map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })?;
 
-    pub fn get(&self, name: &str) -> Option<&KoxValue> {
-        match self.venv.get(name) {
-            Some(value) => Some(value),
-            None => match &self.enclosing {
-                Some(enclosing) => enclosing.get(name),
-                None => None,
+                let mut result = KoxValue::Nil;
+                for (value, entry) in iterator {
+                    // Each iteration has its own variable, so closures made in
+                    // the body keep the value it had then.
+                    let scope = environment.child();
+                    scope.define(0, value);
+                    if value_ident.is_some() {
+                        scope.define(1, entry);
+                    }
+                    match self.evaluate((*body).clone(), &scope)? {
+                        KoxValue::Break(value) => {
+                            result = *value;
+                            break;
+                        }
+                        value @ KoxValue::Return(_) => {
+                            result = value;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(result)
+            }
+            Expression::While {
+                condition,
+                body,
+                line,
+                column,
+            } => loop {
+                let condition = return_check!(self.evaluate((*condition).clone(), environment));
+                match condition {
+                    KoxValue::Boolean(true) => {}
+                    KoxValue::Boolean(false) => break Ok(KoxValue::Nil),
+                    _ => {
+                        break Err(RuntimeError {
+                            message: "Condition must be a boolean".to_string(),
+                            line,
+                            column,
+                        })
+                    }
+                }
+                match self.evaluate((*body).clone(), environment)? {
+                    KoxValue::Break(value) => break Ok(*value),
+                    value @ KoxValue::Return(_) => break Ok(value),
+                    _ => {}
+                }
             },
-        }
-    }
-
-    pub fn insert(&mut self, name: String, value: KoxValue) {
-        self.venv.insert(name, value);
-    }
-
-    pub fn assign(
-        &mut self,
-        identifier: Expression,
-        value: KoxValue,
-    ) -> Result<KoxValue, RuntimeError> {
-        let (name, line, column) = match identifier.clone() {
-            Expression::Identifier {
-                ident,
+            Expression::Break { value, .. } => {
+                let value = return_check!(self.evaluate(*value, environment));
+                Ok(KoxValue::Break(Box::new(value)))
+            }
+            Expression::Continue { .. } => Ok(KoxValue::Continue),
+            Expression::Range {
+                start,
+                end,
+                step,
+                inclusive,
                 line,
                 column,
-            } => (ident, line, column),
-            _ => panic!("attempting to assign to non-identifier expression"),
-        };
-
-        if self.venv.contains_key(&name) {
-            self.venv.insert(name.to_string(), value.clone());
-            return Ok(value);
-        }
-
-        match &mut self.enclosing {
-            Some(enclosing) => enclosing.assign(identifier, value),
-            None => Err(RuntimeError {
-                message: format!("Undefined variable '{}'", name),
+            } => {
+                let start = return_check!(self.evaluate(*start, environment));
+                let end = return_check!(self.evaluate(*end, environment));
+                let step = match step {
+                    Some(step) => return_check!(self.evaluate(*step, environment)),
+                    None => KoxValue::Int(1),
+                };
+                range(start, end, step, inclusive).map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })
+            }
+            Expression::List { elements, .. } => {
+                let mut values = vec![];
+                for element in elements {
+                    values.push(return_check!(self.evaluate(element, environment)));
+                }
+                Ok(KoxValue::List(Rc::new(RefCell::new(values))))
+            }
+            Expression::Map { entries, .. } => {
+                let mut map = IndexMap::new();
+                for (key, value) in entries {
+                    let (line, column) = key.position();
+                    let key = return_check!(self.evaluate(key, environment));
+                    let key = MapKey::new(key).map_err(|message| RuntimeError {
+                        message,
+                        line,
+                        column,
+                    })?;
+                    let value = return_check!(self.evaluate(value, environment));
+                    map.insert(key, value);
+                }
+                Ok(KoxValue::Map(Rc::new(RefCell::new(map))))
+            }
+            Expression::Struct {
+                name,
+                slot,
+                fields,
+                methods,
+                ..
+            } => {
+                let structure = Struct {
+                    name: name.clone(),
+                    fields,
+                    methods: methods_of(methods, environment),
+                    superclass: None,
+                    class: false,
+                };
+                environment.define(resolved(slot), KoxValue::Struct(Rc::new(structure)));
+                Ok(KoxValue::Nil)
+            }
+            Expression::Class {
+                name,
+                slot,
+                superclass,
+                superclass_slot,
+                methods,
                 line,
                 column,
-            }),
-        }
-    }
-}
-
-#[derive(Clone)]
-pub enum KoxValue {
-    Int(i64),
+            } => {
+                let superclass = match superclass {
+                    Some(superclass) => match environment.get(resolved(superclass_slot)) {
+                        Some(KoxValue::Struct(superclass)) if superclass.class => Some(superclass),
+                        value => {
+                            let message = match value {
+                                Some(value) => format!("Superclass must be a class! Not {}", value),
+                                None => format!("undefined variable referenced '{}'", superclass),
+                            };
+                            return Err(RuntimeError {
+                                message,
+                                line,
+                                column,
+                            });
+                        }
+                    },
+                    None => None,
+                };
+                let class = Struct {
+                    name: name.clone(),
+                    fields: vec![],
+                    methods: methods_of(methods, environment),
+                    superclass,
+                    class: true,
+                };
+                environment.define(resolved(slot), KoxValue::Struct(Rc::new(class)));
+                Ok(KoxValue::Nil)
+            }
+            Expression::Super {
+                method,
+                slot,
+                line,
+                column,
+            } => {
+                let error = |message: String| RuntimeError {
+                    message,
+                    line,
+                    column,
+                };
+                let slot = resolved(slot);
+                let this = Slot { index: 0, ..slot };
+                let (superclass, receiver) = match (environment.get(slot), environment.get(this)) {
+                    (Some(KoxValue::Struct(superclass)), Some(KoxValue::Instance(receiver))) => {
+                        (superclass, receiver)
+                    }
+                    _ => {
+                        return Err(error(
+                            "Can't use 'super' outside of a method of a subclass".to_string(),
+                        ))
+                    }
+                };
+                match find_method(&superclass, &method) {
+                    Some((holder, method)) => Ok(KoxValue::BoundMethod(Rc::new(BoundMethod {
+                        receiver,
+                        holder,
+                        method,
+                    }))),
+                    None => Err(error(format!(
+                        "Undefined method '{}' on superclass {}",
+                        method, superclass.name
+                    ))),
+                }
+            }
+            Expression::Construct {
+                name,
+                slot,
+                fields,
+                line,
+                column,
+            } => {
+                let error = |message: String| RuntimeError {
+                    message,
+                    line,
+                    column,
+                };
+                let structure = match environment.get(resolved(slot)) {
+                    Some(KoxValue::Struct(structure)) if !structure.class => structure,
+                    Some(value) => return Err(error(format!("{} is not a struct", value))),
+                    None => return Err(error(format!("undefined variable referenced '{}'", name))),
+                };
+                let mut values = HashMap::new();
+                for (field, value) in fields {
+                    if !structure.fields.contains(&field) {
+                        return Err(error(format!(
+                            "Struct {} has no field '{}'",
+                            structure.name, field
+                        )));
+                    }
+                    let value = return_check!(self.evaluate(value, environment));
+                    if values.insert(field.clone(), value).is_some() {
+                        return Err(error(format!("Field '{}' is given more than once", field)));
+                    }
+                }
+                let mut fields = IndexMap::new();
+                for field in &structure.fields {
+                    match values.remove(field) {
+                        Some(value) => fields.insert(field.clone(), value),
+                        None => {
+                            return Err(error(format!(
+                                "Missing field '{}' of struct {}",
+                                field, structure.name
+                            )))
+                        }
+                    };
+                }
+                Ok(KoxValue::Instance(Rc::new(Instance {
+                    structure,
+                    fields: RefCell::new(fields),
+                })))
+            }
+            Expression::Get {
+                object,
+                name,
+                line,
+                column,
+            } => {
+                let object = return_check!(self.evaluate(*object, environment));
+                get_field(&object, &name).map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+                line,
+                column,
+            } => {
+                let object = return_check!(self.evaluate(*object, environment));
+                let value = return_check!(self.evaluate(*value, environment));
+                set_field(&object, &name, value).map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })
+            }
+            Expression::Enum {
+                name,
+                slot,
+                variants,
+                ..
+            } => {
+                let enumeration = Enum {
+                    name: name.clone(),
+                    variants: variants
+                        .into_iter()
+                        .map(|(variant, fields)| (variant, fields.len()))
+                        .collect(),
+                };
+                environment.define(resolved(slot), KoxValue::Enum(Rc::new(enumeration)));
+                Ok(KoxValue::Nil)
+            }
+            Expression::Match {
+                value,
+                arms,
+                line,
+                column,
+            } => {
+                let error = |message: String| RuntimeError {
+                    message,
+                    line,
+                    column,
+                };
+                let value = return_check!(self.evaluate(*value, environment));
+                for arm in arms {
+                    // Names bound by the pattern are only seen by its arm.
+                    let scope = environment.child();
+                    if !matches(&arm.pattern, &value, &scope, &mut 0).map_err(error)? {
+                        continue;
+                    }
+                    if let Some(guard) = arm.guard {
+                        match return_check!(self.evaluate(guard, &scope)) {
+                            KoxValue::Boolean(true) => {}
+                            KoxValue::Boolean(false) => continue,
+                            _ => return Err(error("Guard must be a boolean".to_string())),
+                        }
+                    }
+                    return self.evaluate(arm.body, &scope);
+                }
+                Ok(KoxValue::Nil)
+            }
+            Expression::Index {
+                object,
+                index,
+                line,
+                column,
+            } => {
+                let object = return_check!(self.evaluate(*object, environment));
+                let index = return_check!(self.evaluate(*index, environment));
+                get_index(&object, &index).map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })
+            }
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                line,
+                column,
+            } => {
+                let object = return_check!(self.evaluate(*object, environment));
+                let index = return_check!(self.evaluate(*index, environment));
+                let value = return_check!(self.evaluate(*value, environment));
+                set_index(&object, &index, value).map_err(|message| RuntimeError {
+                    message,
+                    line,
+                    column,
+                })
+            }
+        }
+    }
+}
+
+/// The slot the resolver filled in.
+fn resolved<T>(slot: Option<T>) -> T {
+    slot.expect("variables are resolved before the program runs")
+}
+
+/// Makes the range `start..end` or `start..=end` counting by `step`.
+pub fn range(
+    start: KoxValue,
+    end: KoxValue,
+    step: KoxValue,
+    inclusive: bool,
+) -> Result<KoxValue, String> {
+    match (start, end, step) {
+        (KoxValue::Int(_), KoxValue::Int(_), KoxValue::Int(0)) => {
+            Err("Range step cannot be zero".to_string())
+        }
+        (KoxValue::Int(start), KoxValue::Int(end), KoxValue::Int(step)) => {
+            Ok(KoxValue::Range(Range {
+                start,
+                end,
+                step,
+                inclusive,
+            }))
+        }
+        _ => Err("Range bounds and step must be integers".to_string()),
+    }
+}
+
+/// The iteration protocol behind `for` loops: turns an iterable value into
+/// the sequence of values the loop variable takes.
+pub fn iterate(value: KoxValue) -> Result<Box<dyn Iterator<Item = KoxValue>>, String> {
+    match value {
+        KoxValue::Range(range) => Ok(Box::new(range.values().map(KoxValue::Int))),
+        // Elements pushed while looping are not visited.
+        KoxValue::List(list) => Ok(Box::new(list.borrow().clone().into_iter())),
+        KoxValue::Map(map) => Ok(Box::new(
+            map.borrow()
+                .keys()
+                .map(MapKey::value)
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )),
+        KoxValue::String(s) => Ok(Box::new(
+            s.chars()
+                .map(|c| KoxValue::String(c.to_string()))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )),
+        value => Err(format!(
+            "Can only iterate over ranges, strings, lists and maps! Not {}",
+            value
+        )),
+    }
+}
+
+/// The key and value pairs `for (key, value) in map` loops over.
+pub fn entries(value: KoxValue) -> Result<Box<dyn Iterator<Item = (KoxValue, KoxValue)>>, String> {
+    match value {
+        KoxValue::Map(map) => Ok(Box::new(
+            map.borrow()
+                .iter()
+                .map(|(key, value)| (key.value(), value.clone()))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )),
+        value => Err(format!(
+            "Can only iterate over the entries of maps! Not {}",
+            value
+        )),
+    }
+}
+
+/// Reads `object[index]`. Negative indices count back from the end of a
+/// list, and maps must have the key.
+pub fn get_index(object: &KoxValue, index: &KoxValue) -> Result<KoxValue, String> {
+    match object {
+        KoxValue::List(list) => {
+            let list = list.borrow();
+            Ok(list[offset(list.len(), index)?].clone())
+        }
+        KoxValue::Map(map) => map
+            .borrow()
+            .get(&MapKey::new(index.clone())?)
+            .cloned()
+            .ok_or_else(|| format!("Key {} not found in map", index)),
+        _ => Err(format!("Can only index lists and maps! Not {}", object)),
+    }
+}
+
+/// Performs `object[index] = value`, giving back the value. Assigning to a
+/// missing key of a map adds it.
+pub fn set_index(object: &KoxValue, index: &KoxValue, value: KoxValue) -> Result<KoxValue, String> {
+    match object {
+        KoxValue::List(list) => {
+            let mut list = list.borrow_mut();
+            let offset = offset(list.len(), index)?;
+            list[offset] = value.clone();
+        }
+        KoxValue::Map(map) => {
+            map.borrow_mut()
+                .insert(MapKey::new(index.clone())?, value.clone());
+        }
+        _ => return Err(format!("Can only index lists and maps! Not {}", object)),
+    }
+    Ok(value)
+}
+
+/// The methods declared in a struct or class body.
+fn methods_of(methods: Vec<Expression>, environment: &Environment) -> HashMap<String, KoxFunction> {
+    methods
+        .into_iter()
+        .filter_map(|method| match method {
+            Expression::Function {
+                name,
+                parameters,
+                defaults,
+                rest,
+                body,
+                ..
+            } => Some((
+                name.clone(),
+                KoxFunction {
+                    name,
+                    // Methods are not variables.
+                    slot: None,
+                    parameters,
+                    defaults,
+                    rest: rest.is_some(),
+                    body: *body,
+                    closure: environment.clone(),
+                    jit: Rc::new(RefCell::new(JitCache::default())),
+                },
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Looks a method up in a struct or class and then its superclasses, along
+/// with the one that defines it.
+fn find_method(structure: &Rc<Struct>, name: &str) -> Option<(Rc<Struct>, KoxFunction)> {
+    let mut holder = structure;
+    loop {
+        if let Some(method) = holder.methods.get(name) {
+            return Some((holder.clone(), method.clone()));
+        }
+        holder = holder.superclass.as_ref()?;
+    }
+}
+
+/// Whether `value` matches `pattern`, binding the names in the pattern to
+/// the parts of the value they match in `scope`. `bound` counts the names
+/// bound so far, which is the slot of the next one.
+fn matches(
+    pattern: &Pattern,
+    value: &KoxValue,
+    scope: &Environment,
+    bound: &mut usize,
+) -> Result<bool, String> {
+    match pattern {
+        Pattern::Wildcard => Ok(true),
+        Pattern::Binding(_) => {
+            scope.define(*bound, value.clone());
+            *bound += 1;
+            Ok(true)
+        }
+        Pattern::Value(literal) => Ok(*value == KoxValue::from(literal.clone())),
+        Pattern::Variant {
+            enumeration,
+            slot,
+            variant,
+            fields,
+        } => {
+            let enumeration = match scope.get(resolved(*slot)) {
+                Some(KoxValue::Enum(enumeration)) => enumeration,
+                Some(value) => return Err(format!("{} is not an enum", value)),
+                None => return Err(format!("undefined variable referenced '{}'", enumeration)),
+            };
+            let (index, arity) = enumeration.variant(variant)?;
+            if arity != fields.len() {
+                return Err(format!(
+                    "Variant {}.{} has {} fields but the pattern has {}",
+                    enumeration.name,
+                    variant,
+                    arity,
+                    fields.len()
+                ));
+            }
+            match value {
+                KoxValue::Variant(value)
+                    if Rc::ptr_eq(&value.enumeration, &enumeration) && value.index == index =>
+                {
+                    for (field, value) in fields.iter().zip(&value.values) {
+                        if !matches(field, value, scope, bound)? {
+                            return Ok(false);
+                        }
+                    }
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        }
+    }
+}
+
+fn instance(object: &KoxValue) -> Result<&Rc<Instance>, String> {
+    match object {
+        KoxValue::Instance(instance) => Ok(instance),
+        _ => Err(format!(
+            "Only instances of structs and classes have fields! Not {}",
+            object
+        )),
+    }
+}
+
+/// Reads `object.name`, which is a field of an instance or one of its
+/// methods, bound to the instance, or a variant of an enum.
+fn get_field(object: &KoxValue, name: &str) -> Result<KoxValue, String> {
+    if let KoxValue::Enum(enumeration) = object {
+        // Variants with fields are made by calling them.
+        let variant = match enumeration.variant(name)? {
+            (index, 0) => KoxValue::Variant(Rc::new(Variant {
+                enumeration: enumeration.clone(),
+                index,
+                values: vec![],
+            })),
+            (index, _) => KoxValue::Constructor(Constructor {
+                enumeration: enumeration.clone(),
+                index,
+            }),
+        };
+        return Ok(variant);
+    }
+    let instance = instance(object)?;
+    if let Some(value) = instance.fields.borrow().get(name) {
+        return Ok(value.clone());
+    }
+    match find_method(&instance.structure, name) {
+        Some((holder, method)) => Ok(KoxValue::BoundMethod(Rc::new(BoundMethod {
+            receiver: instance.clone(),
+            holder,
+            method,
+        }))),
+        None => Err(format!(
+            "Undefined field '{}' on {}",
+            name, instance.structure.name
+        )),
+    }
+}
+
+/// Performs `object.name = value`, giving back the value. Only fields a
+/// struct declares can be assigned, while instances of classes can be given
+/// new ones.
+fn set_field(object: &KoxValue, name: &str, value: KoxValue) -> Result<KoxValue, String> {
+    let instance = instance(object)?;
+    let mut fields = instance.fields.borrow_mut();
+    match fields.get_mut(name) {
+        Some(field) => *field = value.clone(),
+        None if instance.structure.class => {
+            fields.insert(name.to_string(), value.clone());
+        }
+        None => {
+            return Err(format!(
+                "Undefined field '{}' on {}",
+                name, instance.structure.name
+            ))
+        }
+    }
+    Ok(value)
+}
+
+fn offset(length: usize, index: &KoxValue) -> Result<usize, String> {
+    let index = match index {
+        KoxValue::Int(i) => *i,
+        _ => return Err("List index must be an integer".to_string()),
+    };
+    let offset = if index < 0 {
+        index + length as i64
+    } else {
+        index
+    };
+    if (0..length as i64).contains(&offset) {
+        Ok(offset as usize)
+    } else {
+        Err(format!("List index {} out of range", index))
+    }
+}
+
+/// Applies a unary operator to an evaluated operand.
+pub fn unary(
+    operator: &Token,
+    right: KoxValue,
+    arithmetic: Arithmetic,
+) -> Result<KoxValue, String> {
+    match (operator, right) {
+        (Token::Minus, KoxValue::Int(i)) => match (i.checked_neg(), arithmetic) {
+            (Some(i), _) => Ok(KoxValue::Int(i)),
+            (None, Arithmetic::Checked) => Err(overflow()),
+            (None, Arithmetic::Wrapping) => Ok(KoxValue::Int(i.wrapping_neg())),
+            (None, Arithmetic::Promoting) => Ok(KoxValue::BigInt(-BigInt::from(i))),
+        },
+        (Token::Minus, KoxValue::BigInt(i)) => Ok(integer(-i)),
+        (Token::Minus, KoxValue::Float(f)) => Ok(KoxValue::Float(-f)),
+        (Token::Minus, _) => Err("Operand must be a number".to_string()),
+        (Token::Bang, KoxValue::Boolean(b)) => Ok(KoxValue::Boolean(!b)),
+        (Token::Bang, _) => Err("Operand must be a boolean".to_string()),
+        (operator, _) => Err(format!("Invalid unary operator: {:?}", operator)),
+    }
+}
+
+/// Applies a binary operator to two evaluated operands.
+pub fn binary(
+    left: KoxValue,
+    operator: &Token,
+    right: KoxValue,
+    arithmetic: Arithmetic,
+) -> Result<KoxValue, String> {
+    // Any two values can be compared for equality; values of different kinds
+    // are never equal, except for numbers.
+    match operator {
+        Token::EqEq => return Ok(KoxValue::Boolean(left == right)),
+        Token::BangEq => return Ok(KoxValue::Boolean(left != right)),
+        _ => {}
+    }
+
+    match (left, right) {
+        (KoxValue::Int(left), KoxValue::Int(right)) => match operator {
+            Token::Pow if right < 0 => Ok(KoxValue::Float((left as f64).powf(right as f64))),
+            Token::Plus
+            | Token::Minus
+            | Token::Asterisk
+            | Token::Slash
+            | Token::Percent
+            | Token::Pow => match int_binary(left, operator, right, arithmetic)? {
+                Some(result) => Ok(KoxValue::Int(result)),
+                None if arithmetic == Arithmetic::Promoting => {
+                    big_binary(BigInt::from(left), operator, BigInt::from(right))
+                }
+                None => Err(overflow()),
+            },
+            Token::GreaterThan => Ok(KoxValue::Boolean(left > right)),
+            Token::LessThan => Ok(KoxValue::Boolean(left < right)),
+            Token::GreaterThanEqual => Ok(KoxValue::Boolean(left >= right)),
+            Token::LessThanEqual => Ok(KoxValue::Boolean(left <= right)),
+            _ => Err(format!("Invalid operator for integers: {:?}", operator)),
+        },
+        (KoxValue::BigInt(left), KoxValue::Int(right)) => {
+            big_binary(left, operator, BigInt::from(right))
+        }
+        (KoxValue::Int(left), KoxValue::BigInt(right)) => {
+            big_binary(BigInt::from(left), operator, right)
+        }
+        (KoxValue::BigInt(left), KoxValue::BigInt(right)) => big_binary(left, operator, right),
+        // Integers mixed with floats are promoted.
+        (KoxValue::Int(left), KoxValue::Float(right)) => float_binary(left as f64, operator, right),
+        (KoxValue::Float(left), KoxValue::Int(right)) => float_binary(left, operator, right as f64),
+        (KoxValue::BigInt(left), KoxValue::Float(right)) => {
+            float_binary(to_f64(&left), operator, right)
+        }
+        (KoxValue::Float(left), KoxValue::BigInt(right)) => {
+            float_binary(left, operator, to_f64(&right))
+        }
+        (KoxValue::Float(left), KoxValue::Float(right)) => float_binary(left, operator, right),
+        (KoxValue::String(left), KoxValue::String(right)) => match operator {
+            Token::Plus => Ok(KoxValue::String(format!("{}{}", left, right))),
+            _ => Err(format!("Invalid operator for strings: {:?}", operator)),
+        },
+        (KoxValue::Boolean(_), KoxValue::Boolean(_)) => {
+            Err(format!("Invalid operator for booleans: {:?}", operator))
+        }
+        (left, right) if discriminant(&left) != discriminant(&right) => {
+            Err("Operands must be of the same type".to_string())
+        }
+        _ => Err(format!("Invalid operands for operator: {:?}", operator)),
+    }
+}
+
+fn float_binary(left: f64, operator: &Token, right: f64) -> Result<KoxValue, String> {
+    match operator {
+        Token::Plus => Ok(KoxValue::Float(left + right)),
+        Token::Minus => Ok(KoxValue::Float(left - right)),
+        Token::Asterisk => Ok(KoxValue::Float(left * right)),
+        Token::Slash => Ok(KoxValue::Float(left / right)),
+        Token::Percent => Ok(KoxValue::Float(left - (left / right).floor() * right)),
+        Token::Pow => Ok(KoxValue::Float(left.powf(right))),
+        Token::GreaterThan => Ok(KoxValue::Boolean(left > right)),
+        Token::LessThan => Ok(KoxValue::Boolean(left < right)),
+        Token::GreaterThanEqual => Ok(KoxValue::Boolean(left >= right)),
+        Token::LessThanEqual => Ok(KoxValue::Boolean(left <= right)),
+        _ => Err(format!("Invalid operator for floats: {:?}", operator)),
+    }
+}
+
+fn overflow() -> String {
+    "Integer overflow".to_string()
+}
+
+fn division_by_zero() -> String {
+    "Division by zero".to_string()
+}
+
+/// Integer arithmetic, `None` if the result overflows. Division rounds
+/// towards negative infinity, so that `%` takes the sign of the divisor.
+fn int_binary(
+    left: i64,
+    operator: &Token,
+    right: i64,
+    arithmetic: Arithmetic,
+) -> Result<Option<i64>, String> {
+    if matches!(operator, Token::Slash | Token::Percent) && right == 0 {
+        return Err(division_by_zero());
+    }
+    let wrapping = arithmetic == Arithmetic::Wrapping;
+    Ok(match operator {
+        Token::Plus if wrapping => Some(left.wrapping_add(right)),
+        Token::Plus => left.checked_add(right),
+        Token::Minus if wrapping => Some(left.wrapping_sub(right)),
+        Token::Minus => left.checked_sub(right),
+        Token::Asterisk if wrapping => Some(left.wrapping_mul(right)),
+        Token::Asterisk => left.checked_mul(right),
+        Token::Slash => {
+            // Only `i64::MIN / -1` overflows.
+            let quotient = if wrapping {
+                Some(left.wrapping_div(right))
+            } else {
+                left.checked_div(right)
+            };
+            let inexact = left.wrapping_rem(right) != 0 && (left < 0) != (right < 0);
+            quotient.map(|quotient| quotient - inexact as i64)
+        }
+        Token::Percent => {
+            let remainder = left.wrapping_rem(right);
+            if remainder != 0 && (remainder < 0) != (right < 0) {
+                Some(remainder + right)
+            } else {
+                Some(remainder)
+            }
+        }
+        Token::Pow => power(left, right, wrapping),
+        _ => unreachable!("not an arithmetic operator: {:?}", operator),
+    })
+}
+
+/// Raises `base` to a non-negative `exponent` by repeated squaring.
+fn power(mut base: i64, mut exponent: i64, wrapping: bool) -> Option<i64> {
+    let multiply = |a: i64, b: i64| {
+        if wrapping {
+            Some(a.wrapping_mul(b))
+        } else {
+            a.checked_mul(b)
+        }
+    };
+    let mut result: i64 = 1;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = multiply(result, base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = multiply(base, base)?;
+        }
+    }
+    Some(result)
+}
+
+/// Arithmetic on integers too large for an `i64`, or whose result is.
+fn big_binary(left: BigInt, operator: &Token, right: BigInt) -> Result<KoxValue, String> {
+    if matches!(operator, Token::Slash | Token::Percent) && right.is_zero() {
+        return Err(division_by_zero());
+    }
+    Ok(match operator {
+        Token::Plus => integer(left + right),
+        Token::Minus => integer(left - right),
+        Token::Asterisk => integer(left * right),
+        Token::Slash => integer(left.div_floor(&right)),
+        Token::Percent => integer(left.mod_floor(&right)),
+        Token::Pow if right.is_negative() => KoxValue::Float(to_f64(&left).powf(to_f64(&right))),
+        Token::Pow => match right.to_u32() {
+            Some(exponent) => integer(left.pow(exponent)),
+            None => return Err(overflow()),
+        },
+        Token::GreaterThan => KoxValue::Boolean(left > right),
+        Token::LessThan => KoxValue::Boolean(left < right),
+        Token::GreaterThanEqual => KoxValue::Boolean(left >= right),
+        Token::LessThanEqual => KoxValue::Boolean(left <= right),
+        _ => return Err(format!("Invalid operator for integers: {:?}", operator)),
+    })
+}
+
+/// An integer result, kept in an `Int` whenever it fits.
+fn integer(value: BigInt) -> KoxValue {
+    match i64::try_from(&value) {
+        Ok(i) => KoxValue::Int(i),
+        Err(_) => KoxValue::BigInt(value),
+    }
+}
+
+fn to_f64(value: &BigInt) -> f64 {
+    value.to_f64().unwrap_or(f64::NAN)
+}
+
+/// How many arguments a function takes: at least `min`, and at most `max`
+/// unless a rest parameter takes any number more.
+#[derive(Clone, Copy)]
+pub(crate) struct Arity {
+    pub(crate) min: usize,
+    pub(crate) max: Option<usize>,
+}
+
+impl Arity {
+    pub(crate) fn exactly(count: usize) -> Self {
+        Self {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    pub(crate) fn accepts(&self, given: usize) -> bool {
+        given >= self.min && self.max.is_none_or(|max| given <= max)
+    }
+
+    pub(crate) fn mismatch(&self, given: usize) -> String {
+        match self.max {
+            Some(max) if max == self.min => {
+                format!("Expected {} arguments but got {}", max, given)
+            }
+            Some(max) => format!(
+                "Expected {} to {} arguments but got {}",
+                self.min, max, given
+            ),
+            None => format!("Expected at least {} arguments but got {}", self.min, given),
+        }
+    }
+}
+
+trait Callable {
+    fn arity(&self) -> Arity;
+    /// Calls the function with `args` and then the arguments given by name,
+    /// reporting errors the callee has no position for at `position`, the
+    /// call site. There are never more of `args` than the arity allows.
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        args: &[KoxValue],
+        named: &[(String, KoxValue)],
+        position: (usize, usize),
+    ) -> Result<KoxValue, RuntimeError>;
+}
+
+/// Checks the arguments to a callable whose parameters have no names, so
+/// can't be given by name.
+fn positional(
+    arity: Arity,
+    args: &[KoxValue],
+    named: &[(String, KoxValue)],
+    (line, column): (usize, usize),
+) -> Result<(), RuntimeError> {
+    let message = match named.first() {
+        Some((name, _)) => format!("No parameter named '{}'", name),
+        None if args.len() < arity.min => arity.mismatch(args.len()),
+        None => return Ok(()),
+    };
+    Err(RuntimeError {
+        message,
+        line,
+        column,
+    })
+}
+
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub arity: u8,
+    pub callable: fn(&[KoxValue]) -> Result<KoxValue, String>,
+}
+
+impl Callable for NativeFunction {
+    fn arity(&self) -> Arity {
+        Arity::exactly(self.arity as usize)
+    }
+
+    fn call(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        args: &[KoxValue],
+        named: &[(String, KoxValue)],
+        (line, column): (usize, usize),
+    ) -> Result<KoxValue, RuntimeError> {
+        positional(self.arity(), args, named, (line, column))?;
+        (self.callable)(args).map_err(|message| RuntimeError {
+            message,
+            line,
+            column,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct KoxFunction {
+    pub name: String,
+    /// The index of the function's own variable in `closure`, unless it is
+    /// anonymous or a method.
+    pub slot: Option<usize>,
+    pub body: Expression,
+    pub closure: Environment,
+    pub parameters: Vec<String>,
+    /// The default values of the last parameters, evaluated at each call
+    /// that leaves them out.
+    pub defaults: Vec<Expression>,
+    /// Whether a list of any further arguments follows the parameters.
+    pub rest: bool,
+    pub jit: Rc<RefCell<JitCache>>,
+}
+
+impl Callable for KoxFunction {
+    fn arity(&self) -> Arity {
+        Arity {
+            min: self.parameters.len() - self.defaults.len(),
+            max: (!self.rest).then_some(self.parameters.len()),
+        }
+    }
+
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        args: &[KoxValue],
+        named: &[(String, KoxValue)],
+        (line, column): (usize, usize),
+    ) -> Result<KoxValue, RuntimeError> {
+        let error = |message: String| RuntimeError {
+            message,
+            line,
+            column,
+        };
+
+        let count = self.parameters.len();
+        if named.is_empty() && !self.rest && args.len() == count {
+            if let Some(compiled) = interpreter.compiled(self, args) {
+                match compiled.call(args) {
+                    // Compiled code only uses its arguments, so running the
+                    // call again is safe. Its integers outgrow native ones,
+                    // so it stays in the interpreter from now on.
+                    Err(e)
+                        if interpreter.arithmetic == Arithmetic::Promoting
+                            && e.message == overflow() =>
+                    {
+                        self.jit.borrow_mut().eligible = Some(false);
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        let mut values: Vec<Option<KoxValue>> = vec![None; count];
+        for (value, arg) in values.iter_mut().zip(args) {
+            *value = Some(arg.clone());
+        }
+        for (name, arg) in named {
+            let Some(index) = self.parameters.iter().position(|p| p == name) else {
+                return Err(error(format!("No parameter named '{}'", name)));
+            };
+            if values[index].replace(arg.clone()).is_some() {
+                return Err(error(format!(
+                    "Parameter '{}' is given more than once",
+                    name
+                )));
+            }
+        }
+
+        // Parameters are defined in order, so a default value can use the
+        // ones before it.
+        let environment = self.closure.child();
+        let first_default = count - self.defaults.len();
+        for (index, value) in values.into_iter().enumerate() {
+            let value = match value {
+                Some(value) => value,
+                None if index >= first_default => {
+                    let default = self.defaults[index - first_default].clone();
+                    match interpreter.evaluate(default, &environment)? {
+                        KoxValue::Return(value) => return Ok(KoxValue::Return(value)),
+                        value => value,
+                    }
+                }
+                None => {
+                    return Err(error(format!(
+                        "Missing argument for parameter '{}'",
+                        self.parameters[index]
+                    )))
+                }
+            };
+            environment.define(index, value);
+        }
+        if self.rest {
+            let rest = args.get(count..).unwrap_or_default().to_vec();
+            environment.define(count, KoxValue::List(Rc::new(RefCell::new(rest))));
+        }
+
+        interpreter.evaluate(self.body.clone(), &environment)
+    }
+}
+
+/// A method read from an instance. When called, the instance is `self` in
+/// a struct method and `this` in a class method.
+#[derive(Clone)]
+pub struct BoundMethod {
+    pub receiver: Rc<Instance>,
+    /// The struct or class that defines the method, whose superclass is
+    /// what `super` refers to in it.
+    pub holder: Rc<Struct>,
+    pub method: KoxFunction,
+}
+
+impl Callable for BoundMethod {
+    fn arity(&self) -> Arity {
+        self.method.arity()
+    }
+
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        args: &[KoxValue],
+        named: &[(String, KoxValue)],
+        position: (usize, usize),
+    ) -> Result<KoxValue, RuntimeError> {
+        // The receiver, `this` or `self`, and then `super` are the variables
+        // of a scope around the parameters.
+        let mut method = self.method.clone();
+        method.closure = method.closure.child();
+        method
+            .closure
+            .define(0, KoxValue::Instance(self.receiver.clone()));
+        if let Some(superclass) = &self.holder.superclass {
+            method
+                .closure
+                .define(1, KoxValue::Struct(superclass.clone()));
+        }
+        method.call(interpreter, args, named, position)
+    }
+}
+
+/// Calling a class makes an instance and passes the arguments to its `init`
+/// method, if it has one.
+impl Callable for Rc<Struct> {
+    fn arity(&self) -> Arity {
+        find_method(self, "init").map_or(Arity::exactly(0), |(_, init)| init.arity())
+    }
+
+    fn call(
+        &mut self,
+        interpreter: &mut Interpreter,
+        args: &[KoxValue],
+        named: &[(String, KoxValue)],
+        position: (usize, usize),
+    ) -> Result<KoxValue, RuntimeError> {
+        let instance = Rc::new(Instance {
+            structure: self.clone(),
+            fields: RefCell::new(IndexMap::new()),
+        });
+        if let Some((holder, init)) = find_method(self, "init") {
+            let mut init = BoundMethod {
+                receiver: instance.clone(),
+                holder,
+                method: init,
+            };
+            init.call(interpreter, args, named, position)?;
+        } else {
+            positional(Arity::exactly(0), args, named, position)?;
+        }
+        Ok(KoxValue::Instance(instance))
+    }
+}
+
+/// Calling a variant with fields makes a value of it holding the arguments.
+impl Callable for Constructor {
+    fn arity(&self) -> Arity {
+        Arity::exactly(self.enumeration.variants[self.index].1)
+    }
+
+    fn call(
+        &mut self,
+        _interpreter: &mut Interpreter,
+        args: &[KoxValue],
+        named: &[(String, KoxValue)],
+        position: (usize, usize),
+    ) -> Result<KoxValue, RuntimeError> {
+        positional(self.arity(), args, named, position)?;
+        Ok(KoxValue::Variant(Rc::new(Variant {
+            enumeration: self.enumeration.clone(),
+            index: self.index,
+            values: args.to_vec(),
+        })))
+    }
+}
+
+/// A scope of variables. Environments are shared rather than copied, so
+/// blocks, loops and closures assign the same variables as the code around
+/// them. Variables are found by the slots the resolver gives them rather
+/// than by name.
+#[derive(Clone)]
+pub struct Environment(Rc<RefCell<Scope>>);
+
+struct Scope {
+    enclosing: Option<Environment>,
+    /// The variables by index, each `None` until its declaration runs.
+    slots: Vec<Option<KoxValue>>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self(Rc::new(RefCell::new(Scope {
+            enclosing: None,
+            slots: vec![],
+        })))
+    }
+
+    /// A new scope nested in this one.
+    pub fn child(&self) -> Self {
+        Self(Rc::new(RefCell::new(Scope {
+            enclosing: Some(self.clone()),
+            slots: vec![],
+        })))
+    }
+
+    pub fn get(&self, slot: Slot) -> Option<KoxValue> {
+        let scope = self.0.borrow();
+        match slot.depth {
+            0 => scope.slots.get(slot.index).cloned().flatten(),
+            depth => scope.enclosing.as_ref()?.get(Slot {
+                depth: depth - 1,
+                index: slot.index,
+            }),
+        }
+    }
+
+    /// Declares the variable at `index` in this scope.
+    pub fn define(&self, index: usize, value: KoxValue) {
+        let slots = &mut self.0.borrow_mut().slots;
+        if slots.len() <= index {
+            slots.resize(index + 1, None);
+        }
+        slots[index] = Some(value);
+    }
+
+    /// Assigns a variable, unless its declaration has not run.
+    pub fn assign(&self, slot: Slot, value: KoxValue) -> bool {
+        let mut scope = self.0.borrow_mut();
+        match slot.depth {
+            0 => match scope.slots.get_mut(slot.index) {
+                Some(Some(variable)) => {
+                    *variable = value;
+                    true
+                }
+                _ => false,
+            },
+            depth => match &scope.enclosing {
+                Some(enclosing) => enclosing.assign(
+                    Slot {
+                        depth: depth - 1,
+                        index: slot.index,
+                    },
+                    value,
+                ),
+                None => false,
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum KoxValue {
+    Int(i64),
+    /// Only holds integers that do not fit in an `Int`.
+    BigInt(BigInt),
     Float(f64),
     String(String),
     Boolean(bool),
     Nil,
     NativeFunction(NativeFunction),
-    KoxFunction(KoxFunction),
+    KoxFunction(Rc<KoxFunction>),
+    Closure(Rc<Closure>),
+    Range(Range),
+    List(List),
+    Map(Map),
+    Struct(Rc<Struct>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    Enum(Rc<Enum>),
+    Variant(Rc<Variant>),
+    Constructor(Constructor),
     Return(Box<KoxValue>),
+    Break(Box<KoxValue>),
+    Continue,
+    /// Where the VM is in what a `for` loop iterates over.
+    Iterator(Rc<RefCell<Entries>>),
+}
+
+/// The values a `for` loop takes, each with the value of its key when it
+/// loops over the entries of a map, and nil otherwise.
+pub type Entries = Box<dyn Iterator<Item = (KoxValue, KoxValue)>>;
+
+/// Lists are shared, so a change made through one binding is seen by all.
+pub type List = Rc<RefCell<Vec<KoxValue>>>;
+
+/// Maps are shared like lists and remember the order keys were added in.
+pub type Map = Rc<RefCell<IndexMap<MapKey, KoxValue>>>;
+
+/// A struct or class declaration, shared by all of its instances.
+pub struct Struct {
+    pub name: String,
+    /// Classes declare no fields.
+    pub fields: Vec<String>,
+    pub methods: HashMap<String, KoxFunction>,
+    pub superclass: Option<Rc<Struct>>,
+    /// Whether this is a class, which is called to make instances that
+    /// compare by identity and can be given any field.
+    pub class: bool,
+}
+
+/// Instances are shared like lists. Those of a struct hold every field it
+/// declares, in order.
+pub struct Instance {
+    pub structure: Rc<Struct>,
+    pub fields: RefCell<IndexMap<String, KoxValue>>,
+}
+
+/// An enum declaration, with the number of fields of each variant.
+pub struct Enum {
+    pub name: String,
+    pub variants: Vec<(String, usize)>,
+}
+
+impl Enum {
+    /// The index of a variant and how many fields it has.
+    fn variant(&self, name: &str) -> Result<(usize, usize), String> {
+        self.variants
+            .iter()
+            .position(|(variant, _)| variant == name)
+            .map(|index| (index, self.variants[index].1))
+            .ok_or_else(|| format!("Undefined variant '{}' of enum {}", name, self.name))
+    }
+}
+
+/// A value of an enum. Variants are immutable, so they can be shared.
+pub struct Variant {
+    pub enumeration: Rc<Enum>,
+    pub index: usize,
+    pub values: Vec<KoxValue>,
+}
+
+/// A variant with fields, read from its enum but not yet called.
+#[derive(Clone)]
+pub struct Constructor {
+    pub enumeration: Rc<Enum>,
+    pub index: usize,
+}
+
+impl From<Value> for KoxValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(i) => KoxValue::Int(i),
+            Value::BigInt(i) => KoxValue::BigInt(i),
+            Value::Float(f) => KoxValue::Float(f),
+            Value::String(s) => KoxValue::String(s),
+            Value::Boolean(b) => KoxValue::Boolean(b),
+            Value::Nil => KoxValue::Nil,
+        }
+    }
+}
+
+/// The values that can be map keys, which are the ones compared by value
+/// without involving floats.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Int(i64),
+    BigInt(BigInt),
+    String(String),
+    Boolean(bool),
+    Nil,
+}
+
+impl MapKey {
+    pub fn new(value: KoxValue) -> Result<Self, String> {
+        match value {
+            KoxValue::Int(i) => Ok(MapKey::Int(i)),
+            KoxValue::BigInt(i) => Ok(MapKey::BigInt(i)),
+            KoxValue::String(s) => Ok(MapKey::String(s)),
+            KoxValue::Boolean(b) => Ok(MapKey::Boolean(b)),
+            KoxValue::Nil => Ok(MapKey::Nil),
+            value => Err(format!(
+                "Map keys must be strings, integers, booleans or nil! Not {}",
+                value
+            )),
+        }
+    }
+
+    pub fn value(&self) -> KoxValue {
+        match self {
+            MapKey::Int(i) => KoxValue::Int(*i),
+            MapKey::BigInt(i) => KoxValue::BigInt(i.clone()),
+            MapKey::String(s) => KoxValue::String(s.clone()),
+            MapKey::Boolean(b) => KoxValue::Boolean(*b),
+            MapKey::Nil => KoxValue::Nil,
+        }
+    }
+}
+
+/// An integer range, iterated lazily. `step` is never zero and may be
+/// negative to count down.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    pub inclusive: bool,
+}
+
+impl Range {
+    pub fn values(self) -> impl Iterator<Item = i64> {
+        let Range {
+            start,
+            end,
+            step,
+            inclusive,
+        } = self;
+        std::iter::successors(Some(start), move |i| i.checked_add(step)).take_while(
+            move |&i| match (step > 0, inclusive) {
+                (true, false) => i < end,
+                (true, true) => i <= end,
+                (false, false) => i > end,
+                (false, true) => i >= end,
+            },
+        )
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = if self.inclusive { "..=" } else { ".." };
+        write!(f, "{}{}{}", self.start, operator, self.end)?;
+        if self.step != 1 {
+            write!(f, " step {}", self.step)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for KoxValue {
+    /// Numbers compare by value, with integers promoted to floats, lists,
+    /// maps, instances of the same struct and variants of the same enum by
+    /// their contents, and functions, structs, classes, enums and instances of
+    /// classes by identity.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (KoxValue::Int(a), KoxValue::Int(b)) => a == b,
+            (KoxValue::Int(a), KoxValue::Float(b)) | (KoxValue::Float(b), KoxValue::Int(a)) => {
+                *a as f64 == *b
+            }
+            (KoxValue::Float(a), KoxValue::Float(b)) => a == b,
+            (KoxValue::BigInt(a), KoxValue::BigInt(b)) => a == b,
+            (KoxValue::Int(_), KoxValue::BigInt(_)) | (KoxValue::BigInt(_), KoxValue::Int(_)) => {
+                false
+            }
+            (KoxValue::BigInt(a), KoxValue::Float(b))
+            | (KoxValue::Float(b), KoxValue::BigInt(a)) => to_f64(a) == *b,
+            (KoxValue::String(a), KoxValue::String(b)) => a == b,
+            (KoxValue::Boolean(a), KoxValue::Boolean(b)) => a == b,
+            (KoxValue::Nil, KoxValue::Nil) => true,
+            (KoxValue::NativeFunction(a), KoxValue::NativeFunction(b)) => {
+                std::ptr::fn_addr_eq(a.callable, b.callable)
+            }
+            (KoxValue::KoxFunction(a), KoxValue::KoxFunction(b)) => Rc::ptr_eq(&a.jit, &b.jit),
+            (KoxValue::Closure(a), KoxValue::Closure(b)) => Rc::ptr_eq(a, b),
+            (KoxValue::Range(a), KoxValue::Range(b)) => a == b,
+            (KoxValue::List(a), KoxValue::List(b)) => {
+                Rc::ptr_eq(a, b) || *a.borrow() == *b.borrow()
+            }
+            (KoxValue::Map(a), KoxValue::Map(b)) => Rc::ptr_eq(a, b) || *a.borrow() == *b.borrow(),
+            (KoxValue::Struct(a), KoxValue::Struct(b)) => Rc::ptr_eq(a, b),
+            (KoxValue::Instance(a), KoxValue::Instance(b)) => {
+                Rc::ptr_eq(a, b)
+                    || (!a.structure.class
+                        && Rc::ptr_eq(&a.structure, &b.structure)
+                        && *a.fields.borrow() == *b.fields.borrow())
+            }
+            (KoxValue::BoundMethod(a), KoxValue::BoundMethod(b)) => {
+                Rc::ptr_eq(&a.receiver, &b.receiver) && Rc::ptr_eq(&a.method.jit, &b.method.jit)
+            }
+            (KoxValue::Enum(a), KoxValue::Enum(b)) => Rc::ptr_eq(a, b),
+            (KoxValue::Variant(a), KoxValue::Variant(b)) => {
+                Rc::ptr_eq(&a.enumeration, &b.enumeration)
+                    && a.index == b.index
+                    && a.values == b.values
+            }
+            (KoxValue::Constructor(a), KoxValue::Constructor(b)) => {
+                Rc::ptr_eq(&a.enumeration, &b.enumeration) && a.index == b.index
+            }
+            _ => false,
+        }
+    }
 }
 
 impl Display for KoxValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             KoxValue::Int(i) => write!(f, "{}", i),
+            KoxValue::BigInt(i) => write!(f, "{}", i),
             KoxValue::Float(fl) => write!(f, "{}", fl),
             KoxValue::String(s) => write!(f, "{}", s),
             KoxValue::Boolean(b) => write!(f, "{}", b),
             KoxValue::Nil => write!(f, "nil"),
             KoxValue::NativeFunction(_) => write!(f, "<native function>"),
-            KoxValue::KoxFunction(_) => write!(f, "<function>"),
-            KoxValue::Return(value) => write!(f, "{}", value),
+            KoxValue::KoxFunction(_)
+            | KoxValue::Closure(_)
+            | KoxValue::BoundMethod(_)
+            | KoxValue::Constructor(_) => write!(f, "<function>"),
+            KoxValue::Range(range) => write!(f, "{}", range),
+            KoxValue::List(list) => {
+                write!(f, "[")?;
+                for (i, element) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            KoxValue::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key.value(), value)?;
+                }
+                write!(f, "}}")
+            }
+            KoxValue::Struct(class) if class.class => write!(f, "<class {}>", class.name),
+            KoxValue::Struct(structure) => write!(f, "<struct {}>", structure.name),
+            KoxValue::Instance(instance) if instance.structure.class => {
+                write!(f, "<instance of {}>", instance.structure.name)
+            }
+            KoxValue::Instance(instance) => {
+                write!(f, "{} {{", instance.structure.name)?;
+                for (i, (field, value)) in instance.fields.borrow().iter().enumerate() {
+                    let separator = if i > 0 { "," } else { "" };
+                    write!(f, "{} {}: {}", separator, field, value)?;
+                }
+                if instance.fields.borrow().is_empty() {
+                    write!(f, "}}")
+                } else {
+                    write!(f, " }}")
+                }
+            }
+            KoxValue::Enum(enumeration) => write!(f, "<enum {}>", enumeration.name),
+            KoxValue::Variant(variant) => {
+                write!(f, "{}", variant.enumeration.variants[variant.index].0)?;
+                if variant.values.is_empty() {
+                    return Ok(());
+                }
+                write!(f, "(")?;
+                for (i, value) in variant.values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, ")")
+            }
+            KoxValue::Return(value) | KoxValue::Break(value) => write!(f, "{}", value),
+            KoxValue::Continue => write!(f, "nil"),
+            KoxValue::Iterator(_) => write!(f, "<iterator>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed;
+
+    fn evaluate(input: &str, arithmetic: Arithmetic) -> Result<KoxValue, RuntimeError> {
+        let program = parsed(input);
+        let mut interpreter = Interpreter::new();
+        interpreter.disable_jit();
+        interpreter.set_arithmetic(arithmetic);
+        interpreter.evaluate_program(program, &interpreter.global_environment())
+    }
+
+    /// The value of the last expression of `input`, as `print` shows it.
+    fn run(input: &str) -> String {
+        match evaluate(input, Arithmetic::default()) {
+            Ok(value) => value.to_string(),
+            Err(e) => panic!("{}", e),
         }
     }
+
+    fn error(input: &str) -> String {
+        match evaluate(input, Arithmetic::default()) {
+            Ok(value) => panic!("evaluated to {}", value),
+            Err(e) => e.message,
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run("1 + 2 * 3;"), "7");
+        assert_eq!(run("7 / 2;"), "3");
+        assert_eq!(run("7.0 / 2;"), "3.5");
+        assert_eq!(run("2 ^ 10;"), "1024");
+        assert_eq!(run("-7 % 3;"), "2");
+        assert_eq!(run("1 == 1.0;"), "true");
+        assert_eq!(run("\"a\" + \"b\";"), "ab");
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error_when_checked() {
+        let overflow = "9223372036854775807 + 1;";
+        let e = evaluate(overflow, Arithmetic::Checked).err().unwrap();
+        assert_eq!(e.message, "Integer overflow");
+        let value = evaluate(overflow, Arithmetic::Wrapping).ok().unwrap();
+        assert_eq!(value.to_string(), "-9223372036854775808");
+    }
+
+    #[test]
+    fn integer_overflow_promotes_to_big_integers() {
+        assert_eq!(run("9223372036854775807 + 1;"), "9223372036854775808");
+    }
+
+    #[test]
+    fn closures_keep_their_variables() {
+        let counter = "
+            fn counter() { let n = 0; fn() { n = n + 1; n } }
+            let c = counter();
+            c(); c();";
+        assert_eq!(run(counter), "2");
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(
+            run("let total = 0; for i in 0..=10 { total = total + i; } total;"),
+            "55"
+        );
+        assert_eq!(
+            run("let i = 0; while true { i = i + 1; if i == 3 { break; } } i;"),
+            "3"
+        );
+    }
+
+    #[test]
+    fn default_rest_and_named_arguments() {
+        assert_eq!(run("fn f(a, b = a + 1) { a * b } f(2);"), "6");
+        assert_eq!(run("fn f(a, b = 2) { a * b } f(b: 5, a: 2);"), "10");
+        assert_eq!(run("fn f(a, ...r) { r } f(1, 2, 3);"), "[2, 3]");
+        assert_eq!(
+            error("fn f(a, b = 1) { a } f(1, 2, 3);"),
+            "Expected 1 to 2 arguments but got 3"
+        );
+        assert_eq!(
+            error("fn f(a, b) { a } f(b: 1);"),
+            "Missing argument for parameter 'a'"
+        );
+        assert_eq!(error("fn f(a) { a } f(b: 1);"), "No parameter named 'b'");
+    }
+
+    #[test]
+    fn classes() {
+        let classes = "
+            class A { fn init(x) { this.x = x; } fn get() { this.x } }
+            class B < A { fn get() { super.get() + 1 } }
+            B(1).get();";
+        assert_eq!(run(classes), "2");
+    }
+
+    #[test]
+    fn enums() {
+        let enums = "
+            enum Shape { Circle(r), Square(s) }
+            let area = match Shape.Circle(2) { Shape.Circle(r) => r * 3, Shape.Square(s) => s };
+            area;";
+        assert_eq!(run(enums), "6");
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(run("let l = [1, 2]; push(l, 3); l;"), "[1, 2, 3]");
+        assert_eq!(run("[1, 2] == [1, 2];"), "true");
+    }
+
+    #[test]
+    fn maps() {
+        assert_eq!(
+            run("let m = {\"a\": 1}; m[\"b\"] = 2; m[\"a\"] + m[\"b\"];"),
+            "3"
+        );
+        assert_eq!(
+            run("let s = 0; for (k, v) in {\"a\": 1, \"b\": 2} { s = s + v; } s;"),
+            "3"
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(error("1 + true;"), "Operands must be of the same type");
+        assert_eq!(error("let x = 1; x();"), "Can only call functions! Not 1");
+    }
 }
@@ -0,0 +1,506 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, CStr},
+    mem,
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use cranelift::codegen::ir::UserFuncName;
+use cranelift::prelude::*;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use crate::{
+    ast::{Expression, Slot, Value as AstValue},
+    interpreter::{Arithmetic, KoxValue, RuntimeError},
+    native::{CompileError, Definition, Errors, NativeType, Program, Ty},
+    token::Token,
+};
+
+/// Set by [`raise`] so that compiled code returns as soon as it notices.
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static ERROR: RefCell<Option<RuntimeError>> = const { RefCell::new(None) };
+}
+
+/// Called by compiled code on a runtime error.
+extern "C" fn raise(message: *const c_char, line: i64, column: i64) {
+    let message = unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned();
+    ERROR.with(|error| {
+        *error.borrow_mut() = Some(RuntimeError {
+            message,
+            line: line as usize,
+            column: column as usize,
+        })
+    });
+    FAILED.store(true, Ordering::SeqCst);
+}
+
+/// Float `^` in compiled code.
+extern "C" fn pow(base: f64, exponent: f64) -> f64 {
+    base.powf(exponent)
+}
+
+/// The types of `args` if every one of them can be passed to compiled code.
+pub fn argument_types(args: &[KoxValue]) -> Option<Vec<NativeType>> {
+    args.iter()
+        .map(|arg| match arg {
+            KoxValue::Int(_) => Some(NativeType::Int),
+            KoxValue::Float(_) => Some(NativeType::Float),
+            KoxValue::Boolean(_) => Some(NativeType::Boolean),
+            _ => None,
+        })
+        .collect()
+}
+
+fn encode(value: &KoxValue) -> u64 {
+    match value {
+        KoxValue::Int(i) => *i as u64,
+        KoxValue::Float(f) => f.to_bits(),
+        KoxValue::Boolean(b) => *b as u64,
+        _ => unreachable!("only numeric values are passed to compiled functions"),
+    }
+}
+
+/// Tiered execution state for a function, shared between all copies of it.
+#[derive(Default)]
+pub struct JitCache {
+    pub calls: usize,
+    pub eligible: Option<bool>,
+    /// Compiled code keyed by the argument types it was specialized for.
+    /// `None` records that the JIT could not compile it.
+    pub specializations: HashMap<Vec<NativeType>, Option<Rc<CompiledFunction>>>,
+}
+
+/// Cheap syntactic check for whether a function body is worth handing to the
+/// JIT at all. Passing it does not guarantee that compilation succeeds.
+///
+/// `own` is where the function's own variable is seen from its parameters,
+/// if it has one. Calls through it are recursion, the only calls compiled
+/// code makes.
+pub fn eligible(own: Option<Slot>, body: &Expression) -> bool {
+    match body {
+        Expression::Value { value, .. } => {
+            !matches!(value, AstValue::String(_) | AstValue::BigInt(_))
+        }
+        Expression::Identifier { .. } => true,
+        // An integer raised to a negative power is a float, so compiled code
+        // needs to know the sign of the exponent, which only literals give.
+        Expression::Binary {
+            operator: Token::Pow,
+            right,
+            ..
+        } if !matches!(
+            **right,
+            Expression::Value {
+                value: AstValue::Int(_) | AstValue::Float(_),
+                ..
+            }
+        ) =>
+        {
+            false
+        }
+        Expression::Binary { left, right, .. } => eligible(own, left) && eligible(own, right),
+        Expression::Logical { left, right, .. } => eligible(own, left) && eligible(own, right),
+        Expression::Unary { right, .. } => eligible(own, right),
+        Expression::Let { value, .. }
+        | Expression::Assign { value, .. }
+        | Expression::Return { value, .. } => eligible(own, value),
+        Expression::Block { expressions, .. } => {
+            // The block is a scope of its own.
+            let own = own.map(|slot| Slot {
+                depth: slot.depth + 1,
+                ..slot
+            });
+            expressions
+                .iter()
+                .all(|expression| eligible(own, expression))
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            eligible(own, condition)
+                && eligible(own, consequence)
+                && alternative
+                    .as_ref()
+                    .is_none_or(|alternative| eligible(own, alternative))
+        }
+        Expression::Call {
+            function,
+            arguments,
+            named,
+            ..
+        } => {
+            matches!(&**function, Expression::Identifier { slot, .. } if own.is_some() && *slot == own)
+                && named.is_empty()
+                && arguments.iter().all(|argument| eligible(own, argument))
+        }
+        Expression::While {
+            condition, body, ..
+        } => eligible(own, condition) && eligible(own, body),
+        Expression::Break { value, .. } => eligible(own, value),
+        Expression::Continue { .. } => true,
+        Expression::Function { .. }
+        | Expression::Lambda { .. }
+        | Expression::For { .. }
+        | Expression::Range { .. }
+        | Expression::List { .. }
+        | Expression::Index { .. }
+        | Expression::SetIndex { .. }
+        | Expression::Map { .. }
+        | Expression::Struct { .. }
+        | Expression::Class { .. }
+        | Expression::Super { .. }
+        | Expression::Construct { .. }
+        | Expression::Get { .. }
+        | Expression::Set { .. }
+        | Expression::Enum { .. }
+        | Expression::Match { .. } => false,
+    }
+}
+
+/// A native function specialized for one set of argument types.
+pub struct CompiledFunction {
+    returns: Ty,
+    entry: extern "C" fn(*const u64) -> u64,
+}
+
+impl CompiledFunction {
+    /// Calls the compiled code. The arguments must have the types it was
+    /// specialized for.
+    pub fn call(&self, args: &[KoxValue]) -> Result<KoxValue, RuntimeError> {
+        let encoded: Vec<u64> = args.iter().map(encode).collect();
+        let result = (self.entry)(encoded.as_ptr());
+        if FAILED.swap(false, Ordering::SeqCst) {
+            return Err(ERROR.with(|error| error.borrow_mut().take()).unwrap());
+        }
+        Ok(match self.returns {
+            Ty::Value(NativeType::Int) => KoxValue::Int(result as i64),
+            Ty::Value(NativeType::Float) => KoxValue::Float(f64::from_bits(result)),
+            Ty::Value(NativeType::Boolean) => KoxValue::Boolean(result != 0),
+            _ => KoxValue::Nil,
+        })
+    }
+}
+
+pub struct Jit {
+    builder_context: FunctionBuilderContext,
+    ctx: codegen::Context,
+    module: JITModule,
+    compiled: usize,
+    raise: FuncId,
+}
+
+impl Jit {
+    pub fn new() -> Result<Self, CompileError> {
+        let error = |message: String| CompileError {
+            message,
+            line: 0,
+            column: 0,
+        };
+
+        let mut flag_builder = settings::builder();
+        flag_builder
+            .set("use_colocated_libcalls", "false")
+            .map_err(|e| error(e.to_string()))?;
+        flag_builder
+            .set("is_pic", "false")
+            .map_err(|e| error(e.to_string()))?;
+        flag_builder
+            .set("opt_level", "speed")
+            .map_err(|e| error(e.to_string()))?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| error(format!("host machine is not supported: {}", e)))?
+            .finish(settings::Flags::new(flag_builder))
+            .map_err(|e| error(e.to_string()))?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("kox_raise", raise as *const u8);
+        builder.symbol("pow", pow as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let pointer = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(types::I64));
+        signature.params.push(AbiParam::new(types::I64));
+        let raise = module
+            .declare_function("kox_raise", Linkage::Import, &signature)
+            .map_err(|e| error(e.to_string()))?;
+
+        Ok(Self {
+            builder_context: FunctionBuilderContext::new(),
+            ctx: module.make_context(),
+            module,
+            compiled: 0,
+            raise,
+        })
+    }
+
+    /// Compiles a Kox function for the given argument types. Only numeric
+    /// code is supported: locals, arithmetic, comparisons, `if`, `return`
+    /// and calls of the function to itself.
+    pub fn compile(
+        &mut self,
+        name: &str,
+        parameters: &[String],
+        defaults: &[Expression],
+        body: &Expression,
+        arguments: &[NativeType],
+        arithmetic: Arithmetic,
+    ) -> Result<CompiledFunction, CompileError> {
+        let errors = Errors {
+            raise: self.raise,
+            failed: Some(FAILED.as_ptr() as i64),
+        };
+        let mut program = Program::new(
+            vec![Definition {
+                name,
+                parameters,
+                defaults,
+                body,
+            }],
+            None,
+            errors,
+            arithmetic,
+        );
+        let entry = program.specialize(name, arguments)?;
+        let returns = program.instances()[entry].returns.unwrap();
+
+        let id = self.compiled;
+        self.compiled += 1;
+        let ids = program.define(
+            &mut self.module,
+            &mut self.ctx,
+            &mut self.builder_context,
+            |i, instance| {
+                (
+                    format!("__kox_{}_{}_{}", instance.definition.name, id, i),
+                    Linkage::Local,
+                )
+            },
+        )?;
+
+        let entry_id = self.trampoline(ids[entry], arguments, returns, id)?;
+        self.module
+            .finalize_definitions()
+            .map_err(|e| CompileError::module(e, body))?;
+
+        let code = self.module.get_finalized_function(entry_id);
+        Ok(CompiledFunction {
+            returns,
+            entry: unsafe { mem::transmute::<*const u8, extern "C" fn(*const u64) -> u64>(code) },
+        })
+    }
+
+    /// Emits a `fn(*const u64) -> u64` wrapper around a compiled function so
+    /// that it can be called from Rust regardless of its signature.
+    fn trampoline(
+        &mut self,
+        func_id: FuncId,
+        arguments: &[NativeType],
+        returns: Ty,
+        id: usize,
+    ) -> Result<FuncId, CompileError> {
+        let pointer = self.module.target_config().pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let entry_id = self.module.declare_function(
+            &format!("__kox_entry_{}", id),
+            Linkage::Local,
+            &signature,
+        )?;
+        self.ctx.func.signature = signature;
+        self.ctx.func.name = UserFuncName::user(0, entry_id.as_u32());
+
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
+        let callee = self.module.declare_func_in_func(func_id, builder.func);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+
+        let args_pointer = builder.block_params(block)[0];
+        let mut args = vec![];
+        for (i, argument) in arguments.iter().enumerate() {
+            let raw = builder.ins().load(
+                types::I64,
+                MemFlags::trusted(),
+                args_pointer,
+                (i * 8) as i32,
+            );
+            args.push(match argument {
+                NativeType::Float => builder.ins().bitcast(types::F64, MemFlags::new(), raw),
+                NativeType::Boolean => builder.ins().ireduce(types::I8, raw),
+                _ => raw,
+            });
+        }
+        let call = builder.ins().call(callee, &args);
+        let result = match returns {
+            Ty::Value(NativeType::Float) => {
+                let result = builder.inst_results(call)[0];
+                builder.ins().bitcast(types::I64, MemFlags::new(), result)
+            }
+            Ty::Value(NativeType::Boolean) => {
+                let result = builder.inst_results(call)[0];
+                builder.ins().uextend(types::I64, result)
+            }
+            Ty::Value(_) => builder.inst_results(call)[0],
+            _ => builder.ins().iconst(types::I64, 0),
+        };
+        builder.ins().return_(&[result]);
+        builder.finalize();
+
+        let result = self.module.define_function(entry_id, &mut self.ctx);
+        self.module.clear_context(&mut self.ctx);
+        result?;
+        Ok(entry_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interpreter::Interpreter, parsed};
+
+    /// Compiles the function `input` defines for `arguments`.
+    fn compile(input: &str, arguments: &[NativeType]) -> Result<CompiledFunction, CompileError> {
+        let program = parsed(input);
+        let Expression::Function {
+            name,
+            parameters,
+            defaults,
+            body,
+            ..
+        } = &program[0]
+        else {
+            panic!("expected a function, got {:?}", program[0]);
+        };
+        Jit::new()?.compile(
+            name,
+            parameters,
+            defaults,
+            body,
+            arguments,
+            Arithmetic::Checked,
+        )
+    }
+
+    /// The value of the last expression of `input`, or its error, with
+    /// every function compiled on its first call if `jit` is set.
+    fn run(input: &str, jit: bool) -> String {
+        let mut interpreter = Interpreter::new();
+        if jit {
+            interpreter.set_jit_threshold(0);
+        } else {
+            interpreter.disable_jit();
+        }
+        let environment = interpreter.global_environment();
+        match interpreter.evaluate_program(parsed(input), &environment) {
+            Ok(value) => value.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const FIB: &str = "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }";
+
+    #[test]
+    fn only_numbers_and_booleans_are_passed_to_compiled_code() {
+        assert_eq!(
+            argument_types(&[
+                KoxValue::Int(1),
+                KoxValue::Float(1.0),
+                KoxValue::Boolean(true)
+            ]),
+            Some(vec![
+                NativeType::Int,
+                NativeType::Float,
+                NativeType::Boolean
+            ])
+        );
+        assert_eq!(argument_types(&[KoxValue::Nil]), None);
+    }
+
+    #[test]
+    fn eligibility_is_syntactic() {
+        let eligible = |input: &str| {
+            let program = parsed(input);
+            let Expression::Function { slot, body, .. } = &program[0] else {
+                panic!("expected a function, got {:?}", program[0]);
+            };
+            super::eligible(slot.map(|index| Slot { depth: 1, index }), body)
+        };
+        assert!(eligible(FIB));
+        assert!(eligible(
+            "fn f(x) { let i = 0; while i < x { i = i + 1; } i }"
+        ));
+        assert!(!eligible("fn f(x) { \"a\" }"));
+        assert!(!eligible("fn f(x) { x ^ x }"));
+        assert!(!eligible("fn f(x) { g(x) } fn g(x) { x }"));
+        // Only calls of the function's own variable are recursion.
+        assert!(eligible("fn f(x) { if x > 0 { f(x - 1) } else { 0 } }"));
+        assert!(!eligible("fn f(f) { f(1) }"));
+        assert!(!eligible("fn f(x) { [x] }"));
+    }
+
+    #[test]
+    fn compiled_functions_are_specialized_to_the_argument_types() {
+        let call = |input: &str, argument: KoxValue| {
+            let types = argument_types(std::slice::from_ref(&argument)).unwrap();
+            let function = compile(input, &types).unwrap_or_else(|e| panic!("{}", e));
+            match function.call(&[argument]) {
+                Ok(value) => value.to_string(),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(call(FIB, KoxValue::Int(20)), "6765");
+        assert_eq!(call("fn half(x) { x / 2 }", KoxValue::Int(7)), "3");
+        assert_eq!(call("fn half(x) { x / 2 }", KoxValue::Float(7.0)), "3.5");
+    }
+
+    #[test]
+    fn unsupported_code_is_not_compiled() {
+        assert!(compile("fn f(x) { print(x) }", &[NativeType::Int]).is_err());
+        assert!(compile("fn f(x) { x + \"a\" }", &[NativeType::Int]).is_err());
+        assert!(compile("fn f(x) { [x] }", &[NativeType::Int]).is_err());
+    }
+
+    #[test]
+    fn branches_of_different_types_can_only_be_discarded() {
+        let int = &[NativeType::Int];
+        assert!(compile("fn f(n) { if n > 0 { n * 1.5 } else { 0 } }", int).is_err());
+        assert!(compile("fn f(n) { if n > 0 { 1 } }", int).is_err());
+        assert!(compile("fn f(n) { let x = if n > 0 { 1 }; 2 }", int).is_err());
+        assert!(compile("fn f(n) { if n > 0 { n = 1 } n }", int).is_ok());
+    }
+
+    #[test]
+    fn compiled_code_matches_the_interpreter() {
+        let programs = [
+            format!("{} fib(12);", FIB),
+            "fn f(x) { x * 2.5 - 1 } f(3.0);".to_string(),
+            "fn f(a, b) { a < b and !(a == b) } f(1, 2);".to_string(),
+            "fn f(n) { let s = 0; let i = 0; while i < n { i = i + 1; if i % 2 == 0 { continue; } s = s + i; } s } f(100);".to_string(),
+            "fn f(n, acc = 1) { if n == 0 { acc } else { f(n - 1, acc * 2) } } f(8);".to_string(),
+            "fn f(x) { x ^ 3 } f(1.5);".to_string(),
+            "fn f(x) { -x % 3 } f(7);".to_string(),
+            format!("{} let g = fib; fib = fn(n) {{ 100 }}; g(5);", FIB),
+            // Integers outgrowing 64 bits are left to the interpreter.
+            "fn f(n) { let x = 1; while n > 0 { x = x * 2; n = n - 1; } x } f(70);".to_string(),
+            "fn f(x) { -x } f(-9223372036854775807 - 1);".to_string(),
+        ];
+        for program in programs {
+            assert_eq!(run(&program, true), run(&program, false), "{}", program);
+        }
+    }
+}
@@ -0,0 +1,781 @@
+//! The `.koxc` file format for compiled bytecode.
+//!
+//! All integers are little endian and strings are a `u32` length followed
+//! by UTF-8 bytes.
+//!
+//! ```text
+//! magic     "KOXC"
+//! version   u16
+//! checksum  u32 FNV-1a hash of everything after it
+//! globals   u32 count, then a string per global
+//! functions u32 count, then each function (see below)
+//! main      u32 index of the top-level code in the function table
+//! ```
+//!
+//! Functions come after every function they create closures of, so the
+//! table can be rebuilt in a single pass. Each one is stored as
+//!
+//! ```text
+//! name       string
+//! parameters u8 count, then a string each
+//! entries    u16 count, then a u32 code offset each
+//! rest       u8, 1 if a rest parameter follows the others
+//! slots      u16
+//! upvalues   u16 count, then u8 (1 for a local) and u16 index each
+//! constants  u32 count, then a u8 tag and the value each
+//! code       u32 count, then a u8 opcode and its operand each
+//! lines      u32 line and u32 column per instruction
+//! ```
+//!
+//! Integers beyond 64 bits are stored as a `u32` length followed by their
+//! little endian two's complement bytes.
+
+use std::{collections::HashMap, fmt::Display, rc::Rc};
+
+use num_bigint::BigInt;
+
+use crate::bytecode::{Capture, Chunk, Constant, Instruction, Program, Prototype};
+
+pub const MAGIC: &[u8; 4] = b"KOXC";
+
+/// Bumped whenever the layout or the instruction set changes.
+pub const VERSION: u16 = 10;
+
+pub struct LoadError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Load error at byte {}: {}", self.offset, self.message)
+    }
+}
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_STRING: u8 = 2;
+const TAG_FUNCTION: u8 = 3;
+const TAG_BIGINT: u8 = 4;
+
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut functions = vec![];
+    let mut indices = HashMap::new();
+    collect(&program.main, &mut functions, &mut indices);
+
+    let mut writer = Writer { bytes: vec![] };
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(VERSION);
+    let header = writer.bytes.len();
+    writer.u32(program.globals.len() as u32);
+    for global in &program.globals {
+        writer.string(global);
+    }
+    writer.u32(functions.len() as u32);
+    for function in &functions {
+        writer.function(function, &indices);
+    }
+    writer.u32(indices[&Rc::as_ptr(&program.main)]);
+    let checksum = checksum(&writer.bytes[header..]);
+    writer.bytes.splice(header..header, checksum.to_le_bytes());
+    writer.bytes
+}
+
+/// 32-bit FNV-1a, to catch files damaged after they were written.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Lists `prototype` and the functions it creates, each after the ones it
+/// refers to.
+fn collect<'a>(
+    prototype: &'a Rc<Prototype>,
+    functions: &mut Vec<&'a Prototype>,
+    indices: &mut HashMap<*const Prototype, u32>,
+) {
+    if indices.contains_key(&Rc::as_ptr(prototype)) {
+        return;
+    }
+    for constant in &prototype.chunk.constants {
+        if let Constant::Function(function) = constant {
+            collect(function, functions, indices);
+        }
+    }
+    indices.insert(Rc::as_ptr(prototype), functions.len() as u32);
+    functions.push(prototype);
+}
+
+pub fn read(bytes: &[u8]) -> Result<Program, LoadError> {
+    let mut reader = Reader { bytes, offset: 0 };
+
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(reader.error("not a compiled Kox file".to_string()));
+    }
+    reader.offset = MAGIC.len();
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(LoadError {
+            message: format!(
+                "compiled for bytecode version {}, but this is version {}",
+                version, VERSION
+            ),
+            offset: MAGIC.len(),
+        });
+    }
+    let checksum = reader.u32()?;
+    let body = reader.offset;
+
+    let count = reader.u32()?;
+    let mut globals = vec![];
+    for _ in 0..count {
+        globals.push(reader.string()?);
+    }
+
+    let count = reader.u32()?;
+    let mut functions: Vec<Rc<Prototype>> = vec![];
+    for _ in 0..count {
+        let start = reader.offset;
+        let function = reader.function(&functions)?;
+        validate(&function, &functions, globals.len()).map_err(|message| LoadError {
+            message: format!("invalid function '{}': {}", function.name, message),
+            offset: start,
+        })?;
+        functions.push(Rc::new(function));
+    }
+
+    let main = reader.u32()? as usize;
+    let main = match functions.get(main) {
+        Some(main)
+            if main.parameters.is_empty()
+                && main.entries == [0]
+                && !main.rest
+                && main.upvalues.is_empty() =>
+        {
+            main.clone()
+        }
+        _ => return Err(reader.error("invalid main function".to_string())),
+    };
+    if reader.offset != bytes.len() {
+        return Err(reader.error("unexpected data after the program".to_string()));
+    }
+    if checksum != self::checksum(&bytes[body..]) {
+        return Err(LoadError {
+            message: "checksum mismatch, the file is damaged".to_string(),
+            offset: body - 4,
+        });
+    }
+
+    Ok(Program { globals, main })
+}
+
+/// Checks that running a function cannot index outside its constants,
+/// slots, upvalues or code, or outside the globals.
+fn validate(
+    function: &Prototype,
+    functions: &[Rc<Prototype>],
+    globals: usize,
+) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let arity = function.parameters.len();
+    if (function.slots as usize) < arity + 1 + function.rest as usize {
+        return Err("fewer slots than parameters".to_string());
+    }
+    // Calls start at the entry for how many of the parameters with default
+    // values they give, so there is at least one and no more than one per
+    // parameter besides.
+    if function.entries.is_empty() || function.entries.len() > arity + 1 {
+        return Err("invalid number of entry points".to_string());
+    }
+    if function
+        .entries
+        .iter()
+        .any(|&entry| entry as usize >= chunk.code.len())
+    {
+        return Err("entry point outside the code".to_string());
+    }
+    match chunk.code.last() {
+        Some(Instruction::Return | Instruction::Jump(_)) => {}
+        _ => return Err("code does not end in a return".to_string()),
+    }
+
+    let constant = |index: u16| chunk.constants.get(index as usize);
+    for instruction in &chunk.code {
+        let valid = match *instruction {
+            Instruction::Constant(index) => {
+                matches!(constant(index), Some(constant) if !matches!(constant, Constant::Function(_)))
+            }
+            Instruction::GetLocal(slot)
+            | Instruction::SetLocal(slot)
+            | Instruction::CloseUpvalues(slot) => slot < function.slots,
+            Instruction::GetUpvalue(index) | Instruction::SetUpvalue(index) => {
+                (index as usize) < function.upvalues.len()
+            }
+            Instruction::GetGlobal(index)
+            | Instruction::SetGlobal(index)
+            | Instruction::DefineGlobal(index) => (index as usize) < globals,
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::ForIter(target) => (target as usize) < chunk.code.len(),
+            Instruction::Closure(index) => match constant(index) {
+                Some(Constant::Function(closure)) => closure.upvalues.iter().all(|capture| {
+                    if capture.local {
+                        capture.index < function.slots
+                    } else {
+                        (capture.index as usize) < function.upvalues.len()
+                    }
+                }),
+                _ => false,
+            },
+            _ => true,
+        };
+        if !valid {
+            return Err(format!("invalid operand in {:?}", instruction));
+        }
+    }
+
+    // Nested functions are checked against their own table entry when they
+    // are read, so they only need to be ones that were already read.
+    for constant in &chunk.constants {
+        if let Constant::Function(nested) = constant {
+            if !functions
+                .iter()
+                .any(|function| Rc::ptr_eq(function, nested))
+            {
+                return Err("refers to an unknown function".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn function(&mut self, function: &Prototype, indices: &HashMap<*const Prototype, u32>) {
+        self.string(&function.name);
+        self.u8(function.parameters.len() as u8);
+        for parameter in &function.parameters {
+            self.string(parameter);
+        }
+        self.u16(function.entries.len() as u16);
+        for &entry in &function.entries {
+            self.u32(entry);
+        }
+        self.u8(function.rest as u8);
+        self.u16(function.slots);
+
+        self.u16(function.upvalues.len() as u16);
+        for capture in &function.upvalues {
+            self.u8(capture.local as u8);
+            self.u16(capture.index);
+        }
+
+        let chunk = &function.chunk;
+        self.u32(chunk.constants.len() as u32);
+        for constant in &chunk.constants {
+            match constant {
+                Constant::Int(i) => {
+                    self.u8(TAG_INT);
+                    self.u64(*i as u64);
+                }
+                Constant::BigInt(i) => {
+                    let bytes = i.to_signed_bytes_le();
+                    self.u8(TAG_BIGINT);
+                    self.u32(bytes.len() as u32);
+                    self.bytes.extend_from_slice(&bytes);
+                }
+                Constant::Float(f) => {
+                    self.u8(TAG_FLOAT);
+                    self.u64(f.to_bits());
+                }
+                Constant::String(s) => {
+                    self.u8(TAG_STRING);
+                    self.string(s);
+                }
+                Constant::Function(function) => {
+                    self.u8(TAG_FUNCTION);
+                    self.u32(indices[&Rc::as_ptr(function)]);
+                }
+            }
+        }
+
+        self.u32(chunk.code.len() as u32);
+        for instruction in &chunk.code {
+            self.instruction(*instruction);
+        }
+        for (line, column) in &chunk.positions {
+            self.u32(u32::try_from(*line).unwrap_or(u32::MAX));
+            self.u32(u32::try_from(*column).unwrap_or(u32::MAX));
+        }
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Constant(index) => {
+                self.u8(0);
+                self.u16(index);
+            }
+            Instruction::Nil => self.u8(1),
+            Instruction::True => self.u8(2),
+            Instruction::False => self.u8(3),
+            Instruction::Pop => self.u8(4),
+            Instruction::GetLocal(slot) => {
+                self.u8(5);
+                self.u16(slot);
+            }
+            Instruction::SetLocal(slot) => {
+                self.u8(6);
+                self.u16(slot);
+            }
+            Instruction::GetUpvalue(index) => {
+                self.u8(7);
+                self.u16(index);
+            }
+            Instruction::SetUpvalue(index) => {
+                self.u8(8);
+                self.u16(index);
+            }
+            Instruction::GetGlobal(index) => {
+                self.u8(9);
+                self.u16(index);
+            }
+            Instruction::SetGlobal(index) => {
+                self.u8(10);
+                self.u16(index);
+            }
+            Instruction::DefineGlobal(index) => {
+                self.u8(11);
+                self.u16(index);
+            }
+            Instruction::Add => self.u8(12),
+            Instruction::Subtract => self.u8(13),
+            Instruction::Multiply => self.u8(14),
+            Instruction::Divide => self.u8(15),
+            Instruction::Power => self.u8(16),
+            Instruction::Equal => self.u8(17),
+            Instruction::NotEqual => self.u8(18),
+            Instruction::Less => self.u8(19),
+            Instruction::Greater => self.u8(20),
+            Instruction::LessEqual => self.u8(21),
+            Instruction::GreaterEqual => self.u8(22),
+            Instruction::Jump(target) => {
+                self.u8(23);
+                self.u32(target);
+            }
+            Instruction::JumpIfFalse(target) => {
+                self.u8(24);
+                self.u32(target);
+            }
+            Instruction::Call(arguments) => {
+                self.u8(25);
+                self.u8(arguments);
+            }
+            Instruction::Closure(index) => {
+                self.u8(26);
+                self.u16(index);
+            }
+            Instruction::CloseUpvalues(slot) => {
+                self.u8(27);
+                self.u16(slot);
+            }
+            Instruction::Return => self.u8(28),
+            Instruction::Negate => self.u8(29),
+            Instruction::Not => self.u8(30),
+            Instruction::Modulo => self.u8(31),
+            Instruction::BuildList(count) => {
+                self.u8(32);
+                self.u16(count);
+            }
+            Instruction::GetIndex => self.u8(33),
+            Instruction::SetIndex => self.u8(34),
+            Instruction::BuildMap(count) => {
+                self.u8(35);
+                self.u16(count);
+            }
+            Instruction::BuildRange(inclusive) => {
+                self.u8(36);
+                self.u8(inclusive as u8);
+            }
+            Instruction::GetIter => self.u8(37),
+            Instruction::GetEntries => self.u8(38),
+            Instruction::ForIter(target) => {
+                self.u8(39);
+                self.u32(target);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, message: String) -> LoadError {
+        LoadError {
+            message,
+            offset: self.offset,
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&[u8], LoadError> {
+        if self.bytes.len() - self.offset < count {
+            return Err(self.error("unexpected end of file".to_string()));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let length = self.u32()? as usize;
+        let start = self.offset;
+        let bytes = self.take(length)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| LoadError {
+            message: "string is not valid UTF-8".to_string(),
+            offset: start,
+        })
+    }
+
+    fn function(&mut self, functions: &[Rc<Prototype>]) -> Result<Prototype, LoadError> {
+        let name = self.string()?;
+        let count = self.u8()?;
+        let mut parameters = vec![];
+        for _ in 0..count {
+            parameters.push(self.string()?);
+        }
+        let count = self.u16()?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            entries.push(self.u32()?);
+        }
+        let rest = match self.u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(self.error(format!("invalid rest flag {}", flag))),
+        };
+        let slots = self.u16()?;
+
+        let count = self.u16()?;
+        let mut upvalues = vec![];
+        for _ in 0..count {
+            let local = match self.u8()? {
+                0 => false,
+                1 => true,
+                flag => return Err(self.error(format!("invalid upvalue kind {}", flag))),
+            };
+            let index = self.u16()?;
+            upvalues.push(Capture { local, index });
+        }
+
+        let count = self.u32()?;
+        let mut constants = vec![];
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                TAG_INT => Constant::Int(self.u64()? as i64),
+                TAG_BIGINT => {
+                    let length = self.u32()? as usize;
+                    Constant::BigInt(BigInt::from_signed_bytes_le(self.take(length)?))
+                }
+                TAG_FLOAT => Constant::Float(f64::from_bits(self.u64()?)),
+                TAG_STRING => Constant::String(self.string()?),
+                TAG_FUNCTION => {
+                    let index = self.u32()? as usize;
+                    match functions.get(index) {
+                        Some(function) => Constant::Function(function.clone()),
+                        None => {
+                            return Err(self.error(format!("unknown function {}", index)));
+                        }
+                    }
+                }
+                tag => return Err(self.error(format!("invalid constant tag {}", tag))),
+            };
+            constants.push(constant);
+        }
+
+        let count = self.u32()?;
+        let mut code = vec![];
+        for _ in 0..count {
+            code.push(self.instruction()?);
+        }
+        let mut positions = vec![];
+        for _ in 0..count {
+            let line = self.u32()? as usize;
+            let column = self.u32()? as usize;
+            positions.push((line, column));
+        }
+
+        Ok(Prototype {
+            name,
+            parameters,
+            entries,
+            rest,
+            slots,
+            upvalues,
+            chunk: Chunk {
+                code,
+                positions,
+                constants,
+            },
+        })
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, LoadError> {
+        let instruction = match self.u8()? {
+            0 => Instruction::Constant(self.u16()?),
+            1 => Instruction::Nil,
+            2 => Instruction::True,
+            3 => Instruction::False,
+            4 => Instruction::Pop,
+            5 => Instruction::GetLocal(self.u16()?),
+            6 => Instruction::SetLocal(self.u16()?),
+            7 => Instruction::GetUpvalue(self.u16()?),
+            8 => Instruction::SetUpvalue(self.u16()?),
+            9 => Instruction::GetGlobal(self.u16()?),
+            10 => Instruction::SetGlobal(self.u16()?),
+            11 => Instruction::DefineGlobal(self.u16()?),
+            12 => Instruction::Add,
+            13 => Instruction::Subtract,
+            14 => Instruction::Multiply,
+            15 => Instruction::Divide,
+            16 => Instruction::Power,
+            17 => Instruction::Equal,
+            18 => Instruction::NotEqual,
+            19 => Instruction::Less,
+            20 => Instruction::Greater,
+            21 => Instruction::LessEqual,
+            22 => Instruction::GreaterEqual,
+            23 => Instruction::Jump(self.u32()?),
+            24 => Instruction::JumpIfFalse(self.u32()?),
+            25 => Instruction::Call(self.u8()?),
+            26 => Instruction::Closure(self.u16()?),
+            27 => Instruction::CloseUpvalues(self.u16()?),
+            28 => Instruction::Return,
+            29 => Instruction::Negate,
+            30 => Instruction::Not,
+            31 => Instruction::Modulo,
+            32 => Instruction::BuildList(self.u16()?),
+            33 => Instruction::GetIndex,
+            34 => Instruction::SetIndex,
+            35 => Instruction::BuildMap(self.u16()?),
+            36 => match self.u8()? {
+                0 => Instruction::BuildRange(false),
+                1 => Instruction::BuildRange(true),
+                flag => return Err(self.error(format!("invalid range kind {}", flag))),
+            },
+            37 => Instruction::GetIter,
+            38 => Instruction::GetEntries,
+            39 => Instruction::ForIter(self.u32()?),
+            opcode => {
+                self.offset -= 1;
+                return Err(self.error(format!("invalid opcode {}", opcode)));
+            }
+        };
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, parsed};
+
+    fn compile(input: &str) -> Program {
+        Compiler::new()
+            .compile(&parsed(input))
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// A program whose top-level code is `code`, each at line 1.
+    fn program(code: Vec<Instruction>) -> Program {
+        let positions = vec![(1, 0); code.len()];
+        Program {
+            globals: vec![],
+            main: Rc::new(Prototype {
+                name: "<main>".to_string(),
+                parameters: vec![],
+                entries: vec![0],
+                rest: false,
+                slots: 1,
+                upvalues: vec![],
+                chunk: Chunk {
+                    code,
+                    positions,
+                    constants: vec![],
+                },
+            }),
+        }
+    }
+
+    fn error(bytes: &[u8]) -> LoadError {
+        match read(bytes) {
+            Ok(program) => panic!("read {:?}", program),
+            Err(e) => e,
+        }
+    }
+
+    /// Updates the checksum after `bytes` were changed on purpose.
+    fn reseal(bytes: &mut [u8]) {
+        let body = MAGIC.len() + 6;
+        let checksum = checksum(&bytes[body..]);
+        bytes[body - 4..body].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    #[test]
+    fn programs_survive_a_round_trip() {
+        let program = compile(
+            "
+            let big = 123456789012345678901234567890;
+            fn f(a, b = 2.5, ...rest) { let n = 0; fn() { n = n + a; [b, rest] } }
+            for (k, v) in {\"a\": 1} { print(k + \"!\"); }
+            print(f(1)()); print(0..=3);",
+        );
+        assert_eq!(read(&write(&program)).ok(), Some(program));
+    }
+
+    #[test]
+    fn shared_functions_are_written_once() {
+        let function = compile("fn f() { 1 }").main.chunk.constants[0].clone();
+        let mut program = program(vec![Instruction::Nil, Instruction::Return]);
+        Rc::get_mut(&mut program.main).unwrap().chunk.constants = vec![function.clone(), function];
+
+        let read = read(&write(&program)).ok().unwrap();
+        assert_eq!(read, program);
+        match &read.main.chunk.constants[..] {
+            [Constant::Function(a), Constant::Function(b)] => assert!(Rc::ptr_eq(a, b)),
+            constants => panic!("expected two functions, got {:?}", constants),
+        }
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert_eq!(error(b"#!/bin/sh").message, "not a compiled Kox file");
+        assert_eq!(error(b"").message, "not a compiled Kox file");
+
+        let mut bytes = write(&program(vec![Instruction::Nil, Instruction::Return]));
+        bytes[MAGIC.len()] = bytes[MAGIC.len()].wrapping_add(1);
+        let e = error(&bytes);
+        assert!(e.message.starts_with("compiled for bytecode version"));
+        assert_eq!(e.offset, MAGIC.len());
+    }
+
+    #[test]
+    fn damage_is_caught_by_the_checksum() {
+        let mut bytes = write(&compile("print(\"hello\");"));
+        let last = bytes.len() - 1;
+        bytes[last - 8] ^= 0x40;
+        assert_eq!(
+            error(&bytes).message,
+            "checksum mismatch, the file is damaged"
+        );
+    }
+
+    #[test]
+    fn truncated_and_padded_files_are_rejected() {
+        let bytes = write(&compile("print(1);"));
+        for length in [MAGIC.len() + 1, 12, bytes.len() - 1] {
+            assert_eq!(
+                error(&bytes[..length]).message,
+                "unexpected end of file",
+                "truncated to {} bytes",
+                length
+            );
+        }
+
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert_eq!(error(&padded).message, "unexpected data after the program");
+    }
+
+    #[test]
+    fn operands_are_validated() {
+        let bytes = write(&program(vec![Instruction::Jump(7), Instruction::Return]));
+        assert_eq!(
+            error(&bytes).message,
+            "invalid function '<main>': invalid operand in Jump(7)"
+        );
+
+        let bytes = write(&program(vec![
+            Instruction::GetLocal(1),
+            Instruction::Return,
+        ]));
+        assert_eq!(
+            error(&bytes).message,
+            "invalid function '<main>': invalid operand in GetLocal(1)"
+        );
+
+        let bytes = write(&program(vec![Instruction::Nil]));
+        assert_eq!(
+            error(&bytes).message,
+            "invalid function '<main>': code does not end in a return"
+        );
+    }
+
+    #[test]
+    fn entry_points_are_validated() {
+        let mut program = program(vec![Instruction::Nil, Instruction::Return]);
+        Rc::get_mut(&mut program.main).unwrap().entries = vec![5];
+        assert_eq!(
+            error(&write(&program)).message,
+            "invalid function '<main>': entry point outside the code"
+        );
+
+        Rc::get_mut(&mut program.main).unwrap().entries = vec![1];
+        assert_eq!(error(&write(&program)).message, "invalid main function");
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        let mut bytes = write(&program(vec![Instruction::Nil, Instruction::Return]));
+        // The code comes after the name, the entry point, the rest flag,
+        // the slots and the empty upvalue and constant tables.
+        let name = 4 + "<main>".len();
+        let code = MAGIC.len() + 6 + 4 + 4 + name + 1 + 2 + 4 + 1 + 2 + 2 + 4 + 4;
+        assert_eq!(bytes[code], 1, "the opcode of Nil");
+        bytes[code] = 0xff;
+        reseal(&mut bytes);
+        assert!(error(&bytes).message.contains("255"));
+    }
+}
@@ -1,5 +1,3 @@
-use std::fmt::{Display, Error};
-
 use crate::token::Token;
 
 pub struct Lexer {
@@ -10,23 +8,6 @@ pub struct Lexer {
     pub column: usize,
 }
 
-#[derive(Debug)]
-pub struct LexerErrorInfo {
-    pub line: usize,
-    pub column: usize,
-    pub message: String,
-}
-
-impl Display for LexerErrorInfo {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), Error> {
-        write!(
-            f,
-            "Lexer error at line {} column {}: {}",
-            self.line, self.column, self.message
-        )
-    }
-}
-
 impl Lexer {
     pub fn next_token(&mut self) -> Token {
         while self.ch.is_whitespace() {
@@ -43,13 +24,16 @@ impl Lexer {
         let tok = match ch {
             '\0' => Token::Eof,
             '=' if self.match_next('=') => Token::EqEq,
+            '=' if self.match_next('>') => Token::FatArrow,
             '=' => Token::Eq,
             '+' => Token::Plus,
-            '-' if self.peek().is_digit(10) => self.read_number(),
             '-' => Token::Minus,
             '!' if self.match_next('=') => Token::BangEq,
             '!' => Token::Bang,
+            '&' if self.match_next('&') => Token::And,
+            '|' if self.match_next('|') => Token::Or,
             '*' => Token::Asterisk,
+            '%' => Token::Percent,
             '^' => Token::Pow,
             '/' if self.match_next('/') => self.skip_line(),
             '/' => Token::Slash,
@@ -58,14 +42,28 @@ impl Lexer {
             '>' if self.match_next('=') => Token::GreaterThanEqual,
             '>' => Token::GreaterThan,
             ',' => Token::Comma,
+            ':' => Token::Colon,
+            '_' => Token::Underscore,
             ';' => Token::Semicolon,
             '(' => Token::LParen,
             ')' => Token::RParen,
             '{' => Token::LBrace,
             '}' => Token::RBrace,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '.' if self.match_next('.') => {
+                if self.match_next('=') {
+                    Token::DotDotEq
+                } else if self.match_next('.') {
+                    Token::Ellipsis
+                } else {
+                    Token::DotDot
+                }
+            }
+            '.' => Token::Dot,
             '"' | '\'' => self.read_string(ch),
             _ if self.ch.is_alphabetic() => return self.read_identifier(),
-            _ if self.ch.is_digit(10) => return self.read_number(),
+            _ if self.ch.is_ascii_digit() => return self.read_number(),
             _ => return Token::Illegal(format!("unexpected character: {}", ch)),
         };
 
@@ -96,18 +94,15 @@ impl Lexer {
     fn read_number(&mut self) -> Token {
         let pos = self.position;
 
-        if self.ch == '-' {
+        while self.ch.is_ascii_digit() {
             self.advance();
         }
 
-        while self.ch.is_digit(10) {
-            self.advance();
-        }
-
-        if self.ch == '.' {
+        // A dot not followed by a digit starts a range, as in `0..10`.
+        if self.ch == '.' && self.peek().is_ascii_digit() {
             self.advance();
 
-            while self.ch.is_digit(10) {
+            while self.ch.is_ascii_digit() {
                 self.advance();
             }
 
@@ -115,7 +110,10 @@ impl Lexer {
             Token::Float(num.parse().unwrap())
         } else {
             let num = &self.input[pos..self.position];
-            Token::Int(num.parse().unwrap())
+            match num.parse() {
+                Ok(i) => Token::Int(i),
+                Err(_) => Token::BigInt(num.parse().unwrap()),
+            }
         }
     }
 
@@ -138,6 +136,16 @@ impl Lexer {
             "return" => Token::Return,
             "for" => Token::For,
             "in" => Token::In,
+            "and" => Token::And,
+            "or" => Token::Or,
+            "while" => Token::While,
+            "break" => Token::Break,
+            "continue" => Token::Continue,
+            "struct" => Token::Struct,
+            "class" => Token::Class,
+            "super" => Token::Super,
+            "enum" => Token::Enum,
+            "match" => Token::Match,
             _ => Token::Ident(ident.to_string()),
         }
     }
@@ -175,9 +183,121 @@ impl Lexer {
     }
 
     fn read_char_at(&self, position: usize) -> char {
-        match self.input.chars().nth(position) {
-            Some(c) => c,
-            None => '\0',
+        self.input.chars().nth(position).unwrap_or('\0')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tokens of `input`, up to the end or the first illegal one.
+    fn tokens(input: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(input.to_string());
+        let mut tokens = vec![];
+        loop {
+            match lexer.next_token() {
+                Token::Eof => return tokens,
+                Token::Illegal(message) => {
+                    tokens.push(Token::Illegal(message));
+                    return tokens;
+                }
+                token => tokens.push(token),
+            }
         }
     }
+
+    #[test]
+    fn operators_take_the_longest_match() {
+        assert_eq!(
+            tokens("== = != ! <= < >= >"),
+            vec![
+                Token::EqEq,
+                Token::Eq,
+                Token::BangEq,
+                Token::Bang,
+                Token::LessThanEqual,
+                Token::LessThan,
+                Token::GreaterThanEqual,
+                Token::GreaterThan,
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(tokens("12 1.5"), vec![Token::Int(12), Token::Float(1.5)]);
+    }
+
+    #[test]
+    fn ranges_are_not_floats() {
+        assert_eq!(
+            tokens("0..3 0..=3"),
+            vec![
+                Token::Int(0),
+                Token::DotDot,
+                Token::Int(3),
+                Token::Int(0),
+                Token::DotDotEq,
+                Token::Int(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn integers_beyond_64_bits_are_big() {
+        assert_eq!(
+            tokens("9223372036854775807 9223372036854775808"),
+            vec![
+                Token::Int(i64::MAX),
+                Token::BigInt("9223372036854775808".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn match_arms_use_fat_arrows() {
+        assert_eq!(tokens("=> ="), vec![Token::FatArrow, Token::Eq]);
+    }
+
+    #[test]
+    fn rest_parameters_use_an_ellipsis() {
+        assert_eq!(
+            tokens("... ..= .. ."),
+            vec![Token::Ellipsis, Token::DotDotEq, Token::DotDot, Token::Dot]
+        );
+    }
+
+    #[test]
+    fn keywords_identifiers_and_strings() {
+        assert_eq!(
+            tokens("fn add(a, b) { a + \"x\" } // comment\nreturn"),
+            vec![
+                Token::Function,
+                Token::Ident("add".to_string()),
+                Token::LParen,
+                Token::Ident("a".to_string()),
+                Token::Comma,
+                Token::Ident("b".to_string()),
+                Token::RParen,
+                Token::LBrace,
+                Token::Ident("a".to_string()),
+                Token::Plus,
+                Token::String("x".to_string()),
+                Token::RBrace,
+                Token::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_characters_are_illegal() {
+        assert_eq!(
+            tokens("1 $"),
+            vec![
+                Token::Int(1),
+                Token::Illegal("unexpected character: $".to_string())
+            ]
+        );
+    }
 }
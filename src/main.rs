@@ -1,40 +1,116 @@
 use std::env;
 
-use interpreter::Interpreter;
+use compiler::Compiler;
+use interpreter::{Arithmetic, Interpreter};
 use parser::Parser;
+use resolver::Resolver;
+use transpiler::Transpiler;
+use vm::Vm;
 
+mod aot;
 mod ast;
+mod bytecode;
+mod compiler;
 mod interpreter;
+mod jit;
+mod koxc;
 mod lexer;
+mod native;
 mod parser;
 mod repl;
+mod resolver;
 mod token;
-mod jit;
 mod transpiler;
+mod vm;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("build") => {
+            build(&args[1..]);
+            return;
+        }
+        Some("transpile") => {
+            transpile(&args[1..]);
+            return;
+        }
+        Some("compile") => {
+            compile(&args[1..]);
+            return;
+        }
+        _ => {}
+    }
+    let flags: Vec<&str> = args
+        .iter()
+        .filter(|arg| arg.starts_with("--"))
+        .map(|arg| arg.as_str())
+        .collect();
+    let files: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    if files.is_empty() {
         repl::repl();
         return;
     }
-    let filepath = &args[1];
-    run_file(filepath);
+    run_file(files[0], &flags);
+}
+
+/// Integers that overflow become arbitrary-precision ones, unless
+/// `--wrapping` makes them wrap around or `--checked` makes overflow a
+/// runtime error.
+fn arithmetic(flags: &[&str]) -> Arithmetic {
+    if flags.contains(&"--wrapping") {
+        Arithmetic::Wrapping
+    } else if flags.contains(&"--checked") {
+        Arithmetic::Checked
+    } else {
+        Arithmetic::Promoting
+    }
 }
 
-fn run_file(filepath: &str) {
+/// What `pulsar build` does on overflow, which can't promote.
+fn native_arithmetic(flags: &[&str]) -> Arithmetic {
+    match arithmetic(flags) {
+        Arithmetic::Promoting => Arithmetic::Checked,
+        arithmetic => arithmetic,
+    }
+}
+
+fn run_file(filepath: &str, flags: &[&str]) {
+    if filepath.ends_with(".koxc") {
+        run_compiled(filepath, flags);
+        return;
+    }
     let contents =
         std::fs::read_to_string(filepath).expect("Something went wrong reading the file");
 
-    let mut parser = Parser::new(contents.clone());
-    let ast = parser.parse_program();
-    match ast {
+    match parse(contents) {
         Ok(ast) => {
             // output ast to ./ast
             std::fs::write("./ast", format!("{:#?}", ast)).expect("Unable to write file");
 
+            if flags.contains(&"--vm") {
+                let program = match Compiler::new().compile(&ast) {
+                    Ok(program) => program,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return;
+                    }
+                };
+                let mut vm = Vm::new();
+                vm.set_arithmetic(arithmetic(flags));
+                if let Err(e) = vm.run(&program) {
+                    eprintln!("runtime error: {}", e);
+                }
+                return;
+            }
+
             let mut interpreter = Interpreter::new();
-            let result = interpreter.evaluate_program(ast, &mut interpreter.global_environment());
+            interpreter.set_arithmetic(arithmetic(flags));
+            if flags.contains(&"--no-jit") {
+                interpreter.disable_jit();
+            } else if flags.contains(&"--jit") {
+                interpreter.set_jit_threshold(0);
+            }
+            let result = interpreter.evaluate_program(ast, &interpreter.global_environment());
             match result {
                 Ok(_) => {}
                 Err(e) => {
@@ -47,3 +123,231 @@ fn run_file(filepath: &str) {
         }
     }
 }
+
+/// Parses a program and resolves its variables.
+fn parse(contents: String) -> Result<Vec<ast::Expression>, String> {
+    let mut ast = Parser::new(contents)
+        .parse_program()
+        .map_err(|e| e.to_string())?;
+    Resolver::new()
+        .resolve(&mut ast)
+        .map_err(|e| e.to_string())?;
+    Ok(ast)
+}
+
+/// Parses and resolves `input` for tests, which have no use for the errors.
+#[cfg(test)]
+fn parsed(input: &str) -> Vec<ast::Expression> {
+    parse(input.to_string()).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Runs a `.koxc` file produced by `pulsar compile` on the VM.
+fn run_compiled(filepath: &str, flags: &[&str]) {
+    let bytes = std::fs::read(filepath).expect("Something went wrong reading the file");
+    let program = match koxc::read(&bytes) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", filepath, e);
+            return;
+        }
+    };
+    let mut vm = Vm::new();
+    vm.set_arithmetic(arithmetic(flags));
+    if let Err(e) = vm.run(&program) {
+        eprintln!("runtime error: {}", e);
+    }
+}
+
+/// Splits `<file> [-o <output>]` arguments.
+fn input_output(args: &[String]) -> (Option<String>, Option<String>) {
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next().cloned(),
+            _ => input = Some(arg.clone()),
+        }
+    }
+    (input, output)
+}
+
+/// `pulsar build foo.kox [--wrapping] [-o foo.o]` compiles a program to an
+/// object file which can be linked into an executable with `cc foo.o -o foo`,
+/// adding `-lm` if it raises floats to a power. Native code has no big
+/// integers, so overflow is a runtime error unless it wraps.
+fn build(args: &[String]) {
+    let flags: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| arg.starts_with("--"))
+        .collect();
+    let args: Vec<String> = args
+        .iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .cloned()
+        .collect();
+    let (input, output) = input_output(&args);
+    let input = match input {
+        Some(input) => input,
+        None => {
+            eprintln!("usage: pulsar build <file> [--wrapping] [-o <output>]");
+            return;
+        }
+    };
+    let output = output.unwrap_or_else(|| {
+        std::path::Path::new(&input)
+            .with_extension("o")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let contents = std::fs::read_to_string(&input).expect("Something went wrong reading the file");
+    match parse(contents) {
+        Ok(ast) => match aot::compile(&ast, native_arithmetic(&flags)) {
+            Ok(object) => std::fs::write(output, object).expect("Unable to write file"),
+            Err(e) => eprintln!("{}", e),
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+/// `pulsar transpile foo.kox [--target js|c|py] [-o foo.js]` translates a
+/// program to another language, JavaScript by default, printing it unless an
+/// output file is given.
+fn transpile(args: &[String]) {
+    let usage = "usage: pulsar transpile <file> [--target js|c|py] [-o <output>]\n\
+                 no target supports named arguments";
+    let mut args = args.to_vec();
+    let target = match args.iter().position(|arg| arg == "--target") {
+        Some(i) if i + 1 < args.len() => {
+            let target = args.remove(i + 1);
+            args.remove(i);
+            target
+        }
+        Some(_) => {
+            eprintln!("{}", usage);
+            return;
+        }
+        None => "js".to_string(),
+    };
+    let backend = match transpiler::backend(&target) {
+        Some(backend) => backend,
+        None => {
+            eprintln!("unknown target '{}'", target);
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+    // Flags like `--wrapping` only apply to running a program.
+    let args: Vec<String> = args
+        .into_iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let (input, output) = input_output(&args);
+    let input = match input {
+        Some(input) => input,
+        None => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+
+    let contents = std::fs::read_to_string(&input).expect("Something went wrong reading the file");
+    match Transpiler::new(contents, backend).transpile() {
+        Ok(source) => match output {
+            Some(output) => std::fs::write(output, source).expect("Unable to write file"),
+            None => print!("{}", source),
+        },
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+/// What the bytecode compiler rejects, and so neither `--vm` nor `.koxc`
+/// files can run.
+const UNSUPPORTED_BY_VM: &[&str] = &[
+    "named arguments",
+    "structs",
+    "classes",
+    "enums",
+    "match expressions",
+];
+
+/// `pulsar compile foo.kox [-o foo.koxc]` compiles a program to bytecode,
+/// which `pulsar foo.koxc` runs on the VM without parsing it again.
+fn compile(args: &[String]) {
+    // Flags like `--wrapping` only apply to running the program.
+    let args: Vec<String> = args
+        .iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .cloned()
+        .collect();
+    let (input, output) = input_output(&args);
+    let input = match input {
+        Some(input) => input,
+        None => {
+            eprintln!("usage: pulsar compile <file> [-o <output>]");
+            eprintln!(
+                "the VM, which runs .koxc files and --vm, does not support {}",
+                UNSUPPORTED_BY_VM.join(", ")
+            );
+            return;
+        }
+    };
+    let output = output.unwrap_or_else(|| {
+        std::path::Path::new(&input)
+            .with_extension("koxc")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let contents = std::fs::read_to_string(&input).expect("Something went wrong reading the file");
+    match parse(contents) {
+        Ok(ast) => match Compiler::new().compile(&ast) {
+            Ok(program) => {
+                std::fs::write(output, koxc::write(&program)).expect("Unable to write file")
+            }
+            Err(e) => eprintln!("{}", e),
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        assert_eq!(arithmetic(&[]), Arithmetic::Promoting);
+        assert_eq!(arithmetic(&["--vm", "--wrapping"]), Arithmetic::Wrapping);
+        assert_eq!(arithmetic(&["--checked"]), Arithmetic::Checked);
+        assert_eq!(native_arithmetic(&[]), Arithmetic::Checked);
+        assert_eq!(native_arithmetic(&["--wrapping"]), Arithmetic::Wrapping);
+    }
+
+    #[test]
+    fn output_files() {
+        assert_eq!(
+            input_output(&strings(&["a.kox", "-o", "b.o"])),
+            (Some("a.kox".to_string()), Some("b.o".to_string()))
+        );
+        assert_eq!(
+            input_output(&strings(&["-o", "b.o", "a.kox"])),
+            (Some("a.kox".to_string()), Some("b.o".to_string()))
+        );
+        assert_eq!(
+            input_output(&strings(&["a.kox"])),
+            (Some("a.kox".to_string()), None)
+        );
+        assert_eq!(input_output(&[]), (None, None));
+    }
+}
@@ -0,0 +1,1648 @@
+//! Native code generation shared by the JIT and the ahead-of-time compiler.
+//!
+//! Kox is dynamically typed, so functions are specialized for the argument
+//! types they are called with. Return types are inferred by iterating over
+//! every specialization until they stop changing, which lets recursive
+//! functions settle on the type of their non-recursive returns.
+
+use std::{collections::HashMap, fmt::Display};
+
+use cranelift::codegen::ir::{FuncRef, UserFuncName};
+use cranelift::prelude::*;
+use cranelift_module::{DataDescription, DataId, FuncId, Linkage, Module, ModuleError};
+
+use crate::{
+    ast::{Expression, Value as AstValue},
+    interpreter::Arithmetic,
+    token::Token,
+};
+
+pub struct CompileError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Compile error at line {} column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl From<ModuleError> for CompileError {
+    fn from(error: ModuleError) -> Self {
+        Self {
+            message: error.to_string(),
+            line: 0,
+            column: 0,
+        }
+    }
+}
+
+impl CompileError {
+    pub fn module(error: ModuleError, expression: &Expression) -> Self {
+        let (line, column) = expression.position();
+        Self {
+            message: error.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+macro_rules! unsupported {
+    ($expression:expr, $($arg:tt)*) => {{
+        let (line, column) = $expression.position();
+        return Err(CompileError {
+            message: format!($($arg)*),
+            line,
+            column,
+        });
+    }};
+}
+
+/// The value types compiled code can represent natively. Strings are
+/// NUL-terminated and only available when a [`Runtime`] is linked in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum NativeType {
+    Int,
+    Float,
+    Boolean,
+    String,
+}
+
+impl NativeType {
+    pub fn ir_type(self, pointer: Type) -> Type {
+        match self {
+            NativeType::Int => types::I64,
+            NativeType::Float => types::F64,
+            NativeType::Boolean => types::I8,
+            NativeType::String => pointer,
+        }
+    }
+}
+
+/// Static type of an expression. `Never` is the type of anything that
+/// unconditionally returns, `Unknown` the result of a call whose return type
+/// has not been inferred yet. `Mixed` is the type of an `if` whose branches
+/// have different types, which compiled code can only discard.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ty {
+    Value(NativeType),
+    Nil,
+    Mixed,
+    Never,
+    Unknown,
+}
+
+fn binary_type(operator: &Token, left: NativeType, right: NativeType) -> Option<NativeType> {
+    let comparison = matches!(
+        operator,
+        Token::GreaterThan
+            | Token::LessThan
+            | Token::GreaterThanEqual
+            | Token::LessThanEqual
+            | Token::EqEq
+            | Token::BangEq
+    );
+    match (left, right) {
+        (NativeType::Int, NativeType::Int) => match operator {
+            Token::Plus
+            | Token::Minus
+            | Token::Asterisk
+            | Token::Slash
+            | Token::Percent
+            | Token::Pow => Some(NativeType::Int),
+            _ if comparison => Some(NativeType::Boolean),
+            _ => None,
+        },
+        // Integers mixed with floats are promoted.
+        (NativeType::Int | NativeType::Float, NativeType::Int | NativeType::Float) => {
+            match operator {
+                Token::Plus
+                | Token::Minus
+                | Token::Asterisk
+                | Token::Slash
+                | Token::Percent
+                | Token::Pow => Some(NativeType::Float),
+                _ if comparison => Some(NativeType::Boolean),
+                _ => None,
+            }
+        }
+        (NativeType::String, NativeType::String) => match operator {
+            Token::Plus => Some(NativeType::String),
+            _ => None,
+        },
+        (NativeType::Boolean, NativeType::Boolean) => match operator {
+            Token::EqEq | Token::BangEq => Some(NativeType::Boolean),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The type of an integer raised to `exponent`, which is a float when the
+/// exponent is negative. Only a literal exponent has a sign known ahead of
+/// running the code.
+fn int_pow_type(exponent: &Expression) -> Result<NativeType, CompileError> {
+    match exponent {
+        Expression::Value {
+            value: AstValue::Int(_),
+            ..
+        } => Ok(NativeType::Int),
+        Expression::Unary {
+            operator: Token::Minus,
+            right,
+            ..
+        } if matches!(
+            **right,
+            Expression::Value {
+                value: AstValue::Int(n),
+                ..
+            } if n > 0
+        ) =>
+        {
+            Ok(NativeType::Float)
+        }
+        _ => unsupported!(
+            exponent,
+            "integer powers need a literal exponent, since a negative one makes a float"
+        ),
+    }
+}
+
+fn unary_type(operator: &Token, right: NativeType) -> Option<NativeType> {
+    match (operator, right) {
+        (Token::Minus, NativeType::Int | NativeType::Float) => Some(right),
+        (Token::Bang, NativeType::Boolean) => Some(NativeType::Boolean),
+        _ => None,
+    }
+}
+
+/// The type of an `if`. A branch of unknown type is assumed to match the
+/// other one until its type is inferred.
+fn merge_branches(consequence: Ty, alternative: Ty) -> Ty {
+    match (consequence, alternative) {
+        (Ty::Never, other) | (other, Ty::Never) => other,
+        (Ty::Unknown, other) | (other, Ty::Unknown) => other,
+        (a, b) if a == b => a,
+        _ => Ty::Mixed,
+    }
+}
+
+/// Lexical scopes shared by inference and translation. Blocks get their own
+/// scope; so do `if` branches, so that a `let` in an unbraced branch never
+/// leaks into code that might run without it.
+struct Scopes<T> {
+    scopes: Vec<HashMap<String, T>>,
+}
+
+impl<T: Copy> Scopes<T> {
+    fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    fn push(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: String, value: T) {
+        self.scopes.last_mut().unwrap().insert(name, value);
+    }
+
+    fn get(&self, name: &str) -> Option<T> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+}
+
+/// Functions implemented outside of the compiled program, needed for
+/// `print` and string concatenation.
+pub struct Runtime {
+    pub print_int: FuncId,
+    pub print_float: FuncId,
+    pub print_boolean: FuncId,
+    pub print_string: FuncId,
+    pub print_nil: FuncId,
+    pub concat: FuncId,
+}
+
+/// How compiled code reports runtime errors. `raise` is called with a
+/// NUL-terminated message and the line and column of the error. If `failed`
+/// is set, it is the address of a byte that `raise` sets before returning,
+/// and compiled code returns from every function once it is set; otherwise
+/// `raise` must not return.
+#[derive(Clone, Copy)]
+pub struct Errors {
+    pub raise: FuncId,
+    pub failed: Option<i64>,
+}
+
+/// A function that compiled code may call by name.
+#[derive(Clone, Copy)]
+pub struct Definition<'a> {
+    pub name: &'a str,
+    pub parameters: &'a [String],
+    /// The default values of the last parameters, computed in the callee
+    /// like a `let` for each one a call leaves out.
+    pub defaults: &'a [Expression],
+    pub body: &'a Expression,
+}
+
+impl Definition<'_> {
+    /// Checks that a call giving `given` arguments only leaves out
+    /// parameters with default values.
+    fn check_arguments(&self, given: usize, call: &Expression) -> Result<(), CompileError> {
+        let max = self.parameters.len();
+        let min = max - self.defaults.len();
+        if given > max || given < min {
+            if min == max {
+                unsupported!(call, "expected {} arguments but got {}", max, given);
+            }
+            unsupported!(
+                call,
+                "expected {} to {} arguments but got {}",
+                min,
+                max,
+                given
+            );
+        }
+        Ok(())
+    }
+
+    /// The parameters a call giving `given` arguments leaves out, with
+    /// their default values.
+    fn left_out(&self, given: usize) -> impl Iterator<Item = (&String, &Expression)> {
+        let first_default = self.parameters.len() - self.defaults.len();
+        self.parameters[given..]
+            .iter()
+            .zip(&self.defaults[given - first_default..])
+    }
+}
+
+/// A definition specialized for one set of argument types.
+pub struct Instance<'a> {
+    pub definition: Definition<'a>,
+    pub arguments: Vec<NativeType>,
+    /// Either `Ty::Value` or `Ty::Nil` once inferred.
+    pub returns: Option<Ty>,
+}
+
+impl<'a> Instance<'a> {
+    pub fn signature<M: Module>(&self, module: &M) -> Signature {
+        let pointer = module.target_config().pointer_type();
+        let mut signature = module.make_signature();
+        for argument in &self.arguments {
+            signature
+                .params
+                .push(AbiParam::new(argument.ir_type(pointer)));
+        }
+        if let Some(Ty::Value(returns)) = self.returns {
+            signature
+                .returns
+                .push(AbiParam::new(returns.ir_type(pointer)));
+        }
+        signature
+    }
+}
+
+pub struct Program<'a> {
+    definitions: HashMap<&'a str, Definition<'a>>,
+    instances: Vec<Instance<'a>>,
+    index: HashMap<(&'a str, Vec<NativeType>), usize>,
+    runtime: Option<Runtime>,
+    errors: Errors,
+    /// `Checked` or `Wrapping`, since native code has no big integers to
+    /// promote to.
+    arithmetic: Arithmetic,
+    strings: HashMap<String, DataId>,
+}
+
+impl<'a> Program<'a> {
+    pub fn new(
+        definitions: Vec<Definition<'a>>,
+        runtime: Option<Runtime>,
+        errors: Errors,
+        arithmetic: Arithmetic,
+    ) -> Self {
+        Self {
+            definitions: definitions
+                .into_iter()
+                .map(|definition| (definition.name, definition))
+                .collect(),
+            instances: vec![],
+            index: HashMap::new(),
+            runtime,
+            errors,
+            arithmetic,
+            strings: HashMap::new(),
+        }
+    }
+
+    pub fn instances(&self) -> &[Instance<'a>] {
+        &self.instances
+    }
+
+    /// `print` is provided by the runtime unless the program defines its own.
+    fn is_print(&self, name: &str) -> bool {
+        name == "print" && self.runtime.is_some() && !self.definitions.contains_key(name)
+    }
+
+    fn lookup(&self, name: &str, arguments: &[NativeType]) -> Option<usize> {
+        let definition = self.definitions.get(name)?;
+        self.index
+            .get(&(definition.name, arguments.to_vec()))
+            .copied()
+    }
+
+    fn instance(&mut self, name: &str, arguments: &[NativeType]) -> Option<usize> {
+        let definition = *self.definitions.get(name)?;
+        let key = (definition.name, arguments.to_vec());
+        if let Some(index) = self.index.get(&key) {
+            return Some(*index);
+        }
+        self.instances.push(Instance {
+            definition,
+            arguments: arguments.to_vec(),
+            returns: None,
+        });
+        self.index.insert(key, self.instances.len() - 1);
+        Some(self.instances.len() - 1)
+    }
+
+    /// Specializes `name` for `arguments`, along with everything it calls,
+    /// and infers the return type of every specialization.
+    pub fn specialize(
+        &mut self,
+        name: &str,
+        arguments: &[NativeType],
+    ) -> Result<usize, CompileError> {
+        let entry = match self.instance(name, arguments) {
+            Some(entry) => entry,
+            None => panic!("specializing unknown function '{}'", name),
+        };
+        let definition = self.instances[entry].definition;
+        definition.check_arguments(arguments.len(), definition.body)?;
+
+        for _ in 0..16 {
+            let mut changed = false;
+            let mut i = 0;
+            while i < self.instances.len() {
+                let returns = self.infer(i)?;
+                if returns.is_some() && returns != self.instances[i].returns {
+                    self.instances[i].returns = returns;
+                    changed = true;
+                }
+                i += 1;
+            }
+            if !changed {
+                if let Some(instance) = self.instances.iter().find(|i| i.returns.is_none()) {
+                    unsupported!(
+                        instance.definition.body,
+                        "cannot infer the return type of '{}'",
+                        instance.definition.name
+                    );
+                }
+                return Ok(entry);
+            }
+        }
+
+        unsupported!(
+            definition.body,
+            "cannot infer the return type of '{}'",
+            definition.name
+        )
+    }
+
+    fn infer(&mut self, instance: usize) -> Result<Option<Ty>, CompileError> {
+        let Instance {
+            definition,
+            arguments,
+            ..
+        } = &self.instances[instance];
+        let (definition, arguments) = (*definition, arguments.clone());
+
+        let mut inference = Inference {
+            program: self,
+            returned: None,
+            scopes: Scopes::new(),
+        };
+        for (parameter, argument) in definition.parameters.iter().zip(&arguments) {
+            inference
+                .scopes
+                .declare(parameter.clone(), Ty::Value(*argument));
+        }
+        for (parameter, default) in definition.left_out(arguments.len()) {
+            let ty = inference.infer(default)?;
+            if ty == Ty::Never {
+                return Ok(inference.returned);
+            }
+            inference.expect_value(ty, default)?;
+            inference.scopes.declare(parameter.clone(), ty);
+        }
+        let ty = inference.infer(definition.body)?;
+        if ty != Ty::Never {
+            inference.record_return(ty, definition.body)?;
+        }
+        Ok(inference.returned)
+    }
+
+    /// Defines every specialization in `module`. `declare` names each one and
+    /// picks its linkage; the returned ids are in instance order.
+    pub fn define<M: Module>(
+        &mut self,
+        module: &mut M,
+        ctx: &mut codegen::Context,
+        builder_context: &mut FunctionBuilderContext,
+        declare: impl Fn(usize, &Instance) -> (String, Linkage),
+    ) -> Result<Vec<FuncId>, CompileError> {
+        let mut ids = vec![];
+        for (i, instance) in self.instances.iter().enumerate() {
+            let (name, linkage) = declare(i, instance);
+            let id = module
+                .declare_function(&name, linkage, &instance.signature(module))
+                .map_err(|e| CompileError::module(e, instance.definition.body))?;
+            ids.push(id);
+        }
+
+        for i in 0..self.instances.len() {
+            let body = self.instances[i].definition.body;
+            ctx.func.signature = self.instances[i].signature(module);
+            ctx.func.name = UserFuncName::user(0, ids[i].as_u32());
+            if let Err(e) = self.translate(i, &ids, module, ctx, builder_context) {
+                module.clear_context(ctx);
+                return Err(e);
+            }
+            let result = module.define_function(ids[i], ctx);
+            module.clear_context(ctx);
+            result.map_err(|e| CompileError::module(e, body))?;
+        }
+        Ok(ids)
+    }
+
+    fn translate<M: Module>(
+        &mut self,
+        instance: usize,
+        ids: &[FuncId],
+        module: &mut M,
+        ctx: &mut codegen::Context,
+        builder_context: &mut FunctionBuilderContext,
+    ) -> Result<(), CompileError> {
+        let Instance {
+            definition,
+            arguments,
+            returns,
+        } = &self.instances[instance];
+        let (definition, arguments, returns) = (*definition, arguments.clone(), *returns);
+
+        let mut builder = FunctionBuilder::new(&mut ctx.func, builder_context);
+        let entry_block = builder.create_block();
+        builder.append_block_params_for_function_params(entry_block);
+        builder.switch_to_block(entry_block);
+        builder.seal_block(entry_block);
+
+        let mut translator = Translator {
+            builder,
+            module,
+            program: self,
+            ids,
+            functions: HashMap::new(),
+            returns: returns.expect("return types are inferred before translation"),
+            scopes: Scopes::new(),
+            variables: 0,
+            loops: vec![],
+        };
+        for (i, (parameter, argument)) in definition.parameters.iter().zip(&arguments).enumerate() {
+            let value = translator.builder.block_params(entry_block)[i];
+            let variable = translator.variable(*argument);
+            translator.builder.def_var(variable, value);
+            translator
+                .scopes
+                .declare(parameter.clone(), (variable, *argument));
+        }
+        for (parameter, default) in definition.left_out(arguments.len()) {
+            let typed = translator.translate(default)?;
+            if let Typed::Never = typed {
+                translator.builder.finalize();
+                return Ok(());
+            }
+            let (value, ty) = translator.value(typed, default)?;
+            let variable = translator.variable(ty);
+            translator.builder.def_var(variable, value);
+            translator.scopes.declare(parameter.clone(), (variable, ty));
+        }
+
+        let typed = translator.translate(definition.body)?;
+        match typed {
+            Typed::Never => {}
+            typed if typed.ty() == translator.returns => translator.ret(typed),
+            _ => unsupported!(
+                definition.body,
+                "function does not return a consistent type"
+            ),
+        }
+        translator.builder.finalize();
+        Ok(())
+    }
+}
+
+struct Inference<'p, 'a> {
+    program: &'p mut Program<'a>,
+    returned: Option<Ty>,
+    scopes: Scopes<Ty>,
+}
+
+impl<'p, 'a> Inference<'p, 'a> {
+    fn expect_value(
+        &self,
+        ty: Ty,
+        expression: &Expression,
+    ) -> Result<Option<NativeType>, CompileError> {
+        match ty {
+            Ty::Value(t) => Ok(Some(t)),
+            Ty::Unknown => Ok(None),
+            Ty::Nil => unsupported!(expression, "expression has no value"),
+            Ty::Mixed => unsupported!(expression, "branches of the if have different types"),
+            Ty::Never => unreachable!("diverging expressions are handled by the caller"),
+        }
+    }
+
+    fn record_return(&mut self, ty: Ty, expression: &Expression) -> Result<(), CompileError> {
+        match ty {
+            Ty::Unknown => return Ok(()),
+            Ty::Mixed => unsupported!(expression, "branches of the if have different types"),
+            _ => {}
+        }
+        match self.returned {
+            Some(returned) if returned != ty => unsupported!(
+                expression,
+                "function returns both {:?} and {:?}",
+                returned,
+                ty
+            ),
+            _ => self.returned = Some(ty),
+        }
+        Ok(())
+    }
+
+    fn infer(&mut self, expression: &Expression) -> Result<Ty, CompileError> {
+        match expression {
+            Expression::Value { value, .. } => match value {
+                AstValue::Int(_) => Ok(Ty::Value(NativeType::Int)),
+                AstValue::BigInt(_) => {
+                    unsupported!(expression, "integers beyond 64 bits are not supported")
+                }
+                AstValue::Float(_) => Ok(Ty::Value(NativeType::Float)),
+                AstValue::Boolean(_) => Ok(Ty::Value(NativeType::Boolean)),
+                AstValue::Nil => Ok(Ty::Nil),
+                AstValue::String(_) if self.program.runtime.is_some() => {
+                    Ok(Ty::Value(NativeType::String))
+                }
+                AstValue::String(_) => unsupported!(expression, "strings are not supported"),
+            },
+            Expression::Identifier { ident, .. } => match self.scopes.get(ident) {
+                Some(ty) => Ok(ty),
+                None => unsupported!(expression, "reference to non-local '{}'", ident),
+            },
+            Expression::Binary {
+                left,
+                operator,
+                right,
+                ..
+            } => {
+                let l = self.infer(left)?;
+                if l == Ty::Never {
+                    return Ok(Ty::Never);
+                }
+                let r = self.infer(right)?;
+                if r == Ty::Never {
+                    return Ok(Ty::Never);
+                }
+                let (l, r) = (self.expect_value(l, left)?, self.expect_value(r, right)?);
+                let result = match (l, r) {
+                    (Some(l), Some(r)) => binary_type(operator, l, r),
+                    (Some(t), None) | (None, Some(t)) => binary_type(operator, t, t),
+                    (None, None) => return Ok(Ty::Unknown),
+                };
+                let result = match (result, l, r) {
+                    (Some(NativeType::Int), Some(_), Some(_)) if *operator == Token::Pow => {
+                        Some(int_pow_type(right)?)
+                    }
+                    // The other operand may yet turn out to be a float.
+                    (Some(NativeType::Int), _, _) if *operator == Token::Pow => {
+                        return Ok(Ty::Unknown)
+                    }
+                    (result, _, _) => result,
+                };
+                match result {
+                    Some(t) => Ok(Ty::Value(t)),
+                    None => unsupported!(expression, "unsupported operator {:?}", operator),
+                }
+            }
+            Expression::Logical { left, right, .. } => {
+                let ty = self.infer(left)?;
+                match ty {
+                    Ty::Never => return Ok(Ty::Never),
+                    Ty::Value(NativeType::Boolean) | Ty::Unknown => {}
+                    _ => unsupported!(left, "condition must be a boolean"),
+                }
+                self.scopes.push();
+                let ty = self.infer(right)?;
+                self.scopes.pop();
+                match ty {
+                    Ty::Never | Ty::Value(NativeType::Boolean) | Ty::Unknown => {}
+                    _ => unsupported!(right, "condition must be a boolean"),
+                }
+                Ok(Ty::Value(NativeType::Boolean))
+            }
+            Expression::Unary {
+                operator, right, ..
+            } => {
+                let ty = self.infer(right)?;
+                if ty == Ty::Never {
+                    return Ok(Ty::Never);
+                }
+                match self.expect_value(ty, right)? {
+                    Some(t) => match unary_type(operator, t) {
+                        Some(t) => Ok(Ty::Value(t)),
+                        None => unsupported!(expression, "unsupported operator {:?}", operator),
+                    },
+                    None => Ok(Ty::Unknown),
+                }
+            }
+            Expression::Let { name, value, .. } => {
+                let ty = self.infer(value)?;
+                if ty == Ty::Never {
+                    return Ok(Ty::Never);
+                }
+                self.expect_value(ty, value)?;
+                self.scopes.declare(name.clone(), ty);
+                Ok(ty)
+            }
+            Expression::Assign { name, value, .. } => {
+                let ty = self.infer(value)?;
+                if ty == Ty::Never {
+                    return Ok(Ty::Never);
+                }
+                self.expect_value(ty, value)?;
+                match self.scopes.get(name) {
+                    Some(t) if t == ty || ty == Ty::Unknown => Ok(t),
+                    Some(Ty::Unknown) => Ok(ty),
+                    Some(_) => {
+                        unsupported!(expression, "assignment changes the type of '{}'", name)
+                    }
+                    None => unsupported!(expression, "assignment to non-local '{}'", name),
+                }
+            }
+            Expression::Return { value, .. } => {
+                let ty = self.infer(value)?;
+                if ty != Ty::Never {
+                    self.record_return(ty, value)?;
+                }
+                Ok(Ty::Never)
+            }
+            Expression::Block { expressions, .. } => {
+                self.scopes.push();
+                let mut ty = Ty::Nil;
+                for expression in expressions {
+                    ty = self.infer(expression)?;
+                    if ty == Ty::Never {
+                        break;
+                    }
+                }
+                self.scopes.pop();
+                Ok(ty)
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                let ty = self.infer(condition)?;
+                match ty {
+                    Ty::Never => return Ok(Ty::Never),
+                    Ty::Value(NativeType::Boolean) | Ty::Unknown => {}
+                    _ => unsupported!(condition, "condition must be a boolean"),
+                }
+                self.scopes.push();
+                let consequence = self.infer(consequence)?;
+                self.scopes.pop();
+                let alternative = match alternative {
+                    Some(alternative) => {
+                        self.scopes.push();
+                        let ty = self.infer(alternative)?;
+                        self.scopes.pop();
+                        ty
+                    }
+                    None => Ty::Nil,
+                };
+                Ok(merge_branches(consequence, alternative))
+            }
+            Expression::Call {
+                function,
+                arguments,
+                named,
+                ..
+            } => {
+                if !named.is_empty() {
+                    unsupported!(expression, "named arguments are not supported");
+                }
+                let name = match &**function {
+                    Expression::Identifier { ident, .. } if self.scopes.get(ident).is_none() => {
+                        ident
+                    }
+                    _ => unsupported!(function, "only calls of named functions are supported"),
+                };
+
+                let mut types = vec![];
+                for argument in arguments {
+                    let ty = self.infer(argument)?;
+                    if ty == Ty::Never {
+                        return Ok(Ty::Never);
+                    }
+                    types.push(ty);
+                }
+
+                if self.program.is_print(name) {
+                    if types.len() != 1 {
+                        unsupported!(expression, "expected 1 arguments but got {}", types.len());
+                    }
+                    if types[0] == Ty::Mixed {
+                        unsupported!(arguments[0], "branches of the if have different types");
+                    }
+                    return Ok(Ty::Nil);
+                }
+
+                let mut arguments_types = vec![];
+                for (ty, argument) in types.into_iter().zip(arguments) {
+                    match self.expect_value(ty, argument)? {
+                        Some(t) => arguments_types.push(t),
+                        None => return Ok(Ty::Unknown),
+                    }
+                }
+                let instance = match self.program.instance(name, &arguments_types) {
+                    Some(instance) => instance,
+                    None => unsupported!(function, "call of unknown function '{}'", name),
+                };
+                self.program.instances[instance]
+                    .definition
+                    .check_arguments(arguments_types.len(), expression)?;
+                Ok(self.program.instances[instance]
+                    .returns
+                    .unwrap_or(Ty::Unknown))
+            }
+            Expression::Function { .. } => {
+                unsupported!(expression, "nested functions are not supported")
+            }
+            Expression::Lambda { .. } => {
+                unsupported!(expression, "anonymous functions are not supported")
+            }
+            Expression::While {
+                condition, body, ..
+            } => {
+                let ty = self.infer(condition)?;
+                match ty {
+                    Ty::Never => return Ok(Ty::Never),
+                    Ty::Value(NativeType::Boolean) | Ty::Unknown => {}
+                    _ => unsupported!(condition, "condition must be a boolean"),
+                }
+                self.scopes.push();
+                self.infer(body)?;
+                self.scopes.pop();
+                Ok(Ty::Nil)
+            }
+            Expression::Break { value, .. } => {
+                if !matches!(
+                    **value,
+                    Expression::Value {
+                        value: AstValue::Nil,
+                        ..
+                    }
+                ) {
+                    unsupported!(expression, "break with a value is not supported");
+                }
+                Ok(Ty::Never)
+            }
+            Expression::Continue { .. } => Ok(Ty::Never),
+            Expression::For { .. } => unsupported!(expression, "for loops are not supported"),
+            Expression::Range { .. } => unsupported!(expression, "ranges are not supported"),
+            Expression::List { .. } | Expression::Index { .. } | Expression::SetIndex { .. } => {
+                unsupported!(expression, "lists are not supported")
+            }
+            Expression::Map { .. } => unsupported!(expression, "maps are not supported"),
+            Expression::Struct { .. }
+            | Expression::Construct { .. }
+            | Expression::Get { .. }
+            | Expression::Set { .. } => unsupported!(expression, "structs are not supported"),
+            Expression::Class { .. } | Expression::Super { .. } => {
+                unsupported!(expression, "classes are not supported")
+            }
+            Expression::Enum { .. } => unsupported!(expression, "enums are not supported"),
+            Expression::Match { .. } => {
+                unsupported!(expression, "match expressions are not supported")
+            }
+        }
+    }
+}
+
+/// The result of translating an expression.
+#[derive(Clone, Copy)]
+enum Typed {
+    Value(Value, NativeType),
+    Nil,
+    Never,
+}
+
+impl Typed {
+    fn ty(self) -> Ty {
+        match self {
+            Typed::Value(_, t) => Ty::Value(t),
+            Typed::Nil => Ty::Nil,
+            Typed::Never => Ty::Never,
+        }
+    }
+}
+
+macro_rules! diverge {
+    ($typed:expr) => {
+        match $typed {
+            Typed::Never => return Ok(Typed::Never),
+            typed => typed,
+        }
+    };
+}
+
+struct Translator<'b, 'p, 'a, M: Module> {
+    builder: FunctionBuilder<'b>,
+    module: &'b mut M,
+    program: &'p mut Program<'a>,
+    ids: &'b [FuncId],
+    functions: HashMap<FuncId, FuncRef>,
+    returns: Ty,
+    scopes: Scopes<(Variable, NativeType)>,
+    variables: usize,
+    /// The condition and exit blocks of the enclosing loops, innermost last.
+    loops: Vec<(Block, Block)>,
+}
+
+impl<'b, 'p, 'a, M: Module> Translator<'b, 'p, 'a, M> {
+    fn pointer(&self) -> Type {
+        self.module.target_config().pointer_type()
+    }
+
+    fn variable(&mut self, ty: NativeType) -> Variable {
+        let variable = Variable::new(self.variables);
+        self.variables += 1;
+        let ir_type = ty.ir_type(self.pointer());
+        self.builder.declare_var(variable, ir_type);
+        variable
+    }
+
+    fn value(
+        &self,
+        typed: Typed,
+        expression: &Expression,
+    ) -> Result<(Value, NativeType), CompileError> {
+        match typed {
+            Typed::Value(value, ty) => Ok((value, ty)),
+            _ => unsupported!(expression, "expression has no value"),
+        }
+    }
+
+    fn call(&mut self, id: FuncId, args: &[Value]) -> Option<Value> {
+        let module = &mut *self.module;
+        let builder = &mut self.builder;
+        let callee = *self
+            .functions
+            .entry(id)
+            .or_insert_with(|| module.declare_func_in_func(id, builder.func));
+        let call = self.builder.ins().call(callee, args);
+        self.builder.inst_results(call).first().copied()
+    }
+
+    fn runtime(&self) -> &Runtime {
+        self.program
+            .runtime
+            .as_ref()
+            .expect("strings and print are rejected without a runtime")
+    }
+
+    fn ret(&mut self, typed: Typed) {
+        match typed {
+            Typed::Value(value, _) => self.builder.ins().return_(&[value]),
+            _ => self.builder.ins().return_(&[]),
+        };
+    }
+
+    fn string(&mut self, s: &str, expression: &Expression) -> Result<Value, CompileError> {
+        let id = match self.program.strings.get(s) {
+            Some(id) => *id,
+            None => {
+                let id = self
+                    .module
+                    .declare_anonymous_data(false, false)
+                    .map_err(|e| CompileError::module(e, expression))?;
+                let mut data = DataDescription::new();
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.push(0);
+                data.define(bytes.into_boxed_slice());
+                self.module
+                    .define_data(id, &data)
+                    .map_err(|e| CompileError::module(e, expression))?;
+                self.program.strings.insert(s.to_string(), id);
+                id
+            }
+        };
+        let global = self.module.declare_data_in_func(id, self.builder.func);
+        let pointer = self.pointer();
+        Ok(self.builder.ins().global_value(pointer, global))
+    }
+
+    fn translate(&mut self, expression: &Expression) -> Result<Typed, CompileError> {
+        match expression {
+            Expression::Value { value, .. } => Ok(match value {
+                AstValue::Int(i) => {
+                    Typed::Value(self.builder.ins().iconst(types::I64, *i), NativeType::Int)
+                }
+                AstValue::BigInt(_) => unreachable!("rejected by inference"),
+                AstValue::Float(f) => {
+                    Typed::Value(self.builder.ins().f64const(*f), NativeType::Float)
+                }
+                AstValue::Boolean(b) => Typed::Value(
+                    self.builder.ins().iconst(types::I8, *b as i64),
+                    NativeType::Boolean,
+                ),
+                AstValue::String(s) => {
+                    Typed::Value(self.string(s, expression)?, NativeType::String)
+                }
+                AstValue::Nil => Typed::Nil,
+            }),
+            Expression::Identifier { ident, .. } => match self.scopes.get(ident) {
+                Some((variable, ty)) => Ok(Typed::Value(self.builder.use_var(variable), ty)),
+                None => unsupported!(expression, "reference to non-local '{}'", ident),
+            },
+            Expression::Binary {
+                left,
+                operator,
+                right,
+                ..
+            } => {
+                let l = diverge!(self.translate(left)?);
+                let r = diverge!(self.translate(right)?);
+                let (l, lt) = self.value(l, left)?;
+                let (r, rt) = self.value(r, right)?;
+                let t = match binary_type(operator, lt, rt) {
+                    Some(NativeType::Int) if *operator == Token::Pow => int_pow_type(right)?,
+                    Some(t) => t,
+                    None => unsupported!(expression, "unsupported operator {:?}", operator),
+                };
+                // Mixed operands are promoted, and so are integers raised to
+                // a negative power.
+                if lt == rt && !(lt == NativeType::Int && t == NativeType::Float) {
+                    Ok(Typed::Value(
+                        self.binary(operator, lt, l, r, expression)?,
+                        t,
+                    ))
+                } else {
+                    let l = self.promote(l, lt);
+                    let r = self.promote(r, rt);
+                    Ok(Typed::Value(
+                        self.binary(operator, NativeType::Float, l, r, expression)?,
+                        t,
+                    ))
+                }
+            }
+            Expression::Logical {
+                left,
+                operator,
+                right,
+                ..
+            } => self.translate_logical(left, operator, right),
+            Expression::Unary {
+                operator, right, ..
+            } => {
+                let r = diverge!(self.translate(right)?);
+                let (r, rt) = self.value(r, right)?;
+                if rt == NativeType::Int && self.program.arithmetic == Arithmetic::Checked {
+                    let overflow = self.builder.ins().icmp_imm(IntCC::Equal, r, i64::MIN);
+                    self.guard(overflow, "Integer overflow", expression)?;
+                }
+                let ins = self.builder.ins();
+                match (unary_type(operator, rt), rt) {
+                    (Some(t), NativeType::Int) => Ok(Typed::Value(ins.ineg(r), t)),
+                    (Some(t), NativeType::Float) => Ok(Typed::Value(ins.fneg(r), t)),
+                    (Some(t), NativeType::Boolean) => Ok(Typed::Value(ins.bxor_imm(r, 1), t)),
+                    _ => unsupported!(expression, "unsupported operator {:?}", operator),
+                }
+            }
+            Expression::Let { name, value, .. } => {
+                let typed = diverge!(self.translate(value)?);
+                let (v, ty) = self.value(typed, value)?;
+                let variable = self.variable(ty);
+                self.builder.def_var(variable, v);
+                self.scopes.declare(name.clone(), (variable, ty));
+                Ok(typed)
+            }
+            Expression::Assign { name, value, .. } => {
+                let typed = diverge!(self.translate(value)?);
+                let (v, ty) = self.value(typed, value)?;
+                match self.scopes.get(name) {
+                    Some((variable, t)) if t == ty => {
+                        self.builder.def_var(variable, v);
+                        Ok(typed)
+                    }
+                    _ => unsupported!(expression, "invalid assignment to '{}'", name),
+                }
+            }
+            Expression::Return { value, .. } => {
+                let typed = diverge!(self.translate(value)?);
+                if typed.ty() != self.returns {
+                    unsupported!(
+                        value,
+                        "function returns both {:?} and {:?}",
+                        self.returns,
+                        typed.ty()
+                    );
+                }
+                self.ret(typed);
+                Ok(Typed::Never)
+            }
+            Expression::Block { expressions, .. } => {
+                self.scopes.push();
+                let mut typed = Typed::Nil;
+                for expression in expressions {
+                    typed = self.translate(expression)?;
+                    if let Typed::Never = typed {
+                        break;
+                    }
+                }
+                self.scopes.pop();
+                Ok(typed)
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => self.translate_if(condition, consequence, alternative.as_deref()),
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => {
+                let name = match &**function {
+                    Expression::Identifier { ident, .. } => ident,
+                    _ => unsupported!(function, "only calls of named functions are supported"),
+                };
+                let mut args = vec![];
+                let mut types = vec![];
+                for argument in arguments {
+                    let typed = diverge!(self.translate(argument)?);
+                    if self.program.is_print(name) {
+                        return Ok(self.print(typed));
+                    }
+                    let (v, ty) = self.value(typed, argument)?;
+                    args.push(v);
+                    types.push(ty);
+                }
+                let instance = match self.program.lookup(name, &types) {
+                    Some(instance) => instance,
+                    None => unsupported!(function, "call of unknown function '{}'", name),
+                };
+                let returns = self.program.instances[instance].returns;
+                let result = self.call(self.ids[instance], &args);
+                if let Some(failed) = self.program.errors.failed {
+                    let pointer = self.pointer();
+                    let address = self.builder.ins().iconst(pointer, failed);
+                    let flag = self
+                        .builder
+                        .ins()
+                        .load(types::I8, MemFlags::trusted(), address, 0);
+                    self.unwind_if(flag);
+                }
+                Ok(match (returns, result) {
+                    (Some(Ty::Value(ty)), Some(value)) => Typed::Value(value, ty),
+                    _ => Typed::Nil,
+                })
+            }
+            Expression::While {
+                condition, body, ..
+            } => self.translate_while(condition, body),
+            Expression::Break { .. } => {
+                let (_, exit) = *self.loops.last().expect("break is parsed inside loops");
+                self.builder.ins().jump(exit, &[]);
+                Ok(Typed::Never)
+            }
+            Expression::Continue { .. } => {
+                let (header, _) = *self.loops.last().expect("continue is parsed inside loops");
+                self.builder.ins().jump(header, &[]);
+                Ok(Typed::Never)
+            }
+            Expression::Function { .. }
+            | Expression::Lambda { .. }
+            | Expression::For { .. }
+            | Expression::Range { .. }
+            | Expression::List { .. }
+            | Expression::Index { .. }
+            | Expression::SetIndex { .. }
+            | Expression::Map { .. }
+            | Expression::Struct { .. }
+            | Expression::Class { .. }
+            | Expression::Super { .. }
+            | Expression::Construct { .. }
+            | Expression::Get { .. }
+            | Expression::Set { .. }
+            | Expression::Enum { .. }
+            | Expression::Match { .. } => {
+                unsupported!(expression, "unsupported expression")
+            }
+        }
+    }
+
+    /// Translates `&&` and `||`, only evaluating the right operand when the
+    /// left one does not decide the result.
+    fn translate_logical(
+        &mut self,
+        left: &Expression,
+        operator: &Token,
+        right: &Expression,
+    ) -> Result<Typed, CompileError> {
+        let typed = diverge!(self.translate(left)?);
+        let left_value = match typed {
+            Typed::Value(v, NativeType::Boolean) => v,
+            _ => unsupported!(left, "condition must be a boolean"),
+        };
+
+        let right_block = self.builder.create_block();
+        let merge_block = self.builder.create_block();
+        self.builder.append_block_param(merge_block, types::I8);
+        if *operator == Token::And {
+            self.builder
+                .ins()
+                .brif(left_value, right_block, &[], merge_block, &[left_value]);
+        } else {
+            self.builder
+                .ins()
+                .brif(left_value, merge_block, &[left_value], right_block, &[]);
+        }
+
+        self.builder.switch_to_block(right_block);
+        self.builder.seal_block(right_block);
+        self.scopes.push();
+        let typed = self.translate(right)?;
+        self.scopes.pop();
+        match typed {
+            Typed::Value(v, NativeType::Boolean) => {
+                self.builder.ins().jump(merge_block, &[v]);
+            }
+            Typed::Never => {}
+            _ => unsupported!(right, "condition must be a boolean"),
+        }
+
+        self.builder.switch_to_block(merge_block);
+        self.builder.seal_block(merge_block);
+        Ok(Typed::Value(
+            self.builder.block_params(merge_block)[0],
+            NativeType::Boolean,
+        ))
+    }
+
+    fn translate_while(
+        &mut self,
+        condition: &Expression,
+        body: &Expression,
+    ) -> Result<Typed, CompileError> {
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+        self.builder.ins().jump(header_block, &[]);
+
+        // The condition block can only be sealed once every `continue` and
+        // the back edge have been added.
+        self.builder.switch_to_block(header_block);
+        let typed = self.translate(condition)?;
+        let condition_value = match typed {
+            Typed::Value(v, NativeType::Boolean) => v,
+            Typed::Never => {
+                self.builder.seal_block(header_block);
+                return Ok(Typed::Never);
+            }
+            _ => unsupported!(condition, "condition must be a boolean"),
+        };
+        self.builder
+            .ins()
+            .brif(condition_value, body_block, &[], exit_block, &[]);
+
+        self.builder.switch_to_block(body_block);
+        self.builder.seal_block(body_block);
+        self.loops.push((header_block, exit_block));
+        self.scopes.push();
+        let typed = self.translate(body)?;
+        self.scopes.pop();
+        self.loops.pop();
+        if !matches!(typed, Typed::Never) {
+            self.builder.ins().jump(header_block, &[]);
+        }
+        self.builder.seal_block(header_block);
+
+        self.builder.switch_to_block(exit_block);
+        self.builder.seal_block(exit_block);
+        Ok(Typed::Nil)
+    }
+
+    fn print(&mut self, typed: Typed) -> Typed {
+        let runtime = self.runtime();
+        let (id, args) = match typed {
+            Typed::Value(value, NativeType::Int) => (runtime.print_int, vec![value]),
+            Typed::Value(value, NativeType::Float) => (runtime.print_float, vec![value]),
+            Typed::Value(value, NativeType::Boolean) => (runtime.print_boolean, vec![value]),
+            Typed::Value(value, NativeType::String) => (runtime.print_string, vec![value]),
+            _ => (runtime.print_nil, vec![]),
+        };
+        self.call(id, &args);
+        Typed::Nil
+    }
+
+    fn translate_if(
+        &mut self,
+        condition: &Expression,
+        consequence: &Expression,
+        alternative: Option<&Expression>,
+    ) -> Result<Typed, CompileError> {
+        let typed = diverge!(self.translate(condition)?);
+        let condition_value = match typed {
+            Typed::Value(v, NativeType::Boolean) => v,
+            _ => unsupported!(condition, "condition must be a boolean"),
+        };
+
+        let then_block = self.builder.create_block();
+        let else_block = self.builder.create_block();
+        let merge_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition_value, then_block, &[], else_block, &[]);
+
+        // The value of the `if` flows through a variable rather than a block
+        // parameter, since the type is only known once a branch is translated.
+        let mut result: Option<(Variable, NativeType)> = None;
+        let mut branches = [Ty::Nil, Ty::Nil];
+        for (i, (block, branch)) in [(then_block, Some(consequence)), (else_block, alternative)]
+            .into_iter()
+            .enumerate()
+        {
+            self.builder.switch_to_block(block);
+            self.builder.seal_block(block);
+            self.scopes.push();
+            let typed = match branch {
+                Some(branch) => self.translate(branch)?,
+                None => Typed::Nil,
+            };
+            self.scopes.pop();
+            branches[i] = typed.ty();
+            if let Typed::Value(v, ty) = typed {
+                let variable = match result {
+                    Some((variable, t)) if t == ty => Some(variable),
+                    Some(_) => None,
+                    None => {
+                        let variable = self.variable(ty);
+                        result = Some((variable, ty));
+                        Some(variable)
+                    }
+                };
+                if let Some(variable) = variable {
+                    self.builder.def_var(variable, v);
+                }
+            }
+            if let Typed::Never = typed {
+                continue;
+            }
+            self.builder.ins().jump(merge_block, &[]);
+        }
+
+        let merged = merge_branches(branches[0], branches[1]);
+        if merged == Ty::Never {
+            return Ok(Typed::Never);
+        }
+        self.builder.switch_to_block(merge_block);
+        self.builder.seal_block(merge_block);
+        match (merged, result) {
+            (Ty::Value(ty), Some((variable, _))) => {
+                Ok(Typed::Value(self.builder.use_var(variable), ty))
+            }
+            _ => Ok(Typed::Nil),
+        }
+    }
+
+    /// Converts an integer operand of a mixed operation to a float.
+    fn promote(&mut self, value: Value, ty: NativeType) -> Value {
+        match ty {
+            NativeType::Int => self.builder.ins().fcvt_from_sint(types::F64, value),
+            _ => value,
+        }
+    }
+
+    /// Returns from the function if `condition` is set, with a placeholder
+    /// result, to abandon it after an error.
+    fn unwind_if(&mut self, condition: Value) {
+        let unwind_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, unwind_block, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(unwind_block);
+        self.builder.seal_block(unwind_block);
+        let pointer = self.pointer();
+        match self.returns {
+            Ty::Value(NativeType::Float) => {
+                let zero = self.builder.ins().f64const(0.0);
+                self.builder.ins().return_(&[zero]);
+            }
+            Ty::Value(ty) => {
+                let zero = self.builder.ins().iconst(ty.ir_type(pointer), 0);
+                self.builder.ins().return_(&[zero]);
+            }
+            _ => {
+                self.builder.ins().return_(&[]);
+            }
+        }
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
+    }
+
+    /// Raises a runtime error at `expression` if `condition` is set.
+    fn guard(
+        &mut self,
+        condition: Value,
+        message: &str,
+        expression: &Expression,
+    ) -> Result<(), CompileError> {
+        let raise_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, raise_block, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(raise_block);
+        self.builder.seal_block(raise_block);
+        let message = self.string(message, expression)?;
+        let (line, column) = expression.position();
+        let line = self.builder.ins().iconst(types::I64, line as i64);
+        let column = self.builder.ins().iconst(types::I64, column as i64);
+        self.call(self.program.errors.raise, &[message, line, column]);
+        let always = self.builder.ins().iconst(types::I8, 1);
+        self.unwind_if(always);
+        self.builder.ins().jump(continue_block, &[]);
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
+        Ok(())
+    }
+
+    /// Integer arithmetic, raising errors on division by zero and, unless
+    /// arithmetic wraps, on overflow.
+    fn int_binary(
+        &mut self,
+        operator: &Token,
+        left: Value,
+        right: Value,
+        expression: &Expression,
+    ) -> Result<Value, CompileError> {
+        let checked = self.program.arithmetic == Arithmetic::Checked;
+        let b = &mut self.builder;
+        let (result, overflow) = match operator {
+            Token::Plus => {
+                let result = b.ins().iadd(left, right);
+                // Overflow iff both operands have a sign the result lacks.
+                let l = b.ins().bxor(left, result);
+                let r = b.ins().bxor(right, result);
+                let both = b.ins().band(l, r);
+                (result, b.ins().icmp_imm(IntCC::SignedLessThan, both, 0))
+            }
+            Token::Minus => {
+                let result = b.ins().isub(left, right);
+                let operands = b.ins().bxor(left, right);
+                let l = b.ins().bxor(left, result);
+                let both = b.ins().band(operands, l);
+                (result, b.ins().icmp_imm(IntCC::SignedLessThan, both, 0))
+            }
+            Token::Asterisk => {
+                let result = b.ins().imul(left, right);
+                // The high half must be the sign extension of the low one.
+                let high = b.ins().smulhi(left, right);
+                let sign = b.ins().sshr_imm(result, 63);
+                (result, b.ins().icmp(IntCC::NotEqual, high, sign))
+            }
+            _ => return self.floor_div(operator, left, right, expression),
+        };
+        if checked {
+            self.guard(overflow, "Integer overflow", expression)?;
+        }
+        Ok(result)
+    }
+
+    /// Integer `^` by repeated squaring. The exponent is a non-negative
+    /// literal, see [`int_pow_type`].
+    fn int_pow(
+        &mut self,
+        base: Value,
+        exponent: Value,
+        expression: &Expression,
+    ) -> Result<Value, CompileError> {
+        let header = self.builder.create_block();
+        let body = self.builder.create_block();
+        let exit = self.builder.create_block();
+        for block in [header, body] {
+            for _ in 0..3 {
+                self.builder.append_block_param(block, types::I64);
+            }
+        }
+        self.builder.append_block_param(exit, types::I64);
+        let one = self.builder.ins().iconst(types::I64, 1);
+        self.builder.ins().jump(header, &[one, base, exponent]);
+
+        // The result, the base squared so far and the exponent left.
+        self.builder.switch_to_block(header);
+        let params = self.builder.block_params(header).to_vec();
+        let done = self.builder.ins().icmp_imm(IntCC::Equal, params[2], 0);
+        self.builder
+            .ins()
+            .brif(done, exit, &[params[0]], body, &params);
+
+        self.builder.switch_to_block(body);
+        self.builder.seal_block(body);
+        let params = self.builder.block_params(body).to_vec();
+        let (result, base, exponent) = (params[0], params[1], params[2]);
+        let b = &mut self.builder;
+        let odd = b.ins().band_imm(exponent, 1);
+        let odd = b.ins().icmp_imm(IntCC::NotEqual, odd, 0);
+        let product = b.ins().imul(result, base);
+        let result = b.ins().select(odd, product, result);
+        let exponent = b.ins().sshr_imm(exponent, 1);
+        let square = b.ins().imul(base, base);
+        if self.program.arithmetic == Arithmetic::Checked {
+            // Like `*`, a product overflows unless its high half is the sign
+            // extension of the low one. The base is only squared again if
+            // the exponent has bits left.
+            let high = b.ins().smulhi(params[0], base);
+            let sign = b.ins().sshr_imm(product, 63);
+            let product_overflow = b.ins().icmp(IntCC::NotEqual, high, sign);
+            let product_overflow = b.ins().band(odd, product_overflow);
+            let high = b.ins().smulhi(base, base);
+            let sign = b.ins().sshr_imm(square, 63);
+            let square_overflow = b.ins().icmp(IntCC::NotEqual, high, sign);
+            let more = b.ins().icmp_imm(IntCC::NotEqual, exponent, 0);
+            let square_overflow = b.ins().band(more, square_overflow);
+            let overflow = b.ins().bor(product_overflow, square_overflow);
+            self.guard(overflow, "Integer overflow", expression)?;
+        }
+        self.builder.ins().jump(header, &[result, square, exponent]);
+        self.builder.seal_block(header);
+
+        self.builder.switch_to_block(exit);
+        self.builder.seal_block(exit);
+        Ok(self.builder.block_params(exit)[0])
+    }
+
+    /// Float `^`, which calls `pow` from the C library.
+    fn float_pow(
+        &mut self,
+        base: Value,
+        exponent: Value,
+        expression: &Expression,
+    ) -> Result<Value, CompileError> {
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(types::F64));
+        signature.params.push(AbiParam::new(types::F64));
+        signature.returns.push(AbiParam::new(types::F64));
+        let pow = self
+            .module
+            .declare_function("pow", Linkage::Import, &signature)
+            .map_err(|e| CompileError::module(e, expression))?;
+        Ok(self.call(pow, &[base, exponent]).unwrap())
+    }
+
+    /// Integer `/` and `%` round the quotient towards negative infinity, so
+    /// the remainder takes the sign of the divisor.
+    fn floor_div(
+        &mut self,
+        operator: &Token,
+        left: Value,
+        right: Value,
+        expression: &Expression,
+    ) -> Result<Value, CompileError> {
+        let zero = self.builder.ins().icmp_imm(IntCC::Equal, right, 0);
+        self.guard(zero, "Division by zero", expression)?;
+        let b = &mut self.builder;
+        let minus_one = b.ins().icmp_imm(IntCC::Equal, right, -1);
+        if *operator == Token::Slash && self.program.arithmetic == Arithmetic::Checked {
+            let minimum = b.ins().icmp_imm(IntCC::Equal, left, i64::MIN);
+            let overflow = b.ins().band(minimum, minus_one);
+            self.guard(overflow, "Integer overflow", expression)?;
+        }
+
+        // `sdiv` traps on `i64::MIN / -1`, so dividing by -1 is a negation
+        // instead, which wraps.
+        let b = &mut self.builder;
+        let one = b.ins().iconst(types::I64, 1);
+        let divisor = b.ins().select(minus_one, one, right);
+        let quotient = b.ins().sdiv(left, divisor);
+        let negated = b.ins().ineg(left);
+        let quotient = b.ins().select(minus_one, negated, quotient);
+        let remainder = b.ins().srem(left, divisor);
+        let inexact = b.ins().icmp_imm(IntCC::NotEqual, remainder, 0);
+        let signs = b.ins().bxor(remainder, right);
+        let negative = b.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+        let adjust = b.ins().band(inexact, negative);
+        Ok(if *operator == Token::Slash {
+            let adjust = b.ins().uextend(types::I64, adjust);
+            b.ins().isub(quotient, adjust)
+        } else {
+            let zero = b.ins().iconst(types::I64, 0);
+            let offset = b.ins().select(adjust, right, zero);
+            b.ins().iadd(remainder, offset)
+        })
+    }
+
+    fn binary(
+        &mut self,
+        operator: &Token,
+        ty: NativeType,
+        left: Value,
+        right: Value,
+        expression: &Expression,
+    ) -> Result<Value, CompileError> {
+        if ty == NativeType::String {
+            let concat = self.runtime().concat;
+            return Ok(self.call(concat, &[left, right]).unwrap());
+        }
+        if ty == NativeType::Int
+            && matches!(
+                operator,
+                Token::Plus | Token::Minus | Token::Asterisk | Token::Slash | Token::Percent
+            )
+        {
+            return self.int_binary(operator, left, right, expression);
+        }
+        match (ty, operator) {
+            (NativeType::Int, Token::Pow) => return self.int_pow(left, right, expression),
+            (NativeType::Float, Token::Pow) => return self.float_pow(left, right, expression),
+            _ => {}
+        }
+
+        let ins = self.builder.ins();
+        Ok(match (ty, operator) {
+            (NativeType::Int, Token::GreaterThan) => {
+                ins.icmp(IntCC::SignedGreaterThan, left, right)
+            }
+            (NativeType::Int, Token::LessThan) => ins.icmp(IntCC::SignedLessThan, left, right),
+            (NativeType::Int, Token::GreaterThanEqual) => {
+                ins.icmp(IntCC::SignedGreaterThanOrEqual, left, right)
+            }
+            (NativeType::Int, Token::LessThanEqual) => {
+                ins.icmp(IntCC::SignedLessThanOrEqual, left, right)
+            }
+            (NativeType::Int | NativeType::Boolean, Token::EqEq) => {
+                ins.icmp(IntCC::Equal, left, right)
+            }
+            (NativeType::Int | NativeType::Boolean, Token::BangEq) => {
+                ins.icmp(IntCC::NotEqual, left, right)
+            }
+            (NativeType::Float, Token::Plus) => ins.fadd(left, right),
+            (NativeType::Float, Token::Minus) => ins.fsub(left, right),
+            (NativeType::Float, Token::Asterisk) => ins.fmul(left, right),
+            (NativeType::Float, Token::Slash) => ins.fdiv(left, right),
+            (NativeType::Float, Token::Percent) => {
+                let quotient = ins.fdiv(left, right);
+                let quotient = self.builder.ins().floor(quotient);
+                let product = self.builder.ins().fmul(quotient, right);
+                self.builder.ins().fsub(left, product)
+            }
+            (NativeType::Float, Token::GreaterThan) => ins.fcmp(FloatCC::GreaterThan, left, right),
+            (NativeType::Float, Token::LessThan) => ins.fcmp(FloatCC::LessThan, left, right),
+            (NativeType::Float, Token::GreaterThanEqual) => {
+                ins.fcmp(FloatCC::GreaterThanOrEqual, left, right)
+            }
+            (NativeType::Float, Token::LessThanEqual) => {
+                ins.fcmp(FloatCC::LessThanOrEqual, left, right)
+            }
+            (NativeType::Float, Token::EqEq) => ins.fcmp(FloatCC::Equal, left, right),
+            (NativeType::Float, Token::BangEq) => ins.fcmp(FloatCC::NotEqual, left, right),
+            _ => unreachable!("operator {:?} was type checked", operator),
+        })
+    }
+}
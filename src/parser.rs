@@ -1,15 +1,32 @@
 use crate::{
-    ast::{Expression, Value},
+    ast::{Expression, MatchArm, Pattern, Value},
     lexer::Lexer,
     token::Token,
 };
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display, mem};
 
 pub struct Parser {
     lexer: Lexer,
     lookahead: Token,
     line: usize,
     column: usize,
+    /// How many loops the parser is inside of in the current function.
+    loops: usize,
+    /// Whether a name followed by a brace is a struct literal, and a brace
+    /// where an operand is expected opens a map literal or a block. Neither
+    /// is in the head of an `if`, `while` or `for`, where the brace opens
+    /// the body.
+    struct_literals: bool,
+    /// Whether `=>` after a name or a list of names in parentheses makes an
+    /// arrow function. It does not in the guard of a match arm, which `=>`
+    /// ends.
+    arrow_functions: bool,
+    /// The variants of the enums declared so far and how many fields each
+    /// has, to check the patterns of matches over them.
+    enums: HashMap<String, Vec<(String, usize)>>,
+    /// A map literal which starts an expression, parsed before it was known
+    /// not to be a block, for the operators after it to take.
+    operand: Option<Expression>,
 }
 
 pub struct ParseError {
@@ -28,6 +45,15 @@ impl Display for ParseError {
     }
 }
 
+/// The parameters of a function, `(name, name = default, ...rest)`.
+#[derive(Default)]
+struct Parameters {
+    names: Vec<String>,
+    /// The default values of the last names.
+    defaults: Vec<Expression>,
+    rest: Option<String>,
+}
+
 macro_rules! eat {
     ($self:ident, $token:expr) => {
         if !$self.nibble($token) {
@@ -62,7 +88,7 @@ macro_rules! push_program {
     ($self:ident, $program:ident) => {
         $program.push($self.expression()?);
         match $program.last().unwrap() {
-            Expression::For { body, .. } => {
+            Expression::For { body, .. } | Expression::While { body, .. } => {
                 if let Expression::Block { .. } = **body {
                     continue;
                 }
@@ -72,6 +98,10 @@ macro_rules! push_program {
                     continue;
                 }
             }
+            Expression::Struct { .. }
+            | Expression::Class { .. }
+            | Expression::Enum { .. }
+            | Expression::Match { .. } => continue,
             Expression::If {
                 consequence,
                 alternative,
@@ -102,6 +132,11 @@ impl Parser {
             lookahead,
             line: 1,
             column: 0,
+            loops: 0,
+            struct_literals: true,
+            arrow_functions: true,
+            enums: HashMap::new(),
+            operand: None,
         }
     }
 
@@ -136,10 +171,34 @@ impl Parser {
         // TODO: implement statements (which are really just expressions...)
         match self.lookahead {
             Token::For => self.for_expression(),
-            Token::LBrace => self.block(),
+            Token::While => self.while_expression(),
+            Token::Break => self.break_expression(),
+            Token::Continue => self.continue_expression(),
+            Token::LBrace => match self.block_or_map()? {
+                map @ Expression::Map { .. } => {
+                    self.operand = Some(map);
+                    self.assignment()
+                }
+                block => Ok(block),
+            },
             Token::Let => self.let_expression(),
-            Token::Function => self.function_expression(),
-            Token::If => self.if_expression(),
+            Token::Function => match self.function_expression()? {
+                // An anonymous function can be called right away.
+                lambda @ Expression::Lambda { .. } => self.postfix(lambda),
+                function => Ok(function),
+            },
+            Token::Struct => self.struct_declaration(),
+            Token::Class => self.class_declaration(),
+            Token::Enum => self.enum_declaration(),
+            // Their values can be called, indexed or have fields read.
+            Token::Match => {
+                let match_expression = self.match_expression()?;
+                self.postfix(match_expression)
+            }
+            Token::If => {
+                let if_expression = self.if_expression()?;
+                self.postfix(if_expression)
+            }
             Token::Return => self.return_expression(),
             _ => self.assignment(),
         }
@@ -168,9 +227,38 @@ impl Parser {
         })
     }
 
+    /// The condition of an `if` or `while`, or what a `for` iterates over.
+    fn head(&mut self) -> Result<Expression, ParseError> {
+        let struct_literals = mem::replace(&mut self.struct_literals, false);
+        let head = self.expression();
+        self.struct_literals = struct_literals;
+        head
+    }
+
+    /// An expression between brackets, where struct literals and arrow
+    /// functions are allowed again.
+    fn nested(&mut self) -> Result<Expression, ParseError> {
+        let struct_literals = mem::replace(&mut self.struct_literals, true);
+        let arrow_functions = mem::replace(&mut self.arrow_functions, true);
+        let nested = self.expression();
+        self.struct_literals = struct_literals;
+        self.arrow_functions = arrow_functions;
+        nested
+    }
+
+    /// The guard of a match arm.
+    fn guard(&mut self) -> Result<Expression, ParseError> {
+        let struct_literals = mem::replace(&mut self.struct_literals, true);
+        let arrow_functions = mem::replace(&mut self.arrow_functions, false);
+        let guard = self.expression();
+        self.struct_literals = struct_literals;
+        self.arrow_functions = arrow_functions;
+        guard
+    }
+
     fn if_expression(&mut self) -> Result<Expression, ParseError> {
         eat!(self, Token::If);
-        let condition = self.expression()?;
+        let condition = self.head()?;
         let consequence = self.expression();
         let alternative = if self.nibble(Token::Else) {
             Some(Box::new(self.expression()?))
@@ -186,22 +274,480 @@ impl Parser {
         })
     }
 
+    /// `fn name(parameter, ...) body`, or `fn(parameter, ...) body` for an
+    /// anonymous function.
     fn function_expression(&mut self) -> Result<Expression, ParseError> {
         eat!(self, Token::Function);
-        let name = eat_identifier!(self);
+        let name = match self.lookahead {
+            Token::LParen => None,
+            _ => Some(eat_identifier!(self)),
+        };
         eat!(self, Token::LParen);
-        let mut parameters: Vec<String> = vec![];
-        if !self.is(Token::RParen) {
-            parameters.push(eat_identifier!(self));
-            while self.nibble(Token::Comma) {
-                parameters.push(eat_identifier!(self));
+        let parameters = self.parameters(Parameters::default())?;
+        let body = Box::new(self.function_body()?);
+        Ok(match name {
+            Some(name) => Expression::Function {
+                name,
+                slot: None,
+                parameters: parameters.names,
+                defaults: parameters.defaults,
+                rest: parameters.rest,
+                body,
+                line: self.line,
+                column: self.column,
+            },
+            None => Expression::Lambda {
+                parameters: parameters.names,
+                defaults: parameters.defaults,
+                rest: parameters.rest,
+                body,
+                line: self.line,
+                column: self.column,
+            },
+        })
+    }
+
+    /// The rest of a parameter list up to and including the `)`, after the
+    /// parameters already in `parameters`.
+    fn parameters(&mut self, mut parameters: Parameters) -> Result<Parameters, ParseError> {
+        while !self.is(Token::RParen) {
+            if self.nibble(Token::Ellipsis) {
+                parameters.rest = Some(eat_identifier!(self));
+                if !self.is(Token::RParen) {
+                    return Err(ParseError {
+                        message: "rest parameter must be last".to_string(),
+                        line: self.line,
+                        column: self.column,
+                    });
+                }
+                break;
+            }
+            let name = eat_identifier!(self);
+            let default = match self.nibble(Token::Eq) {
+                true => Some(self.nested()?),
+                false => None,
+            };
+            self.parameter(&mut parameters, name, default)?;
+            if !self.nibble(Token::Comma) {
+                break;
             }
         }
         eat!(self, Token::RParen);
-        Ok(Expression::Function {
+        Ok(parameters)
+    }
+
+    fn parameter(
+        &self,
+        parameters: &mut Parameters,
+        name: String,
+        default: Option<Expression>,
+    ) -> Result<(), ParseError> {
+        match default {
+            Some(default) => parameters.defaults.push(default),
+            // Calls can only leave out the last arguments.
+            None if !parameters.defaults.is_empty() => {
+                return Err(ParseError {
+                    message: format!("parameter '{}' must have a default value", name),
+                    line: self.line,
+                    column: self.column,
+                })
+            }
+            None => (),
+        }
+        parameters.names.push(name);
+        Ok(())
+    }
+
+    /// `=> body`, after the parameters of an arrow function.
+    fn arrow_function(&mut self, parameters: Parameters) -> Result<Expression, ParseError> {
+        eat!(self, Token::FatArrow);
+        let body = self.function_body()?;
+        Ok(Expression::Lambda {
+            parameters: parameters.names,
+            defaults: parameters.defaults,
+            rest: parameters.rest,
+            body: Box::new(body),
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn function_body(&mut self) -> Result<Expression, ParseError> {
+        // Loops around a function can't be broken out of from inside it.
+        let loops = mem::take(&mut self.loops);
+        let body = self.expression();
+        self.loops = loops;
+        body
+    }
+
+    /// `struct Name { field, ... fn method() { ... } ... }`, where methods
+    /// come after the fields.
+    fn struct_declaration(&mut self) -> Result<Expression, ParseError> {
+        eat!(self, Token::Struct);
+        let name = eat_identifier!(self);
+        eat!(self, Token::LBrace);
+        let mut fields: Vec<String> = vec![];
+        while let Token::Ident(_) = self.lookahead {
+            let field = eat_identifier!(self);
+            if fields.contains(&field) {
+                return Err(ParseError {
+                    message: format!("duplicate field '{}' in struct {}", field, name),
+                    line: self.line,
+                    column: self.column,
+                });
+            }
+            fields.push(field);
+            if !self.nibble(Token::Comma) {
+                break;
+            }
+        }
+        let methods = self.methods(&format!("struct {}", name), &fields)?;
+        eat!(self, Token::RBrace);
+        Ok(Expression::Struct {
             name,
-            parameters,
-            body: Box::new(self.expression()?),
+            slot: None,
+            fields,
+            methods,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    /// `class Name < Superclass { fn method() { ... } ... }`, where the
+    /// superclass is optional and the `init` method initializes instances.
+    fn class_declaration(&mut self) -> Result<Expression, ParseError> {
+        eat!(self, Token::Class);
+        let name = eat_identifier!(self);
+        let superclass = if self.nibble(Token::LessThan) {
+            let superclass = eat_identifier!(self);
+            if superclass == name {
+                return Err(ParseError {
+                    message: format!("class {} can't inherit from itself", name),
+                    line: self.line,
+                    column: self.column,
+                });
+            }
+            Some(superclass)
+        } else {
+            None
+        };
+        eat!(self, Token::LBrace);
+        let methods = self.methods(&format!("class {}", name), &[])?;
+        eat!(self, Token::RBrace);
+        Ok(Expression::Class {
+            name,
+            slot: None,
+            superclass,
+            superclass_slot: None,
+            methods,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    /// `enum Name { Variant(field, ...), ... }`, where a variant without
+    /// fields is written without parentheses.
+    fn enum_declaration(&mut self) -> Result<Expression, ParseError> {
+        eat!(self, Token::Enum);
+        let name = eat_identifier!(self);
+        eat!(self, Token::LBrace);
+        let mut variants: Vec<(String, Vec<String>)> = vec![];
+        while let Token::Ident(_) = self.lookahead {
+            let variant = eat_identifier!(self);
+            if variants.iter().any(|(other, _)| *other == variant) {
+                return Err(ParseError {
+                    message: format!("duplicate variant '{}' in enum {}", variant, name),
+                    line: self.line,
+                    column: self.column,
+                });
+            }
+            let mut fields: Vec<String> = vec![];
+            if self.nibble(Token::LParen) {
+                while !self.is(Token::RParen) {
+                    let field = eat_identifier!(self);
+                    if fields.contains(&field) {
+                        return Err(ParseError {
+                            message: format!(
+                                "duplicate field '{}' in variant {}.{}",
+                                field, name, variant
+                            ),
+                            line: self.line,
+                            column: self.column,
+                        });
+                    }
+                    fields.push(field);
+                    if !self.nibble(Token::Comma) {
+                        break;
+                    }
+                }
+                eat!(self, Token::RParen);
+            }
+            variants.push((variant, fields));
+            if !self.nibble(Token::Comma) {
+                break;
+            }
+        }
+        eat!(self, Token::RBrace);
+        self.enums.insert(
+            name.clone(),
+            variants
+                .iter()
+                .map(|(variant, fields)| (variant.clone(), fields.len()))
+                .collect(),
+        );
+        Ok(Expression::Enum {
+            name,
+            slot: None,
+            variants,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    /// `match value { pattern => result, pattern if guard => result, ... }`.
+    /// Arms are separated by commas, which may be left out after a block.
+    fn match_expression(&mut self) -> Result<Expression, ParseError> {
+        eat!(self, Token::Match);
+        let value = self.head()?;
+        eat!(self, Token::LBrace);
+        let mut arms: Vec<MatchArm> = vec![];
+        while !self.is(Token::RBrace) {
+            let pattern = self.pattern()?;
+            let mut bound = vec![];
+            self.bindings(&pattern, &mut bound)?;
+            let guard = if self.nibble(Token::If) {
+                Some(self.guard()?)
+            } else {
+                None
+            };
+            eat!(self, Token::FatArrow);
+            let body = self.nested()?;
+            let block = matches!(body, Expression::Block { .. });
+            arms.push(MatchArm {
+                pattern,
+                guard,
+                body,
+            });
+            if !self.nibble(Token::Comma) && !block {
+                break;
+            }
+        }
+        eat!(self, Token::RBrace);
+        self.exhaustive(&arms)?;
+        Ok(Expression::Match {
+            value: Box::new(value),
+            arms,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    /// A pattern of a match arm: `_`, a name to bind, a literal, or an enum
+    /// variant with a pattern for each of its fields.
+    fn pattern(&mut self) -> Result<Pattern, ParseError> {
+        if self.nibble(Token::Underscore) {
+            return Ok(Pattern::Wildcard);
+        }
+
+        if let Token::Ident(name) = self.lookahead.clone() {
+            self.lookahead = self.next_token();
+            if !self.nibble(Token::Dot) {
+                return Ok(Pattern::Binding(name));
+            }
+            let variant = eat_identifier!(self);
+            let mut fields: Vec<Pattern> = vec![];
+            if self.nibble(Token::LParen) {
+                while !self.is(Token::RParen) {
+                    fields.push(self.pattern()?);
+                    if !self.nibble(Token::Comma) {
+                        break;
+                    }
+                }
+                eat!(self, Token::RParen);
+            }
+            self.check_variant(&name, &variant, fields.len())?;
+            return Ok(Pattern::Variant {
+                enumeration: name,
+                slot: None,
+                variant,
+                fields,
+            });
+        }
+
+        let negative = self.nibble(Token::Minus);
+        let value = match self.lookahead.clone() {
+            Token::Int(i) if negative => Value::Int(-i),
+            Token::Int(i) => Value::Int(i),
+            // `-9223372036854775808` is the one negative literal that fits
+            // in an `Int` but whose digits don't.
+            Token::BigInt(i) if negative => match i64::try_from(-&i) {
+                Ok(i) => Value::Int(i),
+                Err(_) => Value::BigInt(-i),
+            },
+            Token::BigInt(i) => Value::BigInt(i),
+            Token::Float(f) if negative => Value::Float(-f),
+            Token::Float(f) => Value::Float(f),
+            Token::String(s) if !negative => Value::String(s),
+            Token::True if !negative => Value::Boolean(true),
+            Token::False if !negative => Value::Boolean(false),
+            tok => {
+                return Err(ParseError {
+                    message: format!("expected pattern, found {:?}", tok),
+                    line: self.lexer.line,
+                    column: self.lexer.column,
+                })
+            }
+        };
+        self.lookahead = self.next_token();
+        Ok(Pattern::Value(value))
+    }
+
+    /// Checks a variant pattern against the declaration of its enum, if the
+    /// enum has been declared yet.
+    fn check_variant(&self, name: &str, variant: &str, fields: usize) -> Result<(), ParseError> {
+        let variants = match self.enums.get(name) {
+            Some(variants) => variants,
+            None => return Ok(()),
+        };
+        let message = match variants.iter().find(|(other, _)| other == variant) {
+            None => format!("enum {} has no variant '{}'", name, variant),
+            Some((_, arity)) if *arity != fields => format!(
+                "variant {}.{} has {} fields but the pattern has {}",
+                name, variant, arity, fields
+            ),
+            Some(_) => return Ok(()),
+        };
+        Err(ParseError {
+            message,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    /// Collects the names a pattern binds, which must all differ.
+    fn bindings(&self, pattern: &Pattern, bound: &mut Vec<String>) -> Result<(), ParseError> {
+        match pattern {
+            Pattern::Binding(name) if bound.contains(name) => Err(ParseError {
+                message: format!("'{}' is bound more than once in the same pattern", name),
+                line: self.line,
+                column: self.column,
+            }),
+            Pattern::Binding(name) => {
+                bound.push(name.clone());
+                Ok(())
+            }
+            Pattern::Variant { fields, .. } => fields
+                .iter()
+                .try_for_each(|field| self.bindings(field, bound)),
+            Pattern::Wildcard | Pattern::Value(_) => Ok(()),
+        }
+    }
+
+    /// A match over a declared enum must cover every variant, either with an
+    /// arm for it whose fields match anything or with an arm that matches
+    /// anything at all. Arms with guards cover nothing, since the guard may
+    /// fail.
+    fn exhaustive(&self, arms: &[MatchArm]) -> Result<(), ParseError> {
+        let name = arms.iter().find_map(|arm| match &arm.pattern {
+            Pattern::Variant { enumeration, .. } if self.enums.contains_key(enumeration) => {
+                Some(enumeration)
+            }
+            _ => None,
+        });
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let error = |message: String| ParseError {
+            message,
+            line: self.line,
+            column: self.column,
+        };
+
+        for arm in arms {
+            if let Pattern::Variant { enumeration, .. } = &arm.pattern {
+                if enumeration != name && self.enums.contains_key(enumeration) {
+                    return Err(error(format!(
+                        "pattern of enum {} in a match over enum {}",
+                        enumeration, name
+                    )));
+                }
+            }
+        }
+
+        let unguarded = || arms.iter().filter(|arm| arm.guard.is_none());
+        if unguarded().any(|arm| arm.pattern.is_irrefutable()) {
+            return Ok(());
+        }
+        let missing: Vec<&str> = self.enums[name]
+            .iter()
+            .map(|(variant, _)| variant.as_str())
+            .filter(|variant| {
+                !unguarded().any(|arm| match &arm.pattern {
+                    Pattern::Variant {
+                        variant: covered,
+                        fields,
+                        ..
+                    } => covered == variant && fields.iter().all(Pattern::is_irrefutable),
+                    _ => false,
+                })
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(error(format!(
+            "non-exhaustive match over enum {}: {} not covered",
+            name,
+            missing.join(", ")
+        )))
+    }
+
+    /// The methods of a struct or class, which can't share a name with each
+    /// other or with a field.
+    fn methods(&mut self, owner: &str, fields: &[String]) -> Result<Vec<Expression>, ParseError> {
+        let mut methods: Vec<Expression> = vec![];
+        while self.is(Token::Function) {
+            let method = self.function_expression()?;
+            let message = match &method {
+                Expression::Function { name: method, .. } => {
+                    let duplicate = fields.contains(method)
+                        || methods.iter().any(|other| {
+                            matches!(other, Expression::Function { name, .. } if name == method)
+                        });
+                    duplicate.then(|| format!("duplicate member '{}' in {}", method, owner))
+                }
+                _ => Some(format!("methods of {} must have a name", owner)),
+            };
+            if let Some(message) = message {
+                return Err(ParseError {
+                    message,
+                    line: self.line,
+                    column: self.column,
+                });
+            }
+            methods.push(method);
+        }
+        Ok(methods)
+    }
+
+    /// The fields of a struct literal, after its name.
+    fn construct(&mut self, name: String) -> Result<Expression, ParseError> {
+        eat!(self, Token::LBrace);
+        let mut fields: Vec<(String, Expression)> = vec![];
+        while !self.is(Token::RBrace) {
+            let field = eat_identifier!(self);
+            eat!(self, Token::Colon);
+            fields.push((field, self.nested()?));
+            if !self.nibble(Token::Comma) {
+                break;
+            }
+        }
+        eat!(self, Token::RBrace);
+        Ok(Expression::Construct {
+            name,
+            slot: None,
+            fields,
             line: self.line,
             column: self.column,
         })
@@ -214,6 +760,7 @@ impl Parser {
         let value = self.expression()?;
         Ok(Expression::Let {
             name: ident,
+            slot: None,
             value: Box::new(value),
             line: self.line,
             column: self.column,
@@ -221,7 +768,26 @@ impl Parser {
     }
 
     fn block(&mut self) -> Result<Expression, ParseError> {
+        self.braced(false)
+    }
+
+    /// Braces hold a map literal when the first expression in them is
+    /// followed by a colon, as in `{ "key": value }`, or for the empty map
+    /// `{:}`. Anything else is a block.
+    fn block_or_map(&mut self) -> Result<Expression, ParseError> {
+        self.braced(true)
+    }
+
+    fn braced(&mut self, map: bool) -> Result<Expression, ParseError> {
         eat!(self, Token::LBrace);
+        if map && self.nibble(Token::Colon) {
+            eat!(self, Token::RBrace);
+            return Ok(Expression::Map {
+                entries: vec![],
+                line: self.line,
+                column: self.column,
+            });
+        }
         let mut statements: Vec<Expression> = vec![];
 
         let mut semicolon = true;
@@ -230,6 +796,10 @@ impl Parser {
 
             push_program!(self, statements);
 
+            if map && statements.len() == 1 && self.is(Token::Colon) {
+                return self.map(statements.pop().unwrap());
+            }
+
             if self.nibble(Token::Semicolon) {
                 semicolon = true;
                 continue;
@@ -260,23 +830,113 @@ impl Parser {
         })
     }
 
+    /// The rest of a map literal, after its first key.
+    fn map(&mut self, key: Expression) -> Result<Expression, ParseError> {
+        let mut entries = vec![];
+        let mut key = key;
+        loop {
+            eat!(self, Token::Colon);
+            let value = self.expression()?;
+            entries.push((key, value));
+            if !self.nibble(Token::Comma) || self.is(Token::RBrace) {
+                break;
+            }
+            key = self.expression()?;
+        }
+        eat!(self, Token::RBrace);
+        Ok(Expression::Map {
+            entries,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
     fn for_expression(&mut self) -> Result<Expression, ParseError> {
         eat!(self, Token::For);
 
-        let ident = eat_identifier!(self);
+        let (ident, value_ident) = if self.nibble(Token::LParen) {
+            let key = eat_identifier!(self);
+            eat!(self, Token::Comma);
+            let value = eat_identifier!(self);
+            eat!(self, Token::RParen);
+            (key, Some(value))
+        } else {
+            (eat_identifier!(self), None)
+        };
         eat!(self, Token::In);
-        let expr = self.expression()?;
+        let expr = self.head()?;
         Ok(Expression::For {
             ident,
+            value_ident,
             expr: Box::new(expr),
-            body: Box::new(self.block()?),
+            body: Box::new(self.loop_body()?),
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn while_expression(&mut self) -> Result<Expression, ParseError> {
+        eat!(self, Token::While);
+
+        let condition = self.head()?;
+        Ok(Expression::While {
+            condition: Box::new(condition),
+            body: Box::new(self.loop_body()?),
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn loop_body(&mut self) -> Result<Expression, ParseError> {
+        self.loops += 1;
+        let body = self.block();
+        self.loops -= 1;
+        body
+    }
+
+    fn outside_loop(&self, keyword: &str) -> Result<(), ParseError> {
+        if self.loops == 0 {
+            return Err(ParseError {
+                message: format!("'{}' outside of a loop", keyword),
+                line: self.lexer.line,
+                column: self.lexer.column,
+            });
+        }
+        Ok(())
+    }
+
+    fn break_expression(&mut self) -> Result<Expression, ParseError> {
+        self.outside_loop("break")?;
+        eat!(self, Token::Break);
+
+        let value = if self.is(Token::Semicolon) || self.is(Token::RBrace) {
+            Expression::Value {
+                value: Value::Nil,
+                line: self.line,
+                column: self.column,
+            }
+        } else {
+            self.expression()?
+        };
+        Ok(Expression::Break {
+            value: Box::new(value),
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn continue_expression(&mut self) -> Result<Expression, ParseError> {
+        self.outside_loop("continue")?;
+        eat!(self, Token::Continue);
+
+        Ok(Expression::Continue {
             line: self.line,
             column: self.column,
         })
     }
 
     fn assignment(&mut self) -> Result<Expression, ParseError> {
-        let expr = self.equality()?;
+        let expr = self.range()?;
 
         if self.nibble(Token::Eq) {
             let value = self.assignment();
@@ -284,6 +944,25 @@ impl Parser {
                 Expression::Identifier { ident, .. } => {
                     return Ok(Expression::Assign {
                         name: ident,
+                        slot: None,
+                        value: Box::new(value?),
+                        line: self.line,
+                        column: self.column,
+                    })
+                }
+                Expression::Index { object, index, .. } => {
+                    return Ok(Expression::SetIndex {
+                        object,
+                        index,
+                        value: Box::new(value?),
+                        line: self.line,
+                        column: self.column,
+                    })
+                }
+                Expression::Get { object, name, .. } => {
+                    return Ok(Expression::Set {
+                        object,
+                        name,
                         value: Box::new(value?),
                         line: self.line,
                         column: self.column,
@@ -302,6 +981,67 @@ impl Parser {
         Ok(expr)
     }
 
+    fn range(&mut self) -> Result<Expression, ParseError> {
+        let start = self.logical_or()?;
+
+        let inclusive = if self.nibble(Token::DotDot) {
+            false
+        } else if self.nibble(Token::DotDotEq) {
+            true
+        } else {
+            return Ok(start);
+        };
+        let end = self.logical_or()?;
+        // `step` is only a keyword here, where an identifier can't appear.
+        let step = if self.lookahead == Token::Ident("step".to_string()) {
+            self.lookahead = self.next_token();
+            Some(Box::new(self.logical_or()?))
+        } else {
+            None
+        };
+
+        Ok(Expression::Range {
+            start: Box::new(start),
+            end: Box::new(end),
+            step,
+            inclusive,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn logical_or(&mut self) -> Result<Expression, ParseError> {
+        let mut expr = self.logical_and()?;
+
+        while self.nibble(Token::Or) {
+            expr = Expression::Logical {
+                left: Box::new(expr),
+                operator: Token::Or,
+                right: Box::new(self.logical_and()?),
+                line: self.line,
+                column: self.column,
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn logical_and(&mut self) -> Result<Expression, ParseError> {
+        let mut expr = self.equality()?;
+
+        while self.nibble(Token::And) {
+            expr = Expression::Logical {
+                left: Box::new(expr),
+                operator: Token::And,
+                right: Box::new(self.equality()?),
+                line: self.line,
+                column: self.column,
+            };
+        }
+
+        Ok(expr)
+    }
+
     fn equality(&mut self) -> Result<Expression, ParseError> {
         let mut expr = self.comparison()?;
 
@@ -363,7 +1103,7 @@ impl Parser {
     fn factor(&mut self) -> Result<Expression, ParseError> {
         let mut expr = self.exponential()?;
 
-        while self.is(Token::Asterisk) || self.is(Token::Slash) {
+        while self.is(Token::Asterisk) || self.is(Token::Slash) || self.is(Token::Percent) {
             let op = self.lookahead.clone();
             self.lookahead = self.next_token();
             expr = Expression::Binary {
@@ -378,62 +1118,212 @@ impl Parser {
         Ok(expr)
     }
 
+    /// `^` is right associative, so `2 ^ 3 ^ 2` is `2 ^ 9`.
     fn exponential(&mut self) -> Result<Expression, ParseError> {
-        let mut expr = self.unary()?;
+        let expr = self.unary()?;
 
-        while self.nibble(Token::Pow) {
-            expr = Expression::Binary {
+        if self.nibble(Token::Pow) {
+            return Ok(Expression::Binary {
                 left: Box::new(expr),
                 operator: Token::Pow,
-                right: Box::new(self.unary()?),
+                right: Box::new(self.exponential()?),
                 line: self.line,
                 column: self.column,
-            };
+            });
         }
 
         Ok(expr)
     }
 
     fn unary(&mut self) -> Result<Expression, ParseError> {
-        self.call() // TODO
+        if self.operand.is_none() && (self.is(Token::Minus) || self.is(Token::Bang)) {
+            let op = self.lookahead.clone();
+            self.lookahead = self.next_token();
+            return Ok(Expression::Unary {
+                operator: op,
+                right: Box::new(self.unary()?),
+                line: self.line,
+                column: self.column,
+            });
+        }
+
+        self.call()
     }
 
     fn call(&mut self) -> Result<Expression, ParseError> {
-        let callee = self.primary()?;
+        let primary = match self.operand.take() {
+            Some(operand) => operand,
+            None => self.primary()?,
+        };
+        self.postfix(primary)
+    }
 
-        if self.nibble(Token::LParen) {
-            let mut args: Vec<Expression> = vec![];
+    /// Calls, field accesses and indexing after `expression`, in any
+    /// combination, as in `make_adder(1)(2)` or `shapes[0].area()`. A field
+    /// access followed by a call calls a method.
+    fn postfix(&mut self, mut expression: Expression) -> Result<Expression, ParseError> {
+        loop {
+            expression = if self.nibble(Token::Dot) {
+                Expression::Get {
+                    object: Box::new(expression),
+                    name: eat_identifier!(self),
+                    line: self.line,
+                    column: self.column,
+                }
+            } else if self.nibble(Token::LParen) {
+                self.arguments(expression)?
+            } else if self.nibble(Token::LBracket) {
+                let index = self.nested()?;
+                eat!(self, Token::RBracket);
+                Expression::Index {
+                    object: Box::new(expression),
+                    index: Box::new(index),
+                    line: self.line,
+                    column: self.column,
+                }
+            } else {
+                return Ok(expression);
+            };
+        }
+    }
+
+    /// The arguments of a call of `callee`, after the `(`.
+    fn arguments(&mut self, callee: Expression) -> Result<Expression, ParseError> {
+        let mut args: Vec<Expression> = vec![];
+        let mut named: Vec<(String, Expression)> = vec![];
 
-            if !self.is(Token::RParen) {
-                args.push(self.expression()?);
-                while self.nibble(Token::Comma) {
-                    args.push(self.expression()?);
+        if !self.is(Token::RParen) {
+            loop {
+                let arg = self.nested()?;
+                match arg {
+                    Expression::Identifier { ident, .. } if self.nibble(Token::Colon) => {
+                        if named.iter().any(|(name, _)| *name == ident) {
+                            return Err(ParseError {
+                                message: format!("argument '{}' is given more than once", ident),
+                                line: self.line,
+                                column: self.column,
+                            });
+                        }
+                        named.push((ident, self.nested()?));
+                    }
+                    _ if !named.is_empty() => {
+                        return Err(ParseError {
+                            message: "positional arguments must come before named ones".to_string(),
+                            line: self.line,
+                            column: self.column,
+                        })
+                    }
+                    _ => args.push(arg),
+                }
+                if !self.nibble(Token::Comma) {
+                    break;
                 }
             }
-
-            eat!(self, Token::RParen);
-            return Ok(Expression::Call {
-                function: Box::new(callee),
-                arguments: args,
-                line: self.line,
-                column: self.column,
-            });
         }
 
-        Ok(callee)
+        eat!(self, Token::RParen);
+        Ok(Expression::Call {
+            function: Box::new(callee),
+            arguments: args,
+            named,
+            line: self.line,
+            column: self.column,
+        })
+    }
+
+    fn list(&mut self) -> Result<Expression, ParseError> {
+        eat!(self, Token::LBracket);
+        let mut elements: Vec<Expression> = vec![];
+        if !self.is(Token::RBracket) {
+            elements.push(self.nested()?);
+            while self.nibble(Token::Comma) {
+                if self.is(Token::RBracket) {
+                    break;
+                }
+                elements.push(self.nested()?);
+            }
+        }
+        eat!(self, Token::RBracket);
+        Ok(Expression::List {
+            elements,
+            line: self.line,
+            column: self.column,
+        })
     }
 
     fn primary(&mut self) -> Result<Expression, ParseError> {
         if self.nibble(Token::LParen) {
-            let expr = self.expression()?;
+            // `()`, names separated by commas and `...name` can only be the
+            // parameters of an arrow function, and so is a single name or
+            // `name = default` followed by `=>`.
+            if self.is(Token::RParen) || self.is(Token::Ellipsis) {
+                let parameters = self.parameters(Parameters::default())?;
+                return self.arrow_function(parameters);
+            }
+            let expr = self.nested()?;
+            let parameter = match &expr {
+                Expression::Identifier { ident, .. } => Some((ident.clone(), None)),
+                Expression::Assign { name, value, .. } => {
+                    Some((name.clone(), Some((**value).clone())))
+                }
+                _ => None,
+            };
+            if let Some((name, default)) = parameter {
+                if self.nibble(Token::Comma) {
+                    let mut parameters = Parameters::default();
+                    self.parameter(&mut parameters, name, default)?;
+                    let parameters = self.parameters(parameters)?;
+                    return self.arrow_function(parameters);
+                }
+                eat!(self, Token::RParen);
+                if self.arrow_functions && self.is(Token::FatArrow) {
+                    let mut parameters = Parameters::default();
+                    self.parameter(&mut parameters, name, default)?;
+                    return self.arrow_function(parameters);
+                }
+                return Ok(expr);
+            }
             eat!(self, Token::RParen);
             return Ok(expr);
         }
 
+        if self.is(Token::Function) {
+            return self.function_expression();
+        }
+
+        if self.is(Token::LBracket) {
+            return self.list();
+        }
+
+        if self.struct_literals && self.is(Token::LBrace) {
+            return self.block_or_map();
+        }
+
+        if self.nibble(Token::Super) {
+            eat!(self, Token::Dot);
+            return Ok(Expression::Super {
+                method: eat_identifier!(self),
+                slot: None,
+                line: self.line,
+                column: self.column,
+            });
+        }
+
         if let Token::Ident(ident) = self.lookahead.clone() {
             self.lookahead = self.next_token();
+            if self.arrow_functions && self.is(Token::FatArrow) {
+                let parameters = Parameters {
+                    names: vec![ident],
+                    ..Parameters::default()
+                };
+                return self.arrow_function(parameters);
+            }
+            if self.struct_literals && self.is(Token::LBrace) {
+                return self.construct(ident);
+            }
             return Ok(Expression::Identifier {
                 ident,
+                slot: None,
                 line: self.line,
                 column: self.column,
             });
@@ -456,6 +1346,11 @@ impl Parser {
                 line: self.line,
                 column: self.column,
             }),
+            Token::BigInt(i) => Ok(Expression::Value {
+                value: Value::BigInt(i),
+                line: self.line,
+                column: self.column,
+            }),
             Token::Float(f) => Ok(Expression::Value {
                 value: Value::Float(f),
                 line: self.line,
@@ -481,3 +1376,150 @@ impl Parser {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Vec<Expression> {
+        Parser::new(input.to_string())
+            .parse_program()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn error(input: &str) -> ParseError {
+        match Parser::new(input.to_string()).parse_program() {
+            Ok(program) => panic!("parsed {:?}", program),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        let program = parse("1 + 2 * 3;");
+        let Expression::Binary {
+            operator, right, ..
+        } = &program[0]
+        else {
+            panic!("expected a binary expression, got {:?}", program[0]);
+        };
+        assert_eq!(*operator, Token::Plus);
+        assert!(matches!(
+            **right,
+            Expression::Binary {
+                operator: Token::Asterisk,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn default_and_rest_parameters() {
+        let program = parse("fn f(a, b = 1, ...c) { a }");
+        let Expression::Function {
+            parameters,
+            defaults,
+            rest,
+            ..
+        } = &program[0]
+        else {
+            panic!("expected a function, got {:?}", program[0]);
+        };
+        assert_eq!(parameters, &["a", "b"]);
+        assert_eq!(defaults.len(), 1);
+        assert_eq!(rest.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn named_arguments_follow_the_others() {
+        let program = parse("f(1, by: 2);");
+        let Expression::Call {
+            arguments, named, ..
+        } = &program[0]
+        else {
+            panic!("expected a call, got {:?}", program[0]);
+        };
+        assert_eq!(arguments.len(), 1);
+        assert_eq!(named[0].0, "by");
+    }
+
+    #[test]
+    fn map_literals_are_operands() {
+        let program = parse(r#"x == {"a": 1}; ({"a": 1})["a"]; f({"a": 1} == m); {"a": 1} == m;"#);
+        let map = |expression: &Expression| matches!(expression, Expression::Map { .. });
+        let Expression::Binary { right, .. } = &program[0] else {
+            panic!("expected a comparison, got {:?}", program[0]);
+        };
+        assert!(map(right));
+        let Expression::Index { object, .. } = &program[1] else {
+            panic!("expected indexing, got {:?}", program[1]);
+        };
+        assert!(map(object));
+        let Expression::Call { arguments, .. } = &program[2] else {
+            panic!("expected a call, got {:?}", program[2]);
+        };
+        assert!(matches!(&arguments[0], Expression::Binary { left, .. } if map(left)));
+        assert!(matches!(&program[3], Expression::Binary { left, .. } if map(left)));
+
+        // In the head of an `if`, the brace opens the body.
+        let program = parse("if a == b { 1 }");
+        assert!(matches!(
+            &program[0],
+            Expression::If { consequence, .. } if matches!(**consequence, Expression::Block { .. })
+        ));
+    }
+
+    #[test]
+    fn maps_ifs_and_matches_take_postfix_operators() {
+        let index = |expression: &Expression| match expression {
+            Expression::Index { object, .. } => (**object).clone(),
+            _ => panic!("expected indexing, got {:?}", expression),
+        };
+        let program = parse(r#"{"a": [1]}["a"][0];"#);
+        assert!(matches!(index(&index(&program[0])), Expression::Map { .. }));
+
+        let program = parse("if c { a } else { b }[0];");
+        assert!(matches!(index(&program[0]), Expression::If { .. }));
+
+        let program = parse("if c { f } else { g }(1).x;");
+        let Expression::Get { object, .. } = &program[0] else {
+            panic!("expected a field access, got {:?}", program[0]);
+        };
+        assert!(matches!(
+            &**object,
+            Expression::Call { function, .. } if matches!(**function, Expression::If { .. })
+        ));
+
+        let program = parse("enum E { A, B } match E.A { E.A => [1], E.B => [2] }[0];");
+        assert!(matches!(index(&program[1]), Expression::Match { .. }));
+    }
+
+    #[test]
+    fn parameter_errors() {
+        assert_eq!(
+            error("fn f(...a, b) { a }").message,
+            "rest parameter must be last"
+        );
+        assert_eq!(
+            error("fn f(a = 1, b) { a }").message,
+            "parameter 'b' must have a default value"
+        );
+    }
+
+    #[test]
+    fn break_and_continue_must_be_in_a_loop() {
+        assert_eq!(error("break;").message, "'break' outside of a loop");
+        assert_eq!(
+            error("fn f() { continue; }").message,
+            "'continue' outside of a loop"
+        );
+        parse("while true { break; }");
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let e = error("let x = ;");
+        assert_eq!(e.line, 1);
+        assert!(e.column > 0);
+    }
+}
@@ -1,21 +1,41 @@
 use std::io::stdin;
 
-use crate::{interpreter::Interpreter, parser::Parser};
+use crate::{interpreter::Interpreter, parser::Parser, resolver::Resolver};
 
 pub fn repl() {
     let mut interpreter = Interpreter::new();
+    let mut resolver = Resolver::repl();
 
     loop {
         let mut input = String::new();
-        stdin().read_line(&mut input).expect("Failed to read line");
+        // Nothing is read at the end of the input.
+        if stdin().read_line(&mut input).expect("Failed to read line") == 0 {
+            break;
+        }
+        if input.trim().is_empty() {
+            continue;
+        }
         let mut parser = Parser::new(input);
-        let ast = parser.expression();
-        match ast {
-            Ok(ast) => match interpreter.evaluate_expression(ast) {
-                Ok(value) => println!("{}", value),
-                Err(e) => eprintln!("runtime error: {}", e),
-            },
-            Err(e) => eprintln!("Error: {}", e),
+        let mut ast = match parser.expression() {
+            Ok(ast) => ast,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        // A line that fails declares nothing, so later ones cannot use
+        // variables it never defined.
+        let before = resolver.clone();
+        if let Err(e) = resolver.resolve(std::slice::from_mut(&mut ast)) {
+            eprintln!("Error: {}", e);
+            continue;
+        }
+        match interpreter.evaluate_expression(ast) {
+            Ok(value) => println!("{}", value),
+            Err(e) => {
+                eprintln!("runtime error: {}", e);
+                resolver = before;
+            }
         }
     }
 }
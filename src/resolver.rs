@@ -0,0 +1,825 @@
+//! Works out where every variable of a program lives before it runs, so the
+//! interpreter can find it by index instead of looking its name up scope by
+//! scope.
+//!
+//! The resolver mirrors the scopes the interpreter makes: the builtins, the
+//! top level, each block, each `for` iteration, each match arm, each call
+//! and, around the parameters of a method, a scope holding the receiver and
+//! `super`. The declarations of a scope are gathered before any of it is
+//! resolved, so functions can use variables declared after them, while
+//! other code can only use those declared before it.
+
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    ast::{Expression, MatchArm, Pattern, Slot},
+    interpreter::builtins,
+};
+
+pub struct ResolveError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Resolve error at line {} column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+fn error(message: String, (line, column): (usize, usize)) -> ResolveError {
+    ResolveError {
+        message,
+        line,
+        column,
+    }
+}
+
+#[derive(Clone)]
+struct Variable {
+    index: usize,
+    /// Whether the resolver has passed the declaration.
+    declared: bool,
+}
+
+#[derive(Clone)]
+struct Scope {
+    variables: HashMap<String, Variable>,
+    /// How many functions deep the scope is.
+    function: usize,
+}
+
+/// The parameters of a function being resolved.
+struct Parameters<'a> {
+    names: &'a [String],
+    /// The default values of the last names.
+    defaults: &'a mut [Expression],
+    rest: Option<&'a str>,
+}
+
+#[derive(Clone)]
+pub struct Resolver {
+    /// The builtins, the top level and the scopes around the code being
+    /// resolved.
+    scopes: Vec<Scope>,
+    /// How many functions deep the code being resolved is.
+    functions: usize,
+    /// Whether a top-level declaration may take the name of an earlier one,
+    /// as lines of the REPL do.
+    redeclare: bool,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        let builtins = builtins()
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, _))| {
+                let variable = Variable {
+                    index,
+                    declared: true,
+                };
+                (name.to_string(), variable)
+            })
+            .collect();
+        Self {
+            scopes: vec![
+                Scope {
+                    variables: builtins,
+                    function: 0,
+                },
+                Scope {
+                    variables: HashMap::new(),
+                    function: 0,
+                },
+            ],
+            functions: 0,
+            redeclare: false,
+        }
+    }
+
+    /// A resolver for the REPL, where a line can declare a top-level name
+    /// again, replacing the variable rather than adding one.
+    pub fn repl() -> Self {
+        Self {
+            redeclare: true,
+            ..Self::new()
+        }
+    }
+
+    /// Resolves top-level code. The top-level scope is kept, so the REPL can
+    /// resolve each line with the variables of the ones before it.
+    pub fn resolve(&mut self, program: &mut [Expression]) -> Result<(), ResolveError> {
+        let globals = self.scopes[1].clone();
+        let result = self
+            .hoist_all(program)
+            .and_then(|()| self.expressions(program));
+        if result.is_err() {
+            self.scopes.truncate(2);
+            self.scopes[1] = globals;
+            self.functions = 0;
+        }
+        result
+    }
+
+    fn begin(&mut self) {
+        self.scopes.push(Scope {
+            variables: HashMap::new(),
+            function: self.functions,
+        });
+    }
+
+    fn end(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Gives `name` the next slot of the innermost scope.
+    fn declare(
+        &mut self,
+        name: &str,
+        declared: bool,
+        position: (usize, usize),
+    ) -> Result<(), ResolveError> {
+        let top_level = self.scopes.len() == 2;
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        if top_level && self.redeclare && scope.variables.contains_key(name) {
+            return Ok(());
+        }
+        if scope.variables.contains_key(name) {
+            return Err(error(
+                format!("'{}' is already declared in this scope", name),
+                position,
+            ));
+        }
+        let index = scope.variables.len();
+        scope
+            .variables
+            .insert(name.to_string(), Variable { index, declared });
+        Ok(())
+    }
+
+    /// Marks the declaration of `name` in the innermost scope as passed,
+    /// giving its slot.
+    fn define(&mut self, name: &str) -> usize {
+        let variable = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.variables.get_mut(name))
+            .expect("declarations are hoisted");
+        variable.declared = true;
+        variable.index
+    }
+
+    /// Finds the variable `name` refers to. A declaration that has not been
+    /// passed is skipped, unless the code is in a function which only runs
+    /// later.
+    fn lookup(&self, name: &str, position: (usize, usize)) -> Result<Slot, ResolveError> {
+        let mut early = false;
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(variable) = scope.variables.get(name) {
+                if variable.declared || scope.function < self.functions {
+                    return Ok(Slot {
+                        depth,
+                        index: variable.index,
+                    });
+                }
+                early = true;
+            }
+        }
+        let message = if early {
+            format!("'{}' is used before it is declared", name)
+        } else {
+            format!("undefined variable '{}'", name)
+        };
+        Err(error(message, position))
+    }
+
+    fn hoist_all(&mut self, expressions: &[Expression]) -> Result<(), ResolveError> {
+        for expression in expressions {
+            self.hoist(expression)?;
+        }
+        Ok(())
+    }
+
+    /// Declares the variables `expression` adds to the scope it runs in.
+    fn hoist(&mut self, expression: &Expression) -> Result<(), ResolveError> {
+        let mut found = vec![];
+        declarations(expression, &mut found);
+        for (name, position) in found {
+            self.declare(name, false, position)?;
+        }
+        Ok(())
+    }
+
+    fn expressions(&mut self, expressions: &mut [Expression]) -> Result<(), ResolveError> {
+        for expression in expressions {
+            self.expression(expression)?;
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &mut Expression) -> Result<(), ResolveError> {
+        match expression {
+            Expression::Identifier {
+                ident,
+                slot,
+                line,
+                column,
+            } => *slot = Some(self.lookup(ident, (*line, *column))?),
+            Expression::Assign {
+                name,
+                slot,
+                value,
+                line,
+                column,
+            } => {
+                self.expression(value)?;
+                *slot = Some(self.lookup(name, (*line, *column))?);
+            }
+            Expression::Let {
+                name, slot, value, ..
+            } => {
+                self.expression(value)?;
+                *slot = Some(self.define(name));
+            }
+            Expression::Return {
+                value,
+                line,
+                column,
+            } => {
+                if self.functions == 0 {
+                    return Err(error(
+                        "cannot return from top-level code".to_string(),
+                        (*line, *column),
+                    ));
+                }
+                self.expression(value)?;
+            }
+            Expression::Block { expressions, .. } => {
+                self.begin();
+                self.hoist_all(expressions)?;
+                self.expressions(expressions)?;
+                self.end();
+            }
+            Expression::Function {
+                name,
+                slot,
+                parameters,
+                defaults,
+                rest,
+                body,
+                line,
+                column,
+            } => {
+                *slot = Some(self.define(name));
+                let parameters = Parameters {
+                    names: parameters,
+                    defaults,
+                    rest: rest.as_deref(),
+                };
+                self.function(None, parameters, body, (*line, *column))?;
+            }
+            Expression::Lambda {
+                parameters,
+                defaults,
+                rest,
+                body,
+                line,
+                column,
+            } => {
+                let parameters = Parameters {
+                    names: parameters,
+                    defaults,
+                    rest: rest.as_deref(),
+                };
+                self.function(None, parameters, body, (*line, *column))?
+            }
+            Expression::For {
+                ident,
+                value_ident,
+                expr,
+                body,
+                line,
+                column,
+            } => {
+                self.expression(expr)?;
+                self.begin();
+                self.declare(ident, true, (*line, *column))?;
+                if let Some(value_ident) = value_ident {
+                    self.declare(value_ident, true, (*line, *column))?;
+                }
+                self.hoist(body)?;
+                self.expression(body)?;
+                self.end();
+            }
+            Expression::Struct {
+                name,
+                slot,
+                methods,
+                ..
+            } => {
+                *slot = Some(self.define(name));
+                self.methods(methods, "self", false)?;
+            }
+            Expression::Class {
+                name,
+                slot,
+                superclass,
+                superclass_slot,
+                methods,
+                line,
+                column,
+            } => {
+                if let Some(superclass) = superclass {
+                    *superclass_slot = Some(self.lookup(superclass, (*line, *column))?);
+                }
+                *slot = Some(self.define(name));
+                self.methods(methods, "this", superclass.is_some())?;
+            }
+            Expression::Super {
+                slot, line, column, ..
+            } => {
+                let position = (*line, *column);
+                *slot = Some(self.lookup("super", position).map_err(|_| {
+                    error(
+                        "Can't use 'super' outside of a method of a subclass".to_string(),
+                        position,
+                    )
+                })?);
+            }
+            Expression::Construct {
+                name,
+                slot,
+                fields,
+                line,
+                column,
+            } => {
+                *slot = Some(self.lookup(name, (*line, *column))?);
+                for (_, value) in fields {
+                    self.expression(value)?;
+                }
+            }
+            Expression::Enum { name, slot, .. } => *slot = Some(self.define(name)),
+            Expression::Match {
+                value,
+                arms,
+                line,
+                column,
+            } => {
+                self.expression(value)?;
+                for arm in arms {
+                    self.arm(arm, (*line, *column))?;
+                }
+            }
+            Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+                self.expression(left)?;
+                self.expression(right)?;
+            }
+            Expression::Unary { right: value, .. }
+            | Expression::Break { value, .. }
+            | Expression::Get { object: value, .. } => self.expression(value)?,
+            Expression::Call {
+                function,
+                arguments,
+                named,
+                ..
+            } => {
+                self.expression(function)?;
+                self.expressions(arguments)?;
+                for (_, argument) in named {
+                    self.expression(argument)?;
+                }
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => {
+                self.expression(condition)?;
+                self.expression(consequence)?;
+                if let Some(alternative) = alternative {
+                    self.expression(alternative)?;
+                }
+            }
+            Expression::While {
+                condition, body, ..
+            } => {
+                self.expression(condition)?;
+                self.expression(body)?;
+            }
+            Expression::Range {
+                start, end, step, ..
+            } => {
+                self.expression(start)?;
+                self.expression(end)?;
+                if let Some(step) = step {
+                    self.expression(step)?;
+                }
+            }
+            Expression::List { elements, .. } => self.expressions(elements)?,
+            Expression::Index { object, index, .. } => {
+                self.expression(object)?;
+                self.expression(index)?;
+            }
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+            }
+            Expression::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+            }
+            Expression::Set { object, value, .. } => {
+                self.expression(object)?;
+                self.expression(value)?;
+            }
+            Expression::Value { .. } | Expression::Continue { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Resolves a function body in a scope holding its parameters. A method
+    /// has another scope around that one, holding its receiver and, in a
+    /// subclass, `super`.
+    fn function(
+        &mut self,
+        receiver: Option<(&str, bool)>,
+        parameters: Parameters,
+        body: &mut Expression,
+        position: (usize, usize),
+    ) -> Result<(), ResolveError> {
+        self.functions += 1;
+        if let Some((receiver, subclass)) = receiver {
+            self.begin();
+            self.declare(receiver, true, position)?;
+            if subclass {
+                // `super` is a keyword, so no variable can take this name.
+                self.declare("super", true, position)?;
+            }
+        }
+        self.begin();
+        // The rest parameter follows the others, and each default value can
+        // only use the parameters before it.
+        for name in parameters.names {
+            self.declare(name, false, position)?;
+        }
+        if let Some(rest) = parameters.rest {
+            self.declare(rest, false, position)?;
+        }
+        self.hoist_all(parameters.defaults)?;
+        self.hoist(body)?;
+        let first_default = parameters.names.len() - parameters.defaults.len();
+        for (index, name) in parameters.names.iter().enumerate() {
+            if index >= first_default {
+                self.expression(&mut parameters.defaults[index - first_default])?;
+            }
+            self.define(name);
+        }
+        if let Some(rest) = parameters.rest {
+            self.define(rest);
+        }
+        self.expression(body)?;
+        self.end();
+        if receiver.is_some() {
+            self.end();
+        }
+        self.functions -= 1;
+        Ok(())
+    }
+
+    /// Resolves the methods of a struct, whose receiver is `self`, or of a
+    /// class, whose receiver is `this`.
+    fn methods(
+        &mut self,
+        methods: &mut [Expression],
+        receiver: &str,
+        subclass: bool,
+    ) -> Result<(), ResolveError> {
+        for method in methods {
+            if let Expression::Function {
+                parameters,
+                defaults,
+                rest,
+                body,
+                line,
+                column,
+                ..
+            } = method
+            {
+                let parameters = Parameters {
+                    names: parameters,
+                    defaults,
+                    rest: rest.as_deref(),
+                };
+                self.function(
+                    Some((receiver, subclass)),
+                    parameters,
+                    body,
+                    (*line, *column),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves a match arm in a scope which starts with the names its
+    /// pattern binds.
+    fn arm(&mut self, arm: &mut MatchArm, position: (usize, usize)) -> Result<(), ResolveError> {
+        self.begin();
+        let mut bindings = vec![];
+        self.pattern(&mut arm.pattern, &mut bindings, position)?;
+        for binding in bindings {
+            self.declare(&binding, true, position)?;
+        }
+        if let Some(guard) = &arm.guard {
+            self.hoist(guard)?;
+        }
+        self.hoist(&arm.body)?;
+        if let Some(guard) = &mut arm.guard {
+            self.expression(guard)?;
+        }
+        self.expression(&mut arm.body)?;
+        self.end();
+        Ok(())
+    }
+
+    /// Resolves the enums in a pattern, collecting the names it binds.
+    fn pattern(
+        &self,
+        pattern: &mut Pattern,
+        bindings: &mut Vec<String>,
+        position: (usize, usize),
+    ) -> Result<(), ResolveError> {
+        match pattern {
+            Pattern::Wildcard | Pattern::Value(_) => {}
+            Pattern::Binding(name) => bindings.push(name.clone()),
+            Pattern::Variant {
+                enumeration,
+                slot,
+                fields,
+                ..
+            } => {
+                *slot = Some(self.lookup(enumeration, position)?);
+                for field in fields {
+                    self.pattern(field, bindings, position)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the variables `expression` adds to the scope it runs in, in the
+/// order they take their slots there, with where each is declared.
+pub fn declarations<'a>(expression: &'a Expression, found: &mut Vec<(&'a str, (usize, usize))>) {
+    match expression {
+        Expression::Let {
+            name,
+            value,
+            line,
+            column,
+            ..
+        } => {
+            declarations(value, found);
+            found.push((name, (*line, *column)));
+        }
+        Expression::Function {
+            name, line, column, ..
+        }
+        | Expression::Struct {
+            name, line, column, ..
+        }
+        | Expression::Class {
+            name, line, column, ..
+        }
+        | Expression::Enum {
+            name, line, column, ..
+        } => found.push((name, (*line, *column))),
+        // These run in scopes of their own.
+        Expression::Block { .. } | Expression::Lambda { .. } => {}
+        Expression::For { expr, .. } => declarations(expr, found),
+        Expression::Match { value, .. } => declarations(value, found),
+        Expression::Binary { left, right, .. }
+        | Expression::Logical { left, right, .. }
+        | Expression::Index {
+            object: left,
+            index: right,
+            ..
+        }
+        | Expression::Set {
+            object: left,
+            value: right,
+            ..
+        } => {
+            declarations(left, found);
+            declarations(right, found);
+        }
+        Expression::Unary { right: value, .. }
+        | Expression::Assign { value, .. }
+        | Expression::Return { value, .. }
+        | Expression::Break { value, .. }
+        | Expression::Get { object: value, .. } => declarations(value, found),
+        Expression::Call {
+            function,
+            arguments,
+            named,
+            ..
+        } => {
+            declarations(function, found);
+            for argument in arguments.iter().chain(named.iter().map(|(_, a)| a)) {
+                declarations(argument, found);
+            }
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            declarations(condition, found);
+            declarations(consequence, found);
+            if let Some(alternative) = alternative {
+                declarations(alternative, found);
+            }
+        }
+        Expression::While {
+            condition, body, ..
+        } => {
+            declarations(condition, found);
+            declarations(body, found);
+        }
+        Expression::Range {
+            start, end, step, ..
+        } => {
+            declarations(start, found);
+            declarations(end, found);
+            if let Some(step) = step {
+                declarations(step, found);
+            }
+        }
+        Expression::List { elements, .. } => {
+            for element in elements {
+                declarations(element, found);
+            }
+        }
+        Expression::SetIndex {
+            object,
+            index,
+            value,
+            ..
+        } => {
+            declarations(object, found);
+            declarations(index, found);
+            declarations(value, found);
+        }
+        Expression::Map { entries, .. } => {
+            for (key, value) in entries {
+                declarations(key, found);
+                declarations(value, found);
+            }
+        }
+        Expression::Construct { fields, .. } => {
+            for (_, value) in fields {
+                declarations(value, found);
+            }
+        }
+        Expression::Identifier { .. }
+        | Expression::Value { .. }
+        | Expression::Continue { .. }
+        | Expression::Super { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn try_resolve(input: &str) -> Result<Vec<Expression>, ResolveError> {
+        let mut program = Parser::new(input.to_string())
+            .parse_program()
+            .unwrap_or_else(|e| panic!("{}", e));
+        Resolver::new().resolve(&mut program)?;
+        Ok(program)
+    }
+
+    fn resolve(input: &str) -> Vec<Expression> {
+        try_resolve(input).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn error(input: &str) -> String {
+        match try_resolve(input) {
+            Ok(program) => panic!("resolved {:?}", program),
+            Err(e) => e.message,
+        }
+    }
+
+    /// The slot of the identifier `expression` evaluates, looking through
+    /// a `print` call around it.
+    fn slot(expression: &Expression) -> Slot {
+        match expression {
+            Expression::Identifier { slot, .. } => slot.expect("identifiers are resolved"),
+            Expression::Call { arguments, .. } => slot(&arguments[0]),
+            _ => panic!("expected an identifier, got {:?}", expression),
+        }
+    }
+
+    #[test]
+    fn variables_are_found_by_depth_and_index() {
+        let program = resolve("let a = 1; let b = 2; b;");
+        assert_eq!(slot(&program[2]), Slot { depth: 0, index: 1 });
+
+        let program = resolve("let a = 1; if true { let b = 2; print(a); }");
+        let Expression::If { consequence, .. } = &program[1] else {
+            panic!("expected an if, got {:?}", program[1]);
+        };
+        let Expression::Block { expressions, .. } = &**consequence else {
+            panic!("expected a block, got {:?}", consequence);
+        };
+        assert_eq!(slot(&expressions[1]), Slot { depth: 1, index: 0 });
+    }
+
+    #[test]
+    fn builtins_are_outside_the_top_level() {
+        let program = resolve("print;");
+        let index = builtins()
+            .iter()
+            .position(|(name, _)| *name == "print")
+            .unwrap();
+        assert_eq!(slot(&program[0]), Slot { depth: 1, index });
+    }
+
+    #[test]
+    fn functions_can_use_later_declarations() {
+        resolve("fn f() { g() } fn g() { 1 }");
+        assert_eq!(
+            error("print(x); let x = 1;"),
+            "'x' is used before it is declared"
+        );
+        assert_eq!(error("print(y);"), "undefined variable 'y'");
+    }
+
+    #[test]
+    fn defaults_can_only_use_earlier_parameters() {
+        resolve("fn f(a, b = a) { b }");
+        assert_eq!(
+            error("fn f(a = b, b = 1) { a }"),
+            "'b' is used before it is declared"
+        );
+    }
+
+    #[test]
+    fn redeclaring_in_the_same_scope_is_an_error() {
+        assert_eq!(
+            error("fn f(a, a) { a }"),
+            "'a' is already declared in this scope"
+        );
+    }
+
+    #[test]
+    fn errors_leave_the_top_level_as_it_was() {
+        let mut resolver = Resolver::new();
+        let mut parse = |input: &str| {
+            let mut program = Parser::new(input.to_string()).parse_program().ok()?;
+            resolver.resolve(&mut program).ok()
+        };
+        assert!(parse("let a = 1;").is_some());
+        assert!(parse("let b = 2; c;").is_none());
+        assert!(parse("b;").is_none());
+        assert!(parse("a;").is_some());
+    }
+
+    #[test]
+    fn the_repl_can_declare_top_level_names_again() {
+        let mut resolver = Resolver::repl();
+        let mut parse = |input: &str| {
+            let mut program = Parser::new(input.to_string()).parse_program().ok()?;
+            resolver.resolve(&mut program).ok()?;
+            Some(program)
+        };
+        let first = parse("let a = 1; fn f() { a } a; f;").unwrap();
+        let again = parse("let a = a + 1; fn f() { 2 } a; f;").unwrap();
+        assert_eq!(slot(&first[2]), slot(&again[2]));
+        assert_eq!(slot(&first[3]), slot(&again[3]));
+        assert!(parse("{ let b = 1; let b = 2; }").is_none());
+    }
+
+    #[test]
+    fn return_is_only_allowed_in_functions() {
+        assert_eq!(error("return 1;"), "cannot return from top-level code");
+    }
+}
@@ -1,9 +1,13 @@
 use core::fmt;
 
+use num_bigint::BigInt;
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum Token {
     Ident(String),
     Int(i64),
+    /// An integer literal too large for an `i64`.
+    BigInt(BigInt),
     Float(f64),
     String(String),
     Illegal(String),
@@ -14,6 +18,7 @@ pub enum Token {
     Minus,
     Bang,
     Asterisk,
+    Percent,
     Slash,
     Pow,
     LessThan,
@@ -22,12 +27,26 @@ pub enum Token {
     GreaterThanEqual,
     EqEq,
     BangEq,
+    And,
+    Or,
     Comma,
+    Colon,
+    /// `=>`, between a pattern and its result in a `match`.
+    FatArrow,
+    /// `_`, the pattern that matches anything.
+    Underscore,
     Semicolon,
     LParen,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    Dot,
+    DotDot,
+    DotDotEq,
+    /// `...`, before the parameter that collects the rest of the arguments.
+    Ellipsis,
 
     Function,
     Let,
@@ -38,6 +57,14 @@ pub enum Token {
     Return,
     For,
     In,
+    While,
+    Break,
+    Continue,
+    Struct,
+    Class,
+    Super,
+    Enum,
+    Match,
 }
 
 impl fmt::Display for Token {
@@ -1,89 +1,2529 @@
-use crate::parser::Parser;
-use crate::ast::Expression;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::mem;
+
+use crate::ast::{Expression, MatchArm, Pattern, Value};
+use crate::parser::{ParseError, Parser};
+use crate::resolver::{ResolveError, Resolver};
 use crate::token::Token;
 
+mod c;
+mod javascript;
+mod python;
+
+pub struct TranspileError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl TranspileError {
+    fn new(message: String, (line, column): (usize, usize)) -> Self {
+        Self {
+            message,
+            line,
+            column,
+        }
+    }
+}
+
+impl Display for TranspileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Transpile error at line {} column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl From<ParseError> for TranspileError {
+    fn from(error: ParseError) -> Self {
+        Self {
+            message: error.message,
+            line: error.line,
+            column: error.column,
+        }
+    }
+}
+
+impl From<ResolveError> for TranspileError {
+    fn from(error: ResolveError) -> Self {
+        Self {
+            message: error.message,
+            line: error.line,
+            column: error.column,
+        }
+    }
+}
+
+/// Everything a backend needs to assemble the final source file.
+pub struct Output {
+    /// Top-level variables, for backends that hoist functions.
+    pub globals: Vec<String>,
+    /// Names and parameters of hoisted functions.
+    pub functions: Vec<(String, Vec<String>)>,
+    /// Lines of the hoisted function definitions.
+    pub definitions: Vec<String>,
+    /// Lines of the program itself.
+    pub body: Vec<String>,
+    /// Whether the program declares its own top-level `print`.
+    pub defines_print: bool,
+}
+
+/// Whether the value of an expression is known to be an integer or a float,
+/// for targets whose numbers don't tell them apart.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Number {
+    Int,
+    Float,
+    Unknown,
+}
+
+/// Calls `visit` on each expression directly inside `expression`, along with
+/// whether it is evaluated in a function `expression` makes.
+fn children(expression: &Expression, mut visit: impl FnMut(&Expression, bool)) {
+    match expression {
+        // Default values are evaluated when the function is called.
+        Expression::Function { defaults, body, .. } | Expression::Lambda { defaults, body, .. } => {
+            for default in defaults {
+                visit(default, true);
+            }
+            visit(body, true);
+        }
+        Expression::Struct { methods, .. } | Expression::Class { methods, .. } => {
+            for method in methods {
+                visit(method, true);
+            }
+        }
+        Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+            visit(left, false);
+            visit(right, false);
+        }
+        Expression::Unary { right: value, .. }
+        | Expression::Let { value, .. }
+        | Expression::Assign { value, .. }
+        | Expression::Return { value, .. }
+        | Expression::Break { value, .. }
+        | Expression::Get { object: value, .. } => visit(value, false),
+        Expression::Call {
+            function,
+            arguments,
+            named,
+            ..
+        } => {
+            visit(function, false);
+            for argument in arguments {
+                visit(argument, false);
+            }
+            for (_, argument) in named {
+                visit(argument, false);
+            }
+        }
+        Expression::Block { expressions, .. }
+        | Expression::List {
+            elements: expressions,
+            ..
+        } => {
+            for expression in expressions {
+                visit(expression, false);
+            }
+        }
+        Expression::If {
+            condition,
+            consequence,
+            alternative,
+            ..
+        } => {
+            visit(condition, false);
+            visit(consequence, false);
+            if let Some(alternative) = alternative {
+                visit(alternative, false);
+            }
+        }
+        Expression::For { expr, body, .. } => {
+            visit(expr, false);
+            visit(body, false);
+        }
+        Expression::While {
+            condition, body, ..
+        } => {
+            visit(condition, false);
+            visit(body, false);
+        }
+        Expression::Range {
+            start, end, step, ..
+        } => {
+            visit(start, false);
+            visit(end, false);
+            if let Some(step) = step {
+                visit(step, false);
+            }
+        }
+        Expression::Index { object, index, .. } => {
+            visit(object, false);
+            visit(index, false);
+        }
+        Expression::SetIndex {
+            object,
+            index,
+            value,
+            ..
+        } => {
+            visit(object, false);
+            visit(index, false);
+            visit(value, false);
+        }
+        Expression::Map { entries, .. } => {
+            for (key, value) in entries {
+                visit(key, false);
+                visit(value, false);
+            }
+        }
+        Expression::Construct { fields, .. } => {
+            for (_, value) in fields {
+                visit(value, false);
+            }
+        }
+        Expression::Set { object, value, .. } => {
+            visit(object, false);
+            visit(value, false);
+        }
+        Expression::Match { value, arms, .. } => {
+            visit(value, false);
+            for arm in arms {
+                if let Some(guard) = &arm.guard {
+                    visit(guard, false);
+                }
+                visit(&arm.body, false);
+            }
+        }
+        Expression::Identifier { .. }
+        | Expression::Value { .. }
+        | Expression::Continue { .. }
+        | Expression::Super { .. }
+        | Expression::Enum { .. } => {}
+    }
+}
+
+/// The syntax of a target language. `Transpiler` lowers Kox's expression
+/// oriented AST into statements and asks the backend how to spell each one.
+pub trait Backend {
+    /// The language's name, for error messages.
+    fn name(&self) -> &'static str;
+
+    /// A Kox identifier, renamed if it clashes with the target language.
+    fn identifier(&self, ident: &str) -> String;
+
+    /// A top-level variable or function.
+    fn global(&self, ident: &str) -> String {
+        self.identifier(ident)
+    }
+
+    /// The target name of a builtin referenced but not defined by the program.
+    fn builtin(&self, ident: &str) -> Option<String> {
+        (ident == "print").then(|| ident.to_string())
+    }
+
+    fn value(&self, value: &Value) -> String;
+
+    /// How many bits integers hold exactly, not counting the sign, or
+    /// `None` if they have arbitrary precision. Only integer literals that
+    /// fit are passed to `value`.
+    fn integer_bits(&self) -> Option<u32> {
+        Some(64)
+    }
+
+    fn binary(&self, operator: &Token, left: &str, right: &str) -> String;
+
+    /// `/`, given what is known about whether each operand is an integer.
+    fn divide(&self, left: &str, right: &str, _numbers: [Number; 2]) -> String {
+        self.binary(&Token::Slash, left, right)
+    }
+
+    fn unary(&self, operator: &Token, right: &str) -> String;
+
+    /// `&&` or `||`, which must short-circuit.
+    fn logical(&self, operator: &Token, left: &str, right: &str) -> String;
+
+    /// An assignment used as an expression.
+    fn assign(&self, name: &str, value: &str) -> String;
+
+    /// An expression evaluated for its side effects.
+    fn statement(&self, expression: &str) -> String;
+
+    /// Declares a new variable.
+    fn declare(&self, name: &str, value: &str) -> String;
+
+    /// Declares a temporary that is assigned later, if the language needs it.
+    fn temporary(&self, name: &str) -> Option<String>;
+
+    fn if_header(&self, condition: &str) -> String;
+
+    fn else_header(&self) -> String;
+
+    /// `None` if the language has no loop over Kox iterables.
+    fn for_header(&self, ident: &str, iterable: &str) -> Option<String>;
+
+    /// A loop over the keys and values of a map, `None` if the language has
+    /// no such loop.
+    fn for_entries_header(&self, _key: &str, _value: &str, _iterable: &str) -> Option<String> {
+        None
+    }
+
+    fn while_header(&self, condition: &str) -> String;
+
+    fn function_header(&self, name: &str, parameters: &[String]) -> String;
+
+    /// A parameter with a default value, if the language evaluates it at
+    /// each call that leaves the parameter out, after binding the ones
+    /// before it.
+    fn default_parameter(&self, _name: &str, _default: &str) -> Option<String> {
+        None
+    }
+
+    /// A parameter that calls can leave out, whose default value is then
+    /// computed at the start of the body. `None` if the language has no
+    /// such parameters.
+    fn optional_parameter(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Tests whether a call left out an optional parameter.
+    fn missing(&self, _name: &str) -> String {
+        unreachable!("optional parameters are not supported")
+    }
+
+    /// The argument a call passes for an optional parameter it leaves out,
+    /// for backends that hoist functions and so pass every parameter.
+    fn missing_argument(&self) -> String {
+        unreachable!("calls leave out optional parameters")
+    }
+
+    /// A parameter taking any further arguments, `None` if the language has
+    /// no such parameter.
+    fn rest_parameter(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Makes a rest parameter a list at the start of the body, if the
+    /// language passes further arguments as something else.
+    fn rest_list(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Opens the class a struct is declared as, up to and including its
+    /// constructor, which takes the fields in order. Nested lines are
+    /// indented relative to the first. `None` if the language has no classes.
+    fn struct_header(
+        &self,
+        _name: &str,
+        _kox_name: &str,
+        _fields: &[String],
+    ) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Opens the class a Kox class is declared as, which calls the `init`
+    /// method with its arguments when constructed. `None` if the language has
+    /// no classes.
+    fn class_header(
+        &self,
+        _name: &str,
+        _kox_name: &str,
+        _superclass: Option<&str>,
+    ) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Opens a method inside a struct's or a class's class.
+    fn method_header(&self, _name: &str, _parameters: &[String]) -> String {
+        unreachable!("structs are not supported")
+    }
+
+    /// Binds `self` at the start of a method, if the language does not pass
+    /// it as a parameter. Kox's `this` in class methods is output as `self`
+    /// too.
+    fn receiver(&self) -> Option<String> {
+        None
+    }
+
+    /// Creates an instance of a struct from its fields in declared order, or
+    /// of a class from the arguments to its `init` method.
+    fn construct(&self, _name: &str, _fields: &[String]) -> Option<String> {
+        None
+    }
+
+    /// Reads a field of an instance, or a method bound to it.
+    fn get(&self, _object: &str, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Assigns a field of an instance, as an expression.
+    fn set(&self, _object: &str, _name: &str, _value: &str) -> Option<String> {
+        None
+    }
+
+    /// Declares an enum as a class whose variants are its values. Variants
+    /// are given by their Kox names, with the output names of their fields.
+    /// Those with fields are functions making a value of them, and the
+    /// others are values. `None` if the language has no classes.
+    fn enum_declaration(
+        &self,
+        _name: &str,
+        _kox_name: &str,
+        _variants: &[(String, Vec<String>)],
+    ) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Tests whether a value is the variant of an enum with the Kox name
+    /// `variant`.
+    fn is_variant(&self, _value: &str, _enumeration: &str, _variant: &str) -> String {
+        unreachable!("enums are not supported")
+    }
+
+    /// Reads a field of a variant by its position.
+    fn variant_field(&self, _value: &str, _index: usize) -> String {
+        unreachable!("enums are not supported")
+    }
+
+    /// `super.method` in a method of `class`, bound to `self`.
+    fn super_method(&self, _class: &str, _superclass: &str, _method: &str) -> Option<String> {
+        None
+    }
+
+    /// An integer range, `None` if the language can't iterate over one.
+    fn range(&self, _start: &str, _end: &str, _step: &str, _inclusive: bool) -> Option<String> {
+        None
+    }
+
+    /// A list literal, `None` if the language has no lists that are shared
+    /// by reference.
+    fn list(&self, _elements: &[String]) -> Option<String> {
+        None
+    }
+
+    /// A map literal, `None` if the language has no maps that are shared by
+    /// reference and keep their keys in the order they were added.
+    fn map(&self, _entries: &[(String, String)]) -> Option<String> {
+        None
+    }
+
+    /// Reads an element of a list, where negative indices count back from
+    /// the end, or the value of a key that must be in a map.
+    fn index(&self, _object: &str, _index: &str) -> Option<String> {
+        None
+    }
+
+    /// Assigns an element of a list or the value of a key in a map, as an
+    /// expression.
+    fn set_index(&self, _object: &str, _index: &str, _value: &str) -> Option<String> {
+        None
+    }
+
+    /// Opens a bare block, if the language has them.
+    fn open(&self) -> Option<String>;
+
+    /// Closes a block or a body started by one of the headers.
+    fn close(&self) -> Option<String>;
+
+    /// Placeholder for a body with no statements, if one is required.
+    fn empty(&self) -> Option<String> {
+        None
+    }
+
+    /// Declares that a function assigns a variable of an enclosing function
+    /// or a global, if the language needs it.
+    fn nonlocal(&self, _name: &str, _global: bool) -> Option<String> {
+        None
+    }
+
+    /// Whether operands are evaluated left to right.
+    fn ordered(&self) -> bool {
+        true
+    }
+
+    /// Whether functions are moved out of the program and declared up front.
+    /// Such languages have no closures, so functions must be defined at the
+    /// top level, can only be called by name and every name must resolve.
+    fn hoists_functions(&self) -> bool {
+        false
+    }
+
+    /// Whether closures see the variables they capture as they are when
+    /// called rather than when made, so that those made in a loop would all
+    /// see its last iteration. Variables declared in a loop that closures
+    /// capture are then kept in one-element lists, which each closure binds
+    /// when it is made through `captures`.
+    fn late_binding(&self) -> bool {
+        false
+    }
+
+    /// The parameters of a closure that also bind the cells it captures.
+    fn captures(&self, _parameters: &[String], _cells: &[String]) -> Vec<String> {
+        unreachable!("closures bind variables when they are made")
+    }
+
+    fn finish(&self, output: Output) -> String;
+}
+
+/// The backend for a `--target` name.
+pub fn backend(target: &str) -> Option<Box<dyn Backend>> {
+    match target {
+        "js" => Some(Box::new(javascript::JavaScript)),
+        "c" => Some(Box::new(c::C)),
+        "py" => Some(Box::new(python::Python)),
+        _ => None,
+    }
+}
+
+/// Escapes a string as a double-quoted literal, which JavaScript, C and
+/// Python all read the same way.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The parameters of a function or method as written in Kox.
+struct Parameters {
+    names: Vec<String>,
+    defaults: Vec<Expression>,
+    rest: Option<String>,
+}
+
+/// Where the value of a transpiled statement goes. Kox blocks and `if`s are
+/// expressions, so they are lowered to statements that either drop their
+/// value, return it, or store it in a temporary.
+enum Destination {
+    Discard,
+    Return,
+    Assign(String),
+}
+
+struct Scope {
+    /// Kox names declared in the scope and their names in the output.
+    names: HashMap<String, String>,
+    /// How many functions deep the scope is.
+    function: usize,
+    /// Fields of the structs declared in the scope.
+    structs: HashMap<String, Vec<String>>,
+    /// Classes declared in the scope, whose calls construct instances.
+    classes: Vec<String>,
+    /// Enums declared in the scope.
+    enums: Vec<String>,
+    /// Variables declared in the scope that are kept in cells.
+    cells: Vec<String>,
+    /// Variables declared in the scope that always hold an integer or
+    /// always a float.
+    numbers: HashMap<String, Number>,
+}
+
+impl Scope {
+    fn new(function: usize) -> Self {
+        Self {
+            names: HashMap::new(),
+            function,
+            structs: HashMap::new(),
+            classes: vec![],
+            enums: vec![],
+            cells: vec![],
+            numbers: HashMap::new(),
+        }
+    }
+}
+
 pub struct Transpiler {
-    pub parser: Parser
+    pub parser: Parser,
+    backend: Box<dyn Backend>,
+    lines: Vec<String>,
+    depth: usize,
+    temporaries: usize,
+    renamed: usize,
+    functions: usize,
+    scopes: Vec<Scope>,
+    /// Variables of enclosing functions assigned by each function being
+    /// transpiled, and whether they are globals.
+    outer: Vec<Vec<(String, bool)>>,
+    /// The number of parameters of each top-level function when the
+    /// backend hoists them, and how many of them have default values.
+    hoisted: HashMap<String, (usize, usize)>,
+    /// For each enclosing loop, the temporary its value is broken out into,
+    /// if the value is used.
+    loops: Vec<Option<String>>,
+    /// Names referred to by closures in the outermost loop of the function
+    /// being transpiled, when the backend binds them late.
+    captured: Option<HashSet<String>>,
+    /// For each class whose methods are being transpiled, its output name
+    /// and that of its superclass.
+    classes: Vec<(String, Option<String>)>,
+    globals: Vec<String>,
+    prototypes: Vec<(String, Vec<String>)>,
+    definitions: Vec<String>,
+    /// Names assigned anywhere in the program.
+    assigned: HashSet<String>,
 }
 
 impl Transpiler {
-    pub fn new(input: String) -> Self {
+    pub fn new(input: String, backend: Box<dyn Backend>) -> Self {
         let parser = Parser::new(input);
         Transpiler {
             parser,
+            backend,
+            lines: vec![],
+            depth: 0,
+            temporaries: 0,
+            renamed: 0,
+            functions: 0,
+            scopes: vec![Scope::new(0)],
+            outer: vec![],
+            hoisted: HashMap::new(),
+            loops: vec![],
+            captured: None,
+            classes: vec![],
+            globals: vec![],
+            prototypes: vec![],
+            definitions: vec![],
+            assigned: HashSet::new(),
         }
     }
 
-    pub fn transpile(&mut self) -> String {
-        let program: Vec<Expression> = match self.parser.parse_program() {
-            Ok(program) => program,
-            Err(err) => panic!("{}", err)
-        };
+    pub fn transpile(&mut self) -> Result<String, TranspileError> {
+        let mut program: Vec<Expression> = self.parser.parse_program()?;
+        Resolver::new().resolve(&mut program)?;
+        for expression in &program {
+            Self::assigned(expression, &mut self.assigned);
+        }
+
+        let defines_print = program.iter().any(|expression| match expression {
+            Expression::Function { name, .. } | Expression::Let { name, .. } => name == "print",
+            _ => false,
+        });
+
+        if self.backend.hoists_functions() {
+            for expression in &program {
+                if let Expression::Function {
+                    name,
+                    parameters,
+                    defaults,
+                    ..
+                } = expression
+                {
+                    let arity = (parameters.len(), defaults.len());
+                    if self.hoisted.insert(name.clone(), arity).is_some() {
+                        return Err(TranspileError::new(
+                            format!("function '{}' is defined more than once", name),
+                            expression.position(),
+                        ));
+                    }
+                    let target = self.backend.global(name);
+                    self.scopes[0].names.insert(name.clone(), target);
+                }
+            }
+        }
 
-        let mut output = String::new();
         for expression in program {
-            output.push_str(&self.transpile_expression(expression));
+            self.transpile_statement(expression, &Destination::Discard)?;
         }
 
-        return output;
+        Ok(self.backend.finish(Output {
+            globals: mem::take(&mut self.globals),
+            functions: mem::take(&mut self.prototypes),
+            definitions: mem::take(&mut self.definitions),
+            body: mem::take(&mut self.lines),
+            defines_print,
+        }))
+    }
+
+    fn line(&mut self, text: String) {
+        self.lines
+            .push(format!("{}{}", "    ".repeat(self.depth), text));
+    }
+
+    fn nil(&self) -> String {
+        self.backend.value(&Value::Nil)
     }
 
-    fn transpile_operator(&mut self, operator: Token) -> String {
-        (match operator {
-            Token::Plus => "+",
-            Token::Minus => "-",
-            Token::Asterisk => "*",
-            Token::Slash => "/",
-            Token::EqEq => "==",
-            Token::BangEq => "!=",
-            Token::LessThan => "<",
-            Token::GreaterThan => ">",
-            Token::LessThanEqual => "<=",
-            Token::GreaterThanEqual => ">=",
-            _ => panic!("Invalid operator: {:?}", operator),
-        }).to_string()
+    fn temporary(&mut self) -> String {
+        let name = format!("_t{}", self.temporaries);
+        self.temporaries += 1;
+        name
     }
 
-    fn transpile_call(&mut self, function: Expression, arguments: Vec<Expression>) -> String {
-        let mut output = format!("{}(", self.transpile_expression(function));
-        for argument in arguments {
-            output.push_str(&self.transpile_expression(argument));
+    /// Finds the output name of a Kox name, or the element of its cell, and
+    /// the scope it was declared in.
+    fn resolve(&self, name: &str) -> Option<(String, usize)> {
+        let (target, scope) = self.lookup(name)?;
+        if self.scopes[scope].cells.iter().any(|cell| cell == name) {
+            return Some((self.backend.index(&target, "0").unwrap(), scope));
         }
-        output.push_str(")");
-        return output;
+        Some((target, scope))
     }
 
-    fn transpile_assignment(&mut self, name: String, value: Expression) -> String {
-        format!("{} = {}", name, self.transpile_expression(value))
+    /// The output name of a Kox name and the scope it was declared in,
+    /// whether or not it is kept in a cell.
+    fn lookup(&self, name: &str) -> Option<(String, usize)> {
+        self.scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, scope)| scope.names.get(name).map(|target| (target.clone(), i)))
     }
 
-    fn transpile_block(&mut self, expressions: Vec<Expression>) -> String {
-        let mut vec: Vec<String> = vec![];
+    /// Whether a variable just declared in the innermost scope is captured by
+    /// closures made in a loop, and so must be kept in a cell.
+    fn is_captured(&mut self, name: &str) -> bool {
+        let captured = self
+            .captured
+            .as_ref()
+            .is_some_and(|captured| captured.contains(name));
+        if captured {
+            self.scopes.last_mut().unwrap().cells.push(name.to_string());
+        }
+        captured
+    }
 
-        for expression in expressions {
-            vec.push(self.transpile_expression(expression));
+    /// Adds the names an expression refers to in closures to `names`, or
+    /// all of them if it is in a closure itself.
+    fn references(expression: &Expression, closure: bool, names: &mut HashSet<String>) {
+        match expression {
+            Expression::Identifier { ident: name, .. } | Expression::Assign { name, .. }
+                if closure =>
+            {
+                names.insert(name.clone());
+            }
+            _ => {}
         }
+        children(expression, |child, nested| {
+            Self::references(child, closure || nested, names)
+        });
+    }
 
-        let transpiled = vec.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("\n");
-        format!("{{\n{}\n}}", transpiled)
+    /// Adds the names an expression assigns to `names`.
+    fn assigned(expression: &Expression, names: &mut HashSet<String>) {
+        if let Expression::Assign { name, .. } = expression {
+            names.insert(name.clone());
+        }
+        children(expression, |child, _| Self::assigned(child, names));
     }
 
-    fn transpile_expression(&mut self, expression: Expression) -> String {
+    /// Whether an expression is known to be an integer or a float without
+    /// running it. Variables are only known if they are never assigned.
+    fn number(&self, expression: &Expression) -> Number {
+        match expression {
+            Expression::Value { value, .. } => match value {
+                Value::Int(_) | Value::BigInt(_) => Number::Int,
+                Value::Float(_) => Number::Float,
+                _ => Number::Unknown,
+            },
+            Expression::Identifier { ident, .. } => match self.lookup(ident) {
+                Some((_, scope)) => self.scopes[scope]
+                    .numbers
+                    .get(ident)
+                    .copied()
+                    .unwrap_or(Number::Unknown),
+                None => Number::Unknown,
+            },
+            Expression::Unary {
+                operator: Token::Minus,
+                right,
+                ..
+            } => self.number(right),
+            Expression::Binary {
+                left,
+                operator,
+                right,
+                ..
+            } => {
+                let arithmetic = matches!(
+                    operator,
+                    Token::Plus
+                        | Token::Minus
+                        | Token::Asterisk
+                        | Token::Slash
+                        | Token::Percent
+                        | Token::Pow
+                );
+                match (self.number(left), self.number(right)) {
+                    _ if !arithmetic => Number::Unknown,
+                    (Number::Float, _) | (_, Number::Float) => Number::Float,
+                    // An integer raised to a negative power is a float.
+                    (Number::Int, Number::Int) if *operator != Token::Pow => Number::Int,
+                    _ => Number::Unknown,
+                }
+            }
+            _ => Number::Unknown,
+        }
+    }
+
+    /// Whether a name refers to a class.
+    fn is_class(&self, name: &str) -> bool {
+        match self.resolve(name) {
+            Some((_, scope)) => self.scopes[scope].classes.iter().any(|class| class == name),
+            None => false,
+        }
+    }
+
+    /// Whether a name refers to an enum.
+    fn is_enum(&self, name: &str) -> bool {
+        match self.resolve(name) {
+            Some((_, scope)) => self.scopes[scope].enums.iter().any(|other| other == name),
+            None => false,
+        }
+    }
+
+    /// Whether a name refers to a function hoisted by the backend.
+    fn is_hoisted(&self, name: &str) -> bool {
+        self.hoisted.contains_key(name) && matches!(self.resolve(name), Some((_, 0)))
+    }
+
+    /// Adds a name to the innermost scope. Names that shadow another
+    /// variable are renamed, since not every target has block scoping and
+    /// the ones that do would see the new variable in its own initializer.
+    fn declare(&mut self, name: &str, global: bool) -> String {
+        let target = if self.resolve(name).is_some() {
+            self.renamed += 1;
+            format!("{}_{}", self.backend.identifier(name), self.renamed)
+        } else if global {
+            self.backend.global(name)
+        } else {
+            self.backend.identifier(name)
+        };
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .names
+            .insert(name.to_string(), target.clone());
+        target
+    }
+
+    fn transpile_identifier(
+        &self,
+        ident: &str,
+        position: (usize, usize),
+    ) -> Result<String, TranspileError> {
+        if let Some((target, _)) = self.resolve(ident) {
+            return Ok(target);
+        }
+        if let Some(builtin) = self.backend.builtin(ident) {
+            return Ok(builtin);
+        }
+        if self.backend.hoists_functions() {
+            return Err(TranspileError::new(
+                format!("undefined variable referenced '{}'", ident),
+                position,
+            ));
+        }
+        Ok(self.backend.identifier(ident))
+    }
+
+    /// Whether an expression maps to a target expression without first
+    /// emitting any statements.
+    fn is_simple(expression: &Expression) -> bool {
+        match expression {
+            Expression::Value { .. } | Expression::Identifier { .. } | Expression::Super { .. } => {
+                true
+            }
+            Expression::Binary { left, right, .. } => {
+                Self::is_simple(left) && Self::is_simple(right)
+            }
+            Expression::Logical { left, right, .. } => {
+                Self::is_simple(left) && Self::is_simple(right)
+            }
+            Expression::Unary { right, .. } => Self::is_simple(right),
+            Expression::Call {
+                function,
+                arguments,
+                ..
+            } => Self::is_simple(function) && arguments.iter().all(Self::is_simple),
+            Expression::Assign { value, .. } => Self::is_simple(value),
+            Expression::Construct { fields, .. } => {
+                fields.iter().all(|(_, value)| Self::is_simple(value))
+            }
+            Expression::Get { object, .. } => Self::is_simple(object),
+            Expression::Set { object, value, .. } => {
+                Self::is_simple(object) && Self::is_simple(value)
+            }
+            Expression::List { elements, .. } => elements.iter().all(Self::is_simple),
+            Expression::Map { entries, .. } => entries
+                .iter()
+                .all(|(key, value)| Self::is_simple(key) && Self::is_simple(value)),
+            Expression::Index { object, index, .. } => {
+                Self::is_simple(object) && Self::is_simple(index)
+            }
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => Self::is_simple(object) && Self::is_simple(index) && Self::is_simple(value),
+            _ => false,
+        }
+    }
+
+    /// Whether evaluating an expression has no side effects.
+    fn is_pure(expression: &Expression) -> bool {
+        match expression {
+            Expression::Value { .. } | Expression::Identifier { .. } | Expression::Super { .. } => {
+                true
+            }
+            Expression::Binary { left, right, .. } => Self::is_pure(left) && Self::is_pure(right),
+            Expression::Logical { left, right, .. } => Self::is_pure(left) && Self::is_pure(right),
+            Expression::Unary { right, .. } => Self::is_pure(right),
+            Expression::Construct { fields, .. } => {
+                fields.iter().all(|(_, value)| Self::is_pure(value))
+            }
+            Expression::Get { object, .. } => Self::is_pure(object),
+            Expression::List { elements, .. } => elements.iter().all(Self::is_pure),
+            Expression::Map { entries, .. } => entries
+                .iter()
+                .all(|(key, value)| Self::is_pure(key) && Self::is_pure(value)),
+            Expression::Index { object, index, .. } => {
+                Self::is_pure(object) && Self::is_pure(index)
+            }
+            _ => false,
+        }
+    }
+
+    /// Transpiles the operands of a compound expression. If any of them needs
+    /// statements emitted, or the target does not evaluate operands in order
+    /// and more than one has side effects, they are evaluated into
+    /// temporaries first so that side effects still happen left to right.
+    fn transpile_operands(
+        &mut self,
+        operands: Vec<Expression>,
+    ) -> Result<Vec<String>, TranspileError> {
+        let in_order = self.backend.ordered()
+            || operands
+                .iter()
+                .filter(|operand| !Self::is_pure(operand))
+                .count()
+                <= 1;
+        let all_simple = in_order && operands.iter().all(Self::is_simple);
+        let mut transpiled = vec![];
+        for operand in operands {
+            let expression = self.transpile_expression(operand)?;
+            if all_simple {
+                transpiled.push(expression);
+            } else {
+                let temporary = self.temporary();
+                self.line(self.backend.declare(&temporary, &expression));
+                transpiled.push(temporary);
+            }
+        }
+        Ok(transpiled)
+    }
+
+    /// Checks that a call can be made in a language without first-class
+    /// functions, and returns how many optional parameters it leaves out.
+    fn check_call(
+        &self,
+        function: &Expression,
+        arguments: usize,
+        position: (usize, usize),
+    ) -> Result<usize, TranspileError> {
+        let arity = match function {
+            Expression::Identifier { ident, .. } if self.is_hoisted(ident) => {
+                Some(self.hoisted[ident])
+            }
+            Expression::Identifier { ident, .. }
+                if self.resolve(ident).is_none() && self.backend.builtin(ident).is_some() =>
+            {
+                Some((1, 0))
+            }
+            _ => None,
+        };
+        match arity {
+            Some((count, 0)) if count != arguments => Err(TranspileError::new(
+                format!("Expected {} arguments but got {}", count, arguments),
+                position,
+            )),
+            Some((count, defaults)) if arguments + defaults < count || arguments > count => {
+                Err(TranspileError::new(
+                    format!(
+                        "Expected {} to {} arguments but got {}",
+                        count - defaults,
+                        count,
+                        arguments
+                    ),
+                    position,
+                ))
+            }
+            Some((count, _)) => Ok(count - arguments),
+            None => Err(TranspileError::new(
+                format!(
+                    "only named functions can be called when targeting {}",
+                    self.backend.name()
+                ),
+                position,
+            )),
+        }
+    }
+
+    /// Unwraps the output of a backend method for a kind of value, which is
+    /// `None` when the target has no such `values`.
+    fn supported(
+        &self,
+        output: Option<String>,
+        values: &str,
+        position: (usize, usize),
+    ) -> Result<String, TranspileError> {
+        output.ok_or_else(|| {
+            TranspileError::new(
+                format!(
+                    "{} are not supported when targeting {}",
+                    values,
+                    self.backend.name()
+                ),
+                position,
+            )
+        })
+    }
+
+    fn transpile_call(
+        &mut self,
+        function: Expression,
+        arguments: Vec<Expression>,
+        position: (usize, usize),
+    ) -> Result<String, TranspileError> {
+        let left_out = match self.backend.hoists_functions() {
+            true => self.check_call(&function, arguments.len(), position)?,
+            false => 0,
+        };
+
+        let (function, arguments) = match function {
+            // Looking up a name has no side effects, so it does not need to
+            // be ordered with the arguments.
+            Expression::Identifier {
+                ident,
+                line,
+                column,
+                ..
+            } => {
+                let function = self.transpile_identifier(&ident, (line, column))?;
+                let arguments = self.transpile_operands(arguments)?;
+                if self.is_class(&ident) {
+                    let instance = self.backend.construct(&function, &arguments);
+                    return self.supported(instance, "classes", position);
+                }
+                (function, arguments)
+            }
+            // A method is called on its object directly rather than through
+            // a temporary, which would hold a copy bound to the object.
+            Expression::Get {
+                object,
+                name,
+                line,
+                column,
+            } => {
+                let mut operands = vec![*object];
+                operands.extend(arguments);
+                let mut operands = self.transpile_operands(operands)?;
+                let object = operands.remove(0);
+                let method = self.backend.get(&object, &self.backend.identifier(&name));
+                (self.supported(method, "structs", (line, column))?, operands)
+            }
+            function => {
+                let mut operands = vec![function];
+                operands.extend(arguments);
+                let mut operands = self.transpile_operands(operands)?;
+                (operands.remove(0), operands)
+            }
+        };
+        let mut arguments = arguments;
+        arguments.extend((0..left_out).map(|_| self.backend.missing_argument()));
+        Ok(format!("{}({})", function, arguments.join(", ")))
+    }
+
+    /// Transpiles `&&` and `||`. If the right operand needs statements to
+    /// compute, they only run in a branch taken when the left operand does
+    /// not decide the result.
+    fn transpile_logical(
+        &mut self,
+        operator: Token,
+        left: Expression,
+        right: Expression,
+    ) -> Result<String, TranspileError> {
+        let left = self.transpile_expression(left)?;
+        let start = self.lines.len();
+        self.depth += 1;
+        let right = self.transpile_expression(right);
+        self.depth -= 1;
+        let right = right?;
+        if self.lines.len() == start {
+            return Ok(self.backend.logical(&operator, &left, &right));
+        }
+
+        let statements = self.lines.split_off(start);
+        let temporary = self.temporary();
+        self.line(self.backend.declare(&temporary, &left));
+        self.line(self.backend.if_header(&temporary));
+        if operator == Token::Or {
+            if let Some(empty) = self.backend.empty() {
+                self.depth += 1;
+                self.line(empty);
+                self.depth -= 1;
+            }
+            self.line(self.backend.else_header());
+        }
+        self.lines.extend(statements);
+        self.depth += 1;
+        let assignment = format!("{} = {}", temporary, right);
+        self.line(self.backend.statement(&assignment));
+        self.depth -= 1;
+        self.close();
+        Ok(temporary)
+    }
+
+    /// Transpiles the value of an assignment and resolves the variable it
+    /// assigns to.
+    fn transpile_assignment(
+        &mut self,
+        name: String,
+        value: Expression,
+        position: (usize, usize),
+    ) -> Result<(String, String), TranspileError> {
+        let value = self.transpile_expression(value)?;
+        let target = match self.resolve(&name) {
+            Some(_) if self.is_hoisted(&name) => {
+                return Err(TranspileError::new(
+                    format!("cannot assign to function '{}'", name),
+                    position,
+                ))
+            }
+            Some((target, scope)) => {
+                let function = self.scopes[scope].function;
+                let cell = self.scopes[scope].cells.contains(&name);
+                if function < self.functions && !cell {
+                    let outer = self.outer.last_mut().unwrap();
+                    if !outer.iter().any(|(name, _)| *name == target) {
+                        outer.push((target.clone(), function == 0));
+                    }
+                }
+                target
+            }
+            None => self.transpile_identifier(&name, position)?,
+        };
+        Ok((target, value))
+    }
+
+    /// Emits a declaration, or a plain assignment when the name is already
+    /// declared in this scope, since Kox allows redeclaring where the targets
+    /// do not.
+    fn transpile_let(
+        &mut self,
+        name: String,
+        value: Expression,
+        position: (usize, usize),
+    ) -> Result<String, TranspileError> {
+        let number = match self.assigned.contains(&name) {
+            true => Number::Unknown,
+            false => self.number(&value),
+        };
+        let value = self.transpile_expression(value)?;
+        let global = self.functions == 0 && self.scopes.len() == 1;
+        if global && self.is_hoisted(&name) {
+            return Err(TranspileError::new(
+                format!("'{}' is already defined as a function", name),
+                position,
+            ));
+        }
+
+        if self.scopes.last().unwrap().names.contains_key(&name) {
+            let numbers = &mut self.scopes.last_mut().unwrap().numbers;
+            if numbers.get(&name) != Some(&number) {
+                numbers.insert(name.clone(), Number::Unknown);
+            }
+            let (target, _) = self.resolve(&name).unwrap();
+            let assignment = format!("{} = {}", target, value);
+            self.line(self.backend.statement(&assignment));
+            return Ok(target);
+        }
+
+        let target = self.declare(&name, global);
+        if number != Number::Unknown {
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .numbers
+                .insert(name.clone(), number);
+        }
+        if global && self.backend.hoists_functions() {
+            self.globals.push(target.clone());
+            let assignment = format!("{} = {}", target, value);
+            self.line(self.backend.statement(&assignment));
+            return Ok(target);
+        }
+        self.bind(&name, &target, value);
+        Ok(self.resolve(&name).unwrap().0)
+    }
+
+    /// Emits the declaration of a variable just added to the innermost
+    /// scope, in a cell if closures made in a loop capture it.
+    fn bind(&mut self, name: &str, target: &str, value: String) {
+        let value = match self.is_captured(name) {
+            true => self.backend.list(&[value]).unwrap(),
+            false => value,
+        };
+        self.line(self.backend.declare(target, &value));
+    }
+
+    fn transpile_block(
+        &mut self,
+        expressions: Vec<Expression>,
+        destination: &Destination,
+    ) -> Result<(), TranspileError> {
+        self.scopes.push(Scope::new(self.functions));
+        let count = expressions.len();
+        for (i, expression) in expressions.into_iter().enumerate() {
+            if i + 1 == count {
+                self.transpile_statement(expression, destination)?;
+            } else {
+                self.transpile_statement(expression, &Destination::Discard)?;
+            }
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    /// Emits an indented body, flattening a Kox block into it.
+    fn transpile_body(
+        &mut self,
+        header: String,
+        body: Expression,
+        destination: &Destination,
+    ) -> Result<(), TranspileError> {
+        self.line(header);
+        self.depth += 1;
+        let start = self.lines.len();
+        self.transpile_block(Self::statements(body), destination)?;
+        self.fill(start);
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// The statements of a body, which are those of a block rather than the
+    /// block itself.
+    fn statements(body: Expression) -> Vec<Expression> {
+        match body {
+            Expression::Block { expressions, .. } => expressions,
+            body => vec![body],
+        }
+    }
+
+    /// Adds the placeholder for an empty body if nothing was emitted since
+    /// `start`.
+    fn fill(&mut self, start: usize) {
+        if self.lines.len() == start {
+            if let Some(empty) = self.backend.empty() {
+                self.line(empty);
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(close) = self.backend.close() {
+            self.line(close);
+        }
+    }
+
+    fn transpile_if(
+        &mut self,
+        condition: Expression,
+        consequence: Expression,
+        alternative: Option<Box<Expression>>,
+        destination: &Destination,
+    ) -> Result<(), TranspileError> {
+        let condition = self.transpile_expression(condition)?;
+        let header = self.backend.if_header(&condition);
+        self.transpile_body(header, consequence, destination)?;
+        match alternative {
+            Some(alternative) => {
+                let header = self.backend.else_header();
+                self.transpile_body(header, *alternative, destination)?;
+            }
+            None if !matches!(destination, Destination::Discard) => {
+                let nil = Expression::Value {
+                    value: Value::Nil,
+                    line: 0,
+                    column: 0,
+                };
+                let header = self.backend.else_header();
+                self.transpile_body(header, nil, destination)?;
+            }
+            None => {}
+        }
+        self.close();
+        Ok(())
+    }
+
+    /// Emits a match on a temporary holding its value.
+    fn transpile_match(
+        &mut self,
+        value: Expression,
+        arms: Vec<MatchArm>,
+        destination: &Destination,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        let value = self.transpile_expression(value)?;
+        let subject = self.temporary();
+        self.line(self.backend.declare(&subject, &value));
+        self.transpile_arms(&subject, arms, destination, position)
+    }
+
+    /// Emits the arms of a match in order. Each is only tried when none
+    /// before it matched: the arms after one are nested in its `else`, or,
+    /// if it has a guard, in a branch on a flag set when it is taken.
+    fn transpile_arms(
+        &mut self,
+        subject: &str,
+        mut arms: Vec<MatchArm>,
+        destination: &Destination,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        if arms.is_empty() {
+            if !matches!(destination, Destination::Discard) {
+                let nil = Expression::Value {
+                    value: Value::Nil,
+                    line: 0,
+                    column: 0,
+                };
+                self.transpile_statement(nil, destination)?;
+            }
+            return Ok(());
+        }
+        let arm = arms.remove(0);
+
+        let mut tests = vec![];
+        let mut bindings = vec![];
+        self.transpile_pattern(
+            arm.pattern,
+            subject.to_string(),
+            &mut tests,
+            &mut bindings,
+            position,
+        )?;
+        let test = tests
+            .into_iter()
+            .reduce(|left, right| self.backend.logical(&Token::And, &left, &right));
+        // Nothing follows the last arm of a match whose value is unused.
+        let rest = !arms.is_empty() || !matches!(destination, Destination::Discard);
+        let taken = match &arm.guard {
+            Some(_) if rest => {
+                let taken = self.temporary();
+                let untaken = self.backend.value(&Value::Boolean(false));
+                self.line(self.backend.declare(&taken, &untaken));
+                Some(taken)
+            }
+            _ => None,
+        };
+
+        // An arm that always matches only needs a block to scope its names.
+        let opened = match &test {
+            Some(test) => {
+                self.line(self.backend.if_header(test));
+                true
+            }
+            None if bindings.is_empty() => false,
+            None => match self.backend.open() {
+                Some(open) => {
+                    self.line(open);
+                    true
+                }
+                None => false,
+            },
+        };
+        if opened {
+            self.depth += 1;
+        }
+        let start = self.lines.len();
+        self.scopes.push(Scope::new(self.functions));
+        for (name, value) in bindings {
+            let target = self.declare(&name, false);
+            self.bind(&name, &target, value);
+        }
+        match arm.guard {
+            Some(guard) => {
+                let guard = self.transpile_expression(guard)?;
+                self.line(self.backend.if_header(&guard));
+                self.depth += 1;
+                let start = self.lines.len();
+                if let Some(taken) = &taken {
+                    let assignment =
+                        format!("{} = {}", taken, self.backend.value(&Value::Boolean(true)));
+                    self.line(self.backend.statement(&assignment));
+                }
+                self.transpile_block(Self::statements(arm.body), destination)?;
+                self.fill(start);
+                self.depth -= 1;
+                self.close();
+            }
+            None => self.transpile_block(Self::statements(arm.body), destination)?,
+        }
+        self.fill(start);
+        self.scopes.pop();
+        if opened {
+            self.depth -= 1;
+        }
+
+        match (taken, test) {
+            (Some(taken), _) => {
+                if opened {
+                    self.close();
+                }
+                let untaken = self.backend.unary(&Token::Bang, &taken);
+                self.line(self.backend.if_header(&untaken));
+                self.transpile_rest(subject, arms, destination, position)?;
+                self.close();
+            }
+            (None, Some(_)) if rest => {
+                self.line(self.backend.else_header());
+                self.transpile_rest(subject, arms, destination, position)?;
+                self.close();
+            }
+            // The arms after one that always matches are never tried.
+            (None, _) => {
+                if opened {
+                    self.close();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Emits the arms tried after one that did not match, one level deeper.
+    fn transpile_rest(
+        &mut self,
+        subject: &str,
+        arms: Vec<MatchArm>,
+        destination: &Destination,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        self.depth += 1;
+        let start = self.lines.len();
+        let rest = self.transpile_arms(subject, arms, destination, position);
+        self.fill(start);
+        self.depth -= 1;
+        rest
+    }
+
+    /// Rejects integer literals the target cannot hold exactly.
+    fn check_integer(&self, value: &Value, position: (usize, usize)) -> Result<(), TranspileError> {
+        let Some(bits) = self.backend.integer_bits() else {
+            return Ok(());
+        };
+        let fits = match value {
+            Value::Int(i) => bits >= 64 || i.unsigned_abs() < 1 << bits,
+            Value::BigInt(_) => false,
+            _ => true,
+        };
+        if fits {
+            return Ok(());
+        }
+        Err(TranspileError::new(
+            format!(
+                "integers beyond {} bits are not supported when targeting {}",
+                bits,
+                self.backend.name()
+            ),
+            position,
+        ))
+    }
+
+    /// Adds the tests `value` must pass to match a pattern, and the names the
+    /// pattern binds with the parts of `value` they are bound to.
+    fn transpile_pattern(
+        &self,
+        pattern: Pattern,
+        value: String,
+        tests: &mut Vec<String>,
+        bindings: &mut Vec<(String, String)>,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => bindings.push((name, value)),
+            Pattern::Value(literal) => {
+                self.check_integer(&literal, position)?;
+                let literal = self.backend.value(&literal);
+                tests.push(self.backend.binary(&Token::EqEq, &value, &literal));
+            }
+            Pattern::Variant {
+                enumeration,
+                variant,
+                fields,
+                ..
+            } => {
+                if !self.is_enum(&enumeration) {
+                    return Err(TranspileError::new(
+                        format!("undefined enum '{}'", enumeration),
+                        position,
+                    ));
+                }
+                let target = self.transpile_identifier(&enumeration, position)?;
+                tests.push(self.backend.is_variant(&value, &target, &variant));
+                for (i, field) in fields.into_iter().enumerate() {
+                    let field_value = self.backend.variant_field(&value, i);
+                    self.transpile_pattern(field, field_value, tests, bindings, position)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Declares the temporary a loop's value goes to when it is used.
+    fn loop_result(&mut self, destination: &Destination) -> Option<String> {
+        if let Destination::Discard = destination {
+            return None;
+        }
+        let temporary = self.temporary();
+        self.line(self.backend.declare(&temporary, &self.nil()));
+        Some(temporary)
+    }
+
+    /// Starts collecting the names that closures made in a loop refer to,
+    /// if the backend binds them late and no enclosing loop of the function
+    /// already has. Returns whether it did.
+    fn capture_loop(&mut self, parts: &[&Expression]) -> bool {
+        if !self.backend.late_binding() || self.captured.is_some() {
+            return false;
+        }
+        let mut names = HashSet::new();
+        for part in parts {
+            Self::references(part, false, &mut names);
+        }
+        self.captured = Some(names);
+        true
+    }
+
+    /// Emits a `while` loop. A condition that needs statements to compute is
+    /// evaluated at the top of an infinite loop, which is left once it fails.
+    fn transpile_while(
+        &mut self,
+        condition: Expression,
+        body: Expression,
+    ) -> Result<(), TranspileError> {
+        let start = self.lines.len();
+        let condition = self.transpile_expression(condition)?;
+        if self.lines.len() == start {
+            let header = self.backend.while_header(&condition);
+            self.transpile_body(header, body, &Destination::Discard)?;
+            self.close();
+            return Ok(());
+        }
+
+        let statements = self.lines.split_off(start);
+        let forever = self.backend.value(&Value::Boolean(true));
+        self.line(self.backend.while_header(&forever));
+        self.depth += 1;
+        self.lines.extend(
+            statements
+                .into_iter()
+                .map(|statement| format!("    {}", statement)),
+        );
+        let header = self.backend.if_header(&condition);
+        self.transpile_body(header, body, &Destination::Discard)?;
+        self.line(self.backend.else_header());
+        self.depth += 1;
+        self.line(self.backend.statement("break"));
+        self.depth -= 1;
+        self.close();
+        self.depth -= 1;
+        self.close();
+        Ok(())
+    }
+
+    fn transpile_function(
+        &mut self,
+        name: String,
+        parameters: Parameters,
+        body: Expression,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        let hoists = self.backend.hoists_functions();
+        let target = if hoists {
+            if self.scopes.len() > 1 {
+                return Err(TranspileError::new(
+                    format!(
+                        "functions must be defined at the top level when targeting {}",
+                        self.backend.name()
+                    ),
+                    position,
+                ));
+            }
+            self.scopes[0].names[&name].clone()
+        } else {
+            match self.scopes.last().unwrap().names.get(&name) {
+                Some(target) => target.clone(),
+                None => self.declare(&name, false),
+            }
+        };
+
+        // Hoisted functions are written to their own section.
+        let enclosing = hoists.then(|| (mem::take(&mut self.lines), mem::take(&mut self.depth)));
+
+        let parameters =
+            self.transpile_callable(parameters, None, body, position, |backend, parameters| {
+                backend.function_header(&target, parameters)
+            })?;
+
+        if let Some((lines, depth)) = enclosing {
+            let definition = mem::replace(&mut self.lines, lines);
+            self.definitions.extend(definition);
+            self.depth = depth;
+            self.prototypes.push((target, parameters));
+        }
+        Ok(())
+    }
+
+    /// Emits a function or a method in a new function scope, with a header
+    /// made from its parameters in the output, which are returned.
+    /// `receiver` is the Kox name a method's instance is bound to.
+    fn transpile_callable(
+        &mut self,
+        parameters: Parameters,
+        receiver: Option<&str>,
+        body: Expression,
+        position: (usize, usize),
+        header: impl FnOnce(&dyn Backend, &[String]) -> String,
+    ) -> Result<Vec<String>, TranspileError> {
+        let Parameters {
+            names: parameters,
+            defaults,
+            rest,
+        } = parameters;
+
+        // Cells of the enclosing function's loops that the function refers to.
+        let mut names = HashSet::new();
+        Self::references(&body, true, &mut names);
+        for default in &defaults {
+            Self::references(default, true, &mut names);
+        }
+        let mut cells: Vec<String> = names
+            .iter()
+            .filter(|name| {
+                !parameters.contains(name)
+                    && rest.as_ref() != Some(name)
+                    && receiver != Some(name.as_str())
+            })
+            .filter_map(|name| match self.lookup(name) {
+                Some((target, scope))
+                    if self.scopes[scope].function == self.functions
+                        && self.scopes[scope].cells.contains(name) =>
+                {
+                    Some(target)
+                }
+                _ => None,
+            })
+            .collect();
+        cells.sort();
+        let captured = self.captured.take();
+
+        self.functions += 1;
+        let mut scope = Scope::new(self.functions);
+        if let Some(receiver) = receiver {
+            scope.names.insert(receiver.to_string(), "self".to_string());
+        }
+        self.scopes.push(scope);
+        self.outer.push(vec![]);
+
+        // Parameters are declared in order, so that a default value can use
+        // the ones before it. Defaults the language can't take in the header
+        // are computed at the start of the body.
+        let language = self.backend.name();
+        let unsupported = |kind: &str| {
+            TranspileError::new(
+                format!(
+                    "{} parameters are not supported when targeting {}",
+                    kind, language
+                ),
+                position,
+            )
+        };
+        let first_default = parameters.len() - defaults.len();
+        let mut defaults = defaults.into_iter();
+        let mut outputs = vec![];
+        let mut computed = vec![];
+        for (i, parameter) in parameters.into_iter().enumerate() {
+            let target = self.backend.identifier(&parameter);
+            self.scopes
+                .last_mut()
+                .unwrap()
+                .names
+                .insert(parameter, target.clone());
+            if i < first_default {
+                outputs.push(target);
+                continue;
+            }
+            let default = defaults.next().unwrap();
+            let native = match Self::is_simple(&default) {
+                true => {
+                    let value = self.transpile_expression(default.clone())?;
+                    self.backend.default_parameter(&target, &value)
+                }
+                false => None,
+            };
+            match native {
+                Some(parameter) => outputs.push(parameter),
+                None => {
+                    let parameter = self.backend.optional_parameter(&target);
+                    outputs.push(parameter.ok_or_else(|| unsupported("default"))?);
+                    computed.push((target, default));
+                }
+            }
+        }
+        let rest = match rest {
+            Some(rest) => {
+                let target = self.backend.identifier(&rest);
+                self.scopes
+                    .last_mut()
+                    .unwrap()
+                    .names
+                    .insert(rest, target.clone());
+                let parameter = self.backend.rest_parameter(&target);
+                outputs.push(parameter.ok_or_else(|| unsupported("rest"))?);
+                self.backend.rest_list(&target)
+            }
+            None => None,
+        };
+
+        let start = self.lines.len() + 1;
+        let header = match cells.is_empty() {
+            true => header(self.backend.as_ref(), &outputs),
+            false => header(
+                self.backend.as_ref(),
+                &self.backend.captures(&outputs, &cells),
+            ),
+        };
+        self.line(header);
+        self.depth += 1;
+        let body_start = self.lines.len();
+        if let Some(rest) = rest {
+            self.line(rest);
+        }
+        for (target, default) in computed {
+            self.line(self.backend.if_header(&self.backend.missing(&target)));
+            self.depth += 1;
+            self.transpile_statement(default, &Destination::Assign(target))?;
+            self.depth -= 1;
+            self.close();
+        }
+        self.transpile_block(Self::statements(body), &Destination::Return)?;
+        self.fill(body_start);
+        self.depth -= 1;
+        self.close();
+
+        let indent = "    ".repeat(self.depth + 1);
+        let receiver = receiver.and_then(|_| self.backend.receiver());
+        let outer = self.outer.pop().unwrap();
+        let nonlocals = outer
+            .iter()
+            .filter_map(|(name, global)| self.backend.nonlocal(name, *global));
+        let declarations: Vec<String> = receiver
+            .into_iter()
+            .chain(nonlocals)
+            .map(|declaration| format!("{}{}", indent, declaration))
+            .collect();
+        self.lines.splice(start..start, declarations);
+
+        self.scopes.pop();
+        self.functions -= 1;
+        self.captured = captured;
+        Ok(outputs)
+    }
+
+    /// Emits the methods of a struct or class, binding the instance to
+    /// `receiver` in them, and closes its class.
+    fn transpile_methods(
+        &mut self,
+        methods: Vec<Expression>,
+        receiver: &str,
+    ) -> Result<(), TranspileError> {
+        self.depth += 1;
+        for method in methods {
+            if let Expression::Function {
+                name,
+                parameters,
+                defaults,
+                rest,
+                body,
+                line,
+                column,
+                ..
+            } = method
+            {
+                let parameters = Parameters {
+                    names: parameters,
+                    defaults,
+                    rest,
+                };
+                let name = self.backend.identifier(&name);
+                self.transpile_callable(
+                    parameters,
+                    Some(receiver),
+                    *body,
+                    (line, column),
+                    |backend, parameters| backend.method_header(&name, parameters),
+                )?;
+            }
+        }
+        self.depth -= 1;
+        self.close();
+        Ok(())
+    }
+
+    /// The output name of a struct or class being declared.
+    fn declare_type(&mut self, name: &str) -> String {
+        match self.scopes.last().unwrap().names.get(name) {
+            Some(target) => target.clone(),
+            None => self.declare(name, false),
+        }
+    }
+
+    /// Emits the class a struct is declared as, with its methods.
+    fn transpile_struct(
+        &mut self,
+        name: String,
+        fields: Vec<String>,
+        methods: Vec<Expression>,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        let target = self.declare_type(&name);
+        let targets: Vec<String> = fields
+            .iter()
+            .map(|field| self.backend.identifier(field))
+            .collect();
+        let header = self.backend.struct_header(&target, &name, &targets);
+        let header = header.ok_or_else(|| {
+            TranspileError::new(
+                format!(
+                    "structs are not supported when targeting {}",
+                    self.backend.name()
+                ),
+                position,
+            )
+        })?;
+        self.scopes.last_mut().unwrap().structs.insert(name, fields);
+
+        for line in header {
+            self.line(line);
+        }
+        self.transpile_methods(methods, "self")
+    }
+
+    fn transpile_class(
+        &mut self,
+        name: String,
+        superclass: Option<String>,
+        methods: Vec<Expression>,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        let superclass = match superclass {
+            Some(superclass) if self.is_class(&superclass) => {
+                Some(self.transpile_identifier(&superclass, position)?)
+            }
+            Some(superclass) => {
+                return Err(TranspileError::new(
+                    format!("Superclass must be a class! Not '{}'", superclass),
+                    position,
+                ))
+            }
+            None => None,
+        };
+        let target = self.declare_type(&name);
+        let header = self
+            .backend
+            .class_header(&target, &name, superclass.as_deref());
+        let header = header.ok_or_else(|| {
+            TranspileError::new(
+                format!(
+                    "classes are not supported when targeting {}",
+                    self.backend.name()
+                ),
+                position,
+            )
+        })?;
+        self.scopes.last_mut().unwrap().classes.push(name);
+
+        for line in header {
+            self.line(line);
+        }
+        self.classes.push((target, superclass));
+        let methods = self.transpile_methods(methods, "this");
+        self.classes.pop();
+        methods
+    }
+
+    fn transpile_enum(
+        &mut self,
+        name: String,
+        variants: Vec<(String, Vec<String>)>,
+        position: (usize, usize),
+    ) -> Result<(), TranspileError> {
+        let target = self.declare_type(&name);
+        let variants: Vec<(String, Vec<String>)> = variants
+            .into_iter()
+            .map(|(variant, fields)| {
+                let fields = fields
+                    .iter()
+                    .map(|field| self.backend.identifier(field))
+                    .collect();
+                (variant, fields)
+            })
+            .collect();
+        let declaration = self.backend.enum_declaration(&target, &name, &variants);
+        let declaration = declaration.ok_or_else(|| {
+            TranspileError::new(
+                format!(
+                    "enums are not supported when targeting {}",
+                    self.backend.name()
+                ),
+                position,
+            )
+        })?;
+        self.scopes.last_mut().unwrap().enums.push(name);
+
+        for line in declaration {
+            self.line(line);
+        }
+        Ok(())
+    }
+
+    /// Transpiles a struct literal. Its fields are evaluated in source order
+    /// and passed to the constructor in declared order.
+    fn transpile_construct(
+        &mut self,
+        name: String,
+        fields: Vec<(String, Expression)>,
+        position: (usize, usize),
+    ) -> Result<String, TranspileError> {
+        let error = |message: String| TranspileError::new(message, position);
+        let declared = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.structs.get(&name))
+            .cloned()
+            .ok_or_else(|| error(format!("undefined struct '{}'", name)))?;
+        let mut given: Vec<String> = vec![];
+        for (field, _) in &fields {
+            if !declared.contains(field) {
+                return Err(error(format!("Struct {} has no field '{}'", name, field)));
+            }
+            if given.contains(field) {
+                return Err(error(format!("Field '{}' is given more than once", field)));
+            }
+            given.push(field.clone());
+        }
+        if let Some(missing) = declared.iter().find(|field| !given.contains(field)) {
+            return Err(error(format!(
+                "Missing field '{}' of struct {}",
+                missing, name
+            )));
+        }
+
+        let values = fields.into_iter().map(|(_, value)| value).collect();
+        let values = self.transpile_operands(values)?;
+        let ordered: Vec<String> = declared
+            .iter()
+            .map(|field| {
+                let i = given.iter().position(|given| given == field).unwrap();
+                values[i].clone()
+            })
+            .collect();
+        let target = self.transpile_identifier(&name, position)?;
+        let instance = self.backend.construct(&target, &ordered);
+        self.supported(instance, "structs", position)
+    }
+
+    /// Transpiles an expression as one or more statements, sending its value
+    /// to `destination`.
+    fn transpile_statement(
+        &mut self,
+        expression: Expression,
+        destination: &Destination,
+    ) -> Result<(), TranspileError> {
+        let value = match expression {
+            Expression::Block { expressions, .. } => {
+                match self.backend.open() {
+                    Some(open) => {
+                        self.line(open);
+                        self.depth += 1;
+                        self.transpile_block(expressions, destination)?;
+                        self.depth -= 1;
+                        self.close();
+                    }
+                    None => self.transpile_block(expressions, destination)?,
+                }
+                return Ok(());
+            }
+            Expression::If {
+                condition,
+                consequence,
+                alternative,
+                ..
+            } => return self.transpile_if(*condition, *consequence, alternative, destination),
+            Expression::Return {
+                value,
+                line,
+                column,
+            } => {
+                if self.functions == 0 {
+                    return Err(TranspileError {
+                        message: "cannot return from top-level code".to_string(),
+                        line,
+                        column,
+                    });
+                }
+                return self.transpile_statement(*value, &Destination::Return);
+            }
+            Expression::Let {
+                name,
+                value,
+                line,
+                column,
+                ..
+            } => {
+                let name = self.transpile_let(name, *value, (line, column))?;
+                if let Destination::Discard = destination {
+                    return Ok(());
+                }
+                name
+            }
+            Expression::Assign {
+                name,
+                value,
+                line,
+                column,
+                ..
+            } if matches!(destination, Destination::Discard) => {
+                let (target, value) = self.transpile_assignment(name, *value, (line, column))?;
+                let assignment = format!("{} = {}", target, value);
+                self.line(self.backend.statement(&assignment));
+                return Ok(());
+            }
+            Expression::Function {
+                name,
+                parameters,
+                defaults,
+                rest,
+                body,
+                line,
+                column,
+                ..
+            } => {
+                let parameters = Parameters {
+                    names: parameters,
+                    defaults,
+                    rest,
+                };
+                self.transpile_function(name, parameters, *body, (line, column))?;
+                self.nil()
+            }
+            Expression::Struct {
+                name,
+                fields,
+                methods,
+                line,
+                column,
+                ..
+            } => {
+                self.transpile_struct(name, fields, methods, (line, column))?;
+                self.nil()
+            }
+            Expression::Class {
+                name,
+                superclass,
+                methods,
+                line,
+                column,
+                ..
+            } => {
+                self.transpile_class(name, superclass, methods, (line, column))?;
+                self.nil()
+            }
+            Expression::Enum {
+                name,
+                variants,
+                line,
+                column,
+                ..
+            } => {
+                self.transpile_enum(name, variants, (line, column))?;
+                self.nil()
+            }
+            Expression::Match {
+                value,
+                arms,
+                line,
+                column,
+            } => return self.transpile_match(*value, arms, destination, (line, column)),
+            Expression::For {
+                ident,
+                value_ident,
+                expr,
+                body,
+                line,
+                column,
+            } => {
+                let iterable = self.transpile_expression(*expr)?;
+                let result = self.loop_result(destination);
+                let capturing = self.capture_loop(&[&body]);
+                self.scopes.push(Scope::new(self.functions));
+                let mut names = vec![(ident.clone(), self.declare(&ident, false))];
+                let header = match &value_ident {
+                    Some(value_ident) => {
+                        names.push((value_ident.clone(), self.declare(value_ident, false)));
+                        self.backend
+                            .for_entries_header(&names[0].1, &names[1].1, &iterable)
+                    }
+                    None => self.backend.for_header(&names[0].1, &iterable),
+                };
+                let header = header.ok_or_else(|| {
+                    TranspileError::new(
+                        format!(
+                            "for loops are not supported when targeting {}",
+                            self.backend.name()
+                        ),
+                        (line, column),
+                    )
+                })?;
+                self.loops.push(result.clone());
+                self.line(header);
+                self.depth += 1;
+                // Each iteration puts the loop variables in new cells.
+                for (name, target) in names {
+                    if self.is_captured(&name) {
+                        let cell = self.backend.list(std::slice::from_ref(&target)).unwrap();
+                        self.line(self.backend.declare(&target, &cell));
+                    }
+                }
+                let start = self.lines.len();
+                self.transpile_block(Self::statements(*body), &Destination::Discard)?;
+                self.fill(start);
+                self.depth -= 1;
+                self.loops.pop();
+                self.close();
+                self.scopes.pop();
+                if capturing {
+                    self.captured = None;
+                }
+                result.unwrap_or_else(|| self.nil())
+            }
+            Expression::While {
+                condition, body, ..
+            } => {
+                let result = self.loop_result(destination);
+                let capturing = self.capture_loop(&[&condition, &body]);
+                self.loops.push(result.clone());
+                self.transpile_while(*condition, *body)?;
+                self.loops.pop();
+                if capturing {
+                    self.captured = None;
+                }
+                result.unwrap_or_else(|| self.nil())
+            }
+            Expression::Break { value, .. } => {
+                match self.loops.last().cloned().flatten() {
+                    Some(result) => {
+                        self.transpile_statement(*value, &Destination::Assign(result))?
+                    }
+                    None => self.transpile_statement(*value, &Destination::Discard)?,
+                }
+                self.line(self.backend.statement("break"));
+                return Ok(());
+            }
+            Expression::Continue { .. } => {
+                self.line(self.backend.statement("continue"));
+                return Ok(());
+            }
+            Expression::Value {
+                value: Value::Nil, ..
+            } if matches!(destination, Destination::Discard) => return Ok(()),
+            expression => self.transpile_expression(expression)?,
+        };
+
+        let statement = match destination {
+            Destination::Discard if value == self.nil() => return Ok(()),
+            Destination::Discard => value,
+            Destination::Return => format!("return {}", value),
+            Destination::Assign(name) => format!("{} = {}", name, value),
+        };
+        self.line(self.backend.statement(&statement));
+        Ok(())
+    }
+
+    /// Transpiles an expression to a target expression, emitting any
+    /// statements needed to compute it first.
+    fn transpile_expression(&mut self, expression: Expression) -> Result<String, TranspileError> {
         let output = match expression {
-            Expression::Binary { left, operator, right, .. } => format!("{} {} {}", self.transpile_expression(*left),
-                self.transpile_operator(operator), self.transpile_expression(*right)),
-            Expression::Call { function, arguments, .. } => self.transpile_call(*function, arguments),
-            Expression::Identifier { ident, .. } => ident,
-            Expression::Assign { name, value, .. } => self.transpile_assignment(name, *value),
-            Expression::Value { value, .. } => format!("{}", value),
-            Expression::Let { name, value, .. } => format!("let {}", self.transpile_assignment(name, *value)),
-            Expression::Return { value, .. } => format!("return {}", self.transpile_expression(*value)),
-            Expression::Block { expressions, .. } => self.transpile_block(expressions),
-            Expression::If { condition, consequence, alternative, .. } => todo!(),
-            Expression::Function { name, parameters, body, .. } => todo!(),
-            Expression::For { ident, expr, body, .. } => todo!(),
-        };
-
-        todo!()
+            Expression::Binary {
+                left,
+                operator,
+                right,
+                ..
+            } => {
+                let numbers = [self.number(&left), self.number(&right)];
+                let operands = self.transpile_operands(vec![*left, *right])?;
+                match operator {
+                    Token::Slash => self.backend.divide(&operands[0], &operands[1], numbers),
+                    _ => self.backend.binary(&operator, &operands[0], &operands[1]),
+                }
+            }
+            Expression::Logical {
+                left,
+                operator,
+                right,
+                ..
+            } => self.transpile_logical(operator, *left, *right)?,
+            Expression::Unary {
+                operator, right, ..
+            } => {
+                let right = self.transpile_expression(*right)?;
+                self.backend.unary(&operator, &right)
+            }
+            Expression::Call {
+                function,
+                arguments,
+                named,
+                line,
+                column,
+            } => {
+                if !named.is_empty() {
+                    return Err(TranspileError::new(
+                        format!(
+                            "named arguments are not supported when targeting {}",
+                            self.backend.name()
+                        ),
+                        (line, column),
+                    ));
+                }
+                self.transpile_call(*function, arguments, (line, column))?
+            }
+            Expression::Identifier {
+                ident,
+                line,
+                column,
+                ..
+            } => {
+                if self.backend.hoists_functions()
+                    && (self.is_hoisted(&ident)
+                        || (self.resolve(&ident).is_none()
+                            && self.backend.builtin(&ident).is_some()))
+                {
+                    return Err(TranspileError::new(
+                        format!(
+                            "functions cannot be used as values when targeting {}",
+                            self.backend.name()
+                        ),
+                        (line, column),
+                    ));
+                }
+                self.transpile_identifier(&ident, (line, column))?
+            }
+            Expression::Assign {
+                name,
+                value,
+                line,
+                column,
+                ..
+            } => {
+                let cell = match self.lookup(&name) {
+                    Some((target, scope)) if self.scopes[scope].cells.contains(&name) => {
+                        Some(target)
+                    }
+                    _ => None,
+                };
+                let (target, value) = self.transpile_assignment(name, *value, (line, column))?;
+                match cell {
+                    Some(cell) => self.backend.set_index(&cell, "0", &value).unwrap(),
+                    None => self.backend.assign(&target, &value),
+                }
+            }
+            Expression::Value {
+                value,
+                line,
+                column,
+            } => {
+                self.check_integer(&value, (line, column))?;
+                self.backend.value(&value)
+            }
+            Expression::Range {
+                start,
+                end,
+                step,
+                inclusive,
+                line,
+                column,
+            } => {
+                let mut operands = vec![*start, *end];
+                operands.extend(step.map(|step| *step));
+                let mut operands = self.transpile_operands(operands)?;
+                if operands.len() == 2 {
+                    operands.push(self.backend.value(&Value::Int(1)));
+                }
+                self.backend
+                    .range(&operands[0], &operands[1], &operands[2], inclusive)
+                    .ok_or_else(|| {
+                        TranspileError::new(
+                            format!(
+                                "ranges are not supported when targeting {}",
+                                self.backend.name()
+                            ),
+                            (line, column),
+                        )
+                    })?
+            }
+            Expression::List {
+                elements,
+                line,
+                column,
+            } => {
+                let elements = self.transpile_operands(elements)?;
+                let list = self.backend.list(&elements);
+                self.supported(list, "lists", (line, column))?
+            }
+            Expression::Map {
+                entries,
+                line,
+                column,
+            } => {
+                let operands = entries
+                    .into_iter()
+                    .flat_map(|(key, value)| [key, value])
+                    .collect();
+                let operands = self.transpile_operands(operands)?;
+                let entries: Vec<(String, String)> = operands
+                    .chunks(2)
+                    .map(|entry| (entry[0].clone(), entry[1].clone()))
+                    .collect();
+                let map = self.backend.map(&entries);
+                self.supported(map, "maps", (line, column))?
+            }
+            Expression::Construct {
+                name,
+                fields,
+                line,
+                column,
+                ..
+            } => self.transpile_construct(name, fields, (line, column))?,
+            Expression::Super {
+                method,
+                line,
+                column,
+                ..
+            } => match self.classes.last().cloned() {
+                Some((class, Some(superclass))) => self
+                    .backend
+                    .super_method(&class, &superclass, &self.backend.identifier(&method))
+                    .expect("classes are supported"),
+                _ => {
+                    return Err(TranspileError::new(
+                        "Can't use 'super' outside of a method of a subclass".to_string(),
+                        (line, column),
+                    ))
+                }
+            },
+            Expression::Get {
+                object,
+                name,
+                line,
+                column,
+            } => {
+                let object = self.transpile_expression(*object)?;
+                let field = self.backend.get(&object, &self.backend.identifier(&name));
+                self.supported(field, "structs", (line, column))?
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+                line,
+                column,
+            } => {
+                let operands = self.transpile_operands(vec![*object, *value])?;
+                let assignment =
+                    self.backend
+                        .set(&operands[0], &self.backend.identifier(&name), &operands[1]);
+                self.supported(assignment, "structs", (line, column))?
+            }
+            Expression::Index {
+                object,
+                index,
+                line,
+                column,
+            } => {
+                let operands = self.transpile_operands(vec![*object, *index])?;
+                let element = self.backend.index(&operands[0], &operands[1]);
+                self.supported(element, "lists and maps", (line, column))?
+            }
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                line,
+                column,
+            } => {
+                let operands = self.transpile_operands(vec![*object, *index, *value])?;
+                let assignment = self
+                    .backend
+                    .set_index(&operands[0], &operands[1], &operands[2]);
+                self.supported(assignment, "lists and maps", (line, column))?
+            }
+            Expression::Let {
+                name,
+                value,
+                line,
+                column,
+                ..
+            } => self.transpile_let(name, *value, (line, column))?,
+            // Emitted as a function declared under a temporary name.
+            Expression::Lambda {
+                parameters,
+                defaults,
+                rest,
+                body,
+                line,
+                column,
+            } => {
+                if self.backend.hoists_functions() {
+                    return Err(TranspileError::new(
+                        format!(
+                            "anonymous functions are not supported when targeting {}",
+                            self.backend.name()
+                        ),
+                        (line, column),
+                    ));
+                }
+                let parameters = Parameters {
+                    names: parameters,
+                    defaults,
+                    rest,
+                };
+                let temporary = self.temporary();
+                self.transpile_callable(
+                    parameters,
+                    None,
+                    *body,
+                    (line, column),
+                    |backend, parameters| backend.function_header(&temporary, parameters),
+                )?;
+                temporary
+            }
+            expression @ (Expression::Block { .. }
+            | Expression::If { .. }
+            | Expression::Match { .. }
+            | Expression::For { .. }
+            | Expression::While { .. }) => {
+                let temporary = self.temporary();
+                if let Some(declaration) = self.backend.temporary(&temporary) {
+                    self.line(declaration);
+                }
+                self.transpile_statement(expression, &Destination::Assign(temporary.clone()))?;
+                temporary
+            }
+            expression @ (Expression::Return { .. }
+            | Expression::Function { .. }
+            | Expression::Struct { .. }
+            | Expression::Class { .. }
+            | Expression::Enum { .. }
+            | Expression::Break { .. }
+            | Expression::Continue { .. }) => {
+                self.transpile_statement(expression, &Destination::Discard)?;
+                self.nil()
+            }
+        };
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transpile(input: &str, target: &str) -> Result<String, TranspileError> {
+        let backend = backend(target).expect("the target exists");
+        Transpiler::new(input.to_string(), backend).transpile()
+    }
+
+    fn error(input: &str, target: &str) -> TranspileError {
+        match transpile(input, target) {
+            Ok(source) => panic!("transpiled to\n{}", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn targets() {
+        for (target, name) in [("js", "JavaScript"), ("py", "Python"), ("c", "C")] {
+            assert_eq!(backend(target).unwrap().name(), name);
+        }
+        assert!(backend("rb").is_none());
+    }
+
+    #[test]
+    fn parse_and_resolve_errors_are_reported() {
+        for target in ["js", "py", "c"] {
+            assert_eq!(error("let = 1;", target).message, "expected identifier");
+            assert_eq!(error("print(x);", target).message, "undefined variable 'x'");
+        }
+    }
+
+    #[test]
+    fn quoted_strings_escape_special_characters() {
+        assert_eq!(quote("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+    }
+
+    #[test]
+    fn hoisted_calls_are_checked_against_the_arity() {
+        let e = error("fn f(a, b = 1) { a } f();", "c");
+        assert_eq!(e.message, "Expected 1 to 2 arguments but got 0");
+        assert_eq!((e.line, e.column), (1, 24));
+        assert_eq!(
+            error("fn f(a) { a } f(1, 2);", "c").message,
+            "Expected 1 arguments but got 2"
+        );
+        assert!(transpile("fn f(a) { a } f(1, 2);", "js").is_ok());
+    }
+
+    #[test]
+    fn unsupported_features_name_the_target() {
+        assert_eq!(
+            error("let l = [1];", "c").message,
+            "lists are not supported when targeting C"
+        );
+    }
+
+    #[test]
+    fn rest_parameters_are_not_supported_in_c() {
+        assert_eq!(
+            error("fn f(...r) { 1 }", "c").message,
+            "rest parameters are not supported when targeting C"
+        );
+    }
+
+    #[test]
+    fn reserved_names_are_renamed_per_target() {
+        let program = "let arguments = 1; let None = 2; let int = 3;";
+        let js = transpile(program, "js").ok().unwrap();
+        assert!(js.contains("let arguments_ = 1;"));
+        assert!(js.contains("let None = 2;"));
+        let py = transpile(program, "py").ok().unwrap();
+        assert!(py.contains("arguments = 1\n"));
+        assert!(py.contains("None_ = 2\n"));
+        let c = transpile(program, "c").ok().unwrap();
+        assert!(c.contains("int_ = kox_int(3LL);"));
     }
 }
@@ -0,0 +1,630 @@
+use crate::ast::Value;
+use crate::token::Token;
+
+use super::{quote, Backend, Output};
+
+/// Keywords and the macros of the headers the runtime includes.
+const RESERVED: &[&str] = &[
+    "BUFSIZ",
+    "EOF",
+    "EXIT_FAILURE",
+    "EXIT_SUCCESS",
+    "FILENAME_MAX",
+    "FOPEN_MAX",
+    "MB_CUR_MAX",
+    "NULL",
+    "RAND_MAX",
+    "SEEK_CUR",
+    "SEEK_END",
+    "SEEK_SET",
+    "TMP_MAX",
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "errno",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "offsetof",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "stderr",
+    "stdin",
+    "stdout",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+];
+
+/// A dynamically typed value and the operations on it, following the
+/// interpreter's rules. Errors are reported and end the program. Programs
+/// need linking with the math library (`-lm`).
+const PRELUDE: &str = r#"#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* KOX_MISSING is passed for parameters a call leaves out, which the function
+   replaces with their default values. */
+typedef enum { KOX_NIL, KOX_INT, KOX_FLOAT, KOX_BOOLEAN, KOX_STRING, KOX_MISSING } kox_type;
+
+typedef struct {
+    kox_type type;
+    union {
+        long long i;
+        double f;
+        int b;
+        const char *s;
+    } as;
+} kox_value;
+
+static inline kox_value kox_nil(void) {
+    kox_value value;
+    value.type = KOX_NIL;
+    value.as.i = 0;
+    return value;
+}
+
+static inline kox_value kox_missing(void) {
+    kox_value value;
+    value.type = KOX_MISSING;
+    value.as.i = 0;
+    return value;
+}
+
+static inline kox_value kox_int(long long i) {
+    kox_value value;
+    value.type = KOX_INT;
+    value.as.i = i;
+    return value;
+}
+
+static inline kox_value kox_float(double f) {
+    kox_value value;
+    value.type = KOX_FLOAT;
+    value.as.f = f;
+    return value;
+}
+
+static inline kox_value kox_boolean(int b) {
+    kox_value value;
+    value.type = KOX_BOOLEAN;
+    value.as.b = b != 0;
+    return value;
+}
+
+static inline kox_value kox_string(const char *s) {
+    kox_value value;
+    value.type = KOX_STRING;
+    value.as.s = s;
+    return value;
+}
+
+static inline kox_value kox_error(const char *message, const char *operator) {
+    if (operator) {
+        fprintf(stderr, "runtime error: %s: %s\n", message, operator);
+    } else {
+        fprintf(stderr, "runtime error: %s\n", message);
+    }
+    exit(1);
+}
+
+static inline kox_value kox_invalid(kox_value left, const char *operator) {
+    switch (left.type) {
+    case KOX_INT:
+        return kox_error("Invalid operator for integers", operator);
+    case KOX_FLOAT:
+        return kox_error("Invalid operator for floats", operator);
+    case KOX_STRING:
+        return kox_error("Invalid operator for strings", operator);
+    case KOX_BOOLEAN:
+        return kox_error("Invalid operator for booleans", operator);
+    default:
+        return kox_error("Invalid operands for operator", operator);
+    }
+}
+
+/* Integers mixed with floats are promoted. */
+static inline void kox_check(kox_value *left, kox_value *right) {
+    if (left->type == KOX_INT && right->type == KOX_FLOAT) {
+        *left = kox_float((double)left->as.i);
+    } else if (left->type == KOX_FLOAT && right->type == KOX_INT) {
+        *right = kox_float((double)right->as.i);
+    } else if (left->type != right->type) {
+        kox_error("Operands must be of the same type", NULL);
+    }
+}
+
+static inline void kox_check_overflow(int overflow) {
+    if (overflow) {
+        kox_error("Integer overflow", NULL);
+    }
+}
+
+static inline kox_value kox_add(kox_value left, kox_value right) {
+    long long result;
+    kox_check(&left, &right);
+    if (left.type == KOX_INT) {
+        kox_check_overflow(__builtin_add_overflow(left.as.i, right.as.i, &result));
+        return kox_int(result);
+    }
+    if (left.type == KOX_FLOAT) {
+        return kox_float(left.as.f + right.as.f);
+    }
+    if (left.type == KOX_STRING) {
+        size_t a = strlen(left.as.s);
+        size_t b = strlen(right.as.s);
+        char *s = malloc(a + b + 1);
+        memcpy(s, left.as.s, a);
+        memcpy(s + a, right.as.s, b + 1);
+        return kox_string(s);
+    }
+    return kox_invalid(left, "Plus");
+}
+
+static inline kox_value kox_subtract(kox_value left, kox_value right) {
+    long long result;
+    kox_check(&left, &right);
+    if (left.type == KOX_INT) {
+        kox_check_overflow(__builtin_sub_overflow(left.as.i, right.as.i, &result));
+        return kox_int(result);
+    }
+    if (left.type == KOX_FLOAT) {
+        return kox_float(left.as.f - right.as.f);
+    }
+    return kox_invalid(left, "Minus");
+}
+
+static inline kox_value kox_multiply(kox_value left, kox_value right) {
+    long long result;
+    kox_check(&left, &right);
+    if (left.type == KOX_INT) {
+        kox_check_overflow(__builtin_mul_overflow(left.as.i, right.as.i, &result));
+        return kox_int(result);
+    }
+    if (left.type == KOX_FLOAT) {
+        return kox_float(left.as.f * right.as.f);
+    }
+    return kox_invalid(left, "Asterisk");
+}
+
+/* Integer division rounds towards negative infinity, so the remainder takes
+   the sign of the divisor. */
+static inline kox_value kox_divide(kox_value left, kox_value right) {
+    long long quotient;
+    kox_check(&left, &right);
+    if (left.type == KOX_INT) {
+        if (right.as.i == 0) {
+            return kox_error("Division by zero", NULL);
+        }
+        kox_check_overflow(left.as.i == -9223372036854775807LL - 1 && right.as.i == -1);
+        quotient = left.as.i / right.as.i;
+        if (left.as.i % right.as.i != 0 && (left.as.i < 0) != (right.as.i < 0)) {
+            quotient--;
+        }
+        return kox_int(quotient);
+    }
+    if (left.type == KOX_FLOAT) {
+        return kox_float(left.as.f / right.as.f);
+    }
+    return kox_invalid(left, "Slash");
+}
+
+static inline kox_value kox_modulo(kox_value left, kox_value right) {
+    long long remainder;
+    kox_check(&left, &right);
+    if (left.type == KOX_INT) {
+        if (right.as.i == 0) {
+            return kox_error("Division by zero", NULL);
+        }
+        remainder = right.as.i == -1 ? 0 : left.as.i % right.as.i;
+        if (remainder != 0 && (remainder < 0) != (right.as.i < 0)) {
+            remainder += right.as.i;
+        }
+        return kox_int(remainder);
+    }
+    if (left.type == KOX_FLOAT) {
+        return kox_float(left.as.f - floor(left.as.f / right.as.f) * right.as.f);
+    }
+    return kox_invalid(left, "Percent");
+}
+
+static inline kox_value kox_power(kox_value left, kox_value right) {
+    long long result = 1;
+    long long base;
+    long long exponent;
+    kox_check(&left, &right);
+    if (left.type == KOX_INT && right.as.i < 0) {
+        return kox_float(pow((double)left.as.i, (double)right.as.i));
+    }
+    if (left.type == KOX_INT) {
+        base = left.as.i;
+        for (exponent = right.as.i; exponent > 0; exponent >>= 1) {
+            if (exponent & 1) {
+                kox_check_overflow(__builtin_mul_overflow(result, base, &result));
+            }
+            if (exponent > 1) {
+                kox_check_overflow(__builtin_mul_overflow(base, base, &base));
+            }
+        }
+        return kox_int(result);
+    }
+    if (left.type == KOX_FLOAT) {
+        return kox_float(pow(left.as.f, right.as.f));
+    }
+    return kox_invalid(left, "Pow");
+}
+
+static inline kox_value kox_compare(kox_value left, kox_value right, const char *operator) {
+    kox_check(&left, &right);
+    if (left.type == KOX_INT) {
+        return kox_int(left.as.i < right.as.i ? -1 : left.as.i > right.as.i);
+    }
+    if (left.type == KOX_FLOAT) {
+        /* 2 when unordered, so that comparisons with NaN are false. */
+        if (left.as.f < right.as.f) {
+            return kox_int(-1);
+        }
+        return kox_int(left.as.f > right.as.f ? 1 : left.as.f == right.as.f ? 0 : 2);
+    }
+    return kox_invalid(left, operator);
+}
+
+static inline kox_value kox_less(kox_value left, kox_value right) {
+    return kox_boolean(kox_compare(left, right, "LessThan").as.i == -1);
+}
+
+static inline kox_value kox_greater(kox_value left, kox_value right) {
+    return kox_boolean(kox_compare(left, right, "GreaterThan").as.i == 1);
+}
+
+static inline kox_value kox_less_equal(kox_value left, kox_value right) {
+    long long order = kox_compare(left, right, "LessThanEqual").as.i;
+    return kox_boolean(order == -1 || order == 0);
+}
+
+static inline kox_value kox_greater_equal(kox_value left, kox_value right) {
+    long long order = kox_compare(left, right, "GreaterThanEqual").as.i;
+    return kox_boolean(order == 0 || order == 1);
+}
+
+/* Values of different kinds are never equal, except for numbers. */
+static inline kox_value kox_equal(kox_value left, kox_value right) {
+    if (left.type == KOX_INT && right.type == KOX_FLOAT) {
+        return kox_boolean((double)left.as.i == right.as.f);
+    }
+    if (left.type == KOX_FLOAT && right.type == KOX_INT) {
+        return kox_boolean(left.as.f == (double)right.as.i);
+    }
+    if (left.type != right.type) {
+        return kox_boolean(0);
+    }
+    switch (left.type) {
+    case KOX_INT:
+        return kox_boolean(left.as.i == right.as.i);
+    case KOX_FLOAT:
+        return kox_boolean(left.as.f == right.as.f);
+    case KOX_BOOLEAN:
+        return kox_boolean(left.as.b == right.as.b);
+    case KOX_STRING:
+        return kox_boolean(strcmp(left.as.s, right.as.s) == 0);
+    default:
+        return kox_boolean(1);
+    }
+}
+
+static inline kox_value kox_not_equal(kox_value left, kox_value right) {
+    return kox_boolean(!kox_equal(left, right).as.b);
+}
+
+static inline kox_value kox_negate(kox_value right) {
+    if (right.type == KOX_INT) {
+        if (right.as.i == -9223372036854775807LL - 1) {
+            return kox_error("Integer overflow", NULL);
+        }
+        return kox_int(-right.as.i);
+    }
+    if (right.type == KOX_FLOAT) {
+        return kox_float(-right.as.f);
+    }
+    return kox_error("Operand must be a number", NULL);
+}
+
+static inline kox_value kox_not(kox_value right) {
+    if (right.type == KOX_BOOLEAN) {
+        return kox_boolean(!right.as.b);
+    }
+    return kox_error("Operand must be a boolean", NULL);
+}
+
+static inline int kox_truthy(kox_value value) {
+    if (value.type != KOX_BOOLEAN) {
+        kox_error("Condition must be a boolean", NULL);
+    }
+    return value.as.b;
+}
+
+/* Prints the shortest fixed notation that reads back as the same number. */
+static inline void kox_print_float(double f) {
+    char buffer[512];
+    int precision;
+    if (f != f) {
+        puts("NaN");
+        return;
+    }
+    if (f - f != 0) {
+        puts(f > 0 ? "inf" : "-inf");
+        return;
+    }
+    for (precision = 0; precision < 400; precision++) {
+        snprintf(buffer, sizeof buffer, "%.*f", precision, f);
+        if (strtod(buffer, NULL) == f) {
+            break;
+        }
+    }
+    puts(buffer);
+}
+
+static inline kox_value kox_print(kox_value value) {
+    switch (value.type) {
+    case KOX_NIL:
+    case KOX_MISSING:
+        puts("nil");
+        break;
+    case KOX_INT:
+        printf("%lld\n", value.as.i);
+        break;
+    case KOX_FLOAT:
+        kox_print_float(value.as.f);
+        break;
+    case KOX_BOOLEAN:
+        puts(value.as.b ? "true" : "false");
+        break;
+    case KOX_STRING:
+        puts(value.as.s);
+        break;
+    }
+    return kox_nil();
+}
+"#;
+
+pub struct C;
+
+impl Backend for C {
+    fn name(&self) -> &'static str {
+        "C"
+    }
+
+    fn identifier(&self, ident: &str) -> String {
+        if RESERVED.contains(&ident) {
+            format!("{}_", ident)
+        } else {
+            ident.to_string()
+        }
+    }
+
+    /// Everything at file scope gets a suffix so that it cannot collide with
+    /// the C library.
+    fn global(&self, ident: &str) -> String {
+        format!("{}_", ident)
+    }
+
+    fn builtin(&self, ident: &str) -> Option<String> {
+        (ident == "print").then(|| "kox_print".to_string())
+    }
+
+    fn value(&self, value: &Value) -> String {
+        match value {
+            Value::Int(i64::MIN) => "kox_int(-9223372036854775807LL - 1)".to_string(),
+            Value::Int(i) => format!("kox_int({}LL)", i),
+            Value::BigInt(_) => unreachable!("rejected by the transpiler"),
+            Value::Float(f) => format!("kox_float({:?})", f),
+            Value::String(s) => format!("kox_string({})", quote(s)),
+            Value::Boolean(b) => format!("kox_boolean({})", *b as u8),
+            Value::Nil => "kox_nil()".to_string(),
+        }
+    }
+
+    fn binary(&self, operator: &Token, left: &str, right: &str) -> String {
+        let function = match operator {
+            Token::Plus => "kox_add",
+            Token::Minus => "kox_subtract",
+            Token::Asterisk => "kox_multiply",
+            Token::Slash => "kox_divide",
+            Token::Percent => "kox_modulo",
+            Token::Pow => "kox_power",
+            Token::EqEq => "kox_equal",
+            Token::BangEq => "kox_not_equal",
+            Token::LessThan => "kox_less",
+            Token::GreaterThan => "kox_greater",
+            Token::LessThanEqual => "kox_less_equal",
+            Token::GreaterThanEqual => "kox_greater_equal",
+            _ => panic!("Invalid operator: {:?}", operator),
+        };
+        format!("{}({}, {})", function, left, right)
+    }
+
+    fn unary(&self, operator: &Token, right: &str) -> String {
+        let function = match operator {
+            Token::Minus => "kox_negate",
+            Token::Bang => "kox_not",
+            _ => panic!("Invalid unary operator: {:?}", operator),
+        };
+        format!("{}({})", function, right)
+    }
+
+    fn logical(&self, operator: &Token, left: &str, right: &str) -> String {
+        let operator = match operator {
+            Token::And => "&&",
+            Token::Or => "||",
+            _ => panic!("Invalid logical operator: {:?}", operator),
+        };
+        format!(
+            "kox_boolean(kox_truthy({}) {} kox_truthy({}))",
+            left, operator, right
+        )
+    }
+
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("({} = {})", name, value)
+    }
+
+    fn statement(&self, expression: &str) -> String {
+        format!("{};", expression)
+    }
+
+    fn declare(&self, name: &str, value: &str) -> String {
+        format!("kox_value {} = {};", name, value)
+    }
+
+    fn temporary(&self, name: &str) -> Option<String> {
+        Some(format!("kox_value {};", name))
+    }
+
+    fn if_header(&self, condition: &str) -> String {
+        format!("if (kox_truthy({})) {{", condition)
+    }
+
+    fn else_header(&self) -> String {
+        "} else {".to_string()
+    }
+
+    fn for_header(&self, _ident: &str, _iterable: &str) -> Option<String> {
+        None
+    }
+
+    fn while_header(&self, condition: &str) -> String {
+        format!("while (kox_truthy({})) {{", condition)
+    }
+
+    fn function_header(&self, name: &str, parameters: &[String]) -> String {
+        format!("{} {{", prototype(name, parameters))
+    }
+
+    fn optional_parameter(&self, name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    fn missing(&self, name: &str) -> String {
+        format!("kox_boolean({}.type == KOX_MISSING)", name)
+    }
+
+    fn missing_argument(&self) -> String {
+        "kox_missing()".to_string()
+    }
+
+    fn open(&self) -> Option<String> {
+        Some("{".to_string())
+    }
+
+    fn close(&self) -> Option<String> {
+        Some("}".to_string())
+    }
+
+    fn ordered(&self) -> bool {
+        false
+    }
+
+    fn hoists_functions(&self) -> bool {
+        true
+    }
+
+    fn finish(&self, output: Output) -> String {
+        let mut source = String::from(PRELUDE);
+        if !output.globals.is_empty() {
+            source.push('\n');
+            for global in &output.globals {
+                source.push_str(&format!("static kox_value {};\n", global));
+            }
+        }
+        if !output.functions.is_empty() {
+            source.push('\n');
+            for (name, parameters) in &output.functions {
+                source.push_str(&format!("{};\n", prototype(name, parameters)));
+            }
+        }
+        for line in &output.definitions {
+            if line.starts_with("static") {
+                source.push('\n');
+            }
+            source.push_str(line);
+            source.push('\n');
+        }
+        source.push_str("\nint main(void) {\n");
+        for line in &output.body {
+            source.push_str(&format!("    {}\n", line));
+        }
+        source.push_str("    return 0;\n}\n");
+        source
+    }
+}
+
+fn prototype(name: &str, parameters: &[String]) -> String {
+    let parameters: Vec<String> = parameters
+        .iter()
+        .map(|parameter| format!("kox_value {}", parameter))
+        .collect();
+    if parameters.is_empty() {
+        format!("static kox_value {}(void)", name)
+    } else {
+        format!("static kox_value {}({})", name, parameters.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transpiler::Transpiler;
+
+    fn transpile(input: &str) -> String {
+        Transpiler::new(input.to_string(), Box::new(C))
+            .transpile()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn functions_are_declared_before_main() {
+        let output = transpile("fn g() { f(1) } fn f(a) { a + 1 } print(g());");
+        let prototype = output.find("static kox_value f_(kox_value a);").unwrap();
+        let main = output.find("int main(void) {").unwrap();
+        assert!(prototype < main);
+        assert!(output.contains("return f_(kox_int(1LL));"));
+    }
+
+    #[test]
+    fn calls_fill_in_missing_arguments() {
+        let output = transpile("fn f(a, b = a * 2) { b } print(f(1));");
+        assert!(output.contains("f_(kox_int(1LL), kox_missing())"));
+        assert!(output.contains("if (kox_truthy(kox_boolean(b.type == KOX_MISSING))) {"));
+    }
+
+    #[test]
+    fn globals_are_static() {
+        let output = transpile("let x = 2; print(x / 2);");
+        assert!(output.contains("static kox_value x_;"));
+        assert!(output.contains("kox_print(kox_divide(x_, kox_int(2LL)));"));
+    }
+}
@@ -0,0 +1,739 @@
+use crate::ast::Value;
+use crate::token::Token;
+
+use super::{quote, Backend, Number, Output};
+
+const RESERVED: &[&str] = &[
+    "arguments",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "console",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "eval",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "Infinity",
+    "instanceof",
+    "interface",
+    "let",
+    "NaN",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "undefined",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+const PRELUDE: &str = "function print(value) {
+    if (value === null) {
+        value = \"nil\";
+    } else if (Array.isArray(value) || value instanceof Map || value?._kox_name) {
+        value = _kox_str(value);
+    } else if (typeof value === \"object\") {
+        value = String(value);
+    }
+    console.log(value);
+    return null;
+}
+";
+
+/// How values print inside lists, maps and structs.
+const STR: &str = "function _kox_str(value) {
+    if (value === null) {
+        return \"nil\";
+    } else if (Array.isArray(value)) {
+        return `[${value.map(_kox_str).join(\", \")}]`;
+    } else if (value instanceof Map) {
+        const entries = [...value.entries()].map(([k, v]) => `${_kox_str(k)}: ${_kox_str(v)}`);
+        return `{${entries.join(\", \")}}`;
+    } else if (typeof value === \"function\" && value._kox_name) {
+        const kind = value._kox_enum
+            ? \"enum\"
+            : value.prototype instanceof _KoxStruct
+              ? \"struct\"
+              : \"class\";
+        return `<${kind} ${value._kox_name}>`;
+    }
+    return String(value);
+}
+";
+
+/// Kox compares lists, maps, struct instances, variants and ranges by their
+/// contents, where JavaScript compares objects by reference. Values that can
+/// be compared this way have a `_kox_equals` method.
+const EQUALITY: &str = "function _kox_eq(left, right) {
+    if (left === right) {
+        return true;
+    } else if (Array.isArray(left) && Array.isArray(right)) {
+        return left.length === right.length && left.every((item, i) => _kox_eq(item, right[i]));
+    } else if (left instanceof Map && right instanceof Map) {
+        if (left.size !== right.size) {
+            return false;
+        }
+        for (const [key, value] of left.entries()) {
+            if (!right.has(key) || !_kox_eq(value, right.get(key))) {
+                return false;
+            }
+        }
+        return true;
+    } else if (typeof left?._kox_equals === \"function\") {
+        return left._kox_equals(right);
+    }
+    return false;
+}
+";
+
+/// Kox ranges are lazy and can be iterated more than once.
+const RANGE: &str = "function _kox_range(start, end, step, inclusive) {
+    if (step === 0) {
+        throw new Error(\"Range step cannot be zero\");
+    }
+    const past = step > 0 ? (i) => i > end : (i) => i < end;
+    return {
+        *[Symbol.iterator]() {
+            for (let i = start; !past(i) && (inclusive || i !== end); i += step) {
+                yield i;
+            }
+        },
+        _kox_bounds: [start, end, step, inclusive],
+        _kox_equals(other) {
+            return _kox_eq(this._kox_bounds, other?._kox_bounds);
+        },
+        toString() {
+            const text = `${start}${inclusive ? \"..=\" : \"..\"}${end}`;
+            return step === 1 ? text : `${text} step ${step}`;
+        },
+    };
+}
+";
+
+/// Kox divides integers to an integer rounded down, and dividing an integer
+/// by zero is an error. `_kox_idiv` divides operands known to be integers.
+/// JavaScript has no separate integers, so `_kox_div` can only tell those
+/// that might be floats apart by their values, and divides floats with whole
+/// values like integers.
+const DIVISION: &str = "function _kox_idiv(left, right) {
+    if (right === 0) {
+        throw new Error(\"Division by zero\");
+    }
+    return Math.floor(left / right);
+}
+
+function _kox_div(left, right) {
+    if (Number.isInteger(left) && Number.isInteger(right)) {
+        return _kox_idiv(left, right);
+    }
+    return left / right;
+}
+";
+
+/// Kox `%` takes the sign of the divisor, unlike JavaScript's.
+const MODULO: &str = "function _kox_mod(left, right) {
+    return left - right * Math.floor(left / right);
+}
+";
+
+/// Kox lists are arrays whose indices are checked, and its list functions
+/// are not methods. Indexing also looks up keys that must be in a map.
+const LIST: &str = "function _kox_offset(list, index) {
+    const offset = index < 0 ? index + list.length : index;
+    if (!Number.isInteger(offset) || offset < 0 || offset >= list.length) {
+        throw new Error(`List index ${index} out of range`);
+    }
+    return offset;
+}
+
+function _kox_index(list, index) {
+    if (list instanceof Map) {
+        if (!list.has(index)) {
+            throw new Error(`Key ${index} not found in map`);
+        }
+        return list.get(index);
+    }
+    return list[_kox_offset(list, index)];
+}
+
+function _kox_set_index(list, index, value) {
+    if (list instanceof Map) {
+        list.set(index, value);
+        return value;
+    }
+    return (list[_kox_offset(list, index)] = value);
+}
+
+function _kox_len(value) {
+    if (value instanceof Map) {
+        return value.size;
+    }
+    return Array.isArray(value) ? value.length : [...value].length;
+}
+
+function _kox_push(list, value) {
+    list.push(value);
+    return null;
+}
+
+function _kox_pop(list) {
+    if (list.length === 0) {
+        throw new Error(\"Cannot pop from an empty list\");
+    }
+    return list.pop();
+}
+
+function _kox_slice(list, start, end) {
+    return list.slice(start, end);
+}
+";
+
+/// Kox maps iterate over their keys, where a JavaScript `Map` gives entries.
+const MAP: &str = "class _KoxMap extends Map {
+    *[Symbol.iterator]() {
+        yield* this.keys();
+    }
+}
+
+function _kox_keys(map) {
+    return [...map.keys()];
+}
+
+function _kox_values(map) {
+    return [...map.values()];
+}
+
+function _kox_has(map, key) {
+    return map.has(key);
+}
+
+function _kox_remove(map, key) {
+    const value = map.has(key) ? map.get(key) : null;
+    map.delete(key);
+    return value;
+}
+";
+
+/// Struct and class instances print the way Kox does, their methods stay
+/// bound to them when read without being called and classes are initialized
+/// by `init`.
+const STRUCT: &str = "function _kox_bind(instance) {
+    let methods = Object.getPrototypeOf(instance);
+    for (; methods !== Object.prototype; methods = Object.getPrototypeOf(methods)) {
+        for (const name of Object.getOwnPropertyNames(methods)) {
+            if (name !== \"constructor\" && !Object.hasOwn(instance, name)) {
+                const value = methods[name].bind(instance);
+                Object.defineProperty(instance, name, { value, writable: true });
+            }
+        }
+    }
+}
+
+class _KoxStruct {
+    constructor() {
+        _kox_bind(this);
+    }
+
+    _kox_equals(other) {
+        const fields = Object.keys(this);
+        return (
+            other?.constructor === this.constructor &&
+            fields.length === Object.keys(other).length &&
+            fields.every((field) => _kox_eq(this[field], other[field]))
+        );
+    }
+
+    toString() {
+        const fields = Object.entries(this).map(([k, v]) => `${k}: ${_kox_str(v)}`);
+        const name = this.constructor._kox_name;
+        return fields.length === 0 ? `${name} {}` : `${name} { ${fields.join(\", \")} }`;
+    }
+}
+
+class _KoxObject {
+    constructor(...args) {
+        _kox_bind(this);
+        if (this.init) {
+            this.init(...args);
+        }
+    }
+
+    toString() {
+        return `<instance of ${this.constructor._kox_name}>`;
+    }
+}
+";
+
+/// Enums are classes whose instances are their variants, which hold their
+/// fields in order and print the way Kox does.
+const ENUM: &str = "class _KoxVariant {
+    static _kox_enum = true;
+
+    constructor(variant, values) {
+        this._kox_variant = variant;
+        this._kox_values = values;
+    }
+
+    _kox_equals(other) {
+        return (
+            other?.constructor === this.constructor &&
+            other._kox_variant === this._kox_variant &&
+            _kox_eq(this._kox_values, other._kox_values)
+        );
+    }
+
+    toString() {
+        const values = this._kox_values.map(_kox_str).join(\", \");
+        return this._kox_values.length === 0 ? this._kox_variant : `${this._kox_variant}(${values})`;
+    }
+}
+";
+
+/// The helpers in `MAP` a program can refer to.
+const MAP_HELPERS: &[&str] = &[
+    "_KoxMap",
+    "_kox_keys",
+    "_kox_values",
+    "_kox_has",
+    "_kox_remove",
+];
+
+/// The helpers in `LIST` a program can refer to.
+const LIST_HELPERS: &[&str] = &[
+    "_kox_index(",
+    "_kox_set_index(",
+    "_kox_len",
+    "_kox_push",
+    "_kox_pop",
+    "_kox_slice",
+];
+
+pub struct JavaScript;
+
+impl Backend for JavaScript {
+    fn name(&self) -> &'static str {
+        "JavaScript"
+    }
+
+    /// Numbers are doubles, which hold integers exactly up to 2^53.
+    fn integer_bits(&self) -> Option<u32> {
+        Some(53)
+    }
+
+    fn identifier(&self, ident: &str) -> String {
+        if RESERVED.contains(&ident) {
+            format!("{}_", ident)
+        } else {
+            ident.to_string()
+        }
+    }
+
+    fn builtin(&self, ident: &str) -> Option<String> {
+        match ident {
+            "print" => Some(ident.to_string()),
+            "len" | "push" | "pop" | "slice" | "keys" | "values" | "has" | "remove" => {
+                Some(format!("_kox_{}", ident))
+            }
+            _ => None,
+        }
+    }
+
+    fn value(&self, value: &Value) -> String {
+        match value {
+            Value::Int(i) => i.to_string(),
+            Value::BigInt(_) => unreachable!("rejected by the transpiler"),
+            Value::Float(f) => format!("{:?}", f),
+            Value::String(s) => quote(s),
+            Value::Boolean(b) => b.to_string(),
+            Value::Nil => "null".to_string(),
+        }
+    }
+
+    fn binary(&self, operator: &Token, left: &str, right: &str) -> String {
+        let operator = match operator {
+            Token::Percent => return format!("_kox_mod({}, {})", left, right),
+            Token::Slash => return format!("_kox_div({}, {})", left, right),
+            Token::EqEq => return format!("_kox_eq({}, {})", left, right),
+            Token::BangEq => return format!("(!_kox_eq({}, {}))", left, right),
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Asterisk => "*",
+            Token::Pow => "**",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessThanEqual => "<=",
+            Token::GreaterThanEqual => ">=",
+            _ => panic!("Invalid operator: {:?}", operator),
+        };
+        format!("({} {} {})", left, operator, right)
+    }
+
+    fn divide(&self, left: &str, right: &str, numbers: [Number; 2]) -> String {
+        match numbers {
+            [Number::Int, Number::Int] => format!("_kox_idiv({}, {})", left, right),
+            [Number::Float, _] | [_, Number::Float] => format!("({} / {})", left, right),
+            _ => format!("_kox_div({}, {})", left, right),
+        }
+    }
+
+    fn unary(&self, operator: &Token, right: &str) -> String {
+        let operator = match operator {
+            Token::Minus => "-",
+            Token::Bang => "!",
+            _ => panic!("Invalid unary operator: {:?}", operator),
+        };
+        format!("({}{})", operator, right)
+    }
+
+    fn logical(&self, operator: &Token, left: &str, right: &str) -> String {
+        let operator = match operator {
+            Token::And => "&&",
+            Token::Or => "||",
+            _ => panic!("Invalid logical operator: {:?}", operator),
+        };
+        format!("({} {} {})", left, operator, right)
+    }
+
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("({} = {})", name, value)
+    }
+
+    fn statement(&self, expression: &str) -> String {
+        format!("{};", expression)
+    }
+
+    fn declare(&self, name: &str, value: &str) -> String {
+        format!("let {} = {};", name, value)
+    }
+
+    fn temporary(&self, name: &str) -> Option<String> {
+        Some(format!("let {};", name))
+    }
+
+    fn if_header(&self, condition: &str) -> String {
+        format!("if ({}) {{", condition)
+    }
+
+    fn else_header(&self) -> String {
+        "} else {".to_string()
+    }
+
+    fn for_header(&self, ident: &str, iterable: &str) -> Option<String> {
+        Some(format!("for (const {} of {}) {{", ident, iterable))
+    }
+
+    fn for_entries_header(&self, key: &str, value: &str, iterable: &str) -> Option<String> {
+        Some(format!(
+            "for (const [{}, {}] of {}.entries()) {{",
+            key, value, iterable
+        ))
+    }
+
+    fn while_header(&self, condition: &str) -> String {
+        format!("while ({}) {{", condition)
+    }
+
+    fn function_header(&self, name: &str, parameters: &[String]) -> String {
+        format!("function {}({}) {{", name, parameters.join(", "))
+    }
+
+    fn default_parameter(&self, name: &str, default: &str) -> Option<String> {
+        Some(format!("{} = {}", name, default))
+    }
+
+    fn optional_parameter(&self, name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+
+    /// Kox's nil is `null`, so only a parameter left out is `undefined`.
+    fn missing(&self, name: &str) -> String {
+        format!("{} === undefined", name)
+    }
+
+    fn rest_parameter(&self, name: &str) -> Option<String> {
+        Some(format!("...{}", name))
+    }
+
+    fn struct_header(&self, name: &str, kox_name: &str, fields: &[String]) -> Option<Vec<String>> {
+        let mut header = vec![
+            format!("class {} extends _KoxStruct {{", name),
+            format!("    static _kox_name = {};", quote(kox_name)),
+            format!("    constructor({}) {{", fields.join(", ")),
+            "        super();".to_string(),
+        ];
+        header.extend(
+            fields
+                .iter()
+                .map(|field| format!("        this.{} = {};", field, field)),
+        );
+        header.push("    }".to_string());
+        Some(header)
+    }
+
+    fn class_header(
+        &self,
+        name: &str,
+        kox_name: &str,
+        superclass: Option<&str>,
+    ) -> Option<Vec<String>> {
+        Some(vec![
+            format!(
+                "class {} extends {} {{",
+                name,
+                superclass.unwrap_or("_KoxObject")
+            ),
+            format!("    static _kox_name = {};", quote(kox_name)),
+        ])
+    }
+
+    fn method_header(&self, name: &str, parameters: &[String]) -> String {
+        format!("{}({}) {{", name, parameters.join(", "))
+    }
+
+    fn receiver(&self) -> Option<String> {
+        Some("const self = this;".to_string())
+    }
+
+    fn construct(&self, name: &str, fields: &[String]) -> Option<String> {
+        Some(format!("new {}({})", name, fields.join(", ")))
+    }
+
+    fn get(&self, object: &str, name: &str) -> Option<String> {
+        Some(format!("{}.{}", object, name))
+    }
+
+    fn set(&self, object: &str, name: &str, value: &str) -> Option<String> {
+        Some(format!("({}.{} = {})", object, name, value))
+    }
+
+    fn enum_declaration(
+        &self,
+        name: &str,
+        kox_name: &str,
+        variants: &[(String, Vec<String>)],
+    ) -> Option<Vec<String>> {
+        let mut declaration = vec![
+            format!("class {} extends _KoxVariant {{", name),
+            format!("    static _kox_name = {};", quote(kox_name)),
+        ];
+        declaration.extend(variants.iter().map(|(variant, fields)| {
+            let value = format!("new {}({}, [{}])", name, quote(variant), fields.join(", "));
+            let member = self.identifier(variant);
+            if fields.is_empty() {
+                format!("    static {} = {};", member, value)
+            } else {
+                format!(
+                    "    static {} = ({}) => {};",
+                    member,
+                    fields.join(", "),
+                    value
+                )
+            }
+        }));
+        declaration.push("}".to_string());
+        Some(declaration)
+    }
+
+    fn is_variant(&self, value: &str, enumeration: &str, variant: &str) -> String {
+        format!(
+            "({} instanceof {} && {}._kox_variant === {})",
+            value,
+            enumeration,
+            value,
+            quote(variant)
+        )
+    }
+
+    fn variant_field(&self, value: &str, index: usize) -> String {
+        format!("{}._kox_values[{}]", value, index)
+    }
+
+    fn super_method(&self, _class: &str, superclass: &str, method: &str) -> Option<String> {
+        Some(format!("{}.prototype.{}.bind(self)", superclass, method))
+    }
+
+    fn range(&self, start: &str, end: &str, step: &str, inclusive: bool) -> Option<String> {
+        Some(format!(
+            "_kox_range({}, {}, {}, {})",
+            start, end, step, inclusive
+        ))
+    }
+
+    fn list(&self, elements: &[String]) -> Option<String> {
+        Some(format!("[{}]", elements.join(", ")))
+    }
+
+    fn map(&self, entries: &[(String, String)]) -> Option<String> {
+        if entries.is_empty() {
+            return Some("new _KoxMap()".to_string());
+        }
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(key, value)| format!("[{}, {}]", key, value))
+            .collect();
+        Some(format!("new _KoxMap([{}])", entries.join(", ")))
+    }
+
+    fn index(&self, object: &str, index: &str) -> Option<String> {
+        Some(format!("_kox_index({}, {})", object, index))
+    }
+
+    fn set_index(&self, object: &str, index: &str, value: &str) -> Option<String> {
+        Some(format!("_kox_set_index({}, {}, {})", object, index, value))
+    }
+
+    fn open(&self) -> Option<String> {
+        Some("{".to_string())
+    }
+
+    fn close(&self) -> Option<String> {
+        Some("}".to_string())
+    }
+
+    fn finish(&self, output: Output) -> String {
+        let mut source = String::new();
+        let structs = output
+            .body
+            .iter()
+            .any(|line| line.contains("_KoxStruct") || line.contains("_KoxObject"));
+        let enums = output.body.iter().any(|line| line.contains("_KoxVariant"));
+        if !output.defines_print {
+            source.push_str(PRELUDE);
+        }
+        if !output.defines_print || structs || enums {
+            source.push_str(STR);
+        }
+        if output.body.iter().any(|line| line.contains("_kox_range(")) {
+            source.push_str(RANGE);
+        }
+        if output.body.iter().any(|line| line.contains("_kox_eq(")) {
+            source.push_str(EQUALITY);
+        }
+        if output
+            .body
+            .iter()
+            .any(|line| line.contains("_kox_div(") || line.contains("_kox_idiv("))
+        {
+            source.push_str(DIVISION);
+        }
+        if output.body.iter().any(|line| line.contains("_kox_mod(")) {
+            source.push_str(MODULO);
+        }
+        if output
+            .body
+            .iter()
+            .any(|line| LIST_HELPERS.iter().any(|helper| line.contains(helper)))
+        {
+            source.push_str(LIST);
+        }
+        if output
+            .body
+            .iter()
+            .any(|line| MAP_HELPERS.iter().any(|helper| line.contains(helper)))
+        {
+            source.push_str(MAP);
+        }
+        if structs {
+            source.push_str(STRUCT);
+        }
+        if enums {
+            source.push_str(ENUM);
+        }
+        for line in output.body {
+            source.push_str(&line);
+            source.push('\n');
+        }
+        source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transpiler::Transpiler;
+
+    fn transpile(input: &str) -> String {
+        Transpiler::new(input.to_string(), Box::new(JavaScript))
+            .transpile()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn defaults_and_rest_are_native() {
+        let output = transpile("fn f(a, b = 1, ...r) { a }");
+        assert!(output.contains("function f(a, b = 1, ...r) {"));
+        // A default that needs statements is computed in the body instead.
+        let output = transpile("fn g(a, b = { let c = a; c }) { b }");
+        assert!(output.contains("function g(a, b) {"));
+        assert!(output.contains("if (b === undefined) {"));
+    }
+
+    #[test]
+    fn division_and_equality_keep_kox_semantics() {
+        let output = transpile("let x = 7; print(x / 2); print(x == 7.0);");
+        assert!(output.contains("print(_kox_idiv(x, 2));"));
+        assert!(output.contains("print(_kox_eq(x, 7.0));"));
+    }
+
+    #[test]
+    fn division_depends_on_what_is_known_of_the_operands() {
+        let output = transpile("let x = 7.0; print(x / 2); print(-x / 2 + 1);");
+        assert!(output.contains("print((x / 2));"));
+        assert!(output.contains("print((((-x) / 2) + 1));"));
+        // Parameters and assigned variables could hold either.
+        let output = transpile("fn f(n) { n / 2 } let y = 1; y = 1.5; print(y / 2);");
+        assert!(output.contains("return _kox_div(n, 2);"));
+        assert!(output.contains("print(_kox_div(y, 2));"));
+    }
+
+    #[test]
+    fn integers_must_fit_in_a_double() {
+        let output = transpile("print(9007199254740991);");
+        assert!(output.contains("print(9007199254740991);"));
+        let e = Transpiler::new("print(9007199254740992);".to_string(), Box::new(JavaScript))
+            .transpile()
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.message,
+            "integers beyond 53 bits are not supported when targeting JavaScript"
+        );
+    }
+
+    #[test]
+    fn for_loops_bind_a_new_variable_each_iteration() {
+        let output = transpile("for i in 0..2 { print(i); }");
+        assert!(output.contains("for (const i of _kox_range(0, 2, 1, false)) {"));
+    }
+}
@@ -0,0 +1,559 @@
+use crate::ast::Value;
+use crate::token::Token;
+
+use super::{quote, Backend, Output};
+
+/// Keywords, plus the builtins the prelude relies on so that the program
+/// cannot shadow them.
+const RESERVED: &[&str] = &[
+    "False",
+    "None",
+    "True",
+    "and",
+    "as",
+    "assert",
+    "async",
+    "await",
+    "break",
+    "builtins",
+    "callable",
+    "class",
+    "continue",
+    "decimal",
+    "def",
+    "del",
+    "elif",
+    "else",
+    "except",
+    "finally",
+    "float",
+    "for",
+    "format",
+    "from",
+    "global",
+    "if",
+    "import",
+    "in",
+    "int",
+    "is",
+    "isinstance",
+    "lambda",
+    "nonlocal",
+    "not",
+    "or",
+    "pass",
+    "raise",
+    "repr",
+    "return",
+    "str",
+    "try",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Kox integer division gives an integer, its values print differently from
+/// Python's, its ranges can include their end, its list and map functions
+/// are not methods, its structs and enums compare by value and its classes
+/// are initialized by `init`, so these go through helpers.
+const PRELUDE: &str = "import builtins
+import decimal
+
+
+def _kox_div(left, right):
+    if isinstance(left, int) and isinstance(right, int):
+        return left // right
+    return left / right
+
+
+def _kox_str(value):
+    if value is None:
+        return \"nil\"
+    if value is True:
+        return \"true\"
+    if value is False:
+        return \"false\"
+    if isinstance(value, float):
+        if value != value:
+            return \"NaN\"
+        if value in (float(\"inf\"), float(\"-inf\")):
+            return \"inf\" if value > 0 else \"-inf\"
+        text = format(decimal.Decimal(repr(value)), \"f\")
+        return text.rstrip(\"0\").rstrip(\".\") if \".\" in text else text
+    if isinstance(value, type) and issubclass(value, _KoxStruct):
+        return f\"<struct {value._kox_name}>\"
+    if isinstance(value, type) and issubclass(value, _KoxObject):
+        return f\"<class {value._kox_name}>\"
+    if isinstance(value, type) and issubclass(value, _KoxVariant):
+        return f\"<enum {value._kox_name}>\"
+    if callable(value):
+        return \"<function>\"
+    if isinstance(value, list):
+        return \"[\" + \", \".join(_kox_str(item) for item in value) + \"]\"
+    if isinstance(value, dict):
+        entries = (f\"{_kox_str(key)}: {_kox_str(item)}\" for key, item in value.items())
+        return \"{\" + \", \".join(entries) + \"}\"
+    return str(value)
+
+
+# Passed for parameters a call leaves out, whose defaults are computed in the
+# function since Python evaluates them only once.
+_kox_missing = object()
+
+
+class _KoxRange:
+    def __init__(self, start, end, step, inclusive):
+        self._kox_bounds = (start, end, step, inclusive)
+        if inclusive:
+            end += 1 if step > 0 else -1
+        self._kox_values = range(start, end, step)
+
+    def __iter__(self):
+        return iter(self._kox_values)
+
+    def __eq__(self, other):
+        return isinstance(other, _KoxRange) and self._kox_bounds == other._kox_bounds
+
+    def __str__(self):
+        start, end, step, inclusive = self._kox_bounds
+        text = f\"{start}{'..=' if inclusive else '..'}{end}\"
+        return text if step == 1 else f\"{text} step {step}\"
+
+
+def _kox_range(start, end, step, inclusive):
+    if step == 0:
+        raise ValueError(\"Range step cannot be zero\")
+    return _KoxRange(start, end, step, inclusive)
+
+
+def _kox_set_index(items, index, value):
+    items[index] = value
+    return value
+
+
+def _kox_push(items, value):
+    items.append(value)
+
+
+def _kox_pop(items):
+    return items.pop()
+
+
+def _kox_slice(items, start, end):
+    return items[start:end]
+
+
+def _kox_keys(entries):
+    return list(entries.keys())
+
+
+def _kox_values(entries):
+    return list(entries.values())
+
+
+def _kox_has(entries, key):
+    return key in entries
+
+
+def _kox_remove(entries, key):
+    return entries.pop(key, None)
+
+
+class _KoxStruct:
+    def __eq__(self, other):
+        return type(self) is type(other) and vars(self) == vars(other)
+
+    def __str__(self):
+        fields = \", \".join(f\"{name}: {_kox_str(value)}\" for name, value in vars(self).items())
+        return f\"{self._kox_name} {{ {fields} }}\" if fields else f\"{self._kox_name} {{}}\"
+
+
+class _KoxObject:
+    def __init__(self, *args):
+        if hasattr(self, \"init\"):
+            self.init(*args)
+        elif args:
+            raise TypeError(f\"Expected 0 arguments but got {len(args)}\")
+
+    def __str__(self):
+        return f\"<instance of {self._kox_name}>\"
+
+
+class _KoxVariant:
+    def __init__(self, variant, values):
+        self._kox_variant = variant
+        self._kox_values = values
+
+    def __eq__(self, other):
+        return type(self) is type(other) and (self._kox_variant, self._kox_values) == (
+            other._kox_variant,
+            other._kox_values,
+        )
+
+    def __str__(self):
+        if not self._kox_values:
+            return self._kox_variant
+        return f\"{self._kox_variant}({', '.join(_kox_str(value) for value in self._kox_values)})\"
+
+
+def _kox_set_field(instance, name, value):
+    setattr(instance, name, value)
+    return value
+";
+
+const PRINT: &str = "
+
+def print(value):
+    builtins.print(_kox_str(value))
+";
+
+pub struct Python;
+
+impl Backend for Python {
+    fn name(&self) -> &'static str {
+        "Python"
+    }
+
+    fn identifier(&self, ident: &str) -> String {
+        if RESERVED.contains(&ident) {
+            format!("{}_", ident)
+        } else {
+            ident.to_string()
+        }
+    }
+
+    fn builtin(&self, ident: &str) -> Option<String> {
+        match ident {
+            "print" | "len" => Some(ident.to_string()),
+            "push" | "pop" | "slice" | "keys" | "values" | "has" | "remove" => {
+                Some(format!("_kox_{}", ident))
+            }
+            _ => None,
+        }
+    }
+
+    fn value(&self, value: &Value) -> String {
+        match value {
+            Value::Int(i) => i.to_string(),
+            Value::BigInt(i) => i.to_string(),
+            Value::Float(f) => format!("{:?}", f),
+            Value::String(s) => quote(s),
+            Value::Boolean(true) => "True".to_string(),
+            Value::Boolean(false) => "False".to_string(),
+            Value::Nil => "None".to_string(),
+        }
+    }
+
+    fn integer_bits(&self) -> Option<u32> {
+        None
+    }
+
+    fn binary(&self, operator: &Token, left: &str, right: &str) -> String {
+        let operator = match operator {
+            Token::Slash => return format!("_kox_div({}, {})", left, right),
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Asterisk => "*",
+            Token::Percent => "%",
+            Token::Pow => "**",
+            Token::EqEq => "==",
+            Token::BangEq => "!=",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessThanEqual => "<=",
+            Token::GreaterThanEqual => ">=",
+            _ => panic!("Invalid operator: {:?}", operator),
+        };
+        format!("({} {} {})", left, operator, right)
+    }
+
+    fn unary(&self, operator: &Token, right: &str) -> String {
+        let operator = match operator {
+            Token::Minus => "-",
+            Token::Bang => "not ",
+            _ => panic!("Invalid unary operator: {:?}", operator),
+        };
+        format!("({}{})", operator, right)
+    }
+
+    fn logical(&self, operator: &Token, left: &str, right: &str) -> String {
+        let operator = match operator {
+            Token::And => "and",
+            Token::Or => "or",
+            _ => panic!("Invalid logical operator: {:?}", operator),
+        };
+        format!("({} {} {})", left, operator, right)
+    }
+
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("({} := {})", name, value)
+    }
+
+    fn statement(&self, expression: &str) -> String {
+        expression.to_string()
+    }
+
+    fn declare(&self, name: &str, value: &str) -> String {
+        format!("{} = {}", name, value)
+    }
+
+    fn temporary(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn if_header(&self, condition: &str) -> String {
+        format!("if {}:", condition)
+    }
+
+    fn else_header(&self) -> String {
+        "else:".to_string()
+    }
+
+    fn for_header(&self, ident: &str, iterable: &str) -> Option<String> {
+        Some(format!("for {} in {}:", ident, iterable))
+    }
+
+    fn for_entries_header(&self, key: &str, value: &str, iterable: &str) -> Option<String> {
+        Some(format!("for {}, {} in {}.items():", key, value, iterable))
+    }
+
+    fn while_header(&self, condition: &str) -> String {
+        format!("while {}:", condition)
+    }
+
+    fn function_header(&self, name: &str, parameters: &[String]) -> String {
+        format!("def {}({}):", name, parameters.join(", "))
+    }
+
+    fn optional_parameter(&self, name: &str) -> Option<String> {
+        Some(format!("{}=_kox_missing", name))
+    }
+
+    fn missing(&self, name: &str) -> String {
+        format!("{} is _kox_missing", name)
+    }
+
+    fn rest_parameter(&self, name: &str) -> Option<String> {
+        Some(format!("*{}", name))
+    }
+
+    fn rest_list(&self, name: &str) -> Option<String> {
+        Some(format!("{} = list({})", name, name))
+    }
+
+    fn struct_header(&self, name: &str, kox_name: &str, fields: &[String]) -> Option<Vec<String>> {
+        let mut parameters = vec!["self".to_string()];
+        parameters.extend(fields.iter().cloned());
+        let mut header = vec![
+            format!("class {}(_KoxStruct):", name),
+            format!("    _kox_name = {}", quote(kox_name)),
+            format!("    def __init__({}):", parameters.join(", ")),
+        ];
+        header.extend(
+            fields
+                .iter()
+                .map(|field| format!("        self.{} = {}", field, field)),
+        );
+        if fields.is_empty() {
+            header.push("        pass".to_string());
+        }
+        Some(header)
+    }
+
+    fn class_header(
+        &self,
+        name: &str,
+        kox_name: &str,
+        superclass: Option<&str>,
+    ) -> Option<Vec<String>> {
+        Some(vec![
+            format!("class {}({}):", name, superclass.unwrap_or("_KoxObject")),
+            format!("    _kox_name = {}", quote(kox_name)),
+        ])
+    }
+
+    fn method_header(&self, name: &str, parameters: &[String]) -> String {
+        let mut parameters = parameters.to_vec();
+        parameters.insert(0, "self".to_string());
+        self.function_header(name, &parameters)
+    }
+
+    fn construct(&self, name: &str, fields: &[String]) -> Option<String> {
+        Some(format!("{}({})", name, fields.join(", ")))
+    }
+
+    fn get(&self, object: &str, name: &str) -> Option<String> {
+        Some(format!("{}.{}", object, name))
+    }
+
+    fn set(&self, object: &str, name: &str, value: &str) -> Option<String> {
+        Some(format!(
+            "_kox_set_field({}, {}, {})",
+            object,
+            quote(name),
+            value
+        ))
+    }
+
+    fn enum_declaration(
+        &self,
+        name: &str,
+        kox_name: &str,
+        variants: &[(String, Vec<String>)],
+    ) -> Option<Vec<String>> {
+        let mut declaration = vec![
+            format!("class {}(_KoxVariant):", name),
+            format!("    _kox_name = {}", quote(kox_name)),
+        ];
+        // Variants without fields are instances of the class, so they can
+        // only be made once it is declared.
+        let mut values = vec![];
+        for (variant, fields) in variants {
+            let value = format!("{}({}, [{}])", name, quote(variant), fields.join(", "));
+            let member = self.identifier(variant);
+            if fields.is_empty() {
+                values.push(format!("{}.{} = {}", name, member, value));
+            } else {
+                declaration.push(format!(
+                    "    {} = staticmethod(lambda {}: {})",
+                    member,
+                    fields.join(", "),
+                    value
+                ));
+            }
+        }
+        declaration.extend(values);
+        Some(declaration)
+    }
+
+    fn is_variant(&self, value: &str, enumeration: &str, variant: &str) -> String {
+        format!(
+            "(isinstance({}, {}) and {}._kox_variant == {})",
+            value,
+            enumeration,
+            value,
+            quote(variant)
+        )
+    }
+
+    fn variant_field(&self, value: &str, index: usize) -> String {
+        format!("{}._kox_values[{}]", value, index)
+    }
+
+    fn super_method(&self, class: &str, _superclass: &str, method: &str) -> Option<String> {
+        Some(format!("super({}, self).{}", class, method))
+    }
+
+    fn range(&self, start: &str, end: &str, step: &str, inclusive: bool) -> Option<String> {
+        let inclusive = if inclusive { "True" } else { "False" };
+        Some(format!(
+            "_kox_range({}, {}, {}, {})",
+            start, end, step, inclusive
+        ))
+    }
+
+    fn list(&self, elements: &[String]) -> Option<String> {
+        Some(format!("[{}]", elements.join(", ")))
+    }
+
+    fn map(&self, entries: &[(String, String)]) -> Option<String> {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect();
+        Some(format!("{{{}}}", entries.join(", ")))
+    }
+
+    fn index(&self, object: &str, index: &str) -> Option<String> {
+        Some(format!("{}[{}]", object, index))
+    }
+
+    fn set_index(&self, object: &str, index: &str, value: &str) -> Option<String> {
+        Some(format!("_kox_set_index({}, {}, {})", object, index, value))
+    }
+
+    fn open(&self) -> Option<String> {
+        None
+    }
+
+    fn close(&self) -> Option<String> {
+        None
+    }
+
+    fn empty(&self) -> Option<String> {
+        Some("pass".to_string())
+    }
+
+    fn nonlocal(&self, name: &str, global: bool) -> Option<String> {
+        if global {
+            Some(format!("global {}", name))
+        } else {
+            Some(format!("nonlocal {}", name))
+        }
+    }
+
+    fn late_binding(&self) -> bool {
+        true
+    }
+
+    /// Cells are bound as keyword-only default arguments, which are
+    /// evaluated when the function is defined.
+    fn captures(&self, parameters: &[String], cells: &[String]) -> Vec<String> {
+        let mut parameters = parameters.to_vec();
+        if !parameters
+            .iter()
+            .any(|parameter| parameter.starts_with('*'))
+        {
+            parameters.push("*".to_string());
+        }
+        parameters.extend(cells.iter().map(|cell| format!("{}={}", cell, cell)));
+        parameters
+    }
+
+    fn finish(&self, output: Output) -> String {
+        let mut source = String::from(PRELUDE);
+        if !output.defines_print {
+            source.push_str(PRINT);
+        }
+        source.push_str("\n\n");
+        for line in output.body {
+            source.push_str(&line);
+            source.push('\n');
+        }
+        source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transpiler::Transpiler;
+
+    fn transpile(input: &str) -> String {
+        Transpiler::new(input.to_string(), Box::new(Python))
+            .transpile()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn defaults_are_computed_at_each_call() {
+        let output = transpile("fn f(a, b = [a], ...r) { b }");
+        assert!(output.contains("def f(a, b=_kox_missing, *r):"));
+        assert!(output.contains("    if b is _kox_missing:\n"));
+        assert!(output.contains("    r = list(r)\n"));
+    }
+
+    #[test]
+    fn closures_made_in_a_loop_get_their_own_cells() {
+        let output = transpile("let fs = []; for i in 0..2 { push(fs, fn() { i }); }");
+        assert!(output.contains("    i = [i]\n"));
+        assert!(output.contains("def _t1(*, i=i):"));
+        assert!(output.contains("return i[0]"));
+    }
+
+    #[test]
+    fn assigning_an_outer_variable_declares_it_nonlocal() {
+        let output = transpile("fn counter() { let n = 0; fn() { n = n + 1; n } }");
+        assert!(output.contains("nonlocal n"));
+    }
+}
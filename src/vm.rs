@@ -0,0 +1,551 @@
+//! A stack VM for the bytecode produced by [`crate::compiler`].
+//!
+//! Each call gets a frame whose local slots sit at the bottom of its part of
+//! the value stack, with the operands of the instructions above them.
+//! Variables are scoped lexically, so assignments to a variable of an
+//! enclosing block or function are visible to it.
+
+use std::{cell::RefCell, rc::Rc};
+
+use indexmap::IndexMap;
+
+use crate::{
+    bytecode::{Constant, Instruction, Program, Prototype},
+    interpreter::{self, Arithmetic, Arity, Entries, KoxValue, MapKey, RuntimeError},
+    token::Token,
+};
+
+/// Deepest call stack allowed before reporting a stack overflow.
+const FRAMES_MAX: usize = 1 << 16;
+
+/// A variable captured by a closure. It points into the stack while the
+/// variable's scope is alive and owns the value afterwards.
+pub enum Upvalue {
+    Open(usize),
+    Closed(KoxValue),
+}
+
+pub struct Closure {
+    pub prototype: Rc<Prototype>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base: usize,
+}
+
+pub struct Vm {
+    stack: Vec<KoxValue>,
+    frames: Vec<Frame>,
+    globals: Vec<Option<KoxValue>>,
+    global_names: Vec<String>,
+    /// Upvalues still pointing into the stack, to share between closures
+    /// capturing the same variable.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    arithmetic: Arithmetic,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self {
+            stack: vec![],
+            frames: vec![],
+            globals: vec![],
+            global_names: vec![],
+            open_upvalues: vec![],
+            arithmetic: Arithmetic::default(),
+        }
+    }
+
+    pub fn set_arithmetic(&mut self, arithmetic: Arithmetic) {
+        self.arithmetic = arithmetic;
+    }
+
+    pub fn run(&mut self, program: &Program) -> Result<KoxValue, RuntimeError> {
+        let builtins = interpreter::builtins();
+        self.global_names = program.globals.clone();
+        self.globals = program
+            .globals
+            .iter()
+            .map(|name| {
+                builtins
+                    .iter()
+                    .find(|(builtin, _)| builtin == name)
+                    .map(|(_, function)| KoxValue::NativeFunction(function.clone()))
+            })
+            .collect();
+
+        let main = Rc::new(Closure {
+            prototype: program.main.clone(),
+            upvalues: vec![],
+        });
+        self.stack.clear();
+        self.stack.push(KoxValue::Closure(main.clone()));
+        self.push_frame(main, 0);
+
+        let result = self.execute();
+        if result.is_err() {
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    /// Reserves the local slots of a call whose callee and arguments are
+    /// already on the stack.
+    fn push_frame(&mut self, closure: Rc<Closure>, arguments: usize) {
+        let base = self.stack.len() - arguments - 1;
+        let slots = closure.prototype.slots as usize;
+        self.stack
+            .resize(base + slots.max(arguments + 1), KoxValue::Nil);
+        self.frames.push(Frame {
+            closure,
+            ip: 0,
+            base,
+        });
+    }
+
+    fn error(&self, message: String) -> RuntimeError {
+        let (line, column) = match self.frames.last() {
+            Some(frame) => frame.closure.prototype.chunk.positions[frame.ip - 1],
+            None => (0, 0),
+        };
+        RuntimeError {
+            message,
+            line,
+            column,
+        }
+    }
+
+    fn pop(&mut self) -> KoxValue {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn peek(&self) -> &KoxValue {
+        self.stack.last().expect("value stack underflow")
+    }
+
+    fn capture(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot) {
+                return upvalue.clone();
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Closes the upvalues of every stack slot from `from` upwards.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= from => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn binary(&mut self, operator: &Token) -> Result<(), RuntimeError> {
+        let right = self.pop();
+        let left = self.pop();
+        let result = interpreter::binary(left, operator, right, self.arithmetic)
+            .map_err(|e| self.error(e))?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn unary(&mut self, operator: &Token) -> Result<(), RuntimeError> {
+        let right = self.pop();
+        let result =
+            interpreter::unary(operator, right, self.arithmetic).map_err(|e| self.error(e))?;
+        self.stack.push(result);
+        Ok(())
+    }
+
+    fn call(&mut self, arguments: usize) -> Result<(), RuntimeError> {
+        let callee = self.stack[self.stack.len() - arguments - 1].clone();
+        let arity = match &callee {
+            KoxValue::Closure(closure) => {
+                let prototype = &closure.prototype;
+                let count = prototype.parameters.len();
+                Arity {
+                    min: count + 1 - prototype.entries.len(),
+                    max: (!prototype.rest).then_some(count),
+                }
+            }
+            KoxValue::NativeFunction(function) => Arity::exactly(function.arity as usize),
+            _ => return Err(self.error(format!("Can only call functions! Not {}", callee))),
+        };
+        if !arity.accepts(arguments) {
+            // Like the interpreter, name the first parameter left out.
+            let message = match &callee {
+                KoxValue::Closure(closure) if arguments < arity.min => format!(
+                    "Missing argument for parameter '{}'",
+                    closure.prototype.parameters[arguments]
+                ),
+                _ => arity.mismatch(arguments),
+            };
+            return Err(self.error(message));
+        }
+
+        match callee {
+            KoxValue::Closure(closure) => {
+                if self.frames.len() >= FRAMES_MAX {
+                    return Err(self.error("Stack overflow".to_string()));
+                }
+                // Parameters left out start as nil until the code at the
+                // entry computes their defaults.
+                let count = closure.prototype.parameters.len();
+                let given = arguments.min(count);
+                let entry = closure.prototype.entries[given - arity.min] as usize;
+                let arguments = if closure.prototype.rest {
+                    let further = self.stack.split_off(self.stack.len() - (arguments - given));
+                    self.stack
+                        .resize(self.stack.len() + count - given, KoxValue::Nil);
+                    self.stack
+                        .push(KoxValue::List(Rc::new(RefCell::new(further))));
+                    count + 1
+                } else {
+                    arguments
+                };
+                self.push_frame(closure, arguments);
+                self.frames.last_mut().unwrap().ip = entry;
+            }
+            KoxValue::NativeFunction(function) => {
+                let args = self.stack.split_off(self.stack.len() - arguments);
+                self.pop();
+                let result = (function.callable)(&args).map_err(|e| self.error(e))?;
+                self.stack.push(result);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<KoxValue, RuntimeError> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let instruction = frame.closure.prototype.chunk.code[frame.ip];
+            frame.ip += 1;
+            let base = frame.base;
+
+            match instruction {
+                Instruction::Constant(index) => {
+                    let frame = self.frames.last().unwrap();
+                    let value = match &frame.closure.prototype.chunk.constants[index as usize] {
+                        Constant::Int(i) => KoxValue::Int(*i),
+                        Constant::BigInt(i) => KoxValue::BigInt(i.clone()),
+                        Constant::Float(f) => KoxValue::Float(*f),
+                        Constant::String(s) => KoxValue::String(s.clone()),
+                        Constant::Function(_) => unreachable!("functions are loaded by Closure"),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::Nil => self.stack.push(KoxValue::Nil),
+                Instruction::True => self.stack.push(KoxValue::Boolean(true)),
+                Instruction::False => self.stack.push(KoxValue::Boolean(false)),
+                Instruction::Pop => {
+                    self.pop();
+                }
+                Instruction::GetLocal(slot) => {
+                    let value = self.stack[base + slot as usize].clone();
+                    self.stack.push(value);
+                }
+                Instruction::SetLocal(slot) => {
+                    self.stack[base + slot as usize] = self.peek().clone();
+                }
+                Instruction::GetUpvalue(index) => {
+                    let frame = self.frames.last().unwrap();
+                    let value = match &*frame.closure.upvalues[index as usize].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::SetUpvalue(index) => {
+                    let value = self.peek().clone();
+                    let frame = self.frames.last().unwrap();
+                    let mut upvalue = frame.closure.upvalues[index as usize].borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Instruction::GetGlobal(index) => match &self.globals[index as usize] {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => {
+                        return Err(self.error(format!(
+                            "undefined variable referenced '{}'",
+                            self.global_names[index as usize]
+                        )))
+                    }
+                },
+                Instruction::SetGlobal(index) => {
+                    if self.globals[index as usize].is_none() {
+                        return Err(self.error(format!(
+                            "Undefined variable '{}'",
+                            self.global_names[index as usize]
+                        )));
+                    }
+                    self.globals[index as usize] = Some(self.peek().clone());
+                }
+                Instruction::DefineGlobal(index) => {
+                    self.globals[index as usize] = Some(self.peek().clone());
+                }
+                Instruction::Add => self.binary(&Token::Plus)?,
+                Instruction::Subtract => self.binary(&Token::Minus)?,
+                Instruction::Multiply => self.binary(&Token::Asterisk)?,
+                Instruction::Divide => self.binary(&Token::Slash)?,
+                Instruction::Modulo => self.binary(&Token::Percent)?,
+                Instruction::Power => self.binary(&Token::Pow)?,
+                Instruction::Equal => self.binary(&Token::EqEq)?,
+                Instruction::NotEqual => self.binary(&Token::BangEq)?,
+                Instruction::Less => self.binary(&Token::LessThan)?,
+                Instruction::Greater => self.binary(&Token::GreaterThan)?,
+                Instruction::LessEqual => self.binary(&Token::LessThanEqual)?,
+                Instruction::GreaterEqual => self.binary(&Token::GreaterThanEqual)?,
+                Instruction::Negate => self.unary(&Token::Minus)?,
+                Instruction::Not => self.unary(&Token::Bang)?,
+                Instruction::Jump(target) => {
+                    self.frames.last_mut().unwrap().ip = target as usize;
+                }
+                Instruction::JumpIfFalse(target) => match self.pop() {
+                    KoxValue::Boolean(true) => {}
+                    KoxValue::Boolean(false) => {
+                        self.frames.last_mut().unwrap().ip = target as usize;
+                    }
+                    _ => return Err(self.error("Condition must be a boolean".to_string())),
+                },
+                Instruction::Call(arguments) => self.call(arguments as usize)?,
+                Instruction::Closure(index) => {
+                    let frame = self.frames.last().unwrap();
+                    let prototype = match &frame.closure.prototype.chunk.constants[index as usize] {
+                        Constant::Function(prototype) => prototype.clone(),
+                        constant => unreachable!("Closure of {:?}", constant),
+                    };
+                    let enclosing = frame.closure.clone();
+                    let upvalues = prototype
+                        .upvalues
+                        .iter()
+                        .map(|capture| {
+                            if capture.local {
+                                self.capture(base + capture.index as usize)
+                            } else {
+                                enclosing.upvalues[capture.index as usize].clone()
+                            }
+                        })
+                        .collect();
+                    self.stack.push(KoxValue::Closure(Rc::new(Closure {
+                        prototype,
+                        upvalues,
+                    })));
+                }
+                Instruction::BuildList(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count as usize);
+                    self.stack
+                        .push(KoxValue::List(Rc::new(RefCell::new(elements))));
+                }
+                Instruction::BuildMap(count) => {
+                    let entries = self.stack.split_off(self.stack.len() - 2 * count as usize);
+                    let mut map = IndexMap::new();
+                    for entry in entries.chunks(2) {
+                        let key = MapKey::new(entry[0].clone()).map_err(|e| self.error(e))?;
+                        map.insert(key, entry[1].clone());
+                    }
+                    self.stack.push(KoxValue::Map(Rc::new(RefCell::new(map))));
+                }
+                Instruction::BuildRange(inclusive) => {
+                    let step = self.pop();
+                    let end = self.pop();
+                    let start = self.pop();
+                    let range = interpreter::range(start, end, step, inclusive)
+                        .map_err(|e| self.error(e))?;
+                    self.stack.push(range);
+                }
+                Instruction::GetIndex => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value =
+                        interpreter::get_index(&object, &index).map_err(|e| self.error(e))?;
+                    self.stack.push(value);
+                }
+                Instruction::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = interpreter::set_index(&object, &index, value)
+                        .map_err(|e| self.error(e))?;
+                    self.stack.push(value);
+                }
+                Instruction::GetIter => {
+                    let iterable = self.pop();
+                    let values = interpreter::iterate(iterable).map_err(|e| self.error(e))?;
+                    let entries: Entries = Box::new(values.map(|value| (value, KoxValue::Nil)));
+                    self.stack
+                        .push(KoxValue::Iterator(Rc::new(RefCell::new(entries))));
+                }
+                Instruction::GetEntries => {
+                    let iterable = self.pop();
+                    let entries = interpreter::entries(iterable).map_err(|e| self.error(e))?;
+                    self.stack
+                        .push(KoxValue::Iterator(Rc::new(RefCell::new(entries))));
+                }
+                Instruction::ForIter(target) => {
+                    let next = match self.peek() {
+                        KoxValue::Iterator(iterator) => iterator.borrow_mut().next(),
+                        value => return Err(self.error(format!("Can't iterate over {}", value))),
+                    };
+                    match next {
+                        Some((value, entry)) => {
+                            self.stack.push(value);
+                            self.stack.push(entry);
+                        }
+                        None => self.frames.last_mut().unwrap().ip = target as usize,
+                    }
+                }
+                Instruction::CloseUpvalues(slot) => self.close_upvalues(base + slot as usize),
+                Instruction::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(base);
+                    self.frames.pop();
+                    self.stack.truncate(base);
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.stack.push(result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, parsed};
+
+    fn execute(input: &str, arithmetic: Arithmetic) -> Result<Vm, RuntimeError> {
+        let program = Compiler::new()
+            .compile(&parsed(input))
+            .unwrap_or_else(|e| panic!("{}", e));
+        let mut vm = Vm::new();
+        vm.set_arithmetic(arithmetic);
+        vm.run(&program)?;
+        Ok(vm)
+    }
+
+    /// The value `input` leaves in the global `result`, as `print` shows it.
+    fn run(input: &str) -> String {
+        let vm = execute(input, Arithmetic::default()).unwrap_or_else(|e| panic!("{}", e));
+        let index = vm
+            .global_names
+            .iter()
+            .position(|name| name == "result")
+            .expect("the program sets `result`");
+        match &vm.globals[index] {
+            Some(value) => value.to_string(),
+            None => panic!("`result` is undefined"),
+        }
+    }
+
+    fn error(input: &str) -> String {
+        match execute(input, Arithmetic::default()) {
+            Ok(_) => panic!("ran without an error"),
+            Err(e) => e.message,
+        }
+    }
+
+    #[test]
+    fn arithmetic_and_strings() {
+        assert_eq!(run("let result = 1 + 2 * 3 - 8 / 2;"), "3");
+        assert_eq!(run("let result = \"a\" + \"b\";"), "ab");
+        let overflow = "let result = 9223372036854775807 + 1;";
+        assert_eq!(run(overflow), "9223372036854775808");
+        let e = execute(overflow, Arithmetic::Checked).err().unwrap();
+        assert_eq!(e.message, "Integer overflow");
+    }
+
+    #[test]
+    fn recursion() {
+        let fib = "
+            fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+            let result = fib(15);";
+        assert_eq!(run(fib), "610");
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let counter = "
+            fn counter() { let n = 0; fn() { n = n + 1; n } }
+            let c = counter();
+            c();
+            let result = c();";
+        assert_eq!(run(counter), "2");
+    }
+
+    #[test]
+    fn loops_give_each_iteration_its_own_variables() {
+        let loops = "
+            let fs = [];
+            for i in 0..3 { push(fs, fn() { i }); }
+            let result = [fs[0](), fs[2]()];";
+        assert_eq!(run(loops), "[0, 2]");
+        let entries = "
+            let result = 0;
+            for (k, v) in {\"a\": 1, \"b\": 2} { result = result + v; }";
+        assert_eq!(run(entries), "3");
+    }
+
+    #[test]
+    fn break_and_continue_keep_the_stack_balanced() {
+        let loops = "
+            let i = 0;
+            let result = 1 + (while true {
+                i = i + 1;
+                if i < 3 { continue; }
+                break i * 10;
+            });";
+        assert_eq!(run(loops), "31");
+    }
+
+    #[test]
+    fn default_and_rest_parameters() {
+        let defaults = "
+            fn f(a, b = a * 2, ...rest) { [a, b, rest] }
+            let result = [f(1), f(1, 5), f(1, 5, 6, 7)];";
+        assert_eq!(run(defaults), "[[1, 2, []], [1, 5, []], [1, 5, [6, 7]]]");
+        assert_eq!(
+            error("fn f(a, b = 1) { a } f();"),
+            "Missing argument for parameter 'a'"
+        );
+        assert_eq!(
+            error("fn f(a, ...b) { a } f();"),
+            "Missing argument for parameter 'a'"
+        );
+        assert_eq!(
+            error("fn f(a, b) { a } f(1);"),
+            "Missing argument for parameter 'b'"
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(error("let x = 1; x();"), "Can only call functions! Not 1");
+        assert_eq!(
+            error("for x in 1 { x; }"),
+            "Can only iterate over ranges, strings, lists and maps! Not 1"
+        );
+        assert_eq!(
+            error("fn f(a) { a } f(1, 2);"),
+            "Expected 1 arguments but got 2"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Runs the same programs through every way `pulsar` can run them: the
+//! tree-walking interpreter, the JIT, the bytecode VM, compiled `.koxc`
+//! files, the JavaScript, Python and C transpilers and native objects.
+//! Targets whose tools (`node`, `python3`, `cc`) are missing are skipped.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Mode {
+    Interpreter,
+    Jit,
+    Vm,
+    Koxc,
+    JavaScript,
+    Python,
+    C,
+    Native,
+}
+
+use Mode::*;
+
+const ALL: &[Mode] = &[Interpreter, Jit, Vm, Koxc, JavaScript, Python, C, Native];
+
+/// A directory of its own for each test, since running a program writes
+/// `ast` to the current directory.
+fn workdir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pulsar-parity-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn pulsar(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pulsar"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// Runs `command` in `dir`, or gives `None` if it isn't installed.
+fn external(dir: &Path, command: &str, args: &[&str]) -> Option<Output> {
+    match Command::new(command).args(args).current_dir(dir).output() {
+        Ok(output) => Some(output),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => panic!("running {}: {}", command, e),
+    }
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Transpiles `file` to `target`, failing the test on any error.
+fn transpile(dir: &Path, file: &str, target: &str, output: &str) {
+    let result = pulsar(dir, &["transpile", file, "--target", target, "-o", output]);
+    assert!(
+        result.stderr.is_empty(),
+        "transpiling to {}: {}",
+        target,
+        text(&result.stderr)
+    );
+}
+
+/// Compiles C `sources` to `executable` and runs it.
+fn cc(dir: &Path, sources: &[&str], executable: &str) -> Option<Output> {
+    let mut args = sources.to_vec();
+    args.extend(["-o", executable, "-lm"]);
+    let compiled = external(dir, "cc", &args)?;
+    assert!(compiled.status.success(), "cc: {}", text(&compiled.stderr));
+    Some(Command::new(dir.join(executable)).output().unwrap())
+}
+
+/// The output of running `source` in `mode`, stdout and stderr, or `None`
+/// if the tools the mode needs are missing.
+fn run(name: &str, source: &str, mode: Mode) -> Option<(String, String)> {
+    let dir = workdir(&format!("{}-{:?}", name, mode));
+    fs::write(dir.join("main.kox"), source).unwrap();
+    let output = match mode {
+        Interpreter => pulsar(&dir, &["main.kox", "--no-jit"]),
+        Jit => pulsar(&dir, &["main.kox", "--jit"]),
+        Vm => pulsar(&dir, &["main.kox", "--vm"]),
+        Koxc => {
+            let compiled = pulsar(&dir, &["compile", "main.kox"]);
+            assert!(compiled.stderr.is_empty(), "{}", text(&compiled.stderr));
+            pulsar(&dir, &["main.koxc"])
+        }
+        JavaScript => {
+            transpile(&dir, "main.kox", "js", "main.js");
+            external(&dir, "node", &["main.js"])?
+        }
+        Python => {
+            transpile(&dir, "main.kox", "py", "main.py");
+            external(&dir, "python3", &["main.py"])?
+        }
+        C => {
+            transpile(&dir, "main.kox", "c", "main.c");
+            cc(&dir, &["main.c"], "main")?
+        }
+        Native => {
+            let built = pulsar(&dir, &["build", "main.kox"]);
+            assert!(built.stderr.is_empty(), "{}", text(&built.stderr));
+            cc(&dir, &["main.o"], "main")?
+        }
+    };
+    fs::remove_dir_all(&dir).unwrap();
+    Some((text(&output.stdout), text(&output.stderr)))
+}
+
+/// Checks that `source` prints `expected` in every mode of `modes`.
+fn check(name: &str, source: &str, expected: &str, modes: &[Mode]) {
+    for &mode in modes {
+        let Some((stdout, stderr)) = run(name, source, mode) else {
+            eprintln!("skipping {} in {:?}: missing tools", name, mode);
+            continue;
+        };
+        assert_eq!(stderr, "", "{} failed in {:?}", name, mode);
+        assert_eq!(
+            stdout, expected,
+            "{} printed the wrong output in {:?}",
+            name, mode
+        );
+    }
+}
+
+/// Checks that `source` stops with the runtime error `expected` after
+/// printing `printed`, in each of the ways `pulsar` runs programs itself.
+fn check_error(name: &str, source: &str, printed: &str, expected: &str, modes: &[Mode]) {
+    for &mode in modes {
+        let (stdout, stderr) = run(name, source, mode).unwrap();
+        assert_eq!(
+            stdout, printed,
+            "{} printed the wrong output in {:?}",
+            name, mode
+        );
+        assert_eq!(
+            stderr,
+            format!("runtime error: {}\n", expected),
+            "{} gave the wrong error in {:?}",
+            name,
+            mode
+        );
+    }
+}
+
+#[test]
+fn arithmetic() {
+    let source = r#"
+        print(1 + 2 * 3);
+        print(7 / 2);
+        print(7.0 / 2);
+        print(10 / 4.0);
+        print(1 / 3.0);
+        print(-7 % 3);
+        print(2 ^ 10);
+        print(2 ^ -1);
+        print(1.5 * 4);
+        print(1 < 2);
+        print(3 == 3.0);
+        print("kox" + "!");
+    "#;
+    check(
+        "arithmetic",
+        source,
+        "7\n3\n3.5\n2.5\n0.3333333333333333\n2\n1024\n0.5\n6\ntrue\ntrue\nkox!\n",
+        ALL,
+    );
+}
+
+#[test]
+fn recursion() {
+    let source = r#"
+        fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+        fn even(n) { if n == 0 { true } else { odd(n - 1) } }
+        fn odd(n) { if n == 0 { false } else { even(n - 1) } }
+        print(fib(20));
+        print(even(10));
+    "#;
+    check("recursion", source, "6765\ntrue\n", ALL);
+}
+
+#[test]
+fn branches_of_different_types() {
+    let source = r#"
+        fn scale(n) { if n > 0 { n * 1.5 } else { 0 } }
+        fn positive(n) { if n > 0 { 1 } }
+        print(scale(2));
+        print(scale(-1));
+        print(positive(1));
+        print(positive(-1));
+    "#;
+    check(
+        "branches",
+        source,
+        "3\n0\n1\nnil\n",
+        &[Interpreter, Jit, Vm, Koxc, JavaScript, Python, C],
+    );
+}
+
+#[test]
+fn loops() {
+    let source = r#"
+        let i = 0;
+        let total = 0;
+        while i < 10 {
+            i = i + 1;
+            if i % 2 == 0 { continue; }
+            if i > 7 { break; }
+            total = total + i;
+        }
+        print(total);
+        for j in 0..3 { print(j); }
+        let found = (while true { break 42; });
+        print(found);
+    "#;
+    check(
+        "loops",
+        source,
+        "16\n0\n1\n2\n42\n",
+        &[Interpreter, Jit, Vm, Koxc, JavaScript, Python],
+    );
+}
+
+#[test]
+fn closures() {
+    let source = r#"
+        fn counter() {
+            let n = 0;
+            fn() { n = n + 1; n }
+        }
+        let c = counter();
+        c();
+        print(c());
+        let fs = [];
+        for i in 0..3 { push(fs, fn(x = i) { x * 10 }); }
+        print(fs[0]() + fs[1]() + fs[2]());
+        let adders = [];
+        let k = 0;
+        while k < 2 {
+            let captured = k;
+            push(adders, fn(y) { y + captured });
+            k = k + 1;
+        }
+        print(adders[1](5));
+    "#;
+    check(
+        "closures",
+        source,
+        "2\n30\n6\n",
+        &[Interpreter, Jit, Vm, Koxc, JavaScript, Python],
+    );
+}
+
+#[test]
+fn scopes() {
+    let source = r#"
+        fn outer() { fn a() { b() } fn b() { 7 } a() }
+        print(outer());
+        fn shadow() {
+            let x = 1;
+            { print(x); let x = 2; print(x); };
+            x
+        }
+        print(shadow());
+        fn later() { { fn a() { y } let y = 3; a() } }
+        print(later());
+    "#;
+    check(
+        "scopes",
+        source,
+        "7\n1\n2\n1\n3\n",
+        &[Interpreter, Jit, Vm, Koxc, JavaScript, Python],
+    );
+}
+
+#[test]
+fn collections() {
+    let source = r#"
+        let m = {"a": 1, "b": 2};
+        m["c"] = 3;
+        let sum = 0;
+        for (key, value) in m { sum = sum + value; }
+        print(sum);
+        print(len([1, 2, 3]));
+        print(0..=3);
+    "#;
+    check(
+        "collections",
+        source,
+        "6\n3\n0..=3\n",
+        &[Interpreter, Jit, Vm, Koxc, JavaScript, Python],
+    );
+}
+
+#[test]
+fn structs_classes_and_enums() {
+    let source = r#"
+        struct Point { x, y, fn sum() { self.x + self.y } }
+        print(Point { x: 1, y: 2 }.sum());
+        class Animal {
+            fn init(name) { this.name = name; }
+            fn speak() { this.name + " makes a sound" }
+        }
+        class Dog < Animal {
+            fn speak() { super.speak() + ", woof" }
+        }
+        print(Dog("Rex").speak());
+        enum Shape { Circle(r), Square(s) }
+        fn area(shape) {
+            match shape { Shape.Circle(r) => 3 * r * r, Shape.Square(s) => s * s }
+        }
+        print(area(Shape.Circle(2)) + area(Shape.Square(3)));
+    "#;
+    check(
+        "classes",
+        source,
+        "3\nRex makes a sound, woof\n21\n",
+        &[Interpreter, Jit, JavaScript, Python],
+    );
+}
+
+#[test]
+fn integer_overflow() {
+    let source = "print(1);\nlet big = 9223372036854775807;\nprint(big + 1);\n";
+    check(
+        "overflow",
+        source,
+        "1\n9223372036854775808\n",
+        &[Interpreter, Jit, Vm, Koxc],
+    );
+    let dir = workdir("checked");
+    fs::write(dir.join("main.kox"), source).unwrap();
+    for mode in ["--no-jit", "--jit", "--vm"] {
+        let output = pulsar(&dir, &["main.kox", "--checked", mode]);
+        assert_eq!(text(&output.stdout), "1\n", "{}", mode);
+        assert_eq!(
+            text(&output.stderr),
+            "runtime error: Runtime error at line 3 column 14: Integer overflow\n",
+            "{}",
+            mode
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn default_and_rest_parameters() {
+    let source = r#"
+        fn fib(n, a = 0, b = 1) {
+            if n == 0 { a } else { fib(n - 1, b, a + b) }
+        }
+        fn scale(x, by = 2, offset = by + 1) { x * by + offset }
+        print(fib(30));
+        print(scale(1));
+        print(scale(1, 3));
+        print(scale(1, 3, 0));
+    "#;
+    check("defaults", source, "832040\n5\n7\n3\n", ALL);
+    let source = r#"
+        fn collect(first, ...others) { push(others, first); others }
+        print(collect(1));
+        print(collect(1, 2, 3));
+    "#;
+    check(
+        "rest",
+        source,
+        "[1]\n[2, 3, 1]\n",
+        &[Interpreter, Jit, Vm, Koxc, JavaScript, Python],
+    );
+    check_error(
+        "arity",
+        "fn f(a, b = 1) { a }\nf(1, 2, 3);\n",
+        "",
+        "Runtime error at line 2 column 11: Expected 1 to 2 arguments but got 3",
+        &[Interpreter, Jit, Vm, Koxc],
+    );
+    check_error(
+        "missing",
+        "fn f(a, b = 1) { a }\nf();\n",
+        "",
+        "Runtime error at line 2 column 4: Missing argument for parameter 'a'",
+        &[Interpreter, Jit, Vm, Koxc],
+    );
+}
+
+#[test]
+fn compiling_ignores_flags_for_running() {
+    let dir = workdir("compile-flags");
+    fs::write(dir.join("main.kox"), "print(1);").unwrap();
+    let compiled = pulsar(
+        &dir,
+        &["compile", "main.kox", "--wrapping", "-o", "out.koxc"],
+    );
+    assert!(compiled.stderr.is_empty(), "{}", text(&compiled.stderr));
+    let output = pulsar(&dir, &["out.koxc", "--wrapping"]);
+    assert_eq!(text(&output.stdout), "1\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn damaged_koxc_files_are_rejected() {
+    let dir = workdir("damaged");
+    fs::write(dir.join("main.kox"), "print(\"hello\");").unwrap();
+    let compiled = pulsar(&dir, &["compile", "main.kox"]);
+    assert!(compiled.stderr.is_empty(), "{}", text(&compiled.stderr));
+    assert_eq!(text(&pulsar(&dir, &["main.koxc"]).stdout), "hello\n");
+
+    let mut bytes = fs::read(dir.join("main.koxc")).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last - 10] ^= 1;
+    fs::write(dir.join("main.koxc"), &bytes).unwrap();
+    let output = pulsar(&dir, &["main.koxc"]);
+    assert_eq!(text(&output.stdout), "");
+    assert!(
+        text(&output.stderr).contains("checksum mismatch, the file is damaged"),
+        "{}",
+        text(&output.stderr)
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn repl_keeps_variables_between_lines() {
+    let dir = workdir("repl");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pulsar"))
+        .current_dir(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"let x = 20\nx + 1\nfn(a = 2) { a * x }()\ny\nx\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(text(&output.stdout), "20\n21\n40\n20\n");
+    assert!(
+        text(&output.stderr).contains("undefined variable 'y'"),
+        "{}",
+        text(&output.stderr)
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn repl_lines_can_declare_names_again() {
+    let dir = workdir("repl-again");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_pulsar"))
+        .current_dir(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b"let x = 1\nlet x = x + 1\nfn f() { 1 }\nfn f() { 2 }\nf()\nlet z = 1 / 0\nz\nx\n",
+        )
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(text(&output.stdout), "1\n2\nnil\nnil\n2\n2\n");
+    let errors = text(&output.stderr);
+    assert!(errors.contains("Division by zero"), "{}", errors);
+    assert!(errors.contains("undefined variable 'z'"), "{}", errors);
+    fs::remove_dir_all(&dir).unwrap();
+}